@@ -1,6 +1,11 @@
 use bytes::Bytes;
 use criterion::{criterion_group, criterion_main, Criterion};
 use rand::Rng;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use zap::{db::Db, options::Opts};
 
 pub fn get_test_key(i: u32) -> Bytes {
@@ -17,13 +22,13 @@ pub fn get_test_value(i: u32) -> Bytes {
 fn benchmark_put(c: &mut Criterion) {
     let options = Opts::new(
         256,
-        1024,
+        2048,
         false,
         true,
         "/tmp/bitcask-rs-bench".to_string(),
         256 * 1024 * 1024,
     );
-    let mut engine = Db::open(&options).unwrap();
+    let engine = Db::open(&options).unwrap();
 
     let mut rnd: rand::rngs::ThreadRng = rand::thread_rng();
 
@@ -38,13 +43,13 @@ fn benchmark_put(c: &mut Criterion) {
 fn benchmark_get(c: &mut Criterion) {
     let options = Opts::new(
         256,
-        1024,
+        2048,
         false,
         true,
         "/tmp/bitcask-rs-bench".to_string(),
         256 * 1024 * 1024,
     );
-    let mut engine = Db::open(&options).unwrap();
+    let engine = Db::open(&options).unwrap();
 
     for i in 0..100000 {
         let res = engine.put(get_test_key(i), get_test_value(i));
@@ -64,13 +69,13 @@ fn benchmark_get(c: &mut Criterion) {
 fn benchmark_delete(c: &mut Criterion) {
     let options = Opts::new(
         256,
-        1024,
+        2048,
         false,
         true,
         "/tmp/bitcask-rs-bench".to_string(),
         256 * 1024 * 1024,
     );
-    let mut engine = Db::open(&options).unwrap();
+    let engine = Db::open(&options).unwrap();
 
     for i in 0..100000 {
         let res = engine.put(get_test_key(i), get_test_value(i));
@@ -88,5 +93,270 @@ fn benchmark_delete(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, benchmark_put, benchmark_get, benchmark_delete);
+fn benchmark_get_inline(c: &mut Criterion) {
+    let mut options = Opts::new(
+        256,
+        1024,
+        false,
+        true,
+        "/tmp/bitcask-rs-bench-inline".to_string(),
+        256 * 1024 * 1024,
+    );
+    options.inline_value_threshold = 8;
+    let engine = Db::open(&options).unwrap();
+
+    for i in 0..100000 {
+        let res = engine.put(get_test_key(i), Bytes::from(i.to_be_bytes().repeat(2)));
+        assert!(res.is_ok());
+    }
+
+    let mut rnd: rand::rngs::ThreadRng = rand::thread_rng();
+
+    c.bench_function("bitcask-get-inline-bench", |b| {
+        b.iter(|| {
+            let i = rnd.gen_range(0..100000);
+            let _ = engine.get(get_test_key(i));
+        })
+    });
+}
+
+fn benchmark_get_large_mmap(c: &mut Criterion) {
+    let options = Opts::new(
+        256,
+        2 * 1024 * 1024,
+        false,
+        true,
+        "/tmp/bitcask-rs-bench-large".to_string(),
+        8 * 1024 * 1024,
+    );
+    let engine = Db::open(&options).unwrap();
+    for i in 0..32 {
+        let res = engine.put(get_test_key(i), Bytes::from(vec![i as u8; 1024 * 1024]));
+        assert!(res.is_ok());
+    }
+    drop(engine);
+
+    // Reopen so that the rotated files are mmap-backed
+    let engine = Db::open(&options).unwrap();
+    let mut rnd: rand::rngs::ThreadRng = rand::thread_rng();
+
+    // Seven values fit a file, so the last four are in the active file and
+    // copied out, and the rest are borrowed from the mapped files
+    c.bench_function("bitcask-get-1mb-copy-bench", |b| {
+        b.iter(|| {
+            let i = rnd.gen_range(28..32);
+            let _ = engine.get(get_test_key(i));
+        })
+    });
+
+    c.bench_function("bitcask-get-1mb-mmap-bench", |b| {
+        b.iter(|| {
+            let i = rnd.gen_range(0..24);
+            let _ = engine.get(get_test_key(i));
+        })
+    });
+}
+
+fn benchmark_merge(c: &mut Criterion) {
+    let options = Opts::new(
+        256,
+        2048,
+        false,
+        true,
+        "/tmp/bitcask-rs-bench-merge".to_string(),
+        64 * 1024 * 1024,
+    );
+    let _ = std::fs::remove_dir_all(&options.dir_path);
+    let engine = Db::open(&options).unwrap();
+
+    // Half of the records are overwritten, so merge both drops and copies
+    for i in 0..100000 {
+        let res = engine.put(get_test_key(i % 50000), get_test_value(i));
+        assert!(res.is_ok());
+    }
+
+    let mut group = c.benchmark_group("bitcask-merge-bench");
+    group.sample_size(10);
+    group.bench_function("merge", |b| b.iter(|| engine.merge().unwrap()));
+    group.finish();
+}
+
+// Evicts the files in `dir` from the page cache, once they're synced
+fn drop_page_cache(dir: &Path) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let file = File::open(entry.unwrap().path()).unwrap();
+        file.sync_all().unwrap();
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+}
+
+fn benchmark_first_gets(c: &mut Criterion) {
+    const KEYS: u32 = 20000;
+
+    let mut options = Opts::new(
+        256,
+        2048,
+        false,
+        false,
+        "/tmp/bitcask-rs-bench-warmup".to_string(),
+        8 * 1024 * 1024,
+    );
+    // Open loads the index from the hint log rather than reading the files
+    options.incremental_hint = true;
+    let _ = std::fs::remove_dir_all(&options.dir_path);
+    let engine = Db::open(&options).unwrap();
+    for i in 0..KEYS {
+        engine.put(get_test_key(i), get_test_value(i)).unwrap();
+    }
+    drop(engine);
+
+    // Only the reads right after open are timed, open and its warmup aren't.
+    // The files are dropped from the page cache before each open, or every
+    // iteration but the first would read from a cache warmed by the last.
+    let mut group = c.benchmark_group("bitcask-first-gets-bench");
+    group.sample_size(10);
+    for warmup in [false, true] {
+        options.warmup = warmup;
+        let name = if warmup { "warmup" } else { "cold" };
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let mut rnd: rand::rngs::ThreadRng = rand::thread_rng();
+                let mut elapsed = std::time::Duration::ZERO;
+                for _ in 0..iters {
+                    drop_page_cache(&options.dir_path);
+                    let engine = Db::open(&options).unwrap();
+                    let start = Instant::now();
+                    for _ in 0..1000 {
+                        let i = rnd.gen_range(0..KEYS);
+                        engine.get(get_test_key(i)).unwrap();
+                    }
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_get_active_under_writes(c: &mut Criterion) {
+    const KEYS: u32 = 10000;
+
+    let options = Opts::new(
+        256,
+        2048,
+        false,
+        false,
+        "/tmp/bitcask-rs-bench-active-get".to_string(),
+        256 * 1024 * 1024,
+    );
+    let _ = std::fs::remove_dir_all(&options.dir_path);
+    let engine = Db::open(&options).unwrap();
+    for i in 0..KEYS {
+        engine.put(get_test_key(i), get_test_value(i)).unwrap();
+    }
+
+    // Every key read lives in the active file while a writer keeps appending;
+    // its values are small so the file doesn't rotate during the run
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let mut i = KEYS;
+            while !stop.load(Ordering::Relaxed) {
+                engine.put(get_test_key(i), Bytes::from("value")).unwrap();
+                i += 1;
+            }
+        });
+
+        let mut rnd: rand::rngs::ThreadRng = rand::thread_rng();
+        c.bench_function("bitcask-get-active-under-writes-bench", |b| {
+            b.iter(|| {
+                let i = rnd.gen_range(0..KEYS);
+                engine.get(get_test_key(i)).unwrap();
+            })
+        });
+
+        let mut latencies = (0..100000)
+            .map(|_| {
+                let i = rnd.gen_range(0..KEYS);
+                let start = Instant::now();
+                engine.get(get_test_key(i)).unwrap();
+                start.elapsed()
+            })
+            .collect::<Vec<_>>();
+        latencies.sort();
+        println!(
+            "active get under writes: p50 {:?}, p99 {:?}",
+            latencies[latencies.len() / 2],
+            latencies[latencies.len() * 99 / 100]
+        );
+        stop.store(true, Ordering::Relaxed);
+    });
+}
+
+#[cfg(feature = "write-shards")]
+fn benchmark_parallel_put(c: &mut Criterion) {
+    use criterion::Throughput;
+    use std::sync::Barrier;
+
+    const THREADS: usize = 4;
+
+    // The same writers over more shards. Each sample starts the writers
+    // together and times only their puts, reported as puts per second.
+    let mut group = c.benchmark_group("bitcask-parallel-put-bench");
+    group.throughput(Throughput::Elements(THREADS as u64));
+    for shards in [1, 2, 4] {
+        let mut options = Opts::new(
+            256,
+            2048,
+            false,
+            false,
+            format!("/tmp/bitcask-rs-bench-shards-{}", shards),
+            256 * 1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&options.dir_path);
+        options.write_shards = shards;
+        let engine = Db::open(&options).unwrap();
+
+        group.bench_function(format!("{}-shards", shards), |b| {
+            b.iter_custom(|iters| {
+                let barrier = Barrier::new(THREADS + 1);
+                let started = std::thread::scope(|s| {
+                    for t in 0..THREADS {
+                        let (engine, barrier) = (&engine, &barrier);
+                        s.spawn(move || {
+                            barrier.wait();
+                            for i in 0..iters as u32 {
+                                let i = i.wrapping_mul(THREADS as u32) + t as u32;
+                                engine.put(get_test_key(i), get_test_value(i)).unwrap();
+                            }
+                        });
+                    }
+                    barrier.wait();
+                    Instant::now()
+                });
+                started.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(feature = "write-shards")]
+criterion_group!(shard_benches, benchmark_parallel_put);
+
+criterion_group!(
+    benches,
+    benchmark_put,
+    benchmark_get,
+    benchmark_delete,
+    benchmark_get_inline,
+    benchmark_get_large_mmap,
+    benchmark_merge,
+    benchmark_get_active_under_writes,
+    benchmark_first_gets
+);
+#[cfg(feature = "write-shards")]
+criterion_main!(benches, shard_benches);
+#[cfg(not(feature = "write-shards"))]
 criterion_main!(benches);
@@ -0,0 +1,13 @@
+fn main() {
+    // Compiles the gRPC service definitions without needing protoc
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/zap.proto");
+        let descriptors = protox::compile(["proto/zap.proto"], ["proto"]).unwrap();
+        // `bytes` fields as `Bytes`, which `Db` takes and returns
+        tonic_build::configure()
+            .bytes(["."])
+            .compile_fds(descriptors)
+            .unwrap();
+    }
+}
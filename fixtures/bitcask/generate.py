@@ -0,0 +1,48 @@
+#!/usr/bin/env python3
+"""Writes the bitcask directory `zap::import` is tested against.
+
+Records follow the layout of bitcask and its Go ports, big-endian:
+crc32 (u32) | tstamp (u32) | ksz (u16) | vsz (u32) | key | value, with the
+CRC over everything after it. Hint entries are
+tstamp (u32) | ksz (u16) | total_sz (u32) | offset (u64) | key.
+
+- 1.bitcask.data: key0..key149 = value{i}-v1, with a hint file
+- 2.bitcask.data: key100..key199 = value{i}-v2, then tombstones for
+  key0..key9, then key199 = value199-v3 with a bad CRC
+"""
+import os
+import struct
+import zlib
+
+TSTAMP = 1700000000
+TOMBSTONE = b"bitcask_tombstone"
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+
+def record(key, value, corrupt=False):
+    body = struct.pack(">IHI", TSTAMP, len(key), len(value)) + key + value
+    crc = zlib.crc32(body) ^ (0xFFFFFFFF if corrupt else 0)
+    return struct.pack(">I", crc) + body
+
+
+def write(file_id, records, hint=False):
+    data, hints, offset = b"", b"", 0
+    for key, value, corrupt in records:
+        rec = record(key, value, corrupt)
+        data += rec
+        hints += struct.pack(">IHIQ", TSTAMP, len(key), len(rec), offset) + key
+        offset += len(rec)
+    with open(os.path.join(HERE, "%d.bitcask.data" % file_id), "wb") as f:
+        f.write(data)
+    if hint:
+        with open(os.path.join(HERE, "%d.bitcask.hint" % file_id), "wb") as f:
+            f.write(hints)
+
+
+write(1, [(b"key%d" % i, b"value%d-v1" % i, False) for i in range(150)], hint=True)
+write(
+    2,
+    [(b"key%d" % i, b"value%d-v2" % i, False) for i in range(100, 200)]
+    + [(b"key%d" % i, TOMBSTONE, False) for i in range(10)]
+    + [(b"key199", b"value199-v3", True)],
+)
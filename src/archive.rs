@@ -0,0 +1,319 @@
+//! Backups streamed as a single archive, for piping to object storage,
+//! compression or another host without staging them on disk.
+//!
+//! An archive is the magic bytes `ZAPARCH1`, then each file as
+//!
+//! ```text
+//! name_len: u16 | name | len: u64 | bytes | crc32: u32
+//! ```
+//!
+//! with `/`-separated names relative to the database directory, then a
+//! `name_len` of `0` and the manifest: the file count as a `u32` and each
+//! file's `name_len | name | len | crc32` again, followed by the manifest's
+//! own CRC32. Integers are big-endian.
+
+use crate::db::{backup_extent, BackupExtent, BackupFile, BackupReport, Db};
+use crate::storage::LOCK_FILE_NAME;
+use crate::{Error, Opts, Result};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"ZAPARCH1";
+// Suffix of the directory a restore unpacks into before it's validated
+const RESTORING_SUFFIX: &str = ".restoring";
+const COPY_BUF_LEN: usize = 64 * 1024;
+
+// A file as the manifest lists it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ManifestEntry {
+    name: String,
+    len: u64,
+    crc: u32,
+}
+
+impl Db {
+    /// Writes a backup to `w` as a single archive, taken at the same point
+    /// as `back_up`: the active files up to their ends once those are
+    /// durable, older files whole and files created since left out.
+    pub fn backup_to<W: Write>(&self, mut w: W) -> Result<BackupReport> {
+        if self.in_memory {
+            return Err(Error::Unsupported(
+                "Archiving a database in memory; use back_up".to_string(),
+            ));
+        }
+        let ends = self.backup_point()?;
+        let mut files = Vec::new();
+        list_files(&self.ctx.opts.dir_path, "", &mut files)?;
+        files.sort();
+
+        w.write_all(ARCHIVE_MAGIC)?;
+        let mut report = BackupReport::default();
+        let mut manifest = Vec::new();
+        for (name, path) in files {
+            let file_name = name.rsplit('/').next().unwrap_or(&name);
+            let len = match backup_extent(&ends, file_name) {
+                Some(BackupExtent::Prefix(end)) => end,
+                Some(BackupExtent::Whole) => fs::metadata(&path)?.len(),
+                None => continue,
+            };
+            write_name(&mut w, &name)?;
+            w.write_all(&len.to_be_bytes())?;
+            let crc = copy_exact(&mut File::open(&path)?, &mut w, len)?;
+            w.write_all(&crc.to_be_bytes())?;
+            report.files.push(BackupFile {
+                name: name.clone(),
+                bytes: len,
+                hard_linked: false,
+            });
+            manifest.push(ManifestEntry { name, len, crc });
+        }
+
+        w.write_all(&0u16.to_be_bytes())?;
+        let mut encoded = (manifest.len() as u32).to_be_bytes().to_vec();
+        for entry in manifest.iter() {
+            write_name(&mut encoded, &entry.name)?;
+            encoded.extend_from_slice(&entry.len.to_be_bytes());
+            encoded.extend_from_slice(&entry.crc.to_be_bytes());
+        }
+        w.write_all(&encoded)?;
+        w.write_all(&crc32fast::hash(&encoded).to_be_bytes())?;
+        w.flush()?;
+        Ok(report)
+    }
+
+    /// Unpacks an archive written by `backup_to` into `opts.dir_path`, which
+    /// must be new or empty, and opens it.
+    ///
+    /// The files are unpacked next to the directory first and only moved in
+    /// place once every file matches its CRC and the manifest matches the
+    /// files, so a truncated or damaged archive leaves nothing behind.
+    pub fn restore_from<R: Read>(mut r: R, opts: &Opts) -> Result<Db> {
+        let dir_path = &opts.dir_path;
+        if dir_path.exists() && fs::read_dir(dir_path)?.next().is_some() {
+            return Err(Error::Unsupported(format!(
+                "Restore directory {} is not empty",
+                dir_path.display()
+            )));
+        }
+        let mut staging = dir_path.clone().into_os_string();
+        staging.push(RESTORING_SUFFIX);
+        let staging = PathBuf::from(staging);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        if let Err(e) = unpack(&mut r, &staging) {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        if dir_path.exists() {
+            fs::remove_dir(dir_path)?;
+        }
+        fs::rename(&staging, dir_path)?;
+        Db::open(opts)
+    }
+}
+
+// Every file under `dir` but the lock file, named relative to the database
+// directory
+pub(crate) fn list_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    for dentry in fs::read_dir(dir)? {
+        let dentry = dentry?;
+        let name = format!("{}{}", prefix, dentry.file_name().to_string_lossy());
+        if dentry.file_type()?.is_dir() {
+            list_files(&dentry.path(), &format!("{}/", name), files)?;
+        } else if name != LOCK_FILE_NAME {
+            files.push((name, dentry.path()));
+        }
+    }
+    Ok(())
+}
+
+fn unpack(r: &mut impl Read, dir: &Path) -> Result<()> {
+    let mut magic = [0; ARCHIVE_MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(damaged("not a zap backup archive"));
+    }
+
+    let mut unpacked = Vec::new();
+    while let Some(name) = read_name(r)? {
+        let path = dir.join(safe_relative_path(&name)?);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let len = u64::from_be_bytes(read_array(r)?);
+        let crc = copy_exact(r, &mut File::create(&path)?, len)?;
+        if u32::from_be_bytes(read_array(r)?) != crc {
+            return Err(damaged(&format!("{} fails its CRC", name)));
+        }
+        unpacked.push(ManifestEntry { name, len, crc });
+    }
+
+    // The manifest, checked against the files unpacked
+    let mut encoded = Vec::new();
+    let count: [u8; 4] = read_array(r)?;
+    encoded.extend_from_slice(&count);
+    let mut manifest = Vec::new();
+    for _ in 0..u32::from_be_bytes(count) {
+        let name = read_name(r)?.ok_or_else(|| damaged("empty name in the manifest"))?;
+        let len: [u8; 8] = read_array(r)?;
+        let crc: [u8; 4] = read_array(r)?;
+        write_name(&mut encoded, &name)?;
+        encoded.extend_from_slice(&len);
+        encoded.extend_from_slice(&crc);
+        manifest.push(ManifestEntry {
+            name,
+            len: u64::from_be_bytes(len),
+            crc: u32::from_be_bytes(crc),
+        });
+    }
+    if u32::from_be_bytes(read_array(r)?) != crc32fast::hash(&encoded) {
+        return Err(damaged("the manifest fails its CRC"));
+    }
+    if manifest != unpacked {
+        return Err(damaged("the files don't match the manifest"));
+    }
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Rejects names that would unpack outside the directory
+fn safe_relative_path(name: &str) -> Result<&Path> {
+    let path = Path::new(name);
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        Ok(path)
+    } else {
+        Err(damaged(&format!("bad file name {:?}", name)))
+    }
+}
+
+fn damaged(message: &str) -> Error {
+    Error::Unsupported(format!("Damaged backup archive: {}", message))
+}
+
+fn write_name(w: &mut impl Write, name: &str) -> Result<()> {
+    let len = u16::try_from(name.len())
+        .map_err(|_| Error::Unsupported(format!("File name too long: {}", name)))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(name.as_bytes())?;
+    Ok(())
+}
+
+// The next file name, `None` at the end of the files
+fn read_name(r: &mut impl Read) -> Result<Option<String>> {
+    let len = u16::from_be_bytes(read_array(r)?) as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut name = vec![0; len];
+    r.read_exact(&mut name)?;
+    String::from_utf8(name)
+        .map(Some)
+        .map_err(|_| damaged("file name isn't UTF-8"))
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Copies exactly `len` bytes from `r` to `w`, returning their CRC32. Fails if
+// `r` ends first, as a file that shrank or a truncated archive does.
+fn copy_exact(r: &mut impl Read, w: &mut impl Write, len: u64) -> Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; COPY_BUF_LEN];
+    let mut left = len;
+    while left > 0 {
+        let chunk = &mut buf[..left.min(COPY_BUF_LEN as u64) as usize];
+        r.read_exact(chunk)?;
+        hasher.update(chunk);
+        w.write_all(chunk)?;
+        left -= chunk.len() as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn archive_opts(name: &str) -> Opts {
+        let opts = Opts::new(256, 1024, false, false, format!("/tmp/{}", name), 1024);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts
+    }
+
+    #[test]
+    fn test_backup_to_and_restore_from() -> Result<()> {
+        let db = Db::open(&archive_opts("test_backup_to"))?;
+        for i in 0..300 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        for i in 0..30 {
+            db.delete(Bytes::from(format!("key{}", i)))?;
+        }
+        db.put_all(&[(Bytes::from("batched"), Bytes::from("value"))])?;
+
+        let mut archive = Vec::new();
+        let report = db.backup_to(&mut archive)?;
+        assert!(report.files.len() > 1);
+        // Writes after the backup point aren't in it
+        db.put(Bytes::from("later"), Bytes::from("value"))?;
+
+        let restore_opts = archive_opts("test_restore_from");
+        let restored = Db::restore_from(archive.as_slice(), &restore_opts)?;
+        let mut keys = db.list_keys()?;
+        keys.retain(|key| key != "later");
+        keys.sort();
+        let mut restored_keys = restored.list_keys()?;
+        restored_keys.sort();
+        assert_eq!(restored_keys, keys);
+        for key in keys {
+            assert_eq!(restored.get(key.clone())?, db.get(key)?);
+        }
+        assert!(!Path::new("/tmp/test_restore_from.restoring").exists());
+
+        // The target has to be fresh
+        assert!(matches!(
+            Db::restore_from(archive.as_slice(), &restore_opts),
+            Err(Error::Unsupported(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_rejects_damaged_archives() -> Result<()> {
+        let db = Db::open(&archive_opts("test_backup_to_damaged"))?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        let mut archive = Vec::new();
+        db.backup_to(&mut archive)?;
+
+        let mut flipped = archive.clone();
+        flipped[ARCHIVE_MAGIC.len() + 40] ^= 0xff;
+        let truncated = &archive[..archive.len() - 10];
+        for damaged in [flipped.as_slice(), truncated, b"not an archive"] {
+            let opts = archive_opts("test_restore_damaged");
+            assert!(Db::restore_from(damaged, &opts).is_err());
+            assert!(!opts.dir_path.exists());
+            assert!(!Path::new("/tmp/test_restore_damaged.restoring").exists());
+        }
+        Ok(())
+    }
+}
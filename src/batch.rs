@@ -1,15 +1,20 @@
-use crate::db::Db;
+use crate::db::{end_position, Db, NON_COMMITTED};
 use crate::index::Indexer;
+use crate::shard::{LARGE_VALUES, SMALL_VALUES};
 use crate::{storage::DataEntry, Result};
 use crate::{Error, KeyDirEntry, State};
 use bytes::{BufMut, Bytes, BytesMut};
 use dashmap::DashMap;
 use prost::{decode_length_delimiter, encode_length_delimiter};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-const COMMITTED_KEY: &[u8] = b"__COMMITTED__";
+// Replay tells a batch's committed marker apart by its `State::Committed`,
+// and the marker has no key of its own, so it can't be taken for a write of
+// any user key: those are never empty. Markers written before carry the key
+// `__COMMITTED__`, which nothing reads.
+pub(crate) const COMMITTED_KEY: &[u8] = b"";
 
 #[allow(dead_code)]
 pub struct WriteBatch<'a> {
@@ -24,21 +29,100 @@ pub struct WriteBatchOptions {
     pub sync_writes: bool,
 }
 
+// A key and its value, `None` if it was deleted
+type TransactionEntry = (Vec<u8>, Option<Vec<u8>>);
+
+/// A committed batch as recorded in the data files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    seq_no: u32,
+    entries: Vec<TransactionEntry>,
+}
+
+impl Transaction {
+    pub fn get_seq_no(&self) -> u32 {
+        self.seq_no
+    }
+
+    /// The batch's writes in the order they were appended, with `None` for a
+    /// delete.
+    pub fn get_entries(&self) -> &[TransactionEntry] {
+        &self.entries
+    }
+}
+
 #[allow(dead_code)]
 impl Db {
-    pub fn new_write_batch(&self, opts: WriteBatchOptions) -> Result<WriteBatch> {
+    pub fn new_write_batch(&self, opts: WriteBatchOptions) -> Result<WriteBatch<'_>> {
         Ok(WriteBatch {
             pending_writes: Arc::new(DashMap::new()),
             db: self,
             opts,
         })
     }
+
+    /// Stores every pair as one batch: after a crash either all of them are
+    /// there or none are. A key given more than once keeps its last value.
+    /// Syncs as `Opts::sync_writes` says.
+    pub fn put_all(&self, pairs: &[(Bytes, Bytes)]) -> Result<()> {
+        let batch = self.new_write_batch(WriteBatchOptions {
+            max_batch_num: pairs.len(),
+            sync_writes: self.ctx.opts.sync_writes,
+        })?;
+        for (key, value) in pairs {
+            self.counters.count_put();
+            self.validate_put(key, value)?;
+            batch.put(key.clone(), value.clone())?;
+        }
+        batch.commit()
+    }
+
+    /// Committed batches still on disk, in commit order.
+    ///
+    /// Scans the data files the way open does, grouping batch entries by
+    /// sequence number and keeping those whose committed marker was written.
+    /// Batches whose files were merged are no longer reported.
+    pub fn iter_transactions(&self) -> impl Iterator<Item = Transaction> {
+        let mut files = self
+            .shards
+            .iter()
+            .map(|shard| shard.active_file.read().clone())
+            .collect::<Vec<_>>();
+        files.extend(self.inactive_files.iter().map(|file| file.clone()));
+        files.sort_by_key(|file| file.get_file_id());
+        files.dedup_by_key(|file| file.get_file_id());
+
+        // A sharded batch has a marker in each file it wrote to
+        let mut committed: BTreeMap<u32, Vec<TransactionEntry>> = BTreeMap::new();
+        for file in files.iter() {
+            let mut pending: HashMap<u32, Vec<TransactionEntry>> = HashMap::new();
+            let mut offset = 0;
+            while let Ok((entry, size)) = file.extract_data_entry(offset) {
+                offset += size as u64;
+                let (key, seq_no) = decode_transaction_key(entry.get_key().clone());
+                if seq_no == NON_COMMITTED {
+                    continue;
+                }
+                if entry.get_state() == State::Committed {
+                    if let Some(entries) = pending.remove(&seq_no) {
+                        committed.entry(seq_no).or_default().extend(entries);
+                    }
+                } else {
+                    let value = entry.is_active().then(|| entry.into_value());
+                    pending.entry(seq_no).or_default().push((key, value));
+                }
+            }
+        }
+        committed
+            .into_iter()
+            .map(|(seq_no, entries)| Transaction { seq_no, entries })
+    }
 }
 #[allow(dead_code)]
 impl WriteBatch<'_> {
     pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
         if key.is_empty() {
-            return Err(Error::Unsupported("Key is required".to_string()));
+            return Err(Error::EmptyKey);
         }
 
         let entry = DataEntry::new(key.clone(), value, State::Active);
@@ -50,7 +134,7 @@ impl WriteBatch<'_> {
 
     pub fn delete(&self, key: Bytes) -> Result<()> {
         if key.is_empty() {
-            return Err(Error::Unsupported("Key is required".to_string()));
+            return Err(Error::EmptyKey);
         }
 
         let index_pos = self.db.ctx.index.get(&key);
@@ -69,55 +153,153 @@ impl WriteBatch<'_> {
     }
 
     pub fn commit(&self) -> Result<()> {
-        if self.pending_writes.len() == 0 {
+        if self.pending_writes.is_empty() {
             return Ok(());
         }
         if self.pending_writes.len() > self.opts.max_batch_num {
             return Err(Error::Unsupported("Exceeds max batch number".to_string()));
         }
 
-        let _lock = self.db.batch_commit_lock.lock();
+        let _lock = self.db.batch_commit_lock.write();
+        self.db.check_open()?;
         // Add a lock to ensure that only one batch is committed at a time
 
         let seq_no = self.db.sequence_number.fetch_add(1, Ordering::SeqCst);
+        trace_span!(
+            "batch_commit",
+            seq = seq_no,
+            entries = self.pending_writes.len()
+        );
+
+        // Lock every shard the batch writes to, in shard order, so no other
+        // write to its keys lands between the batch's appends and its index
+        // updates
+        let key_shards = self
+            .pending_writes
+            .iter()
+            .map(|r| {
+                let item = r.value();
+                let value_len = item.is_active().then(|| item.get_value().len());
+                (r.key().clone(), self.db.shard_for_write(r.key(), value_len))
+            })
+            .collect::<HashMap<_, _>>();
+        // Under size classes, puts that move their key leave a tombstone in
+        // the class they leave, see `Db::leave_size_class`
+        let mut moves = Vec::new();
+        if self.db.ctx.opts.size_class_boundary.is_some() {
+            for (key, shard) in key_shards.iter() {
+                let large = self.db.large_keys.contains(key);
+                match *shard {
+                    LARGE_VALUES if !large => moves.push((key.clone(), SMALL_VALUES)),
+                    SMALL_VALUES if large => moves.push((key.clone(), LARGE_VALUES)),
+                    _ => {}
+                }
+            }
+        }
+        let shards = key_shards
+            .values()
+            .chain(moves.iter().map(|(_, left)| left))
+            .copied()
+            .collect::<BTreeSet<usize>>();
+        let mut active_files = shards
+            .iter()
+            .map(|shard| (*shard, self.db.shards[*shard].active_file.write()))
+            .collect::<BTreeMap<_, _>>();
 
-        let keydir_entries = self.pending_writes.iter().try_fold(
-            HashMap::new(),
-            |mut acc, r| -> Result<HashMap<Vec<u8>, KeyDirEntry>> {
+        let mut entries = self
+            .pending_writes
+            .iter()
+            .map(|r| {
                 let item = r.value();
                 let entry = DataEntry::new(
                     encode_transaction_key(item.get_key().clone(), seq_no),
                     item.get_value().clone(),
                     item.get_state(),
                 );
-
-                let keydir_entry = self.db.append_entry(&entry)?;
-                acc.insert(item.get_key().clone(), keydir_entry);
-                Ok(acc)
-            },
-        )?;
-
+                (key_shards[item.get_key()], item.get_key().clone(), entry)
+            })
+            .collect::<Vec<_>>();
+        let tombstones = moves.iter().map(|(key, left)| {
+            let entry = DataEntry::new(
+                encode_transaction_key(key.clone(), seq_no),
+                Vec::new(),
+                State::Inactive,
+            );
+            (*left, key.clone(), entry)
+        });
+        let written = entries.len();
+        entries.extend(tombstones.collect::<Vec<_>>());
+        // Replay only applies entries followed by a marker in the same file, so
+        // each shard gets its own. With several shards a crash can leave the
+        // batch committed in some of them only.
         let committed_entry = DataEntry::new(
             encode_transaction_key(COMMITTED_KEY.to_vec(), seq_no),
             Vec::new(),
             State::Committed,
         );
-        self.db.append_entry(&committed_entry)?;
 
-        if self.opts.sync_writes {
-            self.db.sync()?;
+        // Rotate up front where the batch wouldn't fit, so no rotation splits
+        // a shard's entries from its marker
+        let mut batch_lens = BTreeMap::new();
+        for (shard, _, entry) in entries.iter() {
+            *batch_lens
+                .entry(*shard)
+                .or_insert(committed_entry.encoded_len() as u64) += entry.encoded_len() as u64;
+        }
+        for (shard, active_file) in active_files.iter_mut() {
+            self.db
+                .make_room_locked(&self.db.shards[*shard], active_file, batch_lens[shard])?;
         }
 
-        self.pending_writes.iter().for_each(|r| {
-            let item = r.value();
-            if item.is_active() {
-                let keydir_entry = keydir_entries.get(item.get_key()).unwrap();
-                self.db.ctx.index.put(item.get_key().clone(), *keydir_entry);
+        let mut keydir_entries = HashMap::new();
+        for (i, (shard, key, entry)) in entries.into_iter().enumerate() {
+            let active_file = active_files.get_mut(&shard).unwrap();
+            let mut keydir_entry = self.db.write_locked(active_file, &entry.encode()?)?;
+            if self.db.ctx.opts.should_inline(entry.get_value().len()) {
+                keydir_entry.set_inline_value(entry.get_value());
             }
-        });
+            if i < written {
+                keydir_entries.insert(key, keydir_entry);
+            }
+        }
+        fail_point!(&self.db.ctx.opts.dir_path, BATCH_BEFORE_MARKER)?;
+        let encoded_marker = committed_entry.encode()?;
+        let committed = active_files
+            .values_mut()
+            .map(|active_file| self.db.write_locked(active_file, &encoded_marker))
+            .collect::<Result<Vec<KeyDirEntry>>>()?;
+        fail_point!(&self.db.ctx.opts.dir_path, BATCH_AFTER_MARKER)?;
+        for (key, left) in moves {
+            if left == SMALL_VALUES {
+                self.db.large_keys.insert(key);
+            } else {
+                self.db.large_keys.remove(&key);
+            }
+        }
+
+        let updates = self
+            .pending_writes
+            .iter()
+            .map(|r| {
+                let item = r.value();
+                let position = item
+                    .is_active()
+                    .then(|| keydir_entries.get(item.get_key()).unwrap().clone());
+                (item.get_key().clone(), position)
+            })
+            .collect::<Vec<_>>();
+        let ticket = self.db.index_sequencer.issue();
 
         self.pending_writes.clear();
+        drop(active_files);
+        drop(_lock);
 
+        // The batch becomes visible once its markers are durable. Waiting
+        // outside the lock lets concurrent commits share an fsync
+        let markers = committed.iter().map(end_position).collect::<Vec<_>>();
+        self.db
+            .make_visible(ticket, &markers, self.opts.sync_writes, updates)?;
+        self.db.wake_evictor();
         Ok(())
     }
 }
@@ -173,4 +355,238 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_puts_serialize_with_batch_commits() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_puts_serialize_with_batch_commits".to_string(),
+            4096,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+
+        // Batches write both keys together; direct puts only touch `a`
+        std::thread::scope(|s| {
+            for t in 0..2 {
+                let db = &db;
+                s.spawn(move || {
+                    for i in 0..200 {
+                        let batch = db
+                            .new_write_batch(WriteBatchOptions {
+                                max_batch_num: 10,
+                                sync_writes: false,
+                            })
+                            .unwrap();
+                        let value = Bytes::from(format!("batch-{}-{}", t, i));
+                        batch.put(Bytes::from("a"), value.clone()).unwrap();
+                        batch.put(Bytes::from("b"), value).unwrap();
+                        batch.commit().unwrap();
+                    }
+                });
+                s.spawn(move || {
+                    for i in 0..200 {
+                        db.put(Bytes::from("a"), Bytes::from(format!("put-{}-{}", t, i)))
+                            .unwrap();
+                        std::thread::yield_now();
+                    }
+                });
+            }
+        });
+
+        let a = db.get(Bytes::from("a"))?;
+        let b = db.get(Bytes::from("b"))?;
+        // `a` was last written by a whole commit or by a put after it
+        assert!(a == b || a.starts_with(b"put-"));
+        drop(db);
+
+        // Replay applies the writes in the same order
+        let db = Db::open(&opts)?;
+        assert_eq!(db.get(Bytes::from("a"))?, a);
+        assert_eq!(db.get(Bytes::from("b"))?, b);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_transactions() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_iter_transactions".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("c"), Bytes::from("direct"))?;
+        let batch_opts = || WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: false,
+        };
+        let batch = db.new_write_batch(batch_opts())?;
+        batch.put(Bytes::from("a"), Bytes::from("1"))?;
+        batch.put(Bytes::from("b"), Bytes::from("1"))?;
+        batch.commit()?;
+        let batch = db.new_write_batch(batch_opts())?;
+        batch.put(Bytes::from("a"), Bytes::from("2"))?;
+        batch.delete(Bytes::from("c"))?;
+        batch.commit()?;
+        // A batch that never got its marker
+        let key = encode_transaction_key(b"lost".to_vec(), 1000);
+        db.append_entry(&DataEntry::new(key, b"value".to_vec(), State::Active))?;
+
+        let transactions = db.iter_transactions().collect::<Vec<_>>();
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions[0].get_seq_no() < transactions[1].get_seq_no());
+        let mut first = transactions[0].get_entries().to_vec();
+        first.sort();
+        assert_eq!(
+            first,
+            vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"b".to_vec(), Some(b"1".to_vec())),
+            ]
+        );
+        let mut second = transactions[1].get_entries().to_vec();
+        second.sort();
+        assert_eq!(
+            second,
+            vec![(b"a".to_vec(), Some(b"2".to_vec())), (b"c".to_vec(), None)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_all_is_atomic() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_put_all_is_atomic".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        let pairs = |round: usize| {
+            (0..5)
+                .map(|i| {
+                    (
+                        Bytes::from(format!("key{}", i)),
+                        Bytes::from(format!("value{}-{}", i, round)),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        db.put_all(&pairs(0))?;
+        for (key, value) in pairs(0) {
+            assert_eq!(db.get(key)?, value);
+        }
+        assert!(db
+            .put_all(&[
+                (Bytes::from("valid"), Bytes::new()),
+                (Bytes::new(), Bytes::new())
+            ])
+            .is_err());
+        assert!(db.get(Bytes::from("valid")).is_err());
+
+        db.put_all(&pairs(1))?;
+        drop(db);
+        // Corrupt the commit marker, the last record written
+        let path = opts.dir_path.join("0.db");
+        let mut data = std::fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data)?;
+
+        // None of the second round survive, all of the first do
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().uncommitted_batch_entries, 5);
+        for (key, value) in pairs(0) {
+            assert_eq!(db.get(key)?, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_key_named_like_marker() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_user_key_named_like_marker".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        let marker = Bytes::from("__COMMITTED__");
+        db.put(marker.clone(), Bytes::from("single"))?;
+        db.put_all(&[
+            (Bytes::from("a"), Bytes::from("1")),
+            (marker.clone(), Bytes::from("batched")),
+        ])?;
+        db.put_all(&[(Bytes::from("a"), Bytes::from("2"))])?;
+        assert_eq!(db.history(&marker)?.len(), 2);
+        drop(db);
+        // Tear the last batch's marker
+        let path = opts.dir_path.join("0.db");
+        let mut data = std::fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data)?;
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().uncommitted_batch_entries, 1);
+        assert_eq!(db.get(Bytes::from("a"))?, "1");
+        assert_eq!(db.get(marker.clone())?, "batched");
+        let transactions = db.iter_transactions().collect::<Vec<_>>();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].get_entries().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches_across_file_boundaries() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_batches_across_file_boundaries".to_string(),
+            96,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        // Batches of varying length end at every offset of a file, so some
+        // only just fit and some leave no room for their marker
+        for i in 0..40 {
+            let batch = db.new_write_batch(WriteBatchOptions {
+                max_batch_num: 10,
+                sync_writes: false,
+            })?;
+            batch.put(
+                Bytes::from(format!("a{}", i)),
+                Bytes::from("x".repeat(i % 7)),
+            )?;
+            batch.put(Bytes::from(format!("b{}", i)), Bytes::from("value"))?;
+            batch.commit()?;
+        }
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().uncommitted_batch_entries, 0);
+        for i in 0..40 {
+            assert_eq!(
+                db.get(Bytes::from(format!("a{}", i)))?,
+                "x".repeat(i % 7).as_bytes()
+            );
+            assert_eq!(db.get(Bytes::from(format!("b{}", i)))?, "value");
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,18 @@
+//! Inspects and edits a database directory, see `zap::cli`.
+
+use std::env;
+use std::io;
+use std::process;
+use zap::cli;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        eprintln!("{}", cli::USAGE);
+        process::exit(2);
+    }
+    if let Err(e) = cli::run(&args, &mut io::stdout().lock()) {
+        eprintln!("zap-cli: {}", e);
+        process::exit(1);
+    }
+}
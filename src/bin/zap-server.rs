@@ -0,0 +1,40 @@
+//! Serves a database directory over the Redis protocol, see `zap::server`.
+//!
+//! Usage: zap-server <dir> [addr], listening on 127.0.0.1:6379 by default.
+
+use std::env;
+use std::net::TcpListener;
+use std::process;
+use std::sync::Arc;
+use zap::db::Db;
+use zap::{server, Opts};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:6379";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let Some(dir_path) = args.next() else {
+        eprintln!("usage: zap-server <dir> [addr]");
+        process::exit(2);
+    };
+    let addr = args.next().unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let opts = Opts {
+        dir_path: dir_path.into(),
+        ..Opts::default()
+    };
+    if let Err(e) = run(&opts, &addr) {
+        eprintln!("zap-server: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opts: &Opts, addr: &str) -> zap::Result<()> {
+    let db = Arc::new(Db::open(opts)?);
+    let listener = TcpListener::bind(addr)?;
+    eprintln!(
+        "zap-server: serving {} on {}",
+        opts.dir_path.display(),
+        listener.local_addr()?
+    );
+    server::serve(db, listener)
+}
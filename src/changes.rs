@@ -0,0 +1,426 @@
+//! Committed writes read back from the data files, for feeding them to
+//! another system.
+//!
+//! `Db::changes_since` walks the data files from a `ChangeCursor` and yields
+//! every put and delete that replay on open would apply, in the order they
+//! were appended. Consumers save the cursor of the last change they handled
+//! and pick up from it later, in this process or another.
+//!
+//! Merges rewrite the data files, so each installed merge starts a new
+//! generation, recorded in a file next to the data files. A cursor from an
+//! older generation, or into a file `compact_active` rewrote, fails with
+//! `Error::CursorExpired`: the consumer has to resync from a full scan and
+//! carry on from `Db::first_change_cursor`.
+
+use crate::batch::decode_transaction_key;
+use crate::db::{Db, NON_COMMITTED};
+use crate::storage::FileHandle;
+use crate::{Error, Result, State};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+// Holds the generation of the data files as decimal text. A merge writes the
+// next one into its output, so it's installed along with the merged files
+pub(crate) const GENERATION_FILE: &str = "generation";
+
+/// A position in the data files to read changes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ChangeCursor {
+    /// Merges installed before the position was read.
+    pub generation: u64,
+    pub file_id: u32,
+    /// Where the next record starts in `file_id`.
+    pub offset: u64,
+}
+
+/// A committed put or delete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    key: Bytes,
+    value: Option<Bytes>,
+    seq_no: Option<u32>,
+    cursor: ChangeCursor,
+}
+
+impl Change {
+    pub fn get_key(&self) -> &Bytes {
+        &self.key
+    }
+
+    /// The value put, `None` for a delete.
+    pub fn get_value(&self) -> Option<&Bytes> {
+        self.value.as_ref()
+    }
+
+    /// Sequence number of the batch the change was committed in, `None` if
+    /// it was written on its own.
+    pub fn get_seq_no(&self) -> Option<u32> {
+        self.seq_no
+    }
+
+    /// Where the changes after this one start.
+    pub fn get_cursor(&self) -> ChangeCursor {
+        self.cursor
+    }
+}
+
+impl Db {
+    /// A cursor before every change still on disk.
+    pub fn first_change_cursor(&self) -> ChangeCursor {
+        ChangeCursor {
+            generation: self.generation,
+            ..Default::default()
+        }
+    }
+
+    /// Iterates over the changes after `cursor`, up to the end of the data
+    /// files when this is called.
+    ///
+    /// Batch entries are only yielded once their committed marker is read,
+    /// so batches that never committed are skipped as open skips them. A
+    /// batch still being written at the end stops the iteration before it.
+    /// Only whole records written outside a batch can follow a batch, so
+    /// any other record after its entries means it was cut short.
+    ///
+    /// Write shards interleave in no recorded order, so databases with more
+    /// than one are not supported.
+    pub fn changes_since(&self, cursor: ChangeCursor) -> Result<ChangeIter> {
+        if self.shards.len() > 1 {
+            return Err(Error::Unsupported(
+                "Changes of a database with write shards".to_string(),
+            ));
+        }
+        if cursor.generation != self.generation {
+            return Err(Error::CursorExpired(cursor));
+        }
+
+        // The active file is cloned first: if it rotates meanwhile, it shows
+        // up among the inactive files too and is deduplicated below
+        let active_file = self.shards[0].active_file.read().clone();
+        let mut files = vec![active_file];
+        files.extend(self.inactive_files.iter().map(|file| file.clone()));
+        files.sort_by_key(|file| file.get_file_id());
+        files.dedup_by_key(|file| file.get_file_id());
+
+        let files = files
+            .into_iter()
+            .filter(|file| file.get_file_id() >= cursor.file_id)
+            .map(|file| {
+                let end = file.get_offset();
+                (file, end)
+            })
+            .collect::<VecDeque<_>>();
+        let mut offset = 0;
+        if cursor.offset > 0 {
+            match files.front() {
+                Some((file, end))
+                    if file.get_file_id() == cursor.file_id && cursor.offset <= *end =>
+                {
+                    offset = cursor.offset
+                }
+                _ => return Err(Error::CursorExpired(cursor)),
+            }
+        }
+        Ok(ChangeIter {
+            files,
+            offset,
+            batch: Vec::new(),
+            ready: VecDeque::new(),
+            cursor,
+        })
+    }
+}
+
+/// Iterator returned by `Db::changes_since`.
+#[derive(Debug)]
+pub struct ChangeIter {
+    // Files left to read, with where each ended when the iteration started
+    files: VecDeque<(FileHandle, u64)>,
+    // Where the next record starts in the first file
+    offset: u64,
+    // Entries of a batch whose marker hasn't been read yet
+    batch: Vec<Change>,
+    ready: VecDeque<Change>,
+    cursor: ChangeCursor,
+}
+
+impl ChangeIter {
+    /// Where the changes after the ones yielded so far start. Once the
+    /// iterator is exhausted, this is where to read the next changes from.
+    pub fn cursor(&self) -> ChangeCursor {
+        self.cursor
+    }
+
+    // Reads the next record into `batch` or `ready`, `false` at the end
+    fn read_next(&mut self) -> Result<bool> {
+        let Some((file, end)) = self.files.front() else {
+            return Ok(false);
+        };
+        let file_id = file.get_file_id();
+        if self.offset >= *end {
+            // A batch that was being written at the end of the last file may
+            // still commit, so it's read again next time
+            if self.files.len() == 1 {
+                return Ok(false);
+            }
+            // Markers are written to the same file as their batch
+            self.batch.clear();
+            self.files.pop_front();
+            self.offset = 0;
+            if let Some((next, _)) = self.files.front() {
+                self.cursor.file_id = next.get_file_id();
+                self.cursor.offset = 0;
+            }
+            return Ok(true);
+        }
+
+        let (entry, size) = file.extract_data_entry(self.offset)?;
+        self.offset += size as u64;
+        let position = ChangeCursor {
+            generation: self.cursor.generation,
+            file_id,
+            offset: self.offset,
+        };
+        let (key, seq_no) = decode_transaction_key(entry.get_key().clone());
+        if self
+            .batch
+            .last()
+            .is_some_and(|change| change.seq_no != Some(seq_no))
+        {
+            self.batch.clear();
+        }
+        if entry.get_state() == State::Committed {
+            if let Some(last) = self.batch.last_mut() {
+                last.cursor = position;
+            }
+            self.ready.extend(self.batch.drain(..));
+            // Nothing was pending, or its changes carry the position
+            if self.ready.is_empty() {
+                self.cursor = position;
+            }
+            return Ok(true);
+        }
+        let value = entry.is_active().then(|| Bytes::from(entry.into_value()));
+        let change = Change {
+            key: Bytes::from(key),
+            value,
+            seq_no: (seq_no != NON_COMMITTED).then_some(seq_no),
+            cursor: position,
+        };
+        if seq_no == NON_COMMITTED {
+            self.ready.push_back(change);
+        } else {
+            self.batch.push(change);
+        }
+        Ok(true)
+    }
+}
+
+impl Iterator for ChangeIter {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.ready.pop_front() {
+                self.cursor = change.cursor;
+                return Some(Ok(change));
+            }
+            match self.read_next() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(e) => {
+                    // Yielded once; the cursor stays before the record
+                    self.files.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// The generation of the data files in `dir_path`, `0` before any merge
+pub(crate) fn read_generation(dir_path: &Path) -> Result<u64> {
+    match fs::read_to_string(dir_path.join(GENERATION_FILE)) {
+        Ok(text) => text.trim().parse().map_err(|_| {
+            Error::Unsupported(format!("Invalid {} file: {:?}", GENERATION_FILE, text))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn write_generation(dir_path: &Path, generation: u64) -> Result<()> {
+    let mut file = fs::File::create(dir_path.join(GENERATION_FILE))?;
+    file.write_all(generation.to_string().as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{encode_transaction_key, WriteBatchOptions};
+    use crate::merge::merge_dir_path;
+    use crate::storage::DataEntry;
+    use crate::Opts;
+    use std::collections::BTreeSet;
+
+    type Op = (Bytes, Option<Bytes>);
+
+    fn opts(name: &str) -> Result<Opts> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            format!("/tmp/test_changes_{}", name),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(merge_dir_path(&opts.dir_path)?);
+        Ok(opts)
+    }
+
+    fn consume(db: &Db, cursor: ChangeCursor, into: &mut Vec<Change>) -> Result<ChangeCursor> {
+        let mut iter = db.changes_since(cursor)?;
+        for change in iter.by_ref() {
+            into.push(change?);
+        }
+        Ok(iter.cursor())
+    }
+
+    fn ops(changes: &[Change]) -> Vec<Op> {
+        changes
+            .iter()
+            .map(|change| (change.get_key().clone(), change.get_value().cloned()))
+            .collect()
+    }
+
+    // Writes `n` puts and a delete outside batches and a batch of three,
+    // returning them in order, the batch last
+    fn write(db: &Db, round: usize, n: usize) -> Result<Vec<Op>> {
+        let mut written = Vec::new();
+        for i in 0..n {
+            let key = Bytes::from(format!("key{}", i));
+            let value = Bytes::from(format!("value{}.{}", round, i));
+            db.put(key.clone(), value.clone())?;
+            written.push((key, Some(value)));
+        }
+        db.delete(Bytes::from("key0"))?;
+        written.push((Bytes::from("key0"), None));
+
+        let batch = db.new_write_batch(WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: false,
+        })?;
+        for i in 0..3 {
+            let key = Bytes::from(format!("batch{}", i));
+            let value = Bytes::from(format!("batch{}.{}", round, i));
+            batch.put(key.clone(), value.clone())?;
+            written.push((key, Some(value)));
+        }
+        batch.commit()?;
+        Ok(written)
+    }
+
+    #[test]
+    fn test_changes_exactly_once() -> Result<()> {
+        let opts = opts("exactly_once")?;
+        let db = Db::open(&opts)?;
+        let mut changes = Vec::new();
+        let mut expected = write(&db, 0, 20)?;
+        let cursor = consume(&db, db.first_change_cursor(), &mut changes)?;
+        // Small files, so the changes span rotations
+        assert!(db.data_file_ids().len() > 1);
+        assert_eq!(ops(&changes).len(), expected.len());
+
+        // A batch that never committed, cut short by a later put
+        let seq_no = db.sequence_number.load(std::sync::atomic::Ordering::SeqCst) + 100;
+        db.append_entry(&DataEntry::new(
+            encode_transaction_key(b"lost".to_vec(), seq_no),
+            b"lost".to_vec(),
+            State::Active,
+        ))?;
+        expected.extend(write(&db, 1, 10)?);
+
+        // A consumer that stops after some changes picks up where it left
+        let mut iter = db.changes_since(cursor)?;
+        for change in iter.by_ref().take(5) {
+            changes.push(change?);
+        }
+        let cursor = iter.cursor();
+        drop(iter);
+        let cursor = consume(&db, cursor, &mut changes)?;
+        // Nothing new, nothing repeated
+        assert_eq!(consume(&db, cursor, &mut changes)?, cursor);
+
+        let delivered = ops(&changes);
+        assert_eq!(delivered.len(), expected.len());
+        // Batch entries come in any order, everything else in write order
+        let single = |ops: &[Op]| {
+            ops.iter()
+                .filter(|(key, _)| !key.starts_with(b"batch"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(single(&delivered), single(&expected));
+        assert_eq!(
+            delivered.iter().collect::<BTreeSet<_>>(),
+            expected.iter().collect::<BTreeSet<_>>()
+        );
+        assert!(changes
+            .iter()
+            .all(|change| change.get_seq_no().is_some() == change.get_key().starts_with(b"batch")));
+
+        // Resuming from inside a batch yields the rest of it
+        let batch = changes.len() - 3;
+        let mut rest = Vec::new();
+        consume(&db, changes[batch].get_cursor(), &mut rest)?;
+        assert_eq!(rest, changes[batch + 1..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor_expires_on_merge() -> Result<()> {
+        let opts = opts("merge")?;
+        let db = Db::open(&opts)?;
+        write(&db, 0, 20)?;
+        let mut changes = Vec::new();
+        let cursor = consume(&db, db.first_change_cursor(), &mut changes)?;
+        db.merge()?;
+        // The merge isn't installed yet
+        write(&db, 1, 5)?;
+        consume(&db, cursor, &mut changes)?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert!(matches!(
+            db.changes_since(cursor),
+            Err(Error::CursorExpired(expired)) if expired == cursor
+        ));
+        // A resync reads the live keys
+        let cursor = db.first_change_cursor();
+        assert_eq!(cursor.generation, 1);
+        let mut resync = Vec::new();
+        consume(&db, cursor, &mut resync)?;
+        let mut live = BTreeSet::new();
+        for change in resync {
+            match change.get_value() {
+                Some(_) => live.insert(change.get_key().clone()),
+                None => live.remove(change.get_key()),
+            };
+        }
+        let mut keys = db.list_keys()?;
+        keys.sort();
+        assert_eq!(live.into_iter().collect::<Vec<_>>(), keys);
+        drop(db);
+
+        // The generation survives another open
+        let db = Db::open(&opts)?;
+        assert_eq!(db.first_change_cursor().generation, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+//! The commands of the `zap-cli` tool, for inspecting and editing a database
+//! directory from the shell.
+//!
+//! Commands that only read open the database read-only, and like any open
+//! they fail while another process has it open. `--force-shared` reads such
+//! a live database anyway, without its lock, as its files are at the moment.
+//! Keys and values are printed with non-printable bytes escaped.
+
+use crate::db::Db;
+use crate::storage::FileRecord;
+use crate::{Error, Opts, Result, State};
+use bytes::Bytes;
+use prost::{decode_length_delimiter, length_delimiter_len};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+usage: zap-cli <dir> <command> [--force-shared]
+
+commands:
+  get <key>             print the value of a key
+  put <key> <value>     store a value
+  del <key>             delete a key
+  scan [--prefix <p>]   print the keys starting with a prefix and their values
+  keys                  print every key
+  stats                 print key and data file counts and sizes
+  merge                 merge the data files, installed on the next open
+  verify                check every record and that every key can be read
+  backup <dir>          copy the database into a directory
+  dump-file <id>        print the records of one data file
+  dump [--utf8-lossy]   print every pair as JSON lines, base64-encoded
+  load <file>           store the pairs of a dump
+
+--utf8-lossy adds each key and value as text, for reading the dump.
+
+--force-shared reads a database another process has open, without its lock.";
+
+#[derive(Debug, Default)]
+struct Args {
+    dir_path: PathBuf,
+    command: String,
+    operands: Vec<String>,
+    prefix: Option<String>,
+    force_shared: bool,
+    utf8_lossy: bool,
+}
+
+/// Runs the command in `args`, the arguments after the program name, writing
+/// its output to `out`.
+pub fn run(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let args = parse(args)?;
+    let writes = matches!(args.command.as_str(), "put" | "del" | "merge" | "load");
+    if writes && args.force_shared {
+        return Err(usage_error(
+            "--force-shared only applies to commands that read",
+        ));
+    }
+    if !writes && !args.dir_path.is_dir() {
+        return Err(Error::Unsupported(format!(
+            "No database in {}",
+            args.dir_path.display()
+        )));
+    }
+    let mut opts = Opts {
+        dir_path: args.dir_path.clone(),
+        read_only: !writes,
+        skip_lock: args.force_shared,
+        // These report corrupt records rather than failing on them
+        skip_corrupt_records: matches!(args.command.as_str(), "verify" | "dump-file"),
+        ..Opts::default()
+    };
+    #[cfg(feature = "write-shards")]
+    {
+        opts.write_shards = recorded_write_shards(&args.dir_path);
+    }
+    if writes {
+        opts.sync_writes = true;
+    }
+    let mut db = Db::open(&opts)?;
+
+    match (args.command.as_str(), args.operands.as_slice()) {
+        ("get", [key]) => {
+            let value = db.get(Bytes::from(key.clone()))?;
+            writeln!(out, "{}", show(&value))?;
+        }
+        ("put", [key, value]) => {
+            db.put(Bytes::from(key.clone()), Bytes::from(value.clone()))?;
+            writeln!(out, "OK")?;
+        }
+        ("del", [key]) => {
+            db.delete(Bytes::from(key.clone()))?;
+            writeln!(out, "OK")?;
+        }
+        ("scan", []) => {
+            let prefix = args.prefix.unwrap_or_default();
+            for key in db.scan_prefix_keys(prefix.as_bytes())? {
+                let value = db.get(key.clone())?;
+                writeln!(out, "{}\t{}", show(&key), show(&value))?;
+            }
+        }
+        ("keys", []) => {
+            let mut keys = db.list_keys()?;
+            keys.sort();
+            for key in keys {
+                writeln!(out, "{}", show(&key))?;
+            }
+        }
+        ("stats", []) => {
+            let stat = db.stat()?;
+            writeln!(out, "keys: {}", stat.key_num)?;
+            writeln!(out, "data files: {}", stat.data_file_num)?;
+            writeln!(out, "disk size: {} bytes", stat.disk_size)?;
+            writeln!(out, "index memory: {} bytes", stat.index_memory)?;
+        }
+        ("merge", []) => {
+            db.merge()?;
+            writeln!(out, "OK")?;
+        }
+        ("verify", []) => verify(&db, out)?,
+        ("backup", [dir_path]) => {
+            let report = db.back_up(Path::new(dir_path))?;
+            writeln!(
+                out,
+                "{} files, {} bytes",
+                report.files.len(),
+                report.total_bytes()
+            )?;
+        }
+        ("dump-file", [file_id]) => {
+            let file_id = file_id
+                .parse::<u32>()
+                .map_err(|_| usage_error("the file id must be a number"))?;
+            dump_file(&db, file_id, out)?;
+        }
+        ("dump", []) => {
+            db.write_jsonl(out, args.utf8_lossy)?;
+        }
+        ("load", [path]) => {
+            let file = std::io::BufReader::new(std::fs::File::open(path)?);
+            let loaded = db.load_jsonl(file)?;
+            writeln!(out, "{} keys", loaded)?;
+        }
+        _ => return Err(usage_error(&format!("bad arguments to {}", args.command))),
+    }
+    Ok(())
+}
+
+fn parse(args: &[String]) -> Result<Args> {
+    let mut parsed = Args::default();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force-shared" => parsed.force_shared = true,
+            "--utf8-lossy" => parsed.utf8_lossy = true,
+            "--prefix" => {
+                let prefix = args
+                    .next()
+                    .ok_or_else(|| usage_error("--prefix needs a value"))?;
+                parsed.prefix = Some(prefix.clone());
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    if positional.len() < 2 {
+        return Err(usage_error("a directory and a command are required"));
+    }
+    parsed.dir_path = PathBuf::from(positional.remove(0));
+    parsed.command = positional.remove(0);
+    parsed.operands = positional;
+    if parsed.prefix.is_some() && parsed.command != "scan" {
+        return Err(usage_error("--prefix only applies to scan"));
+    }
+    if parsed.utf8_lossy && parsed.command != "dump" {
+        return Err(usage_error("--utf8-lossy only applies to dump"));
+    }
+    Ok(parsed)
+}
+
+fn usage_error(message: &str) -> Error {
+    Error::Unsupported(format!("{}\n\n{}", message, USAGE))
+}
+
+// Prints every record of every data file that fails its CRC, then reads
+// every key. Fails if anything was wrong.
+fn verify(db: &Db, out: &mut dyn Write) -> Result<()> {
+    let mut first_problem = None;
+    for file_id in db.data_file_ids() {
+        let (mut records, mut corrupt) = (0, 0);
+        for record in db.file_entries(file_id)? {
+            match record {
+                Ok(record) if record.crc_ok => records += 1,
+                Ok(record) => {
+                    records += 1;
+                    corrupt += 1;
+                    writeln!(
+                        out,
+                        "file {} offset {}: CRC mismatch",
+                        file_id, record.offset
+                    )?;
+                    first_problem.get_or_insert(Error::Corrupted {
+                        file_id,
+                        offset: record.offset,
+                    });
+                }
+                Err(e) => {
+                    corrupt += 1;
+                    writeln!(out, "file {}: {}", file_id, e)?;
+                    first_problem.get_or_insert(e);
+                }
+            }
+        }
+        writeln!(
+            out,
+            "file {}: {} records, {} corrupt",
+            file_id, records, corrupt
+        )?;
+    }
+
+    let keys = db.list_keys()?;
+    let mut unreadable = 0;
+    for key in keys.iter() {
+        if let Err(e) = db.get(key.clone()) {
+            unreadable += 1;
+            writeln!(out, "key {}: {}", show(key), e)?;
+            first_problem.get_or_insert(e);
+        }
+    }
+    writeln!(
+        out,
+        "keys: {} readable, {} not",
+        keys.len() - unreadable,
+        unreadable
+    )?;
+    match first_problem {
+        Some(e) => Err(e),
+        None => {
+            writeln!(out, "OK")?;
+            Ok(())
+        }
+    }
+}
+
+fn dump_file(db: &Db, file_id: u32, out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "offset\tstate\tseq\tkey\tvalue size\tcrc")?;
+    for record in db.file_entries(file_id)? {
+        let FileRecord {
+            offset,
+            state,
+            key,
+            value_size,
+            crc_ok,
+        } = record?;
+        let (seq_no, key) = split_transaction_key(&key);
+        let state = match state {
+            Some(State::Active) => "active",
+            Some(State::Inactive) => "deleted",
+            Some(State::Committed) => "committed",
+            None => "unknown",
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            offset,
+            state,
+            seq_no,
+            show(key),
+            value_size,
+            if crc_ok { "ok" } else { "FAIL" }
+        )?;
+    }
+    Ok(())
+}
+
+// A stored key's batch sequence number and user key. A damaged prefix leaves
+// the key whole.
+fn split_transaction_key(key: &[u8]) -> (u32, &[u8]) {
+    match decode_length_delimiter(key) {
+        Ok(seq_no) if length_delimiter_len(seq_no) <= key.len() => {
+            (seq_no as u32, &key[length_delimiter_len(seq_no)..])
+        }
+        _ => (0, key),
+    }
+}
+
+#[cfg(feature = "write-shards")]
+fn recorded_write_shards(dir_path: &Path) -> usize {
+    std::fs::read_to_string(dir_path.join(crate::shard::SHARDS_FILE_NAME))
+        .ok()
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn show(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn cli(args: &[&str]) -> Result<String> {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let mut out = Vec::new();
+        run(&args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn fresh_dir(name: &str) -> String {
+        let dir = format!("/tmp/{}", name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_put_get_round_trip() -> Result<()> {
+        let dir = fresh_dir("test_cli_round_trip");
+        assert_eq!(cli(&[&dir, "put", "greeting", "hello\tworld"])?, "OK\n");
+        assert_eq!(cli(&[&dir, "get", "greeting"])?, "hello\\tworld\n");
+        assert_eq!(cli(&[&dir, "del", "greeting"])?, "OK\n");
+        assert!(cli(&[&dir, "get", "greeting"]).is_err());
+        // Reads don't create a database
+        assert!(cli(&["/tmp/test_cli_missing", "keys"]).is_err());
+        assert!(cli(&[&dir, "frobnicate"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_output() -> Result<()> {
+        let dir = fresh_dir("test_cli_output");
+        {
+            let db = Db::open(&Opts {
+                dir_path: PathBuf::from(&dir),
+                ..Opts::default()
+            })?;
+            db.put(Bytes::from("user:1"), Bytes::from("alice"))?;
+            db.put(Bytes::from("user:2"), Bytes::from("bob"))?;
+            db.put(Bytes::from("other"), Bytes::from(&b"\x00\xff"[..]))?;
+            db.delete(Bytes::from("user:2"))?;
+            db.put_all(&[(Bytes::from("user:3"), Bytes::from("carol"))])?;
+        }
+
+        assert_eq!(cli(&[&dir, "keys"])?, "other\nuser:1\nuser:3\n");
+        assert_eq!(
+            cli(&[&dir, "scan", "--prefix", "user:"])?,
+            "user:1\talice\nuser:3\tcarol\n"
+        );
+        assert_eq!(cli(&[&dir, "get", "other"])?, "\\x00\\xff\n");
+        assert_eq!(
+            cli(&[&dir, "stats"])?,
+            "keys: 3\ndata files: 1\ndisk size: 92 bytes\nindex memory: 185 bytes\n"
+        );
+        assert_eq!(
+            cli(&[&dir, "dump-file", "0"])?,
+            "offset\tstate\tseq\tkey\tvalue size\tcrc\n\
+             0\tactive\t0\tuser:1\t5\tok\n\
+             19\tactive\t0\tuser:2\t3\tok\n\
+             36\tactive\t0\tother\t2\tok\n\
+             51\tdeleted\t0\tuser:2\t0\tok\n\
+             65\tactive\t1\tuser:3\t5\tok\n\
+             84\tcommitted\t1\t\t0\tok\n"
+        );
+        assert_eq!(
+            cli(&[&dir, "verify"])?,
+            "file 0: 6 records, 0 corrupt\nkeys: 3 readable, 0 not\nOK\n"
+        );
+
+        // Damage the value of `user:1`
+        let path = Path::new(&dir).join("0.db");
+        let mut data = fs::read(&path)?;
+        data[10] ^= 0xff;
+        fs::write(&path, data)?;
+        let mut out = Vec::new();
+        let args = [dir.clone(), "verify".to_string()];
+        assert!(run(&args, &mut out).is_err());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("file 0 offset 0: CRC mismatch\nfile 0: 6 records, 1 corrupt\n"));
+        assert!(cli(&[&dir, "dump-file", "0"])?.contains("\n0\tactive\t0\tuser:1\t5\tFAIL\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_database_needs_force_shared() -> Result<()> {
+        let dir = fresh_dir("test_cli_force_shared");
+        let db = Db::open(&Opts {
+            dir_path: PathBuf::from(&dir),
+            ..Opts::default()
+        })?;
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+
+        assert!(cli(&[&dir, "keys"]).is_err());
+        assert_eq!(cli(&[&dir, "keys", "--force-shared"])?, "key\n");
+        assert!(cli(&[&dir, "put", "key", "other", "--force-shared"]).is_err());
+        // The holder's lock is untouched
+        assert!(cli(&[&dir, "keys"]).is_err());
+        db.put(Bytes::from("later"), Bytes::from("value"))?;
+        assert_eq!(cli(&[&dir, "get", "later", "--force-shared"])?, "value\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_and_load() -> Result<()> {
+        let dir = fresh_dir("test_cli_dump");
+        cli(&[&dir, "put", "user:1", "alice"])?;
+        assert_eq!(
+            cli(&[&dir, "dump", "--utf8-lossy"])?,
+            "{\"key_b64\":\"dXNlcjox\",\"value_b64\":\"YWxpY2U=\",\"key\":\"user:1\",\"value\":\"alice\"}\n"
+        );
+        let dump_path = format!("{}.jsonl", dir);
+        fs::write(&dump_path, cli(&[&dir, "dump"])?)?;
+
+        let copy = fresh_dir("test_cli_load");
+        assert_eq!(cli(&[&copy, "load", &dump_path])?, "1 keys\n");
+        assert_eq!(cli(&[&copy, "get", "user:1"])?, "alice\n");
+        assert!(cli(&[&copy, "keys", "--utf8-lossy"]).is_err());
+        Ok(())
+    }
+}
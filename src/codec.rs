@@ -0,0 +1,440 @@
+//! Keys and values encoded by codecs over a shared `Db`, without serde.
+//!
+//! A `KeyCodec<K>` turns keys into bytes whose byte order is the order of
+//! the keys, so ordered scans come back in key order: `BigEndian` for
+//! integers, `Utf8` for strings, `UuidBytes` for the 16 bytes of a UUID and
+//! a tuple of codecs for a tuple of keys. A `ValueCodec<V>` needs no
+//! ordering. `CodecDb<K, V>` stores keys and values encoded with them.
+//!
+//! Bytes that don't decode fail with `Error::Undecodable`, which holds them.
+
+use crate::db::Db;
+use crate::{Error, Result};
+use bytes::Bytes;
+use std::ops::RangeBounds;
+use std::{fmt, marker::PhantomData, sync::Arc, vec};
+
+// Ends a tuple element, and stands for a zero byte inside one when escaped
+const ELEMENT_END: [u8; 2] = [0x00, 0x01];
+const ESCAPED_ZERO: [u8; 2] = [0x00, 0xff];
+
+/// Encodes keys as bytes that sort like the keys.
+pub trait KeyCodec<K> {
+    fn encode(key: &K) -> Bytes;
+    fn decode(bytes: &[u8]) -> Result<K>;
+}
+
+/// Encodes values as bytes.
+pub trait ValueCodec<V> {
+    fn encode(value: &V) -> Bytes;
+    fn decode(bytes: &[u8]) -> Result<V>;
+}
+
+/// Integers as fixed-width big-endian bytes. Signed integers have their sign
+/// bit flipped so negative numbers sort first.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BigEndian;
+
+/// Strings as their UTF-8 bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8;
+
+/// UUIDs as their 16 bytes, as `uuid::Uuid::as_bytes` gives them, which
+/// sort like the UUIDs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidBytes;
+
+/// Values stored as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+fn undecodable(bytes: &[u8], reason: impl Into<String>) -> Error {
+    Error::Undecodable {
+        bytes: bytes.to_vec(),
+        reason: reason.into(),
+    }
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| undecodable(bytes, format!("expected {} bytes, got {}", N, bytes.len())))
+}
+
+macro_rules! unsigned_codec {
+    ($($int:ty),*) => {$(
+        impl KeyCodec<$int> for BigEndian {
+            fn encode(key: &$int) -> Bytes {
+                Bytes::copy_from_slice(&key.to_be_bytes())
+            }
+
+            fn decode(bytes: &[u8]) -> Result<$int> {
+                Ok(<$int>::from_be_bytes(fixed(bytes)?))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_codec {
+    ($($int:ty => $unsigned:ty),*) => {$(
+        impl KeyCodec<$int> for BigEndian {
+            fn encode(key: &$int) -> Bytes {
+                let flipped = (*key as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                Bytes::copy_from_slice(&flipped.to_be_bytes())
+            }
+
+            fn decode(bytes: &[u8]) -> Result<$int> {
+                let flipped = <$unsigned>::from_be_bytes(fixed(bytes)?);
+                Ok((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $int)
+            }
+        }
+    )*};
+}
+
+unsigned_codec!(u8, u16, u32, u64, u128);
+signed_codec!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyCodec<String> for Utf8 {
+    fn encode(key: &String) -> Bytes {
+        Bytes::copy_from_slice(key.as_bytes())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| undecodable(bytes, e.to_string()))
+    }
+}
+
+impl KeyCodec<[u8; 16]> for UuidBytes {
+    fn encode(key: &[u8; 16]) -> Bytes {
+        Bytes::copy_from_slice(key)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<[u8; 16]> {
+        fixed(bytes)
+    }
+}
+
+// Every key codec encodes values too
+impl<V, C: KeyCodec<V>> ValueCodec<V> for C {
+    fn encode(value: &V) -> Bytes {
+        <C as KeyCodec<V>>::encode(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<V> {
+        <C as KeyCodec<V>>::decode(bytes)
+    }
+}
+
+impl KeyCodec<Bytes> for Raw {
+    fn encode(key: &Bytes) -> Bytes {
+        key.clone()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Bytes> {
+        Ok(Bytes::copy_from_slice(bytes))
+    }
+}
+
+// Tuple elements are escaped and terminated so a shorter element sorts
+// before any it's a prefix of, keeping the tuples in element order
+fn push_element(encoded: &mut Vec<u8>, element: &[u8]) {
+    for &byte in element {
+        match byte {
+            0 => encoded.extend_from_slice(&ESCAPED_ZERO),
+            _ => encoded.push(byte),
+        }
+    }
+    encoded.extend_from_slice(&ELEMENT_END);
+}
+
+// The next element of a tuple encoded in `bytes` from `*at`, unescaped
+fn next_element(bytes: &[u8], at: &mut usize) -> Result<Vec<u8>> {
+    let mut element = Vec::new();
+    loop {
+        match bytes[*at..] {
+            [0x00, 0x01, ..] => {
+                *at += 2;
+                return Ok(element);
+            }
+            [0x00, 0xff, ..] => {
+                element.push(0);
+                *at += 2;
+            }
+            [0x00, ..] => return Err(undecodable(bytes, "bad escape in a tuple")),
+            [byte, ..] => {
+                element.push(byte);
+                *at += 1;
+            }
+            [] => return Err(undecodable(bytes, "unterminated tuple element")),
+        }
+    }
+}
+
+macro_rules! tuple_codec {
+    ($($key:ident $codec:ident),*) => {
+        /// A tuple of codecs encodes the tuple of their keys, ordered by the
+        /// first element, then the second and so on.
+        impl<$($key, $codec: KeyCodec<$key>),*> KeyCodec<($($key,)*)> for ($($codec,)*) {
+            #[allow(non_snake_case)]
+            fn encode(key: &($($key,)*)) -> Bytes {
+                let ($($key,)*) = key;
+                let mut encoded = Vec::new();
+                $(push_element(&mut encoded, &$codec::encode($key));)*
+                Bytes::from(encoded)
+            }
+
+            fn decode(bytes: &[u8]) -> Result<($($key,)*)> {
+                let mut at = 0;
+                let key = ($($codec::decode(&next_element(bytes, &mut at)?)?,)*);
+                if at != bytes.len() {
+                    return Err(undecodable(bytes, "trailing bytes after a tuple"));
+                }
+                Ok(key)
+            }
+        }
+    };
+}
+
+tuple_codec!(A CA, B CB);
+tuple_codec!(A CA, B CB, C CC);
+tuple_codec!(A CA, B CB, C CC, D CD);
+
+// Marks the types without owning values of them, so they needn't be `Send`
+// or `Sync` for the wrappers to be
+type Types<K, V, KC, VC> = PhantomData<fn() -> (K, V, KC, VC)>;
+
+/// A `Db` whose keys are `K`s encoded with `KC` and values `V`s encoded
+/// with `VC`.
+pub struct CodecDb<K, V, KC, VC> {
+    db: Arc<Db>,
+    _types: Types<K, V, KC, VC>,
+}
+
+impl<K, V, KC, VC> CodecDb<K, V, KC, VC>
+where
+    KC: KeyCodec<K>,
+    VC: ValueCodec<V>,
+{
+    pub fn new(db: Arc<Db>) -> Self {
+        CodecDb {
+            db,
+            _types: PhantomData,
+        }
+    }
+
+    /// The database underneath, for the untyped operations.
+    pub fn db(&self) -> &Arc<Db> {
+        &self.db
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        self.db.put(KC::encode(key), VC::encode(value))
+    }
+
+    /// The value of `key`, `None` if there is none.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.db.get_seq(KC::encode(key))? {
+            Some((value, _)) => VC::decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.db.delete(KC::encode(key))
+    }
+
+    /// Iterates over the pairs whose keys are in `range`, in key order
+    /// whatever the index. The keys are listed when this is called; keys
+    /// deleted since are skipped and values are read as they are when
+    /// reached.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<CodecIter<K, V, KC, VC>> {
+        let start = range.start_bound().map(KC::encode);
+        let end = range.end_bound().map(KC::encode);
+        let mut keys = self.db.list_keys()?;
+        keys.retain(|key| RangeBounds::<Bytes>::contains(&(start.as_ref(), end.as_ref()), key));
+        keys.sort();
+        Ok(CodecIter {
+            db: self.db.clone(),
+            keys: keys.into_iter(),
+            _types: PhantomData,
+        })
+    }
+
+    /// Iterates over every pair, in key order.
+    pub fn iter(&self) -> Result<CodecIter<K, V, KC, VC>> {
+        self.range(..)
+    }
+}
+
+impl<K, V, KC, VC> Clone for CodecDb<K, V, KC, VC> {
+    fn clone(&self) -> Self {
+        CodecDb {
+            db: self.db.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, KC, VC> fmt::Debug for CodecDb<K, V, KC, VC> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodecDb").field("db", &self.db).finish()
+    }
+}
+
+/// Iterator returned by `CodecDb::range`, yielding decoded `(K, V)` pairs.
+pub struct CodecIter<K, V, KC, VC> {
+    db: Arc<Db>,
+    keys: vec::IntoIter<Bytes>,
+    _types: Types<K, V, KC, VC>,
+}
+
+impl<K, V, KC, VC> Iterator for CodecIter<K, V, KC, VC>
+where
+    KC: KeyCodec<K>,
+    VC: ValueCodec<V>,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next()?;
+            let value = match self.db.get_seq(key.clone()) {
+                Ok(Some((value, _))) => value,
+                // Deleted since the keys were listed
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            return Some(KC::decode(&key).and_then(|key| Ok((key, VC::decode(&value)?))));
+        }
+    }
+}
+
+impl<K, V, KC, VC> fmt::Debug for CodecIter<K, V, KC, VC> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodecIter")
+            .field("remaining", &self.keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use std::fs;
+
+    fn open(name: &str) -> Result<Arc<Db>> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            format!("/tmp/test_codec_{}", name),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        Ok(Arc::new(Db::open(&opts)?))
+    }
+
+    #[test]
+    fn test_u64_keys_scan_numerically() -> Result<()> {
+        let numbers = CodecDb::<u64, String, BigEndian, Utf8>::new(open("u64")?);
+        // Written out of order
+        for n in (1..1000u64).rev() {
+            numbers.put(&n, &n.to_string())?;
+        }
+        let pairs = numbers.iter()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            pairs.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            (1..1000).collect::<Vec<_>>()
+        );
+        assert_eq!(pairs[8], (9, "9".to_string()));
+
+        // As decimal strings, "100" to "109" would come between "10" and
+        // "11"
+        let keys = numbers
+            .range(9..12)?
+            .map(|pair| pair.map(|(n, _)| n))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, [9, 10, 11]);
+        let keys = numbers
+            .range(995..)?
+            .map(|pair| pair.map(|(n, _)| n))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, [995, 996, 997, 998, 999]);
+
+        numbers.delete(&10)?;
+        assert_eq!(numbers.get(&10)?, None);
+        assert_eq!(numbers.get(&11)?, Some("11".to_string()));
+        assert_eq!(numbers.range(..=11)?.count(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_codecs_keep_order() -> Result<()> {
+        let signed = [i64::MIN, -300, -1, 0, 1, 255, i64::MAX];
+        let encoded = signed
+            .iter()
+            .map(<BigEndian as KeyCodec<i64>>::encode)
+            .collect::<Vec<_>>();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+        for (n, bytes) in signed.iter().zip(encoded.iter()) {
+            assert_eq!(<BigEndian as KeyCodec<i64>>::decode(bytes)?, *n);
+        }
+
+        // Shorter strings, and zero bytes, in their place within a tuple
+        let tuples = [
+            (String::new(), 5u32),
+            ("a".to_string(), 2),
+            ("a".to_string(), 10),
+            ("a\0".to_string(), 0),
+            ("a\0b".to_string(), 0),
+            ("ab".to_string(), 0),
+        ];
+        let encoded = tuples
+            .iter()
+            .map(<(Utf8, BigEndian) as KeyCodec<(String, u32)>>::encode)
+            .collect::<Vec<_>>();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+        for (tuple, bytes) in tuples.iter().zip(encoded.iter()) {
+            assert_eq!(
+                &<(Utf8, BigEndian) as KeyCodec<(String, u32)>>::decode(bytes)?,
+                tuple
+            );
+        }
+
+        let id = [7; 16];
+        let events =
+            CodecDb::<([u8; 16], u64), Bytes, (UuidBytes, BigEndian), Raw>::new(open("tuples")?);
+        events.put(&(id, 2), &Bytes::from("second"))?;
+        events.put(&(id, 1), &Bytes::from("first"))?;
+        events.put(&([8; 16], 0), &Bytes::from("other"))?;
+        let values = events
+            .range((id, 0)..([8; 16], 0))?
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, ["first", "second"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_errors_carry_the_bytes() -> Result<()> {
+        let db = open("errors")?;
+        db.put(Bytes::from("short"), Bytes::from(&b"\xff\xfe"[..]))?;
+        let numbers = CodecDb::<u64, String, BigEndian, Utf8>::new(db.clone());
+        match numbers.iter()?.next() {
+            Some(Err(Error::Undecodable { bytes, .. })) => assert_eq!(bytes, b"short"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let strings = CodecDb::<String, String, Utf8, Utf8>::new(db);
+        match strings.get(&"short".to_string()) {
+            Err(Error::Undecodable { bytes, .. }) => assert_eq!(bytes, b"\xff\xfe"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        type Pair = (u8, String);
+        let unterminated = <(BigEndian, Utf8) as KeyCodec<Pair>>::decode(b"\x07\x00\x01ab");
+        assert!(matches!(unterminated, Err(Error::Undecodable { .. })));
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::batch::{decode_transaction_key, encode_transaction_key};
+use crate::db::{next_file_id, sync_dir, Db, NON_COMMITTED};
+use crate::index::Indexer;
+use crate::shard::{shard_of, WriteShard};
+use crate::storage::{DataEntry, FileHandle};
+use crate::{Error, KeyDirEntry, Result, State};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::sync::atomic::Ordering;
+
+impl Db {
+    /// Rewrites the active file of every shard with only its live entries and
+    /// returns how many bytes that reclaimed.
+    ///
+    /// Overwritten entries and batch markers are dropped. A tombstone is kept
+    /// while the shard has older files that may still hold its key, as replay
+    /// would bring the key back without it. The rewrite gets a new file id, so
+    /// a read that looked a key up in the old file finds it gone and looks
+    /// the key up again. Batches in a rewritten file are no longer reported
+    /// by `iter_transactions`, and change cursors into it expire.
+    pub fn compact_active(&self) -> Result<u64> {
+        if self.ctx.opts.read_only {
+            return Err(Error::Io(ErrorKind::PermissionDenied.into()));
+        }
+
+        // Every write appended so far has to be in the index to tell which
+        // entries are live
+        let _commit_lock = self.batch_commit_lock.write();
+        self.check_open()?;
+        self.index_sequencer.wait_all();
+        let mut reclaimed = 0;
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let mut write_guard = shard.active_file.write();
+            reclaimed += self.compact_locked(shard_index, shard, &mut write_guard)?;
+        }
+        Ok(reclaimed)
+    }
+
+    // Rewrites `active_file`, the locked active file of `shard`
+    fn compact_locked(
+        &self,
+        shard_index: usize,
+        shard: &WriteShard,
+        active_file: &mut FileHandle,
+    ) -> Result<u64> {
+        let file_id = active_file.get_file_id();
+        let shard_count = self.shards.len();
+        let has_older_files = self
+            .inactive_files
+            .iter()
+            .any(|file| shard_of(file.get_file_id(), shard_count) == shard_index);
+
+        let mut live = Vec::new();
+        let mut tombstones = HashSet::new();
+        let mut records = 0;
+        let mut offset = 0;
+        while offset < active_file.get_offset() {
+            let (entry, size) = active_file.extract_stored_entry(offset)?;
+            let (key, _) = decode_transaction_key(entry.get_key().clone());
+            let position = self.ctx.index.get(&key);
+            match entry.get_state() {
+                State::Active => {
+                    if let Some(keydir_entry) = position.filter(|keydir_entry| {
+                        keydir_entry.get_file_id() == file_id && keydir_entry.get_offset() == offset
+                    }) {
+                        live.push((key, entry, keydir_entry));
+                    }
+                }
+                State::Inactive if has_older_files && position.is_none() => {
+                    tombstones.insert(key);
+                }
+                _ => {}
+            }
+            records += 1;
+            offset += size as u64;
+        }
+        if live.len() + tombstones.len() == records {
+            return Ok(0);
+        }
+
+        let new_file_id = next_file_id(file_id, shard_count)?;
+        let mut new_file = self.new_data_file(new_file_id)?;
+        let updates = match write_compacted(&mut new_file, live, tombstones) {
+            Ok(updates) => updates,
+            Err(e) => {
+                let _ = self.remove_data_file(new_file_id);
+                return Err(e);
+            }
+        };
+        if self.ctx.opts.file_crc {
+            new_file.track_crc()?;
+        }
+        if self.ctx.opts.should_sync_dir() {
+            sync_dir(&self.ctx.opts.dir_path)?;
+        }
+
+        // Readers find the new file before any key points into it
+        let reclaimed = active_file.get_offset() - new_file.get_offset();
+        *active_file = new_file;
+        shard.file_id.store(new_file_id, Ordering::SeqCst);
+        shard.publish(active_file);
+        let mut logged = Vec::with_capacity(updates.len());
+        for (key, mut keydir_entry) in updates {
+            if self.ctx.opts.max_db_size.is_some() {
+                keydir_entry.track_references();
+            }
+            if self.hint_log.is_some() {
+                logged.push((key.clone(), Some(keydir_entry.clone())));
+            }
+            self.ctx.index.put(key.into(), keydir_entry);
+        }
+        if let Some(hint_log) = &self.hint_log {
+            hint_log.append(&logged, &[(new_file_id, active_file.get_offset())]);
+        }
+        // Left behind by a crash, the old file replays to the same state
+        // before the new one
+        self.remove_data_file(file_id)?;
+        Ok(reclaimed)
+    }
+}
+
+// Appends the kept entries to `file` outside of any batch and syncs it,
+// returning the new index entries of the live ones
+fn write_compacted(
+    file: &mut FileHandle,
+    live: Vec<(Vec<u8>, DataEntry, KeyDirEntry)>,
+    tombstones: HashSet<Vec<u8>>,
+) -> Result<Vec<(Vec<u8>, KeyDirEntry)>> {
+    let file_id = file.get_file_id();
+    let mut updates = Vec::with_capacity(live.len());
+    for (key, mut entry, old_position) in live {
+        entry.set_key(encode_transaction_key(key.clone(), NON_COMMITTED));
+        let offset = file.get_offset();
+        let written = file.write(&entry.encode()?)?;
+        let mut keydir_entry = KeyDirEntry::new(file_id, offset, written as u32);
+        if let Some(value) = old_position.get_inline_value() {
+            keydir_entry.set_inline_value(value);
+        }
+        updates.push((key, keydir_entry));
+    }
+    for key in tombstones {
+        let entry = DataEntry::new(
+            encode_transaction_key(key, NON_COMMITTED),
+            Vec::new(),
+            State::Inactive,
+        );
+        file.write(&entry.encode()?)?;
+    }
+    file.sync()?;
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatchOptions;
+    use crate::Opts;
+    use bytes::Bytes;
+    use std::fs;
+
+    fn opts(name: &str) -> Opts {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            format!("/tmp/{}", name),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts
+    }
+
+    #[test]
+    fn test_compact_active() -> Result<()> {
+        let opts = opts("test_compact_active");
+        let db = Db::open(&opts)?;
+        for round in 0..20 {
+            for i in 0..10 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("value{}-{}", i, round)),
+                )?;
+            }
+        }
+        let batch = db.new_write_batch(WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: true,
+        })?;
+        batch.put(Bytes::from("batched"), Bytes::from("value"))?;
+        batch.commit()?;
+        db.delete(Bytes::from("key0"))?;
+        let before = db.active_file.read().get_offset();
+
+        let reclaimed = db.compact_active()?;
+        let after = db.active_file.read().get_offset();
+        assert_eq!(before - after, reclaimed);
+        assert!(after < before / 10);
+        assert_eq!(db.compact_active()?, 0);
+
+        let check = |db: &Db| -> Result<()> {
+            assert!(db.get(Bytes::from("key0")).is_err());
+            for i in 1..10 {
+                assert_eq!(
+                    db.get(Bytes::from(format!("key{}", i)))?,
+                    format!("value{}-19", i).into_bytes()
+                );
+            }
+            assert_eq!(db.get(Bytes::from("batched"))?, "value");
+            Ok(())
+        };
+        check(&db)?;
+        // Writes carry on in the rewritten file
+        db.put(Bytes::from("key1"), Bytes::from("new"))?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.get(Bytes::from("key1"))?, "new");
+        db.put(Bytes::from("key1"), Bytes::from("value1-19"))?;
+        check(&db)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_active_keeps_tombstones_of_older_files() -> Result<()> {
+        let opts = opts("test_compact_keeps_tombstones");
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("old"), Bytes::from("value"))?;
+        db.rotate_active_file()?;
+        db.put(Bytes::from("new"), Bytes::from("value"))?;
+        db.delete(Bytes::from("old"))?;
+        db.delete(Bytes::from("new"))?;
+        db.put(Bytes::from("live"), Bytes::from("value"))?;
+
+        assert!(db.compact_active()? > 0);
+        drop(db);
+
+        // Without its tombstone, the put in the older file would come back
+        let db = Db::open(&opts)?;
+        assert!(db.get(Bytes::from("old")).is_err());
+        assert!(db.get(Bytes::from("new")).is_err());
+        assert_eq!(db.get(Bytes::from("live"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_gets_during_compaction() -> Result<()> {
+        let opts = opts("test_gets_during_compaction");
+        let db = Db::open(&opts)?;
+        for i in 0..50 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        std::thread::scope(|s| -> Result<()> {
+            s.spawn(|| {
+                for _ in 0..20 {
+                    for i in 0..50 {
+                        assert_eq!(db.get(Bytes::from(format!("key{}", i))).unwrap(), "value");
+                    }
+                }
+            });
+            for _ in 0..20 {
+                db.put(Bytes::from("key0"), Bytes::from("value"))?;
+                db.compact_active()?;
+            }
+            Ok(())
+        })
+    }
+}
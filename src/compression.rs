@@ -0,0 +1,252 @@
+#[cfg(feature = "compression")]
+use crate::batch::encode_transaction_key;
+#[cfg(feature = "compression")]
+use crate::db::{Db, WhenLocked, NON_COMMITTED};
+#[cfg(feature = "compression")]
+use crate::storage::DataEntry;
+#[cfg(feature = "compression")]
+use crate::State;
+use crate::{Error, Result};
+#[cfg(feature = "compression")]
+use bytes::Bytes;
+
+// The high four bits of a record's state byte hold its compression
+const COMPRESSION_SHIFT: u32 = 4;
+
+/// How a value is compressed in its data file, chosen per write with
+/// `Db::put_compressed`. Reads decompress transparently; without the
+/// `compression` feature, reading a compressed value fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Stored as it is.
+    #[default]
+    None = 0,
+    Lz4 = 1,
+    Snappy = 2,
+    /// LZ4 if that makes the value smaller, otherwise stored as it is, for
+    /// values that may already be compressed. Never recorded itself.
+    Auto = 3,
+}
+
+impl Compression {
+    // The compression recorded in a record's state byte
+    pub(crate) fn from_state_byte(state: u8) -> Result<Self> {
+        match state >> COMPRESSION_SHIFT {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Snappy),
+            v => Err(Error::Unsupported(format!(
+                "Unsupported compression: {}",
+                v
+            ))),
+        }
+    }
+
+    pub(crate) fn state_byte_bits(self) -> u8 {
+        (self as u8) << COMPRESSION_SHIFT
+    }
+
+    // The compression a value ends up stored with and its stored bytes, or
+    // `None` to store it as it is
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress(self, value: &[u8]) -> Result<Option<(Compression, Vec<u8>)>> {
+        match self {
+            Compression::None => Ok(None),
+            Compression::Lz4 => Ok(Some((self, lz4_flex::compress_prepend_size(value)))),
+            Compression::Snappy => {
+                let compressed = snap::raw::Encoder::new()
+                    .compress_vec(value)
+                    .map_err(|e| Error::Unsupported(format!("snappy compression failed: {}", e)))?;
+                Ok(Some((self, compressed)))
+            }
+            Compression::Auto => {
+                let compressed = lz4_flex::compress_prepend_size(value);
+                Ok((compressed.len() < value.len()).then_some((Compression::Lz4, compressed)))
+            }
+        }
+    }
+
+    pub(crate) fn decompress(self, stored: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(stored.to_vec()),
+            #[cfg(feature = "compression")]
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(stored)
+                .map_err(|e| Error::Decode(format!("lz4 decompression failed: {}", e))),
+            #[cfg(feature = "compression")]
+            Compression::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(stored)
+                .map_err(|e| Error::Decode(format!("snappy decompression failed: {}", e))),
+            #[cfg(not(feature = "compression"))]
+            Compression::Lz4 | Compression::Snappy => Err(Error::Unsupported(format!(
+                "{:?} compressed value, built without the compression feature",
+                self
+            ))),
+            Compression::Auto => Err(Error::ReportableBug(
+                "Auto compression recorded in a record".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Db {
+    /// Like `put`, but stores the value compressed with `compression`. The
+    /// value is compressed before any lock is taken, and merges keep it
+    /// compressed. Values inlined in the index are kept uncompressed, and
+    /// `get_mmap_slice` returns `None` for compressed values.
+    pub fn put_compressed(&self, key: Bytes, value: Bytes, compression: Compression) -> Result<()> {
+        let Some((compression, stored)) = compression.compress(&value)? else {
+            return self.put(key, value);
+        };
+        self.counters.count_put();
+        self.validate_put(&key, &value)?;
+        let entry = DataEntry::new(
+            encode_transaction_key(key.to_vec(), NON_COMMITTED),
+            stored,
+            State::Active,
+        )
+        .with_compression(compression);
+
+        self.put_entry(&key, &value, &entry, WhenLocked::Wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::Opts;
+    use bytes::Bytes;
+    use std::fs;
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn test_compressed_values_need_the_feature() -> Result<()> {
+        use crate::batch::encode_transaction_key;
+        use crate::db::NON_COMMITTED;
+        use crate::index::Indexer;
+        use crate::storage::DataEntry;
+        use crate::State;
+
+        let opts = Opts::new(
+            256,
+            4096,
+            false,
+            false,
+            "/tmp/test_compressed_values_need_the_feature".to_string(),
+            4096,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        let entry = DataEntry::new(
+            encode_transaction_key(b"lz4".to_vec(), NON_COMMITTED),
+            b"stored".to_vec(),
+            State::Active,
+        )
+        .with_compression(Compression::Lz4);
+        let keydir_entry = db.append_entry(&entry)?;
+        db.ctx.index.put(b"lz4".as_slice().into(), keydir_entry);
+        assert!(matches!(
+            db.get(Bytes::from("lz4")),
+            Err(Error::Unsupported(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    fn compressible_value() -> Bytes {
+        Bytes::from("the quick brown fox jumps over the lazy dog ".repeat(20))
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_put_compressed_algorithms() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            4096,
+            false,
+            false,
+            "/tmp/test_put_compressed".to_string(),
+            4096,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(crate::merge::merge_dir_path(&opts.dir_path)?);
+        let mut db = Db::open(&opts)?;
+        let value = compressible_value();
+        db.put(Bytes::from("raw"), value.clone())?;
+        let raw_size = db.locate(b"raw").unwrap().get_size();
+        for (key, compression) in [
+            ("none", Compression::None),
+            ("lz4", Compression::Lz4),
+            ("snappy", Compression::Snappy),
+            ("auto", Compression::Auto),
+        ] {
+            db.put_compressed(Bytes::from(key), value.clone(), compression)?;
+            assert_eq!(db.get(Bytes::from(key))?, value);
+            let size = db.locate(key.as_bytes()).unwrap().get_size();
+            match compression {
+                Compression::None => assert_eq!(size, raw_size + 1),
+                _ => assert!(
+                    size < raw_size / 4,
+                    "{:?} stored {} bytes",
+                    compression,
+                    size
+                ),
+            }
+        }
+
+        // Files fill up so the values end up in inactive, mapped files
+        for i in 0..20 {
+            db.put(Bytes::from(format!("filler{}", i)), value.clone())?;
+        }
+        assert!(db.get_mmap_slice(b"lz4")?.is_none());
+        assert_eq!(db.get(Bytes::from("snappy"))?, value);
+        assert_eq!(db.get_seq(Bytes::from("lz4"))?.unwrap().0, value);
+
+        // Replay and merge keep them readable and compressed
+        db.close()?;
+        let mut db = Db::open(&opts)?;
+        let size = db.locate(b"snappy").unwrap().get_size();
+        db.merge()?;
+        db.close()?;
+        let db = Db::open(&opts)?;
+        for key in ["none", "lz4", "snappy", "auto"] {
+            assert_eq!(db.get(Bytes::from(key))?, value);
+        }
+        assert_eq!(db.locate(b"snappy").unwrap().get_size(), size);
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_auto_stores_incompressible_values_raw() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            4096,
+            false,
+            false,
+            "/tmp/test_auto_compression".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        // Bytes of an LCG don't repeat within the value
+        let mut state = 1u32;
+        let value = (0..1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 24) as u8
+            })
+            .collect::<Bytes>();
+        db.put(Bytes::from("raw"), value.clone())?;
+        db.put_compressed(Bytes::from("auto"), value.clone(), Compression::Auto)?;
+        db.put_compressed(Bytes::from("lz4"), value.clone(), Compression::Lz4)?;
+
+        let raw_size = db.locate(b"raw").unwrap().get_size();
+        assert_eq!(db.locate(b"auto").unwrap().get_size(), raw_size + 1);
+        assert!(db.locate(b"lz4").unwrap().get_size() > raw_size);
+        assert_eq!(db.get(Bytes::from("auto"))?, value);
+        assert_eq!(db.get(Bytes::from("lz4"))?, value);
+        Ok(())
+    }
+}
@@ -1,63 +1,260 @@
 use crate::{
     batch::{decode_transaction_key, encode_transaction_key},
-    index::{HashMap, Indexer},
-    io::{MmapIO, StandardIO},
-    merge::MERGE_FINISHED_FILE,
+    changes::read_generation,
+    compression::Compression,
+    evict::Evictor,
+    index::{HashMap, IndexIterator, IndexMode, Indexer},
+    io::{MemoryIO, MmapIO, MmapSlice, StandardIO},
+    limiter::ReadLimiter,
+    memoizer::Memoizer,
+    merge::{merge_dir_path, MERGE_FINISHED_FILE},
+    metrics::Counters,
     options::{Context, Opts},
-    storage::{decode_keydir_entry, DataEntry, FileHandle, HintFile, HINT_FILE_NAME},
-    Error, KeyDirEntry, Result, State,
+    runtime::SharedRuntime,
+    secondary::SecondaryIndexes,
+    sequencer::{IndexSequencer, IndexUpdate},
+    shard::{
+        check_shard_count, shard_count, shard_for_key, shard_for_transaction_key, shard_of,
+        size_class, size_class_of, WriteShard, LARGE_VALUES, SHARD_FILE_IDS, SMALL_VALUES,
+    },
+    storage::{
+        decode_coverage, decode_keydir_entry, read_sidecar, remove_manifest, write_sidecar,
+        DataEntry, EntryIter, FileCrc, FileHandle, FileSummary, HintFile, HintLog, KeyFile,
+        LoadedHintLog, LockFile, Manifest, HINT_FILE_NAME, KEY_FILE_NAME, LOCK_FILE_NAME,
+    },
+    syncer::Position,
+    Error, KeyDirEntry, Result, State, CRC_LEN,
 };
 use bytes::Bytes;
-use dashmap::DashMap;
-use fs2::FileExt;
-use parking_lot::{Mutex, RwLock};
+use dashmap::{DashMap, DashSet};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::{
     fs::{self, create_dir_all, read_dir, remove_dir_all, File},
     io::ErrorKind,
-    sync::{atomic::AtomicU32, Arc},
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
 };
-use std::{path::Path, sync::atomic::Ordering};
 
-const FILE_SUFFIX: &str = ".db";
-const INITIAL_FILE_ID: u32 = 0;
-const FILE_LOCK: &str = "file.lock";
+pub(crate) const FILE_SUFFIX: &str = ".db";
+pub(crate) const INITIAL_FILE_ID: u32 = 0;
+// Ids at the end of each shard's range that only `merge` rotates into, so a
+// database that has run out of ids can still be merged
+const RESERVED_FILE_IDS: u32 = 1024;
+// Lookups of a key whose file has gone missing before the error is returned
+const READ_ATTEMPTS: usize = 3;
+// Threads `Db::get_timeout` reads on
+const TIMED_READ_THREADS: usize = 4;
 pub(crate) const NON_COMMITTED: u32 = 0;
+
+// A put's hold on `Db::batch_commit_lock`, see `Db::lock_for_put`. Only
+// ever dropped.
+#[allow(dead_code)]
+pub(crate) enum PutLock<'a> {
+    Shared(RwLockReadGuard<'a, ()>),
+    Exclusive(RwLockWriteGuard<'a, ()>),
+}
+
+// What `Db::put_entry` does when a lock it needs is held
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum WhenLocked {
+    Wait,
+    // Fails with `Error::Unsupported("would block")`
+    Fail,
+}
+
 #[derive(Debug)]
 pub struct Db {
     pub ctx: Context,
     pub active_file: Arc<RwLock<FileHandle>>,
     pub inactive_files: Arc<DashMap<u32, FileHandle>>,
-    file_id: AtomicU32,
+    // One per write shard; shard 0 shares `active_file`
+    pub(crate) shards: Vec<WriteShard>,
     pub sequence_number: Arc<AtomicU32>,
-    pub batch_commit_lock: Mutex<()>,
-    lock_file: File,
+    // Taken exclusively by batch commits and shared by direct writes
+    pub batch_commit_lock: RwLock<()>,
+    pub(crate) index_sequencer: IndexSequencer,
+    pub(crate) merge_lock: Mutex<()>,
+    // Set once `shutdown` begins
+    pub(crate) closed: AtomicBool,
+    pub(crate) lock_file: Mutex<LockFile>,
+    pub(crate) open_report: OpenReport,
+    pub(crate) counters: Counters,
+    // What background work runs on when not on threads of its own
+    pub(crate) runtime: Option<SharedRuntime>,
+    // Merges installed in the directory, for telling change cursors apart
+    pub(crate) generation: u64,
+    // Entries of batches given to `apply_raw` whose marker hasn't come yet
+    pub(crate) raw_batches: Mutex<std::collections::HashMap<u32, Vec<IndexUpdate>>>,
+    // Set by `Opts::max_concurrent_reads`
+    pub(crate) read_limiter: Option<Arc<ReadLimiter>>,
+    // Runs the reads of `get_timeout`, started on first use
+    pub(crate) read_workers: Mutex<Option<SharedRuntime>>,
+    // Held while evicting under `Opts::max_db_size`
+    pub(crate) eviction_lock: Mutex<()>,
+    // Started by `start_evictor`
+    pub(crate) evictor: Evictor,
+    pub(crate) eviction_listeners: Mutex<Vec<mpsc::Sender<Bytes>>>,
+    // Set by `Opts::incremental_hint` unless read-only
+    pub(crate) hint_log: Option<HintLog>,
+    // Set by `Opts::file_manifest` unless read-only
+    pub(crate) memoizer: Option<Memoizer>,
+    // Under size classes, the keys last written to the large values' class
+    pub(crate) large_keys: DashSet<Vec<u8>>,
+    // Set by `open_in_memory`: data files are `MemoryIO` buffers and
+    // nothing is written to `Opts::dir_path`
+    pub(crate) in_memory: bool,
+    // Kept by `create_index`
+    pub(crate) secondary_indexes: SecondaryIndexes,
+}
+
+/// What `Db::open` did to rebuild the index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Files whose entries were read to rebuild the index.
+    pub replayed_files: Vec<u32>,
+    /// Files whose index contribution was loaded from the manifest instead.
+    pub memoized_files: Vec<u32>,
+    /// Batch entries dropped because their batch outgrew
+    /// `Opts::max_buffered_batch_entries`.
+    pub oversized_batch_entries: u64,
+    /// Batch entries dropped because their batch's committed marker never
+    /// made it into the file.
+    pub uncommitted_batch_entries: u64,
+    /// Corrupt records stepped over under `Opts::skip_corrupt_records`.
+    pub skipped_corrupt_records: u64,
+    /// A hint file was found that doesn't match the data files, so it was
+    /// left out and the index rebuilt by replay alone.
+    pub hint_file_ignored: bool,
+    /// The index was loaded from the hint log of `Opts::incremental_hint`,
+    /// so only what was appended after its last write was replayed.
+    pub loaded_hint_log: bool,
+    /// Files left out under `Opts::tolerate_missing_files` because they
+    /// couldn't be opened or replayed. Their keys aren't in the index.
+    pub skipped_files: Vec<u32>,
+    /// Files that no longer match their sidecar under `Opts::file_crc`, and
+    /// were replayed for what's left of them.
+    pub crc_mismatches: Vec<u32>,
+}
+
+/// The index contribution of one data file.
+#[derive(Debug, Default)]
+pub(crate) struct FileReplay {
+    // Final position of every key the file wrote, `None` if it deleted it
+    entries: std::collections::HashMap<Vec<u8>, Option<KeyDirEntry>>,
+    entry_count: u64,
+    max_seq_no: u32,
+    // Where the last intact record ends
+    pub(crate) size: u64,
+    oversized_batch_entries: u64,
+    uncommitted_batch_entries: u64,
+    skipped_corrupt_records: u64,
+    // The file's size class under `Opts::size_class_boundary`
+    size_class: Option<usize>,
+    // Most batch entries buffered at once
+    #[cfg(test)]
+    peak_buffered: usize,
+}
+
+impl FileReplay {
+    /// The keys the file wrote or deleted.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.entries.keys()
+    }
+}
+
+impl OpenReport {
+    fn add_replay(&mut self, replay: &FileReplay) {
+        self.oversized_batch_entries += replay.oversized_batch_entries;
+        self.uncommitted_batch_entries += replay.uncommitted_batch_entries;
+        self.skipped_corrupt_records += replay.skipped_corrupt_records;
+    }
+}
+
+impl FileReplay {
+    /// Applies the file's records to `index`.
+    ///
+    /// Under size classes a key that moves to another class leaves a
+    /// tombstone in the one it left, and every class replays before the
+    /// next. A key still live in an earlier class was written there after
+    /// this file's records, which are skipped.
+    pub(crate) fn apply(&self, index: &impl Indexer, current_sequence_number: &mut u32) {
+        for (key, position) in self.entries.iter() {
+            if let Some(class) = self.size_class {
+                let live_earlier = index
+                    .get(key)
+                    .is_some_and(|entry| shard_of(entry.get_file_id(), LARGE_VALUES + 1) < class);
+                if live_earlier {
+                    continue;
+                }
+            }
+            match position {
+                Some(keydir_entry) => {
+                    index.put(key.as_slice().into(), keydir_entry.clone());
+                }
+                None => {
+                    index.delete(key);
+                }
+            }
+        }
+        if *current_sequence_number < self.max_seq_no {
+            *current_sequence_number = self.max_seq_no;
+        }
+    }
 }
 
 #[allow(dead_code)]
 impl Db {
     pub fn open(opts: &Opts) -> Result<Self> {
+        Self::open_on(opts, None)
+    }
+
+    /// Opens the database with its background work, such as fsyncing sync
+    /// writes, the `sync_interval` flush and the output of merges, running
+    /// on `runtime` instead of threads of its own. Many databases can share
+    /// one runtime.
+    pub fn open_with_runtime(opts: &Opts, runtime: &SharedRuntime) -> Result<Self> {
+        Self::open_on(opts, Some(runtime.clone()))
+    }
+
+    pub(crate) fn open_on(opts: &Opts, runtime: Option<SharedRuntime>) -> Result<Self> {
+        trace_span!("open", dir = %opts.dir_path.display());
         //Validate options
         validate_options(opts)?;
 
         let dir_path = opts.dir_path.clone();
         //Get iterator of all files in the directory
         if !dir_path.is_dir() {
+            // A read-only open writes nothing, not even the directory
+            if opts.read_only {
+                return Err(Error::Io(ErrorKind::NotFound.into()));
+            }
             if let Err(e) = create_dir_all(&opts.dir_path) {
                 return Err(Error::Io(e));
             }
+            #[cfg(unix)]
+            set_mode(&dir_path, opts.dir_mode)?;
         }
 
         // Check if the directory is already in use
-        let lock_file = fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .append(true)
-            .open(dir_path.join(FILE_LOCK))?;
-        if lock_file.try_lock_exclusive().is_err() {
-            return Err(Error::Unsupported("Database is already in use".to_string()));
-        }
-
-        process_merge_files(&dir_path)?;
+        let lock_file = if opts.skip_lock {
+            LockFile::unlocked(&dir_path)
+        } else {
+            let lock_file = LockFile::acquire(&dir_path, opts.lock_policy)?;
+            // The files the merge replaces still hold everything it does
+            if !opts.read_only {
+                process_merge_files(&dir_path, opts.should_sync_dir())?;
+            }
+            lock_file
+        };
 
         // return_dir will return an error in the following situations, but is not limited to just these cases:
         // 1. The provided path doesn't exist.
@@ -69,139 +266,601 @@ impl Db {
             Err(_) => return Err(Error::Io(ErrorKind::PermissionDenied.into())),
         };
 
-        // Load all file_ids
-        let mut file_ids = dir_iter
-            .filter_map(|file| {
-                if let Ok(file) = file {
-                    let file_name = file.file_name().into_string().unwrap();
-                    if file_name.ends_with(FILE_SUFFIX) {
-                        let file_id = file_name.split(".").next().unwrap();
-                        let file_id = file_id.parse::<u32>().unwrap();
-                        Some(file_id)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<u32>>();
+        // Load all file_ids, with every name each one was parsed from
+        let mut data_files: std::collections::BTreeMap<u32, Vec<PathBuf>> =
+            std::collections::BTreeMap::new();
+        for file in dir_iter.flatten() {
+            let file_name = file.file_name();
+            if let Some(file_id) = file_name.to_str().and_then(parse_data_file_id) {
+                data_files.entry(file_id).or_default().push(file.path());
+            }
+        }
+        // Files are opened as `<id>.db`, so any other name for an id would be
+        // replayed in its place or alongside it
+        for (file_id, paths) in data_files.iter_mut() {
+            let path = dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+            if paths.len() > 1 || paths[0] != path {
+                paths.sort();
+                return Err(Error::ConflictingDataFiles {
+                    file_id: *file_id,
+                    paths: std::mem::take(paths),
+                });
+            }
+        }
 
         // Ensure that the file_ids are in order
-        file_ids.sort();
-        // Create file_handles
-        let mut file_handles = file_ids
-            .iter()
-            .map(|file_id| {
-                let filehandle = FileHandle::new(
-                    *file_id,
-                    MmapIO::new(
-                        &Path::new(&opts.dir_path).join(format!("{}{}", file_id, FILE_SUFFIX)),
-                    )
-                    .unwrap()
-                    .into(),
-                );
-                filehandle
-            })
-            .collect::<Vec<FileHandle>>();
+        let file_ids = data_files.into_keys().collect::<Vec<u32>>();
+        // Create file_handles. Under `tolerate_missing_files` a file that
+        // can't be opened keeps its error, to be skipped below
+        let mut file_handles = Vec::with_capacity(file_ids.len());
+        for file_id in file_ids.iter() {
+            let path = Path::new(&opts.dir_path).join(format!("{}{}", file_id, FILE_SUFFIX));
+            // Mapping only reads, so files this process can't write to
+            // still replay
+            match MmapIO::open_read_only(&path) {
+                Ok(io) => file_handles.push((*file_id, Ok(FileHandle::new(*file_id, io.into())))),
+                Err(e) if opts.tolerate_missing_files => file_handles.push((*file_id, Err(e))),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let shard_count = shard_count(opts);
+        check_shard_count(&dir_path, shard_count, &file_ids)?;
+        let crc_mismatches = match opts.file_crc {
+            true => Self::check_file_crcs(opts, &file_ids)?,
+            false => Vec::new(),
+        };
+
+        // The newest file of each shard stays active
+        let mut active_files = (0..shard_count).map(|_| None).collect::<Vec<_>>();
+        let mut inactive_handles = Vec::new();
+        while let Some(file) = file_handles.pop() {
+            let slot = &mut active_files[shard_of(file.0, shard_count)];
+            if slot.is_none() {
+                *slot = Some(file);
+            } else {
+                inactive_handles.push(file);
+            }
+        }
+        inactive_handles.reverse();
 
         let inactive_files = DashMap::new();
-        let index = HashMap::new();
+        let index = opts.new_index();
         let mut current_sequence_number = NON_COMMITTED;
-        let active_file = match file_handles.pop() {
-            Some(active_file) => {
-                for file in file_handles.iter() {
-                    Self::process_file_handle(file, &index, &mut current_sequence_number);
-                    inactive_files.insert(file.get_file_id(), file.clone());
+        // Under `incremental_hint`, where the hint log ends in each shard
+        let (hint_log, hinted_ends) = match opts.incremental_hint {
+            true => Self::load_hint_log(&dir_path, &file_ids, shard_count)?,
+            false => None,
+        }
+        .unzip();
+        let hint_file_ignored = match hint_log.as_ref() {
+            // It holds what the merge's hint file does and more
+            Some(hint_log) => {
+                for (key, position) in hint_log.updates.iter() {
+                    match position {
+                        Some(keydir_entry) => {
+                            index.put(key.as_slice().into(), keydir_entry.clone());
+                        }
+                        None => {
+                            index.delete(key);
+                        }
+                    }
+                }
+                current_sequence_number = hint_log.sequence_number;
+                false
+            }
+            None => !Self::load_index_from_hint_file(&dir_path, &index)?,
+        };
+        let mut open_report = OpenReport {
+            hint_file_ignored,
+            loaded_hint_log: hint_log.is_some(),
+            crc_mismatches,
+            ..Default::default()
+        };
+        // Whether replay found records the hint log doesn't hold
+        let mut replayed_past_hint_log = false;
+        let mut manifest = if opts.file_manifest {
+            Manifest::load(&dir_path)
+        } else {
+            Manifest::default()
+        };
+        // Each shard replays whole, its active file last, before the next
+        // one's. Keys only move between shards under size classes, which
+        // `FileReplay::apply` orders by shard.
+        let mut shards = Vec::with_capacity(shard_count);
+        for (shard, active_file) in active_files.into_iter().enumerate() {
+            let hinted_end = hinted_ends.as_ref().and_then(|ends| ends[shard]);
+            // Files the hint log covers whole are only opened, and the one
+            // it ends in replays from there
+            let replay_from = |file_id: u32| match hinted_end {
+                Some((hinted_id, _)) if file_id < hinted_id => None,
+                Some((hinted_id, end)) if file_id == hinted_id => Some(end),
+                _ => Some(0),
+            };
+            for (file_id, file) in inactive_handles
+                .iter()
+                .filter(|(file_id, _)| shard_of(*file_id, shard_count) == shard)
+            {
+                let Ok(file) = file else {
+                    open_report.skipped_files.push(*file_id);
+                    continue;
+                };
+                let Some(from) = replay_from(*file_id) else {
+                    file.set_offset(file.file_size()?);
+                    inactive_files.insert(file.get_file_id(), file.freeze());
+                    continue;
+                };
+                let memoized = match from {
+                    0 => Self::load_memoized_file(file, &manifest, opts),
+                    _ => None,
+                };
+                let replay = match memoized {
+                    Some(replay) => {
+                        trace_event!(
+                            file_id,
+                            records = replay.entry_count,
+                            "loaded from manifest"
+                        );
+                        open_report.memoized_files.push(file.get_file_id());
+                        replay
+                    }
+                    None => {
+                        let replay = match Self::process_file_from(file, from, opts) {
+                            Ok(replay) => replay,
+                            Err(_) if opts.tolerate_missing_files => {
+                                open_report.skipped_files.push(*file_id);
+                                continue;
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        open_report.replayed_files.push(file.get_file_id());
+                        open_report.add_replay(&replay);
+                        if opts.file_manifest && from == 0 && !opts.read_only {
+                            Self::memoize_file(file, &replay, &mut manifest, &dir_path)?;
+                        }
+                        replay
+                    }
+                };
+                replayed_past_hint_log |= replay.entry_count > 0;
+                replay.apply(&index, &mut current_sequence_number);
+                file.set_offset(replay.size);
+                inactive_files.insert(file.get_file_id(), file.freeze());
+            }
+            let replayed = active_file.map(|(file_id, file)| {
+                let replay = file.and_then(|file| {
+                    let replay =
+                        Self::process_file_from(&file, replay_from(file_id).unwrap_or(0), opts)?;
+                    Ok((file, replay))
+                });
+                (file_id, replay)
+            });
+            let active_file = match replayed {
+                // Appends go to a new file after it, so the file still
+                // replays after them once it's readable again
+                Some((file_id, Err(_))) if opts.tolerate_missing_files => {
+                    open_report.skipped_files.push(file_id);
+                    let new_file = create_data_file(opts, next_file_id(file_id, shard_count)?)?;
+                    if opts.should_sync_dir() {
+                        sync_dir(&dir_path)?;
+                    }
+                    new_file
+                }
+                Some((_, Err(e))) => return Err(e),
+                Some((_, Ok((mut active_file, replay)))) => {
+                    open_report.replayed_files.push(active_file.get_file_id());
+                    open_report.add_replay(&replay);
+                    replayed_past_hint_log |= replay.entry_count > 0;
+                    replay.apply(&index, &mut current_sequence_number);
+                    active_file.set_offset(replay.size);
+                    match active_file.set_io(&dir_path, opts.read_only) {
+                        Ok(()) => {
+                            if !opts.read_only {
+                                drop_torn_tail(&dir_path, &active_file)?;
+                            }
+                            active_file
+                        }
+                        // Left behind read-only, e.g. restored from a backup:
+                        // retire it and append to a new file instead
+                        Err(Error::Io(e))
+                            if !opts.read_only && e.kind() == ErrorKind::PermissionDenied =>
+                        {
+                            let file_id = next_file_id(active_file.get_file_id(), shard_count)?;
+                            let new_file = create_data_file(opts, file_id)?;
+                            if opts.should_sync_dir() {
+                                sync_dir(&dir_path)?;
+                            }
+                            inactive_files.insert(active_file.get_file_id(), active_file.freeze());
+                            new_file
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                // Nothing to read, and nothing may be created
+                None if opts.read_only => {
+                    let file_id = INITIAL_FILE_ID + shard as u32 * SHARD_FILE_IDS;
+                    FileHandle::new(file_id, MemoryIO::default().into())
+                }
+                None => {
+                    let file_id = INITIAL_FILE_ID + shard as u32 * SHARD_FILE_IDS;
+                    let path = dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+                    let mut active_file = FileHandle::new(file_id, MmapIO::new(&path)?.into());
+                    #[cfg(unix)]
+                    set_mode(&path, opts.file_mode)?;
+                    if opts.should_sync_dir() {
+                        sync_dir(&dir_path)?;
+                    }
+                    active_file.set_io(&dir_path, opts.read_only)?;
+                    active_file
                 }
-                Self::process_file_handle(&active_file, &index, &mut current_sequence_number);
-                active_file
+            };
+            if opts.file_crc && !opts.read_only {
+                active_file.track_crc()?;
+            }
+            shards.push(WriteShard::start(active_file, opts, runtime.as_ref())?);
+        }
+        // Read-only opens may share the directory with a writer
+        let memoizer = if opts.file_manifest && !opts.read_only {
+            if !file_ids.is_empty() {
+                manifest.retain(&file_ids);
+                manifest.save(&dir_path)?;
             }
-            None => FileHandle::new(
-                INITIAL_FILE_ID,
-                MmapIO::new(
-                    &Path::new(&dir_path).join(format!("{}{}", INITIAL_FILE_ID, FILE_SUFFIX,)),
-                )?
-                .into(),
-            ),
+            Some(Memoizer::start(manifest, opts, runtime.as_ref())?)
+        } else {
+            None
         };
+        if opts.max_db_size.is_some() {
+            for key in index.list_keys()? {
+                if let Some(mut entry) = index.get(&key) {
+                    entry.track_references();
+                    index.put(key.to_vec().into(), entry);
+                }
+            }
+        }
+        let large_keys = DashSet::new();
+        if opts.size_class_boundary.is_some() {
+            for key in index.list_keys()? {
+                let large = index.get(&key).is_some_and(|entry| {
+                    shard_of(entry.get_file_id(), shard_count) == LARGE_VALUES
+                });
+                if large {
+                    large_keys.insert(key.to_vec());
+                }
+            }
+        }
+        open_report.replayed_files.sort();
+        if !open_report.skipped_files.is_empty() {
+            open_report.skipped_files.sort();
+            // The hint file may point into them
+            for key in index.list_keys()? {
+                let in_skipped_file = index
+                    .get(&key)
+                    .is_some_and(|entry| open_report.skipped_files.contains(&entry.get_file_id()));
+                if in_skipped_file {
+                    index.delete(&key);
+                }
+            }
+        }
 
-        let file_id = active_file.get_file_id();
-        let db = Db {
+        let mut db = Db {
             ctx: Context::new(opts, index),
-            active_file: Arc::new(RwLock::new(active_file)),
+            active_file: shards[0].active_file.clone(),
             inactive_files: Arc::new(inactive_files),
-            file_id: AtomicU32::from(file_id),
+            shards,
             sequence_number: Arc::new(AtomicU32::new(current_sequence_number + 1)),
-            batch_commit_lock: Mutex::new(()),
-            lock_file,
+            batch_commit_lock: RwLock::new(()),
+            index_sequencer: IndexSequencer::default(),
+            merge_lock: Mutex::new(()),
+            closed: AtomicBool::new(false),
+            lock_file: Mutex::new(lock_file),
+            open_report,
+            counters: Counters::default(),
+            runtime,
+            generation: read_generation(&dir_path)?,
+            raw_batches: Mutex::new(std::collections::HashMap::new()),
+            read_limiter: opts
+                .max_concurrent_reads
+                .map(|limit| Arc::new(ReadLimiter::new(limit))),
+            read_workers: Mutex::new(None),
+            eviction_lock: Mutex::new(()),
+            evictor: Evictor::default(),
+            eviction_listeners: Mutex::new(Vec::new()),
+            hint_log: None,
+            memoizer,
+            large_keys,
+            in_memory: false,
+            secondary_indexes: SecondaryIndexes::default(),
         };
 
-        let mut write_guard = db.active_file.write();
-        write_guard.set_io(&dir_path)?;
-        drop(write_guard);
-
-        for file in db.inactive_files.iter() {
-            let mut file = file.value().to_owned();
-            file.set_io(&dir_path)?;
+        if opts.incremental_hint && !opts.read_only {
+            // A log holding far more updates than keys, or missing what was
+            // replayed, is replaced by a snapshot of the index
+            let key_count = db.ctx.index.list_keys()?.len();
+            let appendable = hint_log.filter(|hint_log| {
+                !replayed_past_hint_log && hint_log.updates.len() <= 2 * key_count
+            });
+            db.hint_log = Some(match appendable {
+                Some(hint_log) => {
+                    HintLog::open(&dir_path, hint_log.len, db.sequence_number.clone())?
+                }
+                None => db.snapshot_hint_log()?,
+            });
         }
 
-        db.load_index_from_hint_file()?;
+        if opts.warmup {
+            db.warm_up();
+        }
 
         Ok(db)
     }
 
-    /// Processes a file handle and loads its entries into the index.
+    // The hint log of `Opts::incremental_hint` and where it ends in each
+    // shard, if every position it holds lies within the data files. A log
+    // ahead of them, say because its writes outlived theirs in a crash, is
+    // left out.
+    fn load_hint_log(
+        dir_path: &Path,
+        file_ids: &[u32],
+        shard_count: usize,
+    ) -> Result<Option<(LoadedHintLog, Vec<Option<Position>>)>> {
+        let Some(hint_log) = HintLog::load(dir_path)? else {
+            return Ok(None);
+        };
+        trace_span!("load_hint_log", updates = hint_log.updates.len());
+        let mut file_sizes = std::collections::HashMap::new();
+        let mut within = |file_id: u32, end: u64| -> Result<bool> {
+            if !file_ids.contains(&file_id) {
+                return Ok(false);
+            }
+            let size = match file_sizes.get(&file_id) {
+                Some(size) => *size,
+                None => {
+                    let path = dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+                    let size = fs::metadata(path)?.len();
+                    file_sizes.insert(file_id, size);
+                    size
+                }
+            };
+            Ok(end <= size)
+        };
+
+        let mut ends: Vec<Option<Position>> = vec![None; shard_count];
+        for (file_id, end) in hint_log.positions.iter() {
+            let hinted_end = &mut ends[shard_of(*file_id, shard_count)];
+            if hinted_end.is_none_or(|hinted_end| hinted_end < (*file_id, *end)) {
+                *hinted_end = Some((*file_id, *end));
+            }
+        }
+        for (file_id, end) in ends.iter().flatten() {
+            if !within(*file_id, *end)? {
+                trace_event!(file_id, "hint log ignored");
+                return Ok(None);
+            }
+        }
+        let mut positions = std::collections::HashMap::new();
+        for (key, position) in hint_log.updates.iter() {
+            positions.insert(key, position);
+        }
+        for keydir_entry in positions.into_values().flatten() {
+            if !within(keydir_entry.get_file_id(), end_position(keydir_entry).1)? {
+                trace_event!(file_id = keydir_entry.get_file_id(), "hint log ignored");
+                return Ok(None);
+            }
+        }
+        Ok(Some((hint_log, ends)))
+    }
+
+    // Replaces the hint log of `Opts::incremental_hint` with a snapshot of
+    // the index, covering the active files up to their ends. Called with no
+    // writes in flight.
+    fn snapshot_hint_log(&self) -> Result<HintLog> {
+        let mut entries = Vec::new();
+        let mut iter = self.ctx.index.iter();
+        while let Some((key, entry)) = iter.next() {
+            entries.push((key.to_vec(), entry.clone()));
+        }
+        drop(iter);
+        let positions = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let active_file = shard.active_file.read();
+                (active_file.get_file_id(), active_file.get_offset())
+            })
+            .collect::<Vec<_>>();
+        HintLog::create(
+            &self.ctx.opts.dir_path,
+            entries,
+            &positions,
+            self.sequence_number.clone(),
+        )
+    }
+
+    /// Processes a file handle and returns its contribution to the index.
+    ///
+    /// Entries written outside a batch apply directly. Batch entries are buffered
+    /// until their committed marker is found and dropped if it never shows up.
+    /// At most `Opts::max_buffered_batch_entries` are buffered; a batch that
+    /// would go over is dropped whole, so a batch that never committed can't
+    /// exhaust memory.
     ///
-    /// This function reads all entries from the specified file handle, updates the index with active entries,
-    /// and collects deleted keys for later removal.
-    fn process_file_handle(file: &FileHandle, index: &HashMap, current_sequence_number: &mut u32) {
-        let mut transactions: std::collections::HashMap<u32, Vec<(DataEntry, KeyDirEntry)>> =
+    /// A record that fails to decode ends the file if nothing intact follows
+    /// it, as after a crash mid-append. One with intact records after it
+    /// fails with `Error::Corrupted`, or is stepped over under
+    /// `Opts::skip_corrupt_records`.
+    pub(crate) fn process_file_handle(file: &FileHandle, opts: &Opts) -> Result<FileReplay> {
+        Self::process_file_from(file, 0, opts)
+    }
+
+    /// Like `process_file_handle`, but starting at the record at `from`, for
+    /// replaying what was appended to a file after its start was replayed.
+    pub(crate) fn process_file_from(
+        file: &FileHandle,
+        from: u64,
+        opts: &Opts,
+    ) -> Result<FileReplay> {
+        trace_span!("replay_file", file_id = file.get_file_id(), from);
+        let mut replay = FileReplay {
+            size_class: size_class_of(file.get_file_id(), opts),
+            ..Default::default()
+        };
+        let mut transactions: std::collections::HashMap<u32, Vec<IndexUpdate>> =
             std::collections::HashMap::new();
-        let mut offset = 0;
+        let mut buffered = 0;
+        let mut oversized = std::collections::HashSet::new();
+        let mut offset = from;
         let file_id = file.get_file_id();
-        while let Ok((mut data_entry, size)) = file.extract_data_entry(offset) {
-            let keydir_entry = KeyDirEntry::new(file_id, offset, size as u32);
+        loop {
+            let (data_entry, size) = match file.extract_data_entry(offset) {
+                Ok(entry) => entry,
+                Err(_) => match Self::corrupt_record_len(file, offset)? {
+                    None => break,
+                    Some(len) if opts.skip_corrupt_records => {
+                        replay.skipped_corrupt_records += 1;
+                        offset += len as u64;
+                        continue;
+                    }
+                    Some(_) => return Err(Error::Corrupted { file_id, offset }),
+                },
+            };
+            let mut keydir_entry = KeyDirEntry::new(file_id, offset, size as u32);
+            if data_entry.is_active() && opts.should_inline(data_entry.get_value().len()) {
+                keydir_entry.set_inline_value(data_entry.get_value());
+            }
+            let position = match data_entry.get_state() {
+                State::Active => Some(keydir_entry),
+                _ => None,
+            };
             let (key, seq_no) = decode_transaction_key(data_entry.get_key().clone());
             if seq_no == NON_COMMITTED {
-                match data_entry.get_state() {
-                    State::Active => {
-                        index.put(key, keydir_entry);
-                    }
-                    _ => {
-                        index.delete(&key);
-                    }
-                }
+                replay.entries.insert(key, position);
             } else if data_entry.get_state() == State::Committed {
-                let entry = transactions.get(&seq_no).unwrap();
-                entry.iter().for_each(|(data_entry, keydir_entry)| {
-                    index.put(data_entry.get_key().clone(), *keydir_entry);
-                    match data_entry.get_state() {
-                        State::Active => {
-                            index.put(data_entry.get_key().clone(), *keydir_entry);
-                        }
-                        _ => {
-                            index.delete(&key);
-                        }
-                    }
-                });
-                transactions.remove(&seq_no);
+                if let Some(entries) = transactions.remove(&seq_no) {
+                    buffered -= entries.len();
+                    replay.entries.extend(entries);
+                }
+            } else if oversized.contains(&seq_no) {
+                replay.oversized_batch_entries += 1;
+            } else if buffered >= opts.max_buffered_batch_entries {
+                let dropped = transactions.remove(&seq_no).unwrap_or_default();
+                buffered -= dropped.len();
+                replay.oversized_batch_entries += dropped.len() as u64 + 1;
+                oversized.insert(seq_no);
             } else {
-                data_entry.set_key(key);
                 transactions
                     .entry(seq_no)
                     .or_default()
-                    .push((data_entry, keydir_entry));
+                    .push((key, position));
+                buffered += 1;
+                #[cfg(test)]
+                {
+                    replay.peak_buffered = replay.peak_buffered.max(buffered);
+                }
             }
-            if *current_sequence_number < seq_no {
-                *current_sequence_number = seq_no;
+            if replay.max_seq_no < seq_no {
+                replay.max_seq_no = seq_no;
             }
+            replay.entry_count += 1;
             offset += size as u64;
         }
-        file.set_offset(offset);
+        replay.size = offset;
+        replay.uncommitted_batch_entries = transactions
+            .values()
+            .map(|entries| entries.len() as u64)
+            .sum();
+        trace_event!(
+            records = replay.entry_count,
+            size = replay.size,
+            skipped = replay.skipped_corrupt_records,
+            "replayed"
+        );
+        Ok(replay)
+    }
+
+    // How far the undecodable record at `offset` runs until the next intact
+    // record, `None` if none follows and it's the torn end of the file
+    fn corrupt_record_len(file: &FileHandle, offset: u64) -> Result<Option<usize>> {
+        let file_size = file.file_size()?;
+        // Usually only the body is damaged and the header says where it ends
+        if let Ok(len) = file.record_len_at(offset) {
+            if intact_record_at(file, offset + len as u64, file_size) {
+                return Ok(Some(len));
+            }
+        }
+        // A damaged length points anywhere, so look further byte by byte
+        Ok((offset + 1..file_size)
+            .find(|next| intact_record_at(file, *next, file_size))
+            .map(|next| (next - offset) as usize))
+    }
+
+    // Loads the recorded contribution of an immutable file if the file still
+    // has the size and checksum the manifest recorded for it.
+    fn load_memoized_file(
+        file: &FileHandle,
+        manifest: &Manifest,
+        opts: &Opts,
+    ) -> Option<FileReplay> {
+        let dir_path = &opts.dir_path;
+        let summary = manifest.get(file.get_file_id())?;
+        let path = dir_path.join(format!("{}{}", file.get_file_id(), FILE_SUFFIX));
+        if fs::metadata(path).ok()?.len() != summary.size
+            || file.checksum(summary.size).ok()? != summary.checksum
+        {
+            return None;
+        }
+        let entries = read_sidecar(dir_path, file.get_file_id()).ok()?;
+        Some(FileReplay {
+            entries: entries.into_iter().collect(),
+            entry_count: summary.entry_count,
+            max_seq_no: summary.max_seq_no,
+            size: summary.size,
+            size_class: size_class_of(file.get_file_id(), opts),
+            ..Default::default()
+        })
+    }
+
+    pub(crate) fn memoize_file(
+        file: &FileHandle,
+        replay: &FileReplay,
+        manifest: &mut Manifest,
+        dir_path: &Path,
+    ) -> Result<()> {
+        let entries = replay.entries.iter().collect::<Vec<_>>();
+        write_sidecar(dir_path, file.get_file_id(), &entries)?;
+        // Compared against the on-disk length and contents, which include
+        // any torn tail
+        let path = dir_path.join(format!("{}{}", file.get_file_id(), FILE_SUFFIX));
+        let size = fs::metadata(path)?.len();
+        manifest.insert(
+            file.get_file_id(),
+            FileSummary {
+                size,
+                entry_count: replay.entry_count,
+                checksum: file.checksum(size)?,
+                max_seq_no: replay.max_seq_no,
+            },
+        );
+        Ok(())
+    }
+
+    // Asks for every data file to be read ahead. Only a hint, so a file the
+    // kernel won't prefetch is just read on demand.
+    fn warm_up(&self) {
+        for file in self.inactive_files.iter() {
+            let _ = file.prefetch();
+        }
+        for shard in self.shards.iter() {
+            let _ = shard.active_file.read().prefetch();
+        }
     }
 
-    pub fn delete(&mut self, key: Bytes) -> Result<()> {
+    /// What `open` did to rebuild the index.
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        self.counters.count_delete();
+        self.check_open()?;
         // Check read-only state
         if self.ctx.opts.read_only {
             return Err(Error::Io(ErrorKind::PermissionDenied.into()));
@@ -209,7 +868,7 @@ impl Db {
 
         // Validate key
         if key.is_empty() {
-            return Err(Error::Unsupported("Key is required".to_string()));
+            return Err(Error::EmptyKey);
         }
 
         if key.len() > self.ctx.opts.max_key_size {
@@ -231,22 +890,258 @@ impl Db {
             Vec::new(),
             State::Inactive,
         );
-        self.append_entry(&deleted_entry)?;
+        let commit_lock = self.batch_commit_lock.read();
+        self.check_open()?;
+        let shard = &self.shards[self.shard_for_write(&key, None)];
+        let mut write_guard = shard.active_file.write();
+        let keydir_entry = self.append_locked(shard, &mut write_guard, &deleted_entry)?;
+        let ticket = self.index_sequencer.issue();
+        drop(write_guard);
+        drop(commit_lock);
+
+        self.make_visible(
+            ticket,
+            &[end_position(&keydir_entry)],
+            self.ctx.opts.sync_writes,
+            vec![(key.to_vec(), None)],
+        )
+    }
+
+    /// Stores `value` under `key`.
+    ///
+    /// Direct writes share `batch_commit_lock`, which batch commits take
+    /// exclusively, so they serialize with batch commits but not with each
+    /// other: a put lands either wholly before or wholly after a commit, and
+    /// the state after replay matches the state before it. With
+    /// `Opts::sync_writes` the value is only readable once it's durable.
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.counters.count_put();
+        self.validate_put(&key, &value)?;
+        let entry = DataEntry::new(
+            encode_transaction_key(key.to_vec(), NON_COMMITTED),
+            value.clone(),
+            State::Active,
+        );
+        self.put_entry(&key, &value, &entry, WhenLocked::Wait)
+    }
+
+    /// Like `put`, but fails with `Error::Unsupported("would block")` rather
+    /// than wait when another write holds the active file. A sync write still
+    /// waits for its fsync once appended.
+    pub fn try_put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.counters.count_put();
+        self.validate_put(&key, &value)?;
+        let entry = DataEntry::new(
+            encode_transaction_key(key.to_vec(), NON_COMMITTED),
+            value.clone(),
+            State::Active,
+        );
+        self.put_entry(&key, &value, &entry, WhenLocked::Fail)
+    }
 
-        // Remove key from index
-        self.ctx.index.delete(&key);
+    /// Appends `entry`, the record of a put of `value` under `key`, and makes
+    /// it visible once `make_visible` allows. `value` is the value as put,
+    /// which picks its size class and is what gets inlined, even if `entry`
+    /// stores it compressed.
+    pub(crate) fn put_entry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        entry: &DataEntry,
+        when_locked: WhenLocked,
+    ) -> Result<()> {
+        let would_block = || Error::Unsupported("would block".to_string());
+        let commit_lock = match when_locked {
+            WhenLocked::Wait => self.lock_for_put(),
+            WhenLocked::Fail => self.try_lock_for_put().ok_or_else(would_block)?,
+        };
+        self.check_open()?;
+        let shard_index = self.shard_for_write(key, Some(value.len()));
+        let shard = &self.shards[shard_index];
+        let mut write_guard = match when_locked {
+            WhenLocked::Wait => shard.active_file.write(),
+            WhenLocked::Fail => shard.active_file.try_write().ok_or_else(would_block)?,
+        };
+        let mut keydir_entry = self.append_locked(shard, &mut write_guard, entry)?;
+        if self.ctx.opts.should_inline(value.len()) {
+            keydir_entry.set_inline_value(value);
+        }
+        let ticket = self.index_sequencer.issue();
+        drop(write_guard);
+        let mut positions = vec![end_position(&keydir_entry)];
+        positions.extend(
+            self.leave_size_class(key, shard_index)?
+                .map(|e| end_position(&e)),
+        );
+        drop(commit_lock);
 
+        self.make_visible(
+            ticket,
+            &positions,
+            self.ctx.opts.sync_writes,
+            vec![(key.to_vec(), Some(keydir_entry))],
+        )?;
+        self.wake_evictor();
         Ok(())
     }
 
-    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<()> {
+    // Waits until `positions` are durable if `sync` is set, then applies
+    // `updates`, the index updates of the write `ticket` was issued for. A
+    // write whose fsync failed is left out of the index.
+    pub(crate) fn make_visible(
+        &self,
+        ticket: u64,
+        positions: &[Position],
+        sync: bool,
+        updates: Vec<IndexUpdate>,
+    ) -> Result<()> {
+        let durable = if sync {
+            positions
+                .iter()
+                .try_for_each(|position| self.wait_durable(*position))
+        } else {
+            Ok(())
+        };
+        let durable =
+            durable.and_then(|_| fail_point!(&self.ctx.opts.dir_path, SYNC_BEFORE_INDEX_UPDATE));
+        #[cfg(test)]
+        tests::before_index_update();
+        let mut updates = if durable.is_ok() { updates } else { Vec::new() };
+        if let (Err(_), Some(hint_log)) = (&durable, &self.hint_log) {
+            // The records are on disk, so replay would apply them
+            hint_log.abandon();
+        }
+        if self.ctx.opts.max_db_size.is_some() {
+            for (_, entry) in updates.iter_mut() {
+                if let Some(entry) = entry {
+                    entry.track_references();
+                }
+            }
+        }
+        let secondary_updates = self.secondary_updates(&updates);
+        self.index_sequencer.complete(
+            ticket,
+            updates,
+            positions,
+            &self.ctx.index,
+            self.hint_log.as_ref(),
+            (&self.secondary_indexes, secondary_updates),
+        );
+        durable
+    }
+
+    /// Returns the value stored under `key`, or stores and returns the one
+    /// built by `f` if there is none. The check and the insert happen under
+    /// the append lock of the key's shard, so concurrent callers agree on a
+    /// single value.
+    pub fn get_or_insert_with(&self, key: Bytes, f: impl FnOnce() -> Bytes) -> Result<Bytes> {
+        self.validate_read_key(&key)?;
+
+        let commit_lock = self.batch_commit_lock.write();
+        self.check_open()?;
+        // Earlier writes to the key may still be waiting on their fsync
+        self.index_sequencer.wait_all();
+        let mut shard = &self.shards[self.shard_for_write(&key, None)];
+        let mut write_guard = shard.active_file.write();
+        if let Some(entry) = self.locate(&key) {
+            // Reading the active file needs its lock
+            drop(write_guard);
+            drop(commit_lock);
+            return match entry.get_inline_value() {
+                Some(value) => Ok(Bytes::copy_from_slice(value)),
+                None => self.read_at(&key, entry).map(Bytes::from),
+            };
+        }
+
+        let value = f();
+        self.validate_put(&key, &value)?;
+        let shard_index = self.shard_for_write(&key, Some(value.len()));
+        let target = &self.shards[shard_index];
+        if !std::ptr::eq(target, shard) {
+            drop(write_guard);
+            shard = target;
+            write_guard = shard.active_file.write();
+        }
+        let keydir_entry = self.append_put(shard, &mut write_guard, &key, value.clone())?;
+        drop(write_guard);
+        let mut positions = vec![end_position(&keydir_entry)];
+        positions.extend(
+            self.leave_size_class(&key, shard_index)?
+                .map(|e| end_position(&e)),
+        );
+        let ticket = self.index_sequencer.issue();
+
+        // Still holding `commit_lock`, so no other caller checks the key
+        // before this value is visible
+        self.make_visible(
+            ticket,
+            &positions,
+            self.ctx.opts.sync_writes,
+            vec![(key.to_vec(), Some(keydir_entry))],
+        )?;
+        drop(commit_lock);
+        self.wake_evictor();
+        Ok(value)
+    }
+
+    /// Stores `value` under `key` and returns the value it replaced, `None`
+    /// if there was none. The read and the write happen under the append
+    /// lock with the new value visible before it's released, so of
+    /// concurrent swaps of a key each gets back a different old value.
+    pub fn swap(&self, key: Bytes, value: Bytes) -> Result<Option<Bytes>> {
+        self.counters.count_put();
+        self.validate_put(&key, &value)?;
+
+        let commit_lock = self.batch_commit_lock.write();
+        self.check_open()?;
+        // Earlier writes to the key may still be waiting on their fsync
+        self.index_sequencer.wait_all();
+        // Reading the active file needs its lock, so before taking it
+        let old_value = match self.locate(&key) {
+            Some(entry) => Some(match entry.get_inline_value() {
+                Some(value) => Bytes::copy_from_slice(value),
+                None => Bytes::from(self.read_data_entry(&key, entry)?.into_value()),
+            }),
+            None => None,
+        };
+
+        let shard_index = self.shard_for_write(&key, Some(value.len()));
+        let shard = &self.shards[shard_index];
+        let mut write_guard = shard.active_file.write();
+        let keydir_entry = self.append_put(shard, &mut write_guard, &key, value)?;
+        drop(write_guard);
+        let mut positions = vec![end_position(&keydir_entry)];
+        positions.extend(
+            self.leave_size_class(&key, shard_index)?
+                .map(|e| end_position(&e)),
+        );
+        let ticket = self.index_sequencer.issue();
+
+        // Still holding `commit_lock`, so no other swap reads the key before
+        // this value is visible
+        self.make_visible(
+            ticket,
+            &positions,
+            self.ctx.opts.sync_writes,
+            vec![(key.to_vec(), Some(keydir_entry))],
+        )?;
+        drop(commit_lock);
+        self.wake_evictor();
+        Ok(old_value)
+    }
+
+    pub(crate) fn validate_put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_open()?;
         // Check read-only state
         if self.ctx.opts.read_only {
             return Err(Error::Io(ErrorKind::PermissionDenied.into()));
         }
 
         // Validate sizes
-        if key.is_empty() || key.len() > self.ctx.opts.max_key_size {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if key.len() > self.ctx.opts.max_key_size {
             return Err(Error::Unsupported(format!(
                 "limited max_key_size: {}, actual key size:{}",
                 self.ctx.opts.max_key_size,
@@ -254,6 +1149,16 @@ impl Db {
             )));
         }
 
+        if let Some(max_db_size) = self.ctx.opts.max_db_size {
+            if value.len() as u64 > max_db_size {
+                return Err(Error::Unsupported(format!(
+                    "limited max_db_size: {}, actual value size:{}",
+                    max_db_size,
+                    value.len()
+                )));
+            }
+        }
+
         if value.len() > self.ctx.opts.max_value_size {
             return Err(Error::Unsupported(format!(
                 "limited max_value_size: {}, actual value size:{}",
@@ -261,482 +1166,3796 @@ impl Db {
                 value.len()
             )));
         }
+        Ok(())
+    }
 
-        // Append entry to data file
+    // Appends a put to the locked active file of `shard`, returning its index
+    // entry
+    fn append_put(
+        &self,
+        shard: &WriteShard,
+        active_file: &mut FileHandle,
+        key: &[u8],
+        value: Bytes,
+    ) -> Result<KeyDirEntry> {
         let entry = DataEntry::new(
-            encode_transaction_key(key.clone().into(), NON_COMMITTED),
+            encode_transaction_key(key.to_vec(), NON_COMMITTED),
             value,
             State::Active,
         );
-        let keydir_entry = self.append_entry(&entry)?;
-
-        self.ctx.index.put(key.into(), keydir_entry);
+        let mut keydir_entry = self.append_locked(shard, active_file, &entry)?;
+        if self.ctx.opts.should_inline(entry.get_value().len()) {
+            keydir_entry.set_inline_value(entry.get_value());
+        }
 
-        Ok(())
+        Ok(keydir_entry)
     }
 
+    /// Appends `entry` to the active file of its key's shard, or under size
+    /// classes of its stored value's class.
     pub fn append_entry(&self, entry: &DataEntry) -> Result<KeyDirEntry> {
-        let encoded_entry = entry.encode()?;
-        let dir_path = self.ctx.opts.dir_path.clone();
-        let record_len = encoded_entry.len() as u64;
-        let mut write_guard = self.active_file.write();
-        if write_guard.get_offset() + record_len > self.ctx.opts.data_file_size {
-            // persist current active file
-            write_guard.sync()?;
-
-            let current_fid = self.file_id.fetch_add(1, Ordering::SeqCst);
-
-            self.inactive_files.insert(current_fid, write_guard.clone());
-            // create new file
-            let new_file = FileHandle::new(
-                current_fid + 1,
-                StandardIO::new(&Path::new(&dir_path).join(format!(
-                    "{}{}",
-                    self.file_id.load(Ordering::SeqCst),
-                    FILE_SUFFIX,
-                )))?
-                .into(),
-            );
-            *write_guard = new_file;
-        }
-
-        // Append entry to data file
-        let written = write_guard.write(&encoded_entry)?;
-
-        Ok(KeyDirEntry::new(
-            self.file_id.load(Ordering::SeqCst),
-            //offset is not active_file offset
-            write_guard.get_offset() - written as u64,
-            encoded_entry.len() as u32,
-        ))
+        let shard = match self.ctx.opts.size_class_boundary {
+            Some(boundary) => size_class(entry.get_value().len(), boundary),
+            None => shard_for_transaction_key(entry.get_key(), self.shards.len()),
+        };
+        self.append_entry_to(shard, entry)
     }
 
-    pub fn rotate_active_file(&self) -> Result<()> {
-        // persist current active file
-        let mut write_guard = self.active_file.write();
-        write_guard.sync()?;
-
-        let current_fid = self.file_id.fetch_add(1, Ordering::SeqCst);
-
-        self.inactive_files.insert(current_fid, write_guard.clone());
-        // create new file
-        let new_file = FileHandle::new(
-            current_fid + 1,
-            StandardIO::new(&Path::new(&self.ctx.opts.dir_path).join(format!(
-                "{}{}",
-                self.file_id.load(Ordering::SeqCst),
-                FILE_SUFFIX,
-            )))?
-            .into(),
-        );
-        *write_guard = new_file;
-        Ok(())
-    }
-    pub fn get(&self, key: Bytes) -> Result<Vec<u8>> {
-        // Validate key
-        if key.is_empty() || key.len() > self.ctx.opts.max_key_size {
-            return Err(Error::Unsupported(format!(
-                "limited max_key_size: {}, actual key size:{}",
-                self.ctx.opts.max_key_size,
-                key.len()
-            )));
+    // What a put holds of `batch_commit_lock`: a share, or under size
+    // classes, where a put can append to both classes and a key's puts must
+    // not interleave, the whole lock.
+    pub(crate) fn lock_for_put(&self) -> PutLock<'_> {
+        match self.ctx.opts.size_class_boundary {
+            Some(_) => PutLock::Exclusive(self.batch_commit_lock.write()),
+            None => PutLock::Shared(self.batch_commit_lock.read()),
         }
+    }
 
-        match self.ctx.index.get(&key) {
-            Some(entry) => {
-                let data_entry = self.read_data_entry(entry)?;
-                return Ok(data_entry.get_value().clone());
-            }
-            None => Err(Error::Unsupported(
-                "Db read error: Key not found".to_string(),
-            )),
+    pub(crate) fn try_lock_for_put(&self) -> Option<PutLock<'_>> {
+        match self.ctx.opts.size_class_boundary {
+            Some(_) => self.batch_commit_lock.try_write().map(PutLock::Exclusive),
+            None => self.batch_commit_lock.try_read().map(PutLock::Shared),
         }
     }
 
-    fn read_data_entry(&self, entry: KeyDirEntry) -> Result<DataEntry> {
-        // Get file_id, offset, length
-        let file_id = entry.get_file_id();
-        let offset = entry.get_offset();
-        // Read from active file
-        let (data_entry, _) = if file_id == self.file_id.load(Ordering::SeqCst) {
-            let read_guard = self.active_file.read();
-            read_guard.extract_data_entry(offset)?
+    // The shard a write of `key` goes to, given the value's length for a
+    // put. Called under `batch_commit_lock`.
+    //
+    // Under size classes a put goes to its value's class and a delete to the
+    // class the key was last written to. A put that moves the key is
+    // followed by a tombstone in the class it left, see `leave_size_class`.
+    pub(crate) fn shard_for_write(&self, key: &[u8], value_len: Option<usize>) -> usize {
+        let Some(boundary) = self.ctx.opts.size_class_boundary else {
+            return shard_for_key(key, self.shards.len());
+        };
+        match value_len {
+            Some(value_len) => size_class(value_len, boundary),
+            None if self.large_keys.contains(key) => LARGE_VALUES,
+            None => SMALL_VALUES,
+        }
+    }
+
+    // Under size classes, records that a put of `key` was appended to
+    // `shard` and, if that moves the key, appends a tombstone to the class it
+    // left, so replay doesn't find the key live there. Returns the
+    // tombstone's position, which the put must wait on like its own.
+    //
+    // A key isn't tracked while small, so its first large put always leaves
+    // a tombstone behind. Called with `batch_commit_lock` held exclusively,
+    // see `lock_for_put`, after the put's shard lock is released.
+    pub(crate) fn leave_size_class(&self, key: &[u8], shard: usize) -> Result<Option<KeyDirEntry>> {
+        if self.ctx.opts.size_class_boundary.is_none() {
+            return Ok(None);
+        }
+        let left = if shard == LARGE_VALUES {
+            self.large_keys.insert(key.to_vec()).then_some(SMALL_VALUES)
         } else {
-            // Read from inactive file
-            match self.inactive_files.get(&file_id) {
-                Some(inactive_file) => inactive_file.extract_data_entry(offset)?,
-                None => {
-                    return Err(Error::Unsupported(
-                        "Db read error: File not found".to_string(),
-                    ))
-                }
+            self.large_keys.remove(key).map(|_| LARGE_VALUES)
+        };
+        let Some(left) = left else {
+            return Ok(None);
+        };
+        let tombstone = DataEntry::new(
+            encode_transaction_key(key.to_vec(), NON_COMMITTED),
+            Vec::new(),
+            State::Inactive,
+        );
+        let shard = &self.shards[left];
+        let mut write_guard = shard.active_file.write();
+        self.append_locked(shard, &mut write_guard, &tombstone)
+            .map(Some)
+    }
+
+    pub(crate) fn append_entry_to(&self, shard: usize, entry: &DataEntry) -> Result<KeyDirEntry> {
+        // The index doesn't learn of the record, so the hint log can't
+        // either
+        if let Some(hint_log) = &self.hint_log {
+            hint_log.abandon();
+        }
+        let shard = &self.shards[shard];
+        let mut write_guard = shard.active_file.write();
+        self.append_locked(shard, &mut write_guard, entry)
+    }
+
+    /// Appends a record encoded by another database, CRC included, to data
+    /// file `file_id` as it is, for a replica applying its primary's records
+    /// in order. `file_id` has to be the active file of its shard or a later
+    /// one, which the shard rotates to, so the replica's files mirror the
+    /// primary's.
+    ///
+    /// Batch entries reach the index once their batch's marker is applied,
+    /// as on replay.
+    pub fn apply_raw(&self, file_id: u32, bytes: &[u8]) -> Result<KeyDirEntry> {
+        if self.ctx.opts.read_only {
+            return Err(Error::Io(ErrorKind::PermissionDenied.into()));
+        }
+        let (key_size, value_size, header_size, state) = DataEntry::decode_record_header(bytes)?;
+        if header_size + key_size + value_size + CRC_LEN != bytes.len() {
+            return Err(Error::Unsupported(format!(
+                "Raw entry of {} bytes doesn't hold one record",
+                bytes.len()
+            )));
+        }
+        let compression = Compression::from_state_byte(state)?;
+        let state = State::try_from(state)?;
+        let (key, seq_no) =
+            decode_transaction_key(bytes[header_size..header_size + key_size].to_vec());
+        let value = &bytes[header_size + key_size..header_size + key_size + value_size];
+
+        let commit_lock = self.batch_commit_lock.write();
+        self.check_open()?;
+        let shard = &self.shards[shard_of(file_id, self.shards.len())];
+        let mut write_guard = shard.active_file.write();
+        match file_id.cmp(&write_guard.get_file_id()) {
+            std::cmp::Ordering::Less => {
+                return Err(Error::Unsupported(format!(
+                    "Data file {} is no longer active",
+                    file_id
+                )))
+            }
+            std::cmp::Ordering::Greater => {
+                self.rotate_to_locked(shard, &mut write_guard, file_id)?
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+        let offset = write_guard.get_offset();
+        let written = write_guard.write_raw_entry(bytes)?;
+        self.counters.add_written(written as u64);
+        let mut keydir_entry = KeyDirEntry::new(file_id, offset, written as u32);
+        // Compressed values are left for reads to decompress
+        if state == State::Active
+            && compression == Compression::None
+            && self.ctx.opts.should_inline(value.len())
+        {
+            keydir_entry.set_inline_value(value);
+        }
+
+        let position = (state == State::Active).then(|| keydir_entry.clone());
+        let updates = if seq_no == NON_COMMITTED {
+            vec![(key, position)]
+        } else {
+            self.sequence_number.fetch_max(seq_no + 1, Ordering::SeqCst);
+            let mut raw_batches = self.raw_batches.lock();
+            if state == State::Committed {
+                raw_batches.remove(&seq_no).unwrap_or_default()
+            } else {
+                raw_batches.entry(seq_no).or_default().push((key, position));
+                Vec::new()
             }
         };
-        if !data_entry.is_active() {
-            return Err(Error::Unsupported(
-                "Db read error: Entry removed".to_string(),
-            ));
+        let ticket = self.index_sequencer.issue();
+        drop(write_guard);
+        drop(commit_lock);
+
+        self.make_visible(
+            ticket,
+            &[end_position(&keydir_entry)],
+            self.ctx.opts.sync_writes,
+            updates,
+        )?;
+        Ok(keydir_entry)
+    }
+
+    // Appends to `active_file`, the locked active file of `shard`
+    pub(crate) fn append_locked(
+        &self,
+        shard: &WriteShard,
+        active_file: &mut FileHandle,
+        entry: &DataEntry,
+    ) -> Result<KeyDirEntry> {
+        let encoded_entry = entry.encode()?;
+        self.make_room_locked(shard, active_file, encoded_entry.len() as u64)?;
+        self.write_locked(active_file, &encoded_entry)
+    }
+
+    // Rotates `active_file`, the locked active file of `shard`, if `len` more
+    // bytes wouldn't fit in it
+    pub(crate) fn make_room_locked(
+        &self,
+        shard: &WriteShard,
+        active_file: &mut FileHandle,
+        len: u64,
+    ) -> Result<()> {
+        if active_file.get_offset() + len > self.ctx.opts.data_file_size {
+            self.rotate_locked(shard, active_file)?;
         }
-        Ok(data_entry)
+        Ok(())
     }
 
-    pub(crate) fn load_index_from_hint_file(&self) -> Result<()> {
-        let hint_file_name = self.ctx.opts.dir_path.join(HINT_FILE_NAME);
+    // Writes an encoded record to the locked `active_file` as it is, without
+    // rotating it
+    pub(crate) fn write_locked(
+        &self,
+        active_file: &mut FileHandle,
+        encoded_entry: &[u8],
+    ) -> Result<KeyDirEntry> {
+        // Take the position from the file actually written to, while it is
+        // still locked
+        let file_id = active_file.get_file_id();
+        let offset = active_file.get_offset();
+        trace_span!("append", file_id, offset, size = encoded_entry.len());
+        let written = active_file.write(encoded_entry)?;
+        self.counters.add_written(written as u64);
+        fail_point!(&self.ctx.opts.dir_path, APPEND_BEFORE_SYNC)?;
 
-        if !hint_file_name.is_file() {
-            return Ok(());
+        Ok(KeyDirEntry::new(file_id, offset, written as u32))
+    }
+
+    /// Retires the active file of every shard and starts new ones.
+    pub fn rotate_active_file(&self) -> Result<()> {
+        self.check_open()?;
+        for shard in self.shards.iter() {
+            let mut write_guard = shard.active_file.write();
+            self.rotate_locked(shard, &mut write_guard)?;
         }
+        Ok(())
+    }
 
-        let hint_file = HintFile::new(&self.ctx.opts.dir_path);
-        let mut offset = 0;
-        loop {
-            let (entry, size) = match hint_file.extract_data_entry(offset) {
-                Ok((entry, size)) => (entry, size),
-                Err(e) => {
-                    if let Error::Io(ref io_error) = e {
-                        if io_error.kind() == ErrorKind::UnexpectedEof {
-                            break;
-                        }
-                    }
-                    return Err(e);
-                }
-            };
+    // The only place an active file is replaced. Callers hold the shard's
+    // active file write lock, so concurrent rotations and appends can't both
+    // retire the same file or skip an id.
+    pub(crate) fn rotate_locked(
+        &self,
+        shard: &WriteShard,
+        active_file: &mut FileHandle,
+    ) -> Result<()> {
+        let new_file_id = next_file_id(active_file.get_file_id(), self.shards.len())?;
+        self.rotate_to_locked(shard, active_file, new_file_id)
+    }
 
-            let keydir_entry = decode_keydir_entry(entry.get_value().clone())?;
+    // Rotates the locked `active_file` of `shard` into the file `new_file_id`
+    pub(crate) fn rotate_to_locked(
+        &self,
+        shard: &WriteShard,
+        active_file: &mut FileHandle,
+        new_file_id: u32,
+    ) -> Result<()> {
+        // persist current active file
+        active_file.sync()?;
+        self.write_file_crc(active_file)?;
 
-            self.ctx.index.put(entry.get_key().clone(), keydir_entry);
-            offset += size as u64;
+        let current_fid = active_file.get_file_id();
+        // Create the new file first so a failure leaves the shard unchanged
+        let new_file = self.new_data_file(new_file_id)?;
+        if self.ctx.opts.file_crc {
+            new_file.track_crc()?;
+        }
+        if self.ctx.opts.should_sync_dir() {
+            sync_dir(&self.ctx.opts.dir_path)?;
+        }
+
+        let retired = active_file.freeze();
+        if let Some(memoizer) = &self.memoizer {
+            memoizer.retire(retired.clone());
         }
+        self.inactive_files.insert(current_fid, retired);
+        *active_file = new_file;
+        shard.file_id.store(new_file_id, Ordering::SeqCst);
+        shard.publish(active_file);
+        self.counters.count_rotation();
+        trace_event!(from = current_fid, to = new_file_id, "rotated");
         Ok(())
     }
-    pub fn sync(&self) -> Result<()> {
-        let read_guard = self.active_file.read();
-        read_guard.sync()
+
+    // A new, empty data file `file_id`, in memory for a database opened with
+    // `open_in_memory`
+    pub(crate) fn new_data_file(&self, file_id: u32) -> Result<FileHandle> {
+        if self.in_memory {
+            return Ok(FileHandle::new(file_id, MemoryIO::default().into()));
+        }
+        create_data_file(&self.ctx.opts, file_id)
     }
 
-    pub fn close(&mut self) -> Result<()> {
-        if !self.ctx.opts.dir_path.is_dir() {
+    // Removes data file `file_id`, its CRC sidecar and its manifest entry,
+    // unless in memory, where dropping its handle is all there is to it
+    pub(crate) fn remove_data_file(&self, file_id: u32) -> Result<()> {
+        if self.in_memory {
             return Ok(());
         }
+        fs::remove_file(
+            self.ctx
+                .opts
+                .dir_path
+                .join(format!("{}{}", file_id, FILE_SUFFIX)),
+        )?;
+        if let Some(memoizer) = &self.memoizer {
+            memoizer.forget(file_id);
+        }
+        FileCrc::remove(&self.ctx.opts.dir_path, file_id)
+    }
 
-        self.sync()?;
+    // Records the length and CRC32 of `file` in its sidecar under
+    // `Opts::file_crc`, once what it covers is synced
+    pub(crate) fn write_file_crc(&self, file: &FileHandle) -> Result<()> {
+        match file.running_crc() {
+            Some(crc) if self.ctx.opts.file_crc => {
+                crc.write(&self.ctx.opts.dir_path, file.get_file_id())
+            }
+            _ => Ok(()),
+        }
+    }
 
-        self.lock_file.unlock()?;
+    // The files that don't match their sidecars. Fails on the first under
+    // `Opts::strict_file_crc`.
+    fn check_file_crcs(opts: &Opts, file_ids: &[u32]) -> Result<Vec<u32>> {
+        let mut mismatches = Vec::new();
+        for file_id in file_ids {
+            let Some(crc) = FileCrc::read(&opts.dir_path, *file_id)? else {
+                continue;
+            };
+            let path = opts.dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+            match crc.matches(&path) {
+                Ok(true) => {}
+                Ok(false) if opts.strict_file_crc => return Err(Error::ChecksumMismatch(*file_id)),
+                Ok(false) => mismatches.push(*file_id),
+                // Left to replay to skip or fail
+                Err(_) if opts.tolerate_missing_files => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(mismatches)
+    }
 
-        Ok(())
+    // Moves shard 0 on to the file `file_id`, as if it had rotated that far
+    #[cfg(test)]
+    pub(crate) fn seed_file_id(&self, file_id: u32) -> Result<()> {
+        let shard = &self.shards[0];
+        let mut write_guard = shard.active_file.write();
+        self.rotate_to_locked(shard, &mut write_guard, file_id)
     }
 
-    pub fn back_up(&self, dir_path: &Path) -> Result<()> {
-        copy_recursive(&self.ctx.opts.dir_path, dir_path)?;
-        Ok(())
+    // The file `file_id` is read from. A file leaves the published snapshot
+    // only after it has been added to the inactive files, so the locked path
+    // is needed just while a new file is being rotated in.
+    fn data_file(&self, file_id: u32) -> Result<FileHandle> {
+        let shard = &self.shards[shard_of(file_id, self.shards.len())];
+        if let Some(file) = shard.published_file(file_id) {
+            return Ok(file);
+        }
+        if let Some(file) = self.inactive_files.get(&file_id) {
+            return Ok(file.clone());
+        }
+        let read_guard = shard.active_file.read();
+        if read_guard.get_file_id() == file_id {
+            return Ok(read_guard.clone());
+        }
+        Err(Error::FileNotFound(file_id))
     }
-}
 
-fn copy_recursive(src: &Path, dst: &Path) -> Result<()> {
-    if !dst.exists() {
-        create_dir_all(dst)?;
+    // The shard whose active file is `file_id`, if any
+    fn active_shard(&self, file_id: u32) -> Option<&WriteShard> {
+        self.shards
+            .get(shard_of(file_id, self.shards.len()))
+            .filter(|shard| shard.get_file_id() == file_id)
     }
-    for dentry in read_dir(src)? {
-        let dentry = dentry?;
-        let src_path = dentry.path();
-        if src_path.file_name().unwrap() == FILE_LOCK {
-            continue;
+
+    /// A value stored in an mmap-backed inactive file is returned as a view
+    /// into the mapping rather than copied out of it. The view keeps the
+    /// mapping alive, so it stays readable after a merge removes the file.
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        let _get = self.counters.start_get();
+        self.validate_read_key(&key)?;
+
+        self.read_live_entry(&key, |entry| match entry.get_inline_value() {
+            Some(value) => {
+                self.counters.count_inline_read();
+                Ok(Bytes::copy_from_slice(value))
+            }
+            None => self.read_value(&key, entry),
+        })
+    }
+
+    // Looks `key` up and reads its record with `read`. If the file the
+    // entry points at is gone, the entry is looked up again, as the key may
+    // have been repointed meanwhile.
+    fn read_live_entry<T>(&self, key: &[u8], read: impl Fn(KeyDirEntry) -> Result<T>) -> Result<T> {
+        let mut attempts = 1;
+        loop {
+            let entry =
+                self.ctx.index.get(key).ok_or_else(|| {
+                    Error::Unsupported("Db read error: Key not found".to_string())
+                })?;
+            entry.mark_referenced();
+            match read(entry) {
+                Err(Error::FileNotFound(_)) if attempts < READ_ATTEMPTS => attempts += 1,
+                result => return result,
+            }
         }
-        let dst_path = dst.join(dentry.file_name());
-        if dentry.file_type()?.is_dir() {
-            copy_recursive(&src_path, &dst_path)?;
-        } else {
-            fs::copy(&src_path, &dst_path)?;
+    }
+
+    /// Like `get`, but also returns the sequence number of the write that
+    /// stored the value, so a client can tell its own writes apart. Batch
+    /// writes carry their batch's number; single puts, and values a merge has
+    /// rewritten, carry `0`. `None` if there is no value for `key`.
+    pub fn get_seq(&self, key: Bytes) -> Result<Option<(Bytes, u32)>> {
+        let _get = self.counters.start_get();
+        self.validate_read_key(&key)?;
+        if self.ctx.index.get(&key).is_none() {
+            return Ok(None);
         }
+
+        // Inline values don't keep the number, so the record is always read
+        self.read_live_entry(&key, |entry| {
+            let data_entry = self.read_record(&key, entry)?;
+            let (_, seq_no) = decode_transaction_key(data_entry.get_key().clone());
+            Ok(Some((Bytes::from(data_entry.into_value()), seq_no)))
+        })
     }
-    Ok(())
-}
 
-fn process_merge_files(dir_path: &Path) -> Result<()> {
-    // Handle merge
-    // Step 1: Check if the merge directory exists
-    let filename = dir_path.file_name().unwrap();
-    let mut merge_dir = dir_path.to_path_buf();
-    merge_dir.set_file_name(format!("{}-merge", filename.to_string_lossy()));
-    let mut unmerged_file_id: u32 = 0;
-    let mut merge_file_names = Vec::new();
-    match read_dir(merge_dir.clone()) {
-        Ok(dir) => {
-            // Check if the merge finished
-            let merge_file = MERGE_FINISHED_FILE.to_string();
-            if merge_dir.join(merge_file.clone()).is_file() {
-                // Merge is finished, load the merged file
-                let file_handle = FileHandle::new(
-                    0,
-                    StandardIO::new(&merge_dir.join(merge_file.clone()))
-                        .unwrap()
-                        .into(),
-                );
-                let entry = match file_handle.extract_data_entry(0) {
-                    Ok((entry, _)) => entry,
-                    Err(_) => {
-                        remove_dir_all(merge_dir)?;
-                        return Ok(());
-                    }
-                };
-                //Parse from bytes to u32
-                let s = String::from_utf8_lossy(entry.get_value());
-                unmerged_file_id = s.parse::<u32>().unwrap();
-                // Handle files in directory use while let
-                for file in dir {
-                    let file = file?;
-                    merge_file_names.push(file.file_name());
-                }
+    /// Like `get`, but gives up with an `Io` error of kind `TimedOut` once
+    /// the read has taken `timeout`, for callers that would rather fail than
+    /// wait on a stalled disk or network filesystem. `None` if there is no
+    /// value for `key`.
+    ///
+    /// Reads run on a small pool of threads the database starts on first
+    /// use, and count against `Opts::max_concurrent_reads`,
+    /// waiting for which counts against `timeout`. A read that times out is
+    /// left to finish in the background, keeping its thread and its place
+    /// under the limit until then.
+    pub fn get_timeout(&self, key: Bytes, timeout: Duration) -> Result<Option<Bytes>> {
+        let _get = self.counters.start_get();
+        self.validate_read_key(&key)?;
+        if self.ctx.index.get(&key).is_none() {
+            return Ok(None);
+        }
+
+        let deadline = Instant::now() + timeout;
+        self.read_live_entry(&key, |entry| {
+            if let Some(value) = entry.get_inline_value() {
+                self.counters.count_inline_read();
+                return Ok(Some(Bytes::copy_from_slice(value)));
             }
+            let file = self.data_file(entry.get_file_id())?;
+            let offset = entry.get_offset();
+            let permit = match &self.read_limiter {
+                Some(limiter) => Some(
+                    limiter
+                        .acquire_until(deadline)
+                        .ok_or(Error::Io(ErrorKind::TimedOut.into()))?,
+                ),
+                None => None,
+            };
+            let workers = self.read_workers()?;
+            let (sender, receiver) = mpsc::sync_channel(1);
+            workers.clone().spawn(move || {
+                // Holding on to the pool, a stalled read doesn't hold up
+                // dropping the database
+                let _workers = workers;
+                let _permit = permit;
+                // Given up on while queued
+                if Instant::now() < deadline {
+                    let _ = sender.send(file.extract_data_entry(offset));
+                }
+            });
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (data_entry, size) = match receiver.recv_timeout(remaining) {
+                Ok(result) => result?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::Io(ErrorKind::TimedOut.into()))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::ReportableBug(
+                        "Read worker exited without a result".to_string(),
+                    ))
+                }
+            };
+            self.counters.add_read(size as u64);
+            check_entry(
+                &key,
+                &entry,
+                data_entry.get_key(),
+                size,
+                data_entry.is_active(),
+            )?;
+            Ok(Some(Bytes::from(data_entry.into_value())))
+        })
+    }
+
+    // The pool `get_timeout` reads on, started on first use
+    fn read_workers(&self) -> Result<SharedRuntime> {
+        let mut workers = self.read_workers.lock();
+        if let Some(workers) = workers.as_ref() {
+            return Ok(workers.clone());
         }
-        Err(_) => {
-            return Ok(());
+        let started = SharedRuntime::new(TIMED_READ_THREADS)?;
+        *workers = Some(started.clone());
+        Ok(started)
+    }
+
+    /// Returns the value of `key` as a view into the mapping of the file it
+    /// is stored in, or `None` if that file isn't mapped (the active file,
+    /// inlined values). The slice keeps the mapping alive for as long as it
+    /// is held, even past the file's removal or the database's close.
+    pub fn get_mmap_slice(&self, key: &[u8]) -> Result<Option<MmapSlice>> {
+        let _get = self.counters.start_get();
+        self.validate_read_key(key)?;
+
+        match self.ctx.index.get(key) {
+            Some(entry) if entry.get_inline_value().is_some() => Ok(None),
+            Some(entry) => self.mapped_value(key, &entry),
+            None => Err(Error::Unsupported(
+                "Db read error: Key not found".to_string(),
+            )),
         }
     }
-    for file_id in 0..unmerged_file_id {
-        let file = dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
-        if file.is_file() {
-            fs::remove_file(file)?;
+
+    fn mapped_value(&self, key: &[u8], entry: &KeyDirEntry) -> Result<Option<MmapSlice>> {
+        // The active file is still being written, so only inactive files are mapped
+        if self.active_shard(entry.get_file_id()).is_some() {
+            return Ok(None);
         }
+        let Some(file) = self.inactive_files.get(&entry.get_file_id()) else {
+            return Ok(None);
+        };
+        let Some(mapped) = file.extract_mapped_entry(entry.get_offset())? else {
+            return Ok(None);
+        };
+        check_entry(
+            key,
+            entry,
+            mapped.get_key(),
+            mapped.get_size(),
+            mapped.is_active(),
+        )?;
+        self.counters.add_read(mapped.get_size() as u64);
+        Ok(Some(mapped.get_value().clone()))
     }
 
-    for file_name in merge_file_names {
-        fs::rename(merge_dir.join(file_name.clone()), dir_path.join(file_name))?;
+    fn validate_read_key(&self, key: &[u8]) -> Result<()> {
+        self.check_open()?;
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if key.len() > self.ctx.opts.max_key_size {
+            return Err(Error::Unsupported(format!(
+                "limited max_key_size: {}, actual key size:{}",
+                self.ctx.opts.max_key_size,
+                key.len()
+            )));
+        }
+        Ok(())
     }
 
-    fs::remove_dir_all(merge_dir.clone())?;
-    Ok(())
-}
+    // Writes check this again under `batch_commit_lock`, so none is
+    // appended after `shutdown` has drained them
+    pub(crate) fn check_open(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
 
-fn validate_options(options: &Opts) -> Result<()> {
-    if options.max_key_size == 0 {
-        return Err(Error::Unsupported(
-            "validate options error: max_key_size is required to be greater than 0".to_string(),
-        ));
+    /// Returns the current position of `key` without reading its value.
+    pub fn locate(&self, key: &[u8]) -> Option<KeyDirEntry> {
+        self.ctx.index.get(key)
     }
 
-    if options.max_value_size == 0 {
-        return Err(Error::Unsupported(
-            "validate options error: max_value_size is required to be greater than 0".to_string(),
-        ));
+    /// Reads the value of `key` at a position previously returned by `locate`.
+    ///
+    /// The position may have gone stale (e.g. a merge moved the entry), in which
+    /// case the record found there belongs to another key and an error is
+    /// returned instead of the wrong value.
+    pub fn read_at(&self, key: &[u8], entry: KeyDirEntry) -> Result<Vec<u8>> {
+        let data_entry = self.read_data_entry(key, entry)?;
+        Ok(data_entry.get_value().clone())
     }
 
-    if options.data_file_size == 0 {
-        return Err(Error::Unsupported(
-            "validate options error: data_file_size is required to be greater than 0".to_string(),
-        ));
+    /// Lists every record of `key` still on disk as `(file_id, offset,
+    /// is_active)`, oldest first.
+    ///
+    /// This is a diagnostic: it scans the data files rather than the index, so
+    /// it also reports overwritten versions and tombstones. Under size
+    /// classes the records in small values' files come first.
+    pub fn history(&self, key: &[u8]) -> Result<Vec<(u32, u64, bool)>> {
+        let shard_idx = shard_for_key(key, self.shards.len());
+        let in_history = |file_id: u32| {
+            self.ctx.opts.size_class_boundary.is_some()
+                || shard_of(file_id, self.shards.len()) == shard_idx
+        };
+        // Clone the active files first: if one rotates meanwhile, it shows up
+        // among the inactive files too and is deduplicated below
+        let mut files = self
+            .shards
+            .iter()
+            .map(|shard| shard.active_file.read().clone())
+            .filter(|file| in_history(file.get_file_id()))
+            .collect::<Vec<_>>();
+        files.extend(
+            self.inactive_files
+                .iter()
+                .filter(|file| in_history(file.get_file_id()))
+                .map(|file| file.clone()),
+        );
+        files.sort_by_key(|file| file.get_file_id());
+        files.dedup_by_key(|file| file.get_file_id());
+
+        let mut versions = Vec::new();
+        for file in files.iter() {
+            let mut offset = 0;
+            while let Ok((entry, size)) = file.extract_data_entry(offset) {
+                let (entry_key, _) = decode_transaction_key(entry.get_key().clone());
+                // Older batch markers are keyed `__COMMITTED__`
+                if entry_key == key && entry.get_state() != State::Committed {
+                    versions.push((file.get_file_id(), offset, entry.is_active()));
+                }
+                offset += size as u64;
+            }
+        }
+        Ok(versions)
     }
 
-    match options.dir_path.to_str() {
-        Some(path) => {
-            if path.is_empty() {
-                return Err(Error::Unsupported(
-                    "validate options error: dir_path is required".to_string(),
-                ));
+    /// Ids of every data file, oldest first.
+    pub fn data_file_ids(&self) -> Vec<u32> {
+        let mut file_ids = self
+            .shards
+            .iter()
+            .map(|shard| shard.get_file_id())
+            .chain(self.inactive_files.iter().map(|file| *file.key()))
+            .collect::<Vec<_>>();
+        file_ids.sort();
+        file_ids.dedup();
+        file_ids
+    }
+
+    /// Walks the records of data file `file_id` as `FileHandle::iter_entries`
+    /// does, for inspecting it.
+    pub fn file_entries(&self, file_id: u32) -> Result<EntryIter> {
+        if let Some(file) = self.inactive_files.get(&file_id) {
+            return file.iter_entries();
+        }
+        self.shards
+            .iter()
+            .find(|shard| shard.get_file_id() == file_id)
+            .ok_or(Error::FileNotFound(file_id))?
+            .active_file
+            .read()
+            .iter_entries()
+    }
+
+    // The value of `key` at `entry`, borrowed from the mapping when the file
+    // it is in is mapped, and copied out otherwise
+    fn read_value(&self, key: &[u8], entry: KeyDirEntry) -> Result<Bytes> {
+        if let Some(mapped) = self.mapped_value(key, &entry)? {
+            return Ok(Bytes::from_owner(mapped));
+        }
+        Ok(Bytes::from(self.read_data_entry(key, entry)?.into_value()))
+    }
+
+    pub(crate) fn read_data_entry(&self, key: &[u8], entry: KeyDirEntry) -> Result<DataEntry> {
+        let mut data_entry = self.read_record(key, entry)?;
+        data_entry.set_key(key);
+        Ok(data_entry)
+    }
+
+    // The live record of `key` at `entry`, with its key still transaction
+    // encoded
+    fn read_record(&self, key: &[u8], entry: KeyDirEntry) -> Result<DataEntry> {
+        let file_id = entry.get_file_id();
+        trace_span!(
+            "read_record",
+            file_id,
+            offset = entry.get_offset(),
+            size = entry.get_size()
+        );
+        let file = self.data_file(file_id)?;
+        let permit = self.read_limiter.as_ref().map(ReadLimiter::acquire);
+        let (data_entry, size) = file.extract_data_entry(entry.get_offset())?;
+        drop(permit);
+        self.counters.add_read(size as u64);
+        check_entry(
+            key,
+            &entry,
+            data_entry.get_key(),
+            size,
+            data_entry.is_active(),
+        )?;
+        Ok(data_entry)
+    }
+
+    /// Seeds `index` from the hint file of the last merge. Runs before the data
+    /// files are replayed, so writes made after the merge take precedence.
+    ///
+    /// The hint is only applied if every record it points at lies within a
+    /// data file that is there, and the files it was written for still have
+    /// the lengths it recorded; a hint kept alongside data files from
+    /// elsewhere is otherwise left out. Returns `false` if a hint was found
+    /// and left out.
+    pub(crate) fn load_index_from_hint_file(dir_path: &Path, index: &HashMap) -> Result<bool> {
+        let hint_file_name = dir_path.join(HINT_FILE_NAME);
+
+        if !hint_file_name.is_file() {
+            return Ok(true);
+        }
+        trace_span!("load_hint_file");
+
+        let hint_file = HintFile::open(dir_path)?;
+        let mut entries = Vec::new();
+        let mut coverage = None;
+        let mut offset = 0;
+        loop {
+            let (entry, size) = match hint_file.extract_data_entry(offset) {
+                Ok((entry, size)) => (entry, size),
+                Err(e) => {
+                    if let Error::Io(ref io_error) = e {
+                        if io_error.kind() == ErrorKind::UnexpectedEof {
+                            break;
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+
+            offset += size as u64;
+            if entry.get_state() == State::Committed {
+                coverage = Some(decode_coverage(entry.get_value())?);
+                continue;
             }
+            let keydir_entry = decode_keydir_entry(entry.get_value().clone())?;
+            // Merge writes hint keys in their on-disk (transaction) encoding
+            let (key, _) = decode_transaction_key(entry.get_key().clone());
+            entries.push((key, keydir_entry));
         }
-        None => {
+
+        let mut file_lens = std::collections::HashMap::new();
+        let mut file_len = |file_id: u32| {
+            *file_lens.entry(file_id).or_insert_with(|| {
+                fs::metadata(dir_path.join(format!("{}{}", file_id, FILE_SUFFIX)))
+                    .map(|metadata| metadata.len())
+                    .ok()
+            })
+        };
+        if let Some(coverage) = &coverage {
+            if coverage
+                .iter()
+                .any(|(file_id, len)| file_len(*file_id) != Some(*len))
+            {
+                return Ok(false);
+            }
+        }
+        let consistent = entries.iter().all(|(_, entry)| {
+            let end = entry.get_offset() + entry.get_size() as u64;
+            let covered = coverage.as_ref().is_none_or(|coverage| {
+                coverage
+                    .iter()
+                    .any(|(file_id, _)| *file_id == entry.get_file_id())
+            });
+            covered && file_len(entry.get_file_id()).is_some_and(|len| end <= len)
+        });
+        if !consistent {
+            trace_event!("hint file ignored");
+            return Ok(false);
+        }
+
+        trace_event!(entries = entries.len(), "loaded hint file");
+        for (key, keydir_entry) in entries {
+            index.put(key.into(), keydir_entry);
+        }
+        Ok(true)
+    }
+    /// Waits until everything written to the active files so far is on disk.
+    pub fn sync(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            let read_guard = shard.active_file.read();
+            let position = (read_guard.get_file_id(), read_guard.get_offset());
+            drop(read_guard);
+            shard.syncer.wait_durable(position)?;
+        }
+        Ok(())
+    }
+
+    // Waits until the log of the shard `position` is in is durable up to it
+    pub(crate) fn wait_durable(&self, position: Position) -> Result<()> {
+        self.shards[shard_of(position.0, self.shards.len())]
+            .syncer
+            .wait_durable(position)
+    }
+
+    /// Syncs the active files. Inactive files are synced as they're retired
+    /// and frozen after, so they have nothing left to flush.
+    pub fn sync_all(&self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Flushes all data files and, if `Opts::key_file` is set, rewrites the key
+    /// file from the current index.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.sync_all()?;
+        if self.ctx.opts.key_file {
+            let mut keys = self.ctx.index.list_keys()?;
+            keys.sort();
+            KeyFile::write_all(&self.ctx.opts.dir_path, &keys)?;
+        }
+        Ok(())
+    }
+
+    pub fn list_keys(&self) -> Result<Vec<Bytes>> {
+        self.ctx.index.list_keys()
+    }
+
+    /// Total on-disk size of the live records of keys in `[start, end)`,
+    /// taken from the index without reading any values.
+    pub fn size_in_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        let mut iter = self.ctx.index.iter();
+        iter.seek(start);
+        let mut size = 0;
+        while let Some((key, entry)) = iter.next() {
+            if key >= end {
+                break;
+            }
+            size += entry.get_size() as u64;
+        }
+        size
+    }
+
+    /// Returns the sorted keys starting with `prefix`.
+    ///
+    /// With `Opts::key_file` this reads the key file, which reflects the last
+    /// checkpoint or merge; otherwise the index is scanned.
+    pub fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Vec<Bytes>> {
+        if self.ctx.opts.key_file && self.ctx.opts.dir_path.join(KEY_FILE_NAME).is_file() {
+            return KeyFile::open(&self.ctx.opts.dir_path)?.scan_prefix(prefix);
+        }
+        let mut keys = self
+            .ctx
+            .index
+            .list_keys()?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect::<Vec<Bytes>>();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Deletes every key whose value fails `f`, returning how many were
+    /// removed. Keys to delete are collected over a snapshot of the index
+    /// before any tombstone is written.
+    pub fn retain(&mut self, f: impl Fn(&[u8], &[u8]) -> bool) -> Result<usize> {
+        let mut doomed = Vec::new();
+        for key in self.ctx.index.list_keys()? {
+            let Some(entry) = self.locate(&key) else {
+                continue;
+            };
+            let value = match entry.get_inline_value() {
+                Some(value) => value.to_vec(),
+                None => self.read_at(&key, entry)?,
+            };
+            if !f(&key, &value) {
+                doomed.push(key);
+            }
+        }
+
+        let removed = doomed.len();
+        for key in doomed {
+            self.delete(key)?;
+        }
+        Ok(removed)
+    }
+
+    /// Deletes every key starting with `prefix`, returning how many were
+    /// removed. The keys are collected before any tombstone is written.
+    pub fn delete_prefix(&mut self, prefix: Bytes) -> Result<usize> {
+        self.check_open()?;
+        let doomed = match &self.ctx.index {
+            // Ordered, so the matching keys are the run from `prefix` on
+            IndexMode::BTree(index) => {
+                let mut iter = index.iter();
+                iter.seek(&prefix);
+                let mut keys = Vec::new();
+                while let Some((key, _)) = iter.next() {
+                    if !key.starts_with(&prefix) {
+                        break;
+                    }
+                    keys.push(Bytes::copy_from_slice(key));
+                }
+                keys
+            }
+            IndexMode::HashMap(index) => index
+                .list_keys()?
+                .into_iter()
+                .filter(|key| key.starts_with(&prefix))
+                .collect(),
+        };
+
+        let deleted = doomed.len();
+        for key in doomed {
+            self.delete(key)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Throws the index away and rebuilds it by replaying every data file,
+    /// for when it may have drifted from what's on disk. The hint file is
+    /// left as is but not read. The index is only replaced once every file
+    /// has replayed, so a file that fails to leaves it untouched.
+    pub fn reindex(&mut self) -> Result<()> {
+        self.check_open()?;
+        let mut files = self
+            .shards
+            .iter()
+            .map(|shard| shard.active_file.read().clone())
+            .chain(self.inactive_files.iter().map(|file| file.clone()))
+            .collect::<Vec<_>>();
+        files.sort_by_key(|file| file.get_file_id());
+
+        let index = self.ctx.opts.new_index();
+        let mut current_sequence_number = NON_COMMITTED;
+        for file in files.iter() {
+            Self::process_file_handle(file, &self.ctx.opts)?
+                .apply(&index, &mut current_sequence_number);
+        }
+        self.ctx.index = index.into();
+        self.sequence_number
+            .fetch_max(current_sequence_number + 1, Ordering::SeqCst);
+        self.rebuild_secondary_indexes()?;
+        if self.hint_log.is_some() {
+            self.hint_log = Some(self.snapshot_hint_log()?);
+        }
+        Ok(())
+    }
+
+    /// Syncs everything written and unlocks the directory. A database
+    /// already shut down is left as it is: `shutdown` did this, and the
+    /// directory may belong to another process by now.
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) || !self.ctx.opts.dir_path.is_dir() {
+            return Ok(());
+        }
+
+        self.sync_all()?;
+        for shard in self.shards.iter() {
+            self.write_file_crc(&shard.active_file.read())?;
+        }
+        if let Some(memoizer) = &self.memoizer {
+            memoizer.stop();
+        }
+        self.evictor.stop();
+
+        self.lock_file.lock().release()?;
+
+        Ok(())
+    }
+
+    /// Copies the database into `dir_path` as of the moment of the call.
+    ///
+    /// The end of each active file is fixed under its lock and only that much
+    /// of it is copied, so writes in flight never leave a torn record in the
+    /// backup; files created after that point are skipped. Inactive files are
+    /// immutable and are hard-linked when the backup is on the same
+    /// filesystem. A database in memory has its data files written out,
+    /// so the backup opens as a database on disk.
+    pub fn back_up(&self, dir_path: &Path) -> Result<BackupReport> {
+        let ends = self.backup_point()?;
+        if self.in_memory {
+            return self.write_out_memory_files(dir_path, &ends);
+        }
+
+        create_dir_all(dir_path)?;
+        let mut report = BackupReport::default();
+        for dentry in read_dir(&self.ctx.opts.dir_path)? {
+            let dentry = dentry?;
+            let name = dentry.file_name().to_string_lossy().into_owned();
+            let src_path = dentry.path();
+            let dst_path = dir_path.join(&name);
+            if name == LOCK_FILE_NAME {
+                continue;
+            }
+            if dentry.file_type()?.is_dir() {
+                copy_recursive(&src_path, &dst_path)?;
+                continue;
+            }
+
+            let (bytes, hard_linked) = match backup_extent(&ends, &name) {
+                Some(BackupExtent::Prefix(end)) => (copy_prefix(&src_path, &dst_path, end)?, false),
+                Some(BackupExtent::Whole) if parse_data_file_id(&name).is_some() => {
+                    let _ = fs::remove_file(&dst_path);
+                    match fs::hard_link(&src_path, &dst_path) {
+                        Ok(()) => (fs::metadata(&dst_path)?.len(), true),
+                        Err(_) => (fs::copy(&src_path, &dst_path)?, false),
+                    }
+                }
+                Some(BackupExtent::Whole) => (fs::copy(&src_path, &dst_path)?, false),
+                // Created after the backup point
+                None => continue,
+            };
+            report.files.push(BackupFile {
+                name,
+                bytes,
+                hard_linked,
+            });
+        }
+        report.files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(report)
+    }
+
+    // The end of each shard's active file once everything up to it is
+    // durable, the point a backup is taken at
+    pub(crate) fn backup_point(&self) -> Result<Vec<Position>> {
+        let ends = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let read_guard = shard.active_file.read();
+                (read_guard.get_file_id(), read_guard.get_offset())
+            })
+            .collect::<Vec<Position>>();
+        for end in ends.iter() {
+            self.wait_durable(*end)?;
+        }
+        Ok(ends)
+    }
+
+    /// Writes every live entry into a single data file in `dir_path`, with a
+    /// hint file, so the directory can be shipped and opened as a database.
+    ///
+    /// Unlike `merge` the output goes to an arbitrary directory, which must be
+    /// new or empty, and `data_file_size` is ignored.
+    pub fn export_single_file(&self, dir_path: &Path) -> Result<()> {
+        if dir_path.exists() && fs::read_dir(dir_path)?.next().is_some() {
+            return Err(Error::Unsupported(format!(
+                "Export directory {} is not empty",
+                dir_path.display()
+            )));
+        }
+        let mut opts = self.ctx.opts.clone();
+        opts.dir_path = dir_path.to_path_buf();
+        opts.data_file_size = u64::MAX;
+        opts.size_class_boundary = None;
+        opts.max_db_size = None;
+        opts.incremental_hint = false;
+        #[cfg(feature = "write-shards")]
+        {
+            opts.write_shards = 1;
+        }
+        let export_db = Db::open_on(&opts, self.runtime.clone())?;
+        let mut hint_file = HintFile::new(&opts.dir_path);
+
+        let mut iter = self.ctx.index.iter();
+        while let Some((key, entry)) = iter.next() {
+            let data_entry = self.read_data_entry(key, entry.clone())?;
+            let key = encode_transaction_key(key.to_vec(), NON_COMMITTED);
+            let entry = DataEntry::new(key.clone(), data_entry.get_value().clone(), State::Active);
+            let keydir_entry = export_db.append_entry(&entry)?;
+            hint_file.write_entry(key, &keydir_entry)?;
+        }
+
+        export_db.sync()?;
+        hint_file.sync()?;
+        Ok(())
+    }
+}
+
+/// Files written by `Db::back_up`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    pub name: String,
+    pub bytes: u64,
+    /// Shared with the database through a hard link rather than copied.
+    pub hard_linked: bool,
+}
+
+impl BackupReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.bytes).sum()
+    }
+}
+
+// The error of a read whose index entry points at a tombstone
+pub(crate) const ENTRY_REMOVED: &str = "Db read error: Entry removed";
+
+// Checks that the record read for `key` at `entry` is really the one the index
+// points at: a merge may have moved the entry and reused the offset.
+fn check_entry(
+    key: &[u8],
+    entry: &KeyDirEntry,
+    entry_key: &[u8],
+    size: usize,
+    is_active: bool,
+) -> Result<()> {
+    if size as u32 != entry.get_size() {
+        return Err(Error::Unsupported("stale offset".to_string()));
+    }
+    let (entry_key, _) = decode_transaction_key(entry_key.to_vec());
+    if entry_key != key {
+        return Err(Error::Unsupported("stale offset".to_string()));
+    }
+    if !is_active {
+        return Err(Error::Unsupported(ENTRY_REMOVED.to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) fn copy_recursive(src: &Path, dst: &Path) -> Result<()> {
+    if !dst.exists() {
+        create_dir_all(dst)?;
+    }
+    for dentry in read_dir(src)? {
+        let dentry = dentry?;
+        let src_path = dentry.path();
+        if src_path.file_name().unwrap() == LOCK_FILE_NAME {
+            continue;
+        }
+        let dst_path = dst.join(dentry.file_name());
+        if dentry.file_type()?.is_dir() {
+            copy_recursive(&src_path, &dst_path)?;
+        } else {
+            fs::copy(&src_path, &dst_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// How much of a file a backup holds.
+pub(crate) enum BackupExtent {
+    Whole,
+    Prefix(u64),
+}
+
+// How much of the file `name` a backup taken at `ends` holds: the active
+// data files up to their ends, everything else whole, and nothing of data
+// files created since
+pub(crate) fn backup_extent(ends: &[Position], name: &str) -> Option<BackupExtent> {
+    let Some(file_id) = parse_data_file_id(name) else {
+        return Some(BackupExtent::Whole);
+    };
+    match ends.get(shard_of(file_id, ends.len())) {
+        Some((active_id, end)) if file_id == *active_id => Some(BackupExtent::Prefix(*end)),
+        Some((active_id, _)) if file_id < *active_id => Some(BackupExtent::Whole),
+        _ => None,
+    }
+}
+
+// Copies the first `len` bytes of `src` to `dst`
+fn copy_prefix(src: &Path, dst: &Path, len: u64) -> Result<u64> {
+    let mut src = File::open(src)?.take(len);
+    let mut dst = File::create(dst)?;
+    Ok(std::io::copy(&mut src, &mut dst)?)
+}
+
+fn process_merge_files(dir_path: &Path, should_sync_dir: bool) -> Result<()> {
+    install_merge_files(dir_path, should_sync_dir)?;
+    renumber_unmerged_files(dir_path, should_sync_dir)
+}
+
+fn install_merge_files(dir_path: &Path, should_sync_dir: bool) -> Result<()> {
+    // Handle merge
+    // Step 1: Check if the merge directory exists
+    let merge_dir = merge_dir_path(dir_path)?;
+    // Per shard, the first file id the merge didn't cover
+    let mut unmerged_file_ids = Vec::new();
+    let mut merge_file_names = Vec::new();
+    match read_dir(merge_dir.clone()) {
+        Ok(dir) => {
+            // Check if the merge finished
+            let merge_file = MERGE_FINISHED_FILE.to_string();
+            if merge_dir.join(merge_file.clone()).is_file() {
+                // Merge is finished, load the merged file
+                let file_handle = FileHandle::new(
+                    0,
+                    StandardIO::new(&merge_dir.join(merge_file.clone()))
+                        .unwrap()
+                        .into(),
+                );
+                let entry = match file_handle.extract_data_entry(0) {
+                    Ok((entry, _)) => entry,
+                    Err(_) => {
+                        remove_dir_all(merge_dir)?;
+                        return Ok(());
+                    }
+                };
+                //Parse from bytes to u32
+                let s = String::from_utf8_lossy(entry.get_value());
+                unmerged_file_ids = s
+                    .split(',')
+                    .map(|ids| ids.split(':').next().unwrap().parse::<u32>().unwrap())
+                    .collect::<Vec<u32>>();
+                // Handle files in directory use while let
+                for file in dir {
+                    let file = file?;
+                    merge_file_names.push(file.file_name());
+                }
+            }
+        }
+        Err(_) => {
+            return Ok(());
+        }
+    }
+    // The merged files reuse the ids of the files they replace
+    remove_manifest(dir_path)?;
+    HintLog::remove(dir_path)?;
+    FileCrc::remove_all(dir_path)?;
+    // Ids can run into the billions, so only the files there are checked
+    if !unmerged_file_ids.is_empty() {
+        let shard_count = unmerged_file_ids.len();
+        for file in read_dir(dir_path)?.flatten() {
+            let Some(file_id) = file.file_name().to_str().and_then(parse_data_file_id) else {
+                continue;
+            };
+            let unmerged_file_id = unmerged_file_ids.get(shard_of(file_id, shard_count));
+            if unmerged_file_id.is_some_and(|unmerged_file_id| file_id < *unmerged_file_id) {
+                fs::remove_file(file.path())?;
+            }
+        }
+    }
+
+    for file_name in merge_file_names {
+        fs::rename(merge_dir.join(file_name.clone()), dir_path.join(file_name))?;
+    }
+    // The renamed data and hint files are only durable with their directory
+    if should_sync_dir {
+        sync_dir(dir_path)?;
+    }
+
+    fs::remove_dir_all(merge_dir.clone())?;
+    Ok(())
+}
+
+// The installed merge records, per shard, the id its output ends before.
+// Files written while it ran are renumbered from there, in order, so the ids
+// the merge freed are reused and the next file id starts low again. Files
+// only move to lower ids, each past the ones already moved, so running this
+// again after a crash carries on where it stopped.
+fn renumber_unmerged_files(dir_path: &Path, should_sync_dir: bool) -> Result<()> {
+    let merge_finished = dir_path.join(MERGE_FINISHED_FILE);
+    if !merge_finished.is_file() {
+        return Ok(());
+    }
+    let file_handle = FileHandle::new(0, StandardIO::new(&merge_finished)?.into());
+    let boundaries = match file_handle.extract_data_entry(0) {
+        Ok((entry, _)) => String::from_utf8_lossy(entry.get_value()).into_owned(),
+        Err(_) => String::new(),
+    };
+    drop(file_handle);
+
+    let mut file_ids = read_dir(dir_path)?
+        .flatten()
+        .filter_map(|file| file.file_name().to_str().and_then(parse_data_file_id))
+        .collect::<Vec<u32>>();
+    file_ids.sort();
+    let shard_count = boundaries.split(',').count();
+    // Sidecars and the hint log are recorded by id
+    remove_manifest(dir_path)?;
+    HintLog::remove(dir_path)?;
+    FileCrc::remove_all(dir_path)?;
+    for (shard, boundary) in boundaries.split(',').enumerate() {
+        // Merges from before renumbering only record where they ended
+        let Some(renumber_from) = boundary
+            .split_once(':')
+            .and_then(|(_, id)| id.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let unmerged = file_ids
+            .iter()
+            .filter(|id| **id >= renumber_from && shard_of(**id, shard_count) == shard);
+        for (new_id, file_id) in (renumber_from..).zip(unmerged) {
+            if new_id != *file_id {
+                fs::rename(
+                    dir_path.join(format!("{}{}", file_id, FILE_SUFFIX)),
+                    dir_path.join(format!("{}{}", new_id, FILE_SUFFIX)),
+                )?;
+            }
+        }
+    }
+    if should_sync_dir {
+        sync_dir(dir_path)?;
+    }
+    fs::remove_file(merge_finished)?;
+    Ok(())
+}
+
+pub(crate) fn validate_options(options: &Opts) -> Result<()> {
+    if options.max_key_size == 0 {
+        return Err(Error::Unsupported(
+            "validate options error: max_key_size is required to be greater than 0".to_string(),
+        ));
+    }
+
+    if options.max_value_size == 0 {
+        return Err(Error::Unsupported(
+            "validate options error: max_value_size is required to be greater than 0".to_string(),
+        ));
+    }
+
+    if options.data_file_size == 0 {
+        return Err(Error::Unsupported(
+            "validate options error: data_file_size is required to be greater than 0".to_string(),
+        ));
+    }
+
+    if options.max_concurrent_reads == Some(0) {
+        return Err(Error::Unsupported(
+            "validate options error: max_concurrent_reads is required to be greater than 0"
+                .to_string(),
+        ));
+    }
+
+    if options.size_class_boundary == Some(0) {
+        return Err(Error::Unsupported(
+            "validate options error: size_class_boundary is required to be greater than 0"
+                .to_string(),
+        ));
+    }
+
+    #[cfg(feature = "write-shards")]
+    if options.size_class_boundary.is_some() && options.write_shards > 1 {
+        return Err(Error::Unsupported(
+            "validate options error: size_class_boundary can't be combined with write_shards"
+                .to_string(),
+        ));
+    }
+
+    if let Some(max_db_size) = options.max_db_size {
+        if max_db_size <= (shard_count(options) as u64).saturating_mul(options.data_file_size) {
+            return Err(Error::Unsupported(
+                "validate options error: max_db_size is required to be greater than the active files' data_file_size"
+                    .to_string(),
+            ));
+        }
+    }
+
+    if options.skip_lock && !options.read_only {
+        return Err(Error::Unsupported(
+            "validate options error: skip_lock requires read_only".to_string(),
+        ));
+    }
+
+    match options.dir_path.to_str() {
+        Some(path) => {
+            if path.is_empty() {
+                return Err(Error::Unsupported(
+                    "validate options error: dir_path is required".to_string(),
+                ));
+            }
+        }
+        None => {
             return Err(Error::Unsupported(
                 "validate options error: dir_path is required".to_string(),
             ));
         }
-    }
+    }
+
+    Ok(())
+}
+
+// Whether a whole record that checks out starts at `offset`. The length is
+// checked against the file first, as a garbage header can claim any size.
+fn intact_record_at(file: &FileHandle, offset: u64, file_size: u64) -> bool {
+    match file.record_len_at(offset) {
+        Ok(len) if offset + len as u64 <= file_size => file.extract_data_entry(offset).is_ok(),
+        _ => false,
+    }
+}
+
+// Writing resumes at the end of the last valid record of the active file, but
+// appends go to the end of the file. Cut off a partly written record left by a
+// crash so the two agree.
+fn drop_torn_tail(dir_path: &Path, active_file: &FileHandle) -> Result<()> {
+    let path = dir_path.join(format!("{}{}", active_file.get_file_id(), FILE_SUFFIX));
+    if fs::metadata(&path)?.len() > active_file.get_offset() {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(active_file.get_offset())?;
+    }
+    Ok(())
+}
+
+// The id of a data file named `<digits>.db`
+pub(crate) fn parse_data_file_id(file_name: &str) -> Option<u32> {
+    let id = file_name.strip_suffix(FILE_SUFFIX)?;
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    id.parse::<u32>().ok()
+}
+
+// The id of the file that follows `file_id` in its shard
+pub(crate) fn next_file_id(file_id: u32, shard_count: usize) -> Result<u32> {
+    if file_id >= last_file_id(file_id, shard_count) - RESERVED_FILE_IDS {
+        return Err(Error::FileIdsExhausted(file_id));
+    }
+    Ok(file_id + 1)
+}
+
+// Like `next_file_id`, but may go into the reserved ids
+pub(crate) fn next_reserved_file_id(file_id: u32, shard_count: usize) -> Result<u32> {
+    if file_id >= last_file_id(file_id, shard_count) {
+        return Err(Error::FileIdsExhausted(file_id));
+    }
+    Ok(file_id + 1)
+}
+
+// The last id in the range of the shard `file_id` belongs to
+fn last_file_id(file_id: u32, shard_count: usize) -> u32 {
+    if shard_count <= 1 {
+        return u32::MAX;
+    }
+    (shard_of(file_id, shard_count) as u32 + 1) * SHARD_FILE_IDS - 1
+}
+
+pub(crate) fn create_data_file(opts: &Opts, file_id: u32) -> Result<FileHandle> {
+    let path = opts.dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+    let file = FileHandle::new(file_id, StandardIO::new(&path)?.into());
+    #[cfg(unix)]
+    set_mode(&path, opts.file_mode)?;
+    Ok(file)
+}
+
+/// Makes entries created or renamed in `dir_path` durable; fsyncing a file
+/// doesn't persist its directory entry. A no-op where directories can't be
+/// opened.
+pub(crate) fn sync_dir(dir_path: &Path) -> Result<()> {
+    #[cfg(test)]
+    tests::SYNCED_DIRS.with(|dirs| dirs.borrow_mut().push(dir_path.to_path_buf()));
+    #[cfg(unix)]
+    fs::File::open(dir_path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir_path;
+    Ok(())
+}
+
+// Applies a unix permission mode to a file or directory just created
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+// The log position just past `entry`
+pub(crate) fn end_position(entry: &KeyDirEntry) -> Position {
+    (
+        entry.get_file_id(),
+        entry.get_offset() + entry.get_size() as u64,
+    )
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        // Panicking here would abort during unwinding; call `close` to see
+        // the error. The lock is released either way.
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+    use bytes::Bytes;
+
+    thread_local! {
+        // Directories passed to `sync_dir` by this test's thread
+        pub(crate) static SYNCED_DIRS: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Root writes to files whatever their mode, so tests of files it can't
+    // write to are skipped under it
+    #[cfg(unix)]
+    fn skip_as_root(test: &str) -> bool {
+        // SAFETY: geteuid has no preconditions
+        let root = unsafe { libc::geteuid() } == 0;
+        if root {
+            eprintln!("{}: skipped, file modes don't apply to root", test);
+        }
+        root
+    }
+
+    fn take_synced_dirs() -> Vec<PathBuf> {
+        SYNCED_DIRS.with(|dirs| dirs.take())
+    }
+
+    thread_local! {
+        // Runs on this test's thread between a write's fsync and its index
+        // update
+        static BEFORE_INDEX_UPDATE: RefCell<Option<Box<dyn Fn()>>> = const { RefCell::new(None) };
+    }
+
+    pub(crate) fn before_index_update() {
+        BEFORE_INDEX_UPDATE.with(|hook| {
+            if let Some(hook) = hook.borrow().as_ref() {
+                hook();
+            }
+        });
+    }
+
+    fn set_before_index_update(hook: Option<Box<dyn Fn()>>) {
+        BEFORE_INDEX_UPDATE.with(|cell| *cell.borrow_mut() = hook);
+    }
+
+    // Runs `test` on a fresh database in `opts.dir_path`, then on one in
+    // memory with the same options, for tests that don't depend on files
+    pub(crate) fn on_both_backends(opts: &Opts, test: impl Fn(Db) -> Result<()>) -> Result<()> {
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        test(Db::open(opts)?)?;
+        test(Db::open_in_memory(opts)?)
+    }
+
+    #[test]
+    fn test_open_db() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/open_db".to_string(),
+            1024 * 1024,
+        );
+
+        on_both_backends(&opts, |db| {
+            for i in 1..100 {
+                let key = Bytes::from(format!("key{}", i));
+                assert_eq!(
+                    db.get(key.clone()).unwrap_err().to_string(),
+                    Error::Unsupported("Db read error: Key not found".to_string()).to_string()
+                );
+            }
+
+            for i in 101..100000 {
+                let key = Bytes::from(format!("key{}", i));
+                let value = Bytes::from(format!("value{}", i));
+                match db.get(key.clone()) {
+                    Ok(read_value) => assert_eq!(value, read_value),
+                    Err(e) => {
+                        println!("read error: key: {:?}, error: {:?}", key, e);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_single_thread_put_and_read() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/put_and_read".to_string(),
+            1024 * 1024,
+        );
+        on_both_backends(&opts, |db| {
+            for i in 1..100000 {
+                let key = Bytes::from(format!("key{}", i));
+                let value = Bytes::from(format!("value{}", i));
+                match db.put(key.clone(), value.clone()) {
+                    Ok(_) => println!("put success: key: {:?}, value: {:?}", key, value),
+                    Err(e) => return Err(e),
+                }
+                assert_eq!(db.get(key.clone()).unwrap(), value);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_concurrent_read() -> anyhow::Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/concurrent_read".to_string(),
+            1024 * 1024,
+        );
+        Ok(on_both_backends(&opts, |db| {
+            // Create shared DB reference
+            let db = Arc::new(db);
+            let start = std::time::Instant::now();
+
+            // Spawn multiple reader threads
+            let mut handles = vec![];
+            for i in 1..1000 {
+                let db = db.clone();
+                let key = Bytes::from(format!("key{}", i));
+                let value = Bytes::from(format!("value{}", i));
+
+                let handle = thread::spawn(move || match db.get(key.clone()) {
+                    Ok(read_value) => {
+                        assert_eq!(read_value, value, "Read value mismatch in thread {}", i)
+                    }
+                    Err(e) => println!("read error: key: {:?}, error: {:?}", key, e),
+                });
+                handles.push(handle);
+            }
+
+            // Wait for all reads to complete
+            for handle in handles {
+                handle.join().expect("reader thread panicked");
+            }
+
+            let duration = start.elapsed();
+            println!("All concurrent reads completed in {:?}", duration);
+
+            Ok(())
+        })?)
+    }
+
+    #[test]
+    fn test_delete() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/delete".to_string(),
+            1024 * 1024,
+        );
+        on_both_backends(&opts, |db| {
+            for i in 1..10000 {
+                let key = Bytes::from(format!("key{}", i));
+                let value = Bytes::from(format!("value{}", i));
+                match db.put(key.clone(), value.clone()) {
+                    Ok(_) => println!("put success: key: {:?}, value: {:?}", key, value),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            for i in 1..100 {
+                let key = Bytes::from(format!("key{}", i));
+                match db.delete(key.clone()) {
+                    Ok(_) => println!("delete success: key: {:?}", key),
+                    Err(e) => return Err(e),
+                }
+                assert_eq!(
+                    db.get(key.clone()).unwrap_err().to_string(),
+                    Error::Unsupported("Db read error: Key not found".to_string()).to_string()
+                );
+            }
+
+            for i in 1..100 {
+                let key = Bytes::from(format!("key{}", i));
+                assert_eq!(
+                    db.get(key.clone()).unwrap_err().to_string(),
+                    Error::Unsupported("Db read error: Key not found".to_string()).to_string()
+                );
+            }
+            Ok(())
+        })
+    }
+    #[test]
+    fn test_sync() -> Result<()> {
+        let opts = Opts::new(256, 1024, false, true, "/tmp/sync".to_string(), 1024 * 1024);
+        on_both_backends(&opts, |db| {
+            println!("db: {:?}", db);
+            let key = Bytes::from("key");
+            let value = Bytes::from("value");
+            db.put(key.clone(), value)?;
+
+            let close_res = db.sync();
+            assert!(close_res.is_ok());
+
+            Ok(())
+        })
+    }
+    #[test]
+    fn test_sync_all_across_rotation() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/sync_all_across_rotation".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            let key = Bytes::from(format!("key{}", i));
+            let value = Bytes::from(format!("value{}", i));
+            db.put(key, value)?;
+        }
+        assert!(!db.inactive_files.is_empty());
+        db.sync_all()?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            let key = Bytes::from(format!("key{}", i));
+            assert_eq!(db.get(key)?, format!("value{}", i).as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_inactive_files_are_frozen() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_inactive_files_are_frozen".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        assert!(!db.active_file.read().is_frozen());
+        let mut retired = db.inactive_files.iter().next().unwrap().clone();
+        assert!(db.inactive_files.iter().all(|file| file.is_frozen()));
+        assert!(retired.write(b"garbage").is_err());
+        drop(db);
+
+        // Files retired by an earlier run are frozen on open too
+        let db = Db::open(&opts)?;
+        assert!(db.inactive_files.iter().all(|file| file.is_frozen()));
+        for i in 0..100 {
+            assert_eq!(db.get(Bytes::from(format!("key{}", i)))?, "value");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_in_range() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_size_in_range".to_string(),
+            1024 * 1024,
+        );
+        on_both_backends(&opts, |db| {
+            for i in 0..10 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(vec![b'v'; 100]),
+                )?;
+            }
+            // Overwritten and deleted keys only count their live record
+            db.put(Bytes::from("key3"), Bytes::from(vec![b'v'; 10]))?;
+            db.delete(Bytes::from("key5"))?;
+
+            let record_len = |key: &str, value_len| {
+                let key = encode_transaction_key(key.as_bytes().to_vec(), NON_COMMITTED);
+                DataEntry::new(key, vec![b'v'; value_len], State::Active).encoded_len() as u64
+            };
+            let (record, small) = (record_len("key0", 100), record_len("key3", 10));
+            // key2, key3, key4 and key6
+            assert_eq!(db.size_in_range(b"key2", b"key7"), 3 * record + small);
+            assert_eq!(db.size_in_range(b"key2", b"key2"), 0);
+            assert_eq!(db.size_in_range(b"", b"z"), 8 * record + small);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_open_with_warmup() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_open_with_warmup".to_string(),
+            4096,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..500 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        db.delete(Bytes::from("key0"))?;
+        drop(db);
+
+        opts.warmup = true;
+        let db = Db::open(&opts)?;
+        assert!(!db.inactive_files.is_empty());
+        // Both the mapped inactive files and the active file take the hint
+        for file in db.inactive_files.iter() {
+            file.prefetch()?;
+        }
+        db.active_file.read().prefetch()?;
+        assert!(db.get(Bytes::from("key0")).is_err());
+        for i in 1..500 {
+            assert_eq!(
+                db.get(Bytes::from(format!("key{}", i)))?,
+                format!("value{}", i).into_bytes()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_file_scan() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/key_file_scan".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.key_file = true;
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            let prefix = if i % 2 == 0 { "even" } else { "odd" };
+            let key = Bytes::from(format!("{}-key{}", prefix, i));
+            db.put(key, Bytes::from(format!("value{}", i)))?;
+        }
+        db.delete(Bytes::from("odd-key1"))?;
+        db.checkpoint()?;
+        assert!(opts.dir_path.join(KEY_FILE_NAME).is_file());
+
+        let mut keys = db.list_keys()?;
+        keys.sort();
+        assert_eq!(db.scan_prefix_keys(b"")?, keys);
+        let odd = db.scan_prefix_keys(b"odd-")?;
+        assert_eq!(odd.len(), 49);
+        assert!(odd.windows(2).all(|w| w[0] < w[1]));
+        assert!(odd.iter().all(|key| key.starts_with(b"odd-")));
+        assert!(db.scan_prefix_keys(b"none")?.is_empty());
+
+        // Merge writes the key file along with its output
+        db.merge()?;
+        drop(db);
+        let db = Db::open(&opts)?;
+        let mut keys = db.list_keys()?;
+        keys.sort();
+        assert_eq!(db.scan_prefix_keys(b"")?, keys);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_skips_unchanged_files() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/open_skips_unchanged_files".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.file_manifest = true;
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            let key = Bytes::from(format!("key{}", i % 60));
+            db.put(key, Bytes::from(format!("value{}", i)))?;
+        }
+        db.delete(Bytes::from("key0"))?;
+        let active_id = db.active_file.read().get_file_id();
+        assert!(active_id >= 3);
+        drop(db);
+
+        // Read-only opens leave the directory as it is
+        let files = || -> Result<Vec<_>> {
+            let mut names = fs::read_dir(&opts.dir_path)?
+                .map(|file| Ok(file?.file_name()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+        let before = files()?;
+        let mut read_only_opts = opts.clone();
+        read_only_opts.read_only = true;
+        drop(Db::open(&read_only_opts)?);
+        // Files were recorded as they rotated out
+        let db = Db::open(&read_only_opts)?;
+        assert_eq!(db.open_report().memoized_files.len() as u32, active_id);
+        drop(db);
+        assert_eq!(files()?, before);
+
+        // Without a manifest open replays everything and records the
+        // inactive files
+        fs::remove_file(opts.dir_path.join("MANIFEST"))?;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().replayed_files.len() as u32, active_id + 1);
+        assert!(db.open_report().memoized_files.is_empty());
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().replayed_files, vec![active_id]);
+        assert_eq!(db.open_report().memoized_files.len() as u32, active_id);
+        assert!(db.get(Bytes::from("key0")).is_err());
+        for i in 1..60 {
+            let key = Bytes::from(format!("key{}", i));
+            let last = if i < 40 { i + 60 } else { i };
+            assert_eq!(db.get(key)?, format!("value{}", last).as_bytes());
+        }
+        drop(db);
+
+        // Touch one file: only it (and the active file) is replayed
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(opts.dir_path.join(format!("1{}", FILE_SUFFIX)))?;
+        std::io::Write::write_all(&mut file, &[0])?;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().replayed_files, vec![1, active_id]);
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().replayed_files, vec![active_id]);
+        for i in 1..60 {
+            let key = Bytes::from(format!("key{}", i));
+            let last = if i < 40 { i + 60 } else { i };
+            assert_eq!(db.get(key)?, format!("value{}", last).as_bytes());
+        }
+        drop(db);
+
+        // Rewritten at the same size, the file is replayed too
+        let path = opts.dir_path.join(format!("2{}", FILE_SUFFIX));
+        let mut data = fs::read(&path)?;
+        let len = data.len();
+        data[len - 1] ^= 0xff;
+        fs::write(&path, data)?;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().replayed_files, vec![2, active_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotated_files_are_memoized() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/rotated_files_are_memoized".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.file_manifest = true;
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        let active_id = db.active_file.read().get_file_id();
+
+        // Recorded while the database is still open, so a crash doesn't
+        // lose them
+        let started = std::time::Instant::now();
+        let memoized = |manifest: &Manifest| (0..active_id).all(|id| manifest.get(id).is_some());
+        while !memoized(&Manifest::load(&opts.dir_path)) {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(Manifest::load(&opts.dir_path).get(active_id).is_none());
+
+        // Evicted files are dropped from it
+        db.remove_data_file(0)?;
+        db.inactive_files.remove(&0);
+        drop(db);
+        assert!(Manifest::load(&opts.dir_path).get(0).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_crc_detects_truncation() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_file_crc".to_string(),
+            512,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.file_crc = true;
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        let file_ids = db.data_file_ids();
+        assert!(file_ids.len() > 2);
+        drop(db);
+        // Retired files get theirs on rotation and the active file on close
+        for file_id in file_ids.iter() {
+            assert!(FileCrc::read(&opts.dir_path, *file_id)?.is_some());
+        }
+
+        // Appending after a reopen keeps the file matching
+        let db = Db::open(&opts)?;
+        assert!(db.open_report().crc_mismatches.is_empty());
+        db.put(Bytes::from("after"), Bytes::from("reopen"))?;
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert!(db.open_report().crc_mismatches.is_empty());
+        drop(db);
+
+        // Cut into the last record of a retired file
+        let file_id = file_ids[1];
+        let path = opts.dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+        let len = fs::metadata(&path)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 5)?;
+
+        opts.strict_file_crc = true;
+        assert!(matches!(
+            Db::open(&opts),
+            Err(Error::ChecksumMismatch(id)) if id == file_id
+        ));
+
+        // Otherwise it's reported and replays as a torn write would
+        opts.strict_file_crc = false;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().crc_mismatches, vec![file_id]);
+        let missing = (0..100)
+            .filter(|i| db.get(Bytes::from(format!("key{}", i))).is_err())
+            .count();
+        assert_eq!(missing, 1);
+        assert_eq!(db.get(Bytes::from("after"))?, "reopen");
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_loads_incremental_hint() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/open_loads_incremental_hint".to_string(),
+            512,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.incremental_hint = true;
+        let index_entries = |db: &Db| {
+            let mut entries = Vec::new();
+            let mut iter = db.ctx.index.iter();
+            while let Some((key, entry)) = iter.next() {
+                entries.push((key.to_vec(), entry.clone()));
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+
+        let db = Db::open(&opts)?;
+        for i in 0..300 {
+            let key = Bytes::from(format!("key{}", i % 100));
+            db.put(key, Bytes::from(format!("value{}", i)))?;
+        }
+        for i in 0..20 {
+            db.delete(Bytes::from(format!("key{}", i * 3)))?;
+        }
+        db.put_all(&[
+            (Bytes::from("key0"), Bytes::from("batched")),
+            (Bytes::from("key1"), Bytes::from("batched")),
+        ])?;
+        let active_id = db.active_file.read().get_file_id();
+        assert!(active_id >= 3);
+        let expected = index_entries(&db);
+        drop(db);
+
+        // Only the active file is read, from where the log ends
+        let mut db = Db::open(&opts)?;
+        assert!(db.open_report().loaded_hint_log);
+        assert_eq!(db.open_report().replayed_files, vec![active_id]);
+        assert_eq!(index_entries(&db), expected);
+        assert!(db.get(Bytes::from("key3")).is_err());
+        assert_eq!(db.get(Bytes::from("key0"))?, "batched".as_bytes());
+        db.reindex()?;
+        assert_eq!(index_entries(&db), expected);
+        db.put(Bytes::from("key3"), Bytes::from("back"))?;
+        let expected = index_entries(&db);
+        drop(db);
+
+        // A torn log counts up to its last intact write and the data after
+        // it replays
+        let log_path = opts.dir_path.join("hint-log");
+        let len = fs::metadata(&log_path)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(len - 3)?;
+        let db = Db::open(&opts)?;
+        assert!(db.open_report().loaded_hint_log);
+        assert_eq!(index_entries(&db), expected);
+        assert_eq!(db.get(Bytes::from("key3"))?, "back".as_bytes());
+        drop(db);
+
+        // A log ahead of the data files is left out
+        let active_path = opts.dir_path.join(format!("{}{}", active_id, FILE_SUFFIX));
+        let active_len = fs::metadata(&active_path)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&active_path)?
+            .set_len(active_len - 1)?;
+        let db = Db::open(&opts)?;
+        assert!(!db.open_report().loaded_hint_log);
+        assert!(db.get(Bytes::from("key3")).is_err());
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert!(db.open_report().loaded_hint_log);
+        assert!(db.get(Bytes::from("key3")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_close() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/close".to_string(),
+            1024 * 1024,
+        );
+        let mut db = Db::open(&opts)?;
+
+        let key = Bytes::from("key");
+        let value = Bytes::from("value");
+        db.put(key, value)?;
+
+        let close_res = db.close();
+        assert!(close_res.is_ok());
+
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_small_values() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/inline_small_values".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.inline_value_threshold = 8;
+        let db = Db::open(&opts)?;
+
+        db.put(Bytes::from("counter"), Bytes::from("00000001"))?;
+        db.put(Bytes::from("blob"), Bytes::from("more than eight bytes"))?;
+
+        let counter = db.ctx.index.get(b"counter").unwrap();
+        assert_eq!(counter.get_inline_value(), Some(b"00000001".as_slice()));
+        assert!(db
+            .ctx
+            .index
+            .get(b"blob")
+            .unwrap()
+            .get_inline_value()
+            .is_none());
+        assert_eq!(db.get(Bytes::from("counter"))?, "00000001");
+        drop(db);
+
+        // Replay repopulates the inline copies
+        let db = Db::open(&opts)?;
+        let counter = db.ctx.index.get(b"counter").unwrap();
+        assert_eq!(counter.get_inline_value(), Some(b"00000001".as_slice()));
+        assert_eq!(db.get(Bytes::from("blob"))?, "more than eight bytes");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_at_stale_offset() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/read_at_stale_offset".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+
+        // After the merge "a" is rewritten where "c" used to live
+        db.put(Bytes::from("a"), Bytes::from("1"))?;
+        db.put(Bytes::from("b"), Bytes::from("1"))?;
+        db.put(Bytes::from("c"), Bytes::from("1"))?;
+        db.put(Bytes::from("a"), Bytes::from("2"))?;
+
+        let located = db.locate(b"c").unwrap();
+        assert_eq!(db.read_at(b"c", located.clone())?, b"1");
+
+        db.merge()?;
+        db.close()?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert_eq!(
+            db.read_at(b"c", located).unwrap_err().to_string(),
+            Error::Unsupported("stale offset".to_string()).to_string()
+        );
+        let located = db.locate(b"c").unwrap();
+        assert_eq!(db.read_at(b"c", located)?, b"1");
+        assert_eq!(db.get(Bytes::from("a"))?, "2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_borrows_mapped_values() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/get_borrows_mapped_values".to_string(),
+            4 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+        for i in 0..100 {
+            let key = Bytes::from(format!("key{}", i));
+            let value = Bytes::from(format!("value{}", i).repeat(10));
+            db.put(key, value)?;
+        }
+        db.close()?;
+        drop(db);
+
+        // Files other than the last one are mapped after reopening
+        let db = Db::open(&opts)?;
+        let value = db.get(Bytes::from("key0"))?;
+        let mapped = db.get_mmap_slice(b"key0")?.unwrap();
+        assert_eq!(value.as_ptr(), mapped.as_ptr());
+        assert_eq!(value, "value0".repeat(10).as_bytes());
+        let value = db.get(Bytes::from("key99"))?;
+        assert!(db.get_mmap_slice(b"key99")?.is_none());
+        assert_eq!(value, "value99".repeat(10).as_bytes());
+
+        // The borrowed value outlives the file it was read from
+        let value = db.get(Bytes::from("key1"))?;
+        fs::remove_file(opts.dir_path.join(format!("0{}", FILE_SUFFIX)))?;
+        assert_eq!(value, "value1".repeat(10).as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_mmap_slice() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_get_mmap_slice".to_string(),
+            4 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            let key = Bytes::from(format!("key{}", i));
+            db.put(key, Bytes::from(format!("value{}", i).repeat(10)))?;
+        }
+        // Not mapped while the file is still active
+        assert!(db.get_mmap_slice(b"key99")?.is_none());
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        let slice = db.get_mmap_slice(b"key0")?.unwrap();
+        assert_eq!(&*slice, "value0".repeat(10).as_bytes());
+        assert!(db.get_mmap_slice(b"key99")?.is_none());
+        assert!(db.get_mmap_slice(b"missing").is_err());
+
+        // The slice keeps the mapping alive past the database and its file
+        drop(db);
+        fs::remove_dir_all(&opts.dir_path)?;
+        assert_eq!(&*slice, "value0".repeat(10).as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retain() -> Result<()> {
+        let mut opts = Opts::new(256, 1024, false, true, "/tmp/test_retain".to_string(), 1024);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.inline_value_threshold = 2;
+        let mut db = Db::open(&opts)?;
+
+        // Values 0..10 stay inline, the rest are read back from disk
+        for i in 0..100u32 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from(i.to_string()))?;
+        }
+        let removed = db.retain(|_, value| {
+            let n: u32 = std::str::from_utf8(value).unwrap().parse().unwrap();
+            n.is_multiple_of(2)
+        })?;
+        assert_eq!(removed, 50);
+
+        db.close()?;
+        let db = Db::open(&opts)?;
+        for i in 0..100u32 {
+            let value = db.get(Bytes::from(format!("key{}", i)));
+            if i.is_multiple_of(2) {
+                assert_eq!(value?, i.to_string().into_bytes());
+            } else {
+                assert!(value.is_err());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dir_and_file_mode() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_dir_and_file_mode".to_string(),
+            128,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.dir_mode = Some(0o700);
+        opts.file_mode = Some(0o600);
+        let db = Db::open(&opts)?;
+        for i in 0..10 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from("value".repeat(4)),
+            )?;
+        }
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&opts.dir_path), 0o700);
+        assert!(db.inactive_files.len() > 1);
+        for id in 0..=db.active_file.read().get_file_id() {
+            assert_eq!(
+                mode(&opts.dir_path.join(format!("{}{}", id, FILE_SUFFIX))),
+                0o600
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_or_insert_with() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_get_or_insert_with".to_string(),
+            1024 * 1024,
+        );
+        on_both_backends(&opts, |db| {
+            let calls = std::sync::atomic::AtomicUsize::new(0);
+
+            let values = thread::scope(|s| {
+                let handles = (0..8)
+                    .map(|i| {
+                        let (db, calls) = (&db, &calls);
+                        s.spawn(move || {
+                            db.get_or_insert_with(Bytes::from("key"), || {
+                                calls.fetch_add(1, Ordering::SeqCst);
+                                Bytes::from(format!("value{}", i))
+                            })
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<Result<Vec<Bytes>>>()
+            })?;
+
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            assert!(values.iter().all(|value| *value == values[0]));
+            assert_eq!(db.get(Bytes::from("key"))?, values[0].to_vec());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_swap_hands_out_each_value_once() -> Result<()> {
+        let opts = Opts::new(256, 1024, false, false, "/tmp/test_swap".to_string(), 4096);
+        on_both_backends(&opts, |db| {
+            assert_eq!(db.swap(Bytes::from("key"), Bytes::from("initial"))?, None);
+
+            let mut old_values = thread::scope(|s| {
+                let handles = (0..8)
+                    .map(|t| {
+                        let db = &db;
+                        s.spawn(move || {
+                            (0..100)
+                                .map(|i| {
+                                    db.swap(Bytes::from("key"), Bytes::from(format!("{}-{}", t, i)))
+                                })
+                                .collect::<Result<Vec<_>>>()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<Result<Vec<_>>>()
+            })?
+            .into_iter()
+            .flatten()
+            .map(|value| value.expect("the key was set"))
+            .collect::<Vec<_>>();
+
+            // Every value but the last was handed back exactly once
+            old_values.push(db.get(Bytes::from("key"))?);
+            old_values.sort();
+            let mut written = (0..8)
+                .flat_map(|t| (0..100).map(move |i| Bytes::from(format!("{}-{}", t, i))))
+                .chain([Bytes::from("initial")])
+                .collect::<Vec<_>>();
+            written.sort();
+            assert_eq!(old_values, written);
+            assert!(db.data_file_ids().len() > 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_try_put_would_block() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_try_put".to_string(),
+            1024 * 1024,
+        );
+        on_both_backends(&opts, |db| {
+            db.try_put(Bytes::from("key"), Bytes::from("value1"))?;
+
+            let (held, release) = (Barrier::new(2), Barrier::new(2));
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let _write_guard = db.shards[0].active_file.write();
+                    held.wait();
+                    release.wait();
+                });
+                held.wait();
+                let result = db.try_put(Bytes::from("key"), Bytes::from("value2"));
+                assert!(matches!(result, Err(Error::Unsupported(ref m)) if m == "would block"));
+                release.wait();
+            });
+            assert_eq!(db.get(Bytes::from("key"))?, "value1");
+
+            db.try_put(Bytes::from("key"), Bytes::from("value3"))?;
+            assert_eq!(db.get(Bytes::from("key"))?, "value3");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_reopen_resumes_active_file() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_reopen_resumes_active_file".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("key1"), Bytes::from("value1"))?;
+        let (file_id, offset) = {
+            let active_file = db.active_file.read();
+            (active_file.get_file_id(), active_file.get_offset())
+        };
+        drop(db);
+
+        // A torn record at the end is dropped rather than appended after
+        let path = opts.dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+        let mut file = fs::OpenOptions::new().append(true).open(&path)?;
+        std::io::Write::write_all(&mut file, &[0, 4, 10, b'k'])?;
+
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("key2"), Bytes::from("value2"))?;
+        {
+            let active_file = db.active_file.read();
+            assert_eq!(active_file.get_file_id(), file_id);
+            assert!(active_file.get_offset() > offset);
+        }
+        assert!(db.inactive_files.is_empty());
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.get(Bytes::from("key1"))?, "value1");
+        assert_eq!(db.get(Bytes::from("key2"))?, "value2");
+        let data_files = fs::read_dir(&opts.dir_path)?
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(FILE_SUFFIX)
+            })
+            .count();
+        assert_eq!(data_files, 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_with_read_only_last_file() -> Result<()> {
+        if skip_as_root("test_open_with_read_only_last_file") {
+            return Ok(());
+        }
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_open_with_read_only_last_file".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        let last_id = db.active_file.read().get_file_id();
+        drop(db);
+
+        let path = opts.dir_path.join(format!("{}{}", last_id, FILE_SUFFIX));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o444))?;
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.active_file.read().get_file_id(), last_id + 1);
+        assert!(db.inactive_files.get(&last_id).unwrap().is_frozen());
+        db.put(Bytes::from("new"), Bytes::from("value"))?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.active_file.read().get_file_id(), last_id + 1);
+        assert_eq!(db.get(Bytes::from("new"))?, "value");
+        for i in 0..100 {
+            assert_eq!(db.get(Bytes::from(format!("key{}", i)))?, "value");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_file_names() -> Result<()> {
+        assert_eq!(parse_data_file_id("12.db"), Some(12));
+        assert_eq!(parse_data_file_id("012.db"), Some(12));
+        for name in ["1.db.bak", "1.backup.db", "+1.db", ".db", "a.db", "1.dbx"] {
+            assert_eq!(parse_data_file_id(name), None);
+        }
+
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_data_file_names".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+        drop(db);
+
+        // Leftovers next to the data files aren't replayed
+        let path = opts.dir_path.join("0.db");
+        for name in ["0.db.bak", "0.backup.db", "notes.db"] {
+            fs::write(opts.dir_path.join(name), b"not a data file")?;
+        }
+        let db = Db::open(&opts)?;
+        assert_eq!(db.get(Bytes::from("key"))?, "value");
+        assert_eq!(db.open_report().replayed_files, vec![0]);
+        drop(db);
+
+        // A second name for id 0 fails open instead of replaying either
+        let alias = opts.dir_path.join("00.db");
+        fs::copy(&path, &alias)?;
+        match Db::open(&opts) {
+            Err(Error::ConflictingDataFiles { file_id, paths }) => {
+                assert_eq!(file_id, 0);
+                assert_eq!(paths, vec![path.clone(), alias.clone()]);
+            }
+            other => panic!("expected conflicting files, got {:?}", other.map(|_| ())),
+        }
+        fs::remove_file(&path)?;
+        assert!(matches!(
+            Db::open(&opts),
+            Err(Error::ConflictingDataFiles { file_id: 0, .. })
+        ));
+        fs::rename(&alias, &path)?;
+        assert_eq!(Db::open(&opts)?.get(Bytes::from("key"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_writes_visible_only_once_durable() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_writes_visible_only_once_durable".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let mut crash_opts = opts.clone();
+        crash_opts.dir_path = PathBuf::from("/tmp/test_writes_visible_only_once_durable-crash");
+        let db = Arc::new(Db::open(&opts)?);
+
+        // Between a write's fsync and its index update: the fsyncs done so
+        // far, whether `key` is readable, and a copy of the directory as a
+        // crash at that point would leave it
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let watch = |key: &'static str| {
+            let (db, seen, crash_dir) = (db.clone(), seen.clone(), crash_opts.dir_path.clone());
+            set_before_index_update(Some(Box::new(move || {
+                let _ = fs::remove_dir_all(&crash_dir);
+                copy_recursive(&db.ctx.opts.dir_path, &crash_dir).unwrap();
+                let readable = db.get(Bytes::from(key)).is_ok();
+                seen.lock()
+                    .push((db.shards[0].syncer.sync_count(), readable));
+            })));
+        };
+        let syncs = || db.shards[0].syncer.sync_count();
+
+        let before = syncs();
+        watch("put");
+        db.put(Bytes::from("put"), Bytes::from("value"))?;
+        assert_eq!(seen.lock().pop(), Some((before + 1, false)));
+        assert_eq!(Db::open(&crash_opts)?.get(Bytes::from("put"))?, "value");
+
+        let before = syncs();
+        watch("put");
+        db.delete(Bytes::from("put"))?;
+        assert_eq!(seen.lock().pop(), Some((before + 1, true)));
+        assert!(Db::open(&crash_opts)?.get(Bytes::from("put")).is_err());
+
+        // The commit marker is fsynced before any of the batch is visible
+        let before = syncs();
+        watch("batched");
+        let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: true,
+        })?;
+        batch.put(Bytes::from("batched"), Bytes::from("value"))?;
+        batch.put(Bytes::from("other"), Bytes::from("value"))?;
+        batch.commit()?;
+        assert_eq!(seen.lock().pop(), Some((before + 1, false)));
+        let crashed = Db::open(&crash_opts)?;
+        assert_eq!(crashed.get(Bytes::from("batched"))?, "value");
+        assert_eq!(crashed.get(Bytes::from("other"))?, "value");
+        drop(crashed);
+
+        let before = syncs();
+        watch("inserted");
+        db.get_or_insert_with(Bytes::from("inserted"), || Bytes::from("value"))?;
+        assert_eq!(seen.lock().pop(), Some((before + 1, false)));
+        assert_eq!(
+            Db::open(&crash_opts)?.get(Bytes::from("inserted"))?,
+            "value"
+        );
+
+        set_before_index_update(None);
+        assert_eq!(db.get(Bytes::from("batched"))?, "value");
+        assert_eq!(db.get(Bytes::from("inserted"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_history".to_string(),
+            128,
+        );
+        on_both_backends(&opts, |db| {
+            for i in 0..3 {
+                db.put(Bytes::from("key"), Bytes::from(format!("value{}", i)))?;
+                db.put(Bytes::from("other"), Bytes::from("padding-padding"))?;
+            }
+            db.delete(Bytes::from("key"))?;
+
+            let history = db.history(b"key")?;
+            assert_eq!(history.len(), 4);
+            assert!(history[..3].iter().all(|(_, _, active)| *active));
+            assert!(!history[3].2);
+            // Versions span rotated files and come back in write order
+            assert!(history.first().unwrap().0 < history.last().unwrap().0);
+            assert!(history.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(db.history(b"missing")?.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_empty_keys() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_empty_keys".to_string(),
+            1024 * 1024,
+        );
+        on_both_backends(&opts, |db| {
+            let empty = Bytes::new;
+
+            assert!(matches!(
+                db.put(empty(), Bytes::from("value")),
+                Err(Error::EmptyKey)
+            ));
+            assert!(matches!(db.get(empty()), Err(Error::EmptyKey)));
+            assert!(matches!(db.delete(empty()), Err(Error::EmptyKey)));
+            assert!(matches!(
+                db.get_or_insert_with(empty(), || Bytes::from("value")),
+                Err(Error::EmptyKey)
+            ));
+            let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+                max_batch_num: 10,
+                sync_writes: true,
+            })?;
+            assert!(matches!(
+                batch.put(empty(), Bytes::from("value")),
+                Err(Error::EmptyKey)
+            ));
+            assert!(matches!(batch.delete(empty()), Err(Error::EmptyKey)));
+            assert!(db.list_keys()?.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_get_seq() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_get_seq".to_string(),
+            1024 * 1024,
+        );
+        let check = |db: &Db, batched_seq: u32| -> Result<()> {
+            assert_eq!(
+                db.get_seq(Bytes::from("single"))?,
+                Some((Bytes::from("value"), NON_COMMITTED))
+            );
+            assert_eq!(
+                db.get_seq(Bytes::from("batched"))?,
+                Some((Bytes::from("value1"), batched_seq))
+            );
+            assert_eq!(db.get_seq(Bytes::from("missing"))?, None);
+            Ok(())
+        };
+        // Returns the sequence number of the last batch
+        let write = |db: &Db| -> Result<u32> {
+            db.put(Bytes::from("single"), Bytes::from("value"))?;
+            let mut seq_nos = Vec::new();
+            for i in 0..2 {
+                let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+                    max_batch_num: 10,
+                    sync_writes: true,
+                })?;
+                batch.put(Bytes::from("batched"), Bytes::from(format!("value{}", i)))?;
+                seq_nos.push(db.sequence_number.load(Ordering::SeqCst));
+                batch.commit()?;
+            }
+            assert!(seq_nos[0] > NON_COMMITTED && seq_nos[0] < seq_nos[1]);
+            check(db, seq_nos[1])?;
+            Ok(seq_nos[1])
+        };
+        on_both_backends(&opts, |db| write(&db).map(drop))?;
+
+        // The sequence numbers survive a reopen
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        let batched_seq = write(&db)?;
+        drop(db);
+        check(&Db::open(&opts)?, batched_seq)
+    }
+
+    #[test]
+    fn test_get_timeout() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_get_timeout".to_string(),
+            128,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("slow"), Bytes::from("value"))?;
+        for i in 0..10 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("filler"))?;
+        }
+        assert_eq!(db.locate(b"slow").unwrap().get_file_id(), 0);
+
+        // Stall every read of the file `slow` is in
+        let path = opts.dir_path.join(format!("0{}", FILE_SUFFIX));
+        let slow_io =
+            StandardIO::open_read_only(&path)?.with_read_delay(Duration::from_millis(500));
+        db.inactive_files.get_mut(&0).unwrap().io = slow_io.into();
+
+        let started = Instant::now();
+        let err = db
+            .get_timeout(Bytes::from("slow"), Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == ErrorKind::TimedOut));
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        assert_eq!(
+            db.get_timeout(Bytes::from("slow"), Duration::from_secs(10))?,
+            Some(Bytes::from("value"))
+        );
+        assert_eq!(
+            db.get_timeout(Bytes::from("key9"), Duration::from_millis(50))?,
+            Some(Bytes::from("filler"))
+        );
+        assert_eq!(
+            db.get_timeout(Bytes::from("missing"), Duration::from_millis(50))?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_timeout_waits_on_read_limit() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_get_timeout_waits_on_read_limit".to_string(),
+            128,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.max_concurrent_reads = Some(1);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("slow"), Bytes::from("value"))?;
+        for i in 0..10 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("filler"))?;
+        }
+        let path = opts.dir_path.join(format!("0{}", FILE_SUFFIX));
+        let slow_io =
+            StandardIO::open_read_only(&path)?.with_read_delay(Duration::from_millis(500));
+        db.inactive_files.get_mut(&0).unwrap().io = slow_io.into();
+
+        // The read that timed out still holds the only permit
+        let timed_out = |result: Result<Option<Bytes>>| matches!(result, Err(Error::Io(e)) if e.kind() == ErrorKind::TimedOut);
+        assert!(timed_out(
+            db.get_timeout(Bytes::from("slow"), Duration::from_millis(50))
+        ));
+        assert!(timed_out(
+            db.get_timeout(Bytes::from("key9"), Duration::from_millis(50))
+        ));
+        assert_eq!(
+            db.get_timeout(Bytes::from("key9"), Duration::from_secs(10))?,
+            Some(Bytes::from("filler"))
+        );
+
+        // Stalled reads share the pool rather than start threads
+        for _ in 0..10 {
+            let _ = db.get_timeout(Bytes::from("slow"), Duration::from_millis(1));
+        }
+        let workers = db.read_workers.lock().clone().unwrap();
+        assert_eq!(workers.threads(), TIMED_READ_THREADS);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_prefix() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_delete_prefix".to_string(),
+            256,
+        );
+        let fill = |db: &Db| -> Result<()> {
+            for i in 0..10 {
+                db.put(Bytes::from(format!("user:{}", i)), Bytes::from("u"))?;
+                db.put(Bytes::from(format!("order:{}", i)), Bytes::from("o"))?;
+            }
+            db.put(Bytes::from("user"), Bytes::from("bare"))?;
+            db.put(Bytes::from("users:0"), Bytes::from("s"))?;
+            Ok(())
+        };
+        let count = |db: &Db, prefix: &str| -> Result<usize> {
+            Ok(db.scan_prefix_keys(prefix.as_bytes())?.len())
+        };
+
+        // Both the unordered and the ordered index
+        on_both_backends(&opts, |mut db| {
+            for ordered in [false, true] {
+                fill(&db)?;
+                if ordered {
+                    let index = crate::index::BTree::new();
+                    for key in db.list_keys()? {
+                        index.put(key.to_vec().into(), db.locate(&key).unwrap());
+                    }
+                    db.ctx.index = index.into();
+                }
+                assert_eq!(db.delete_prefix(Bytes::from("user:"))?, 10);
+                assert_eq!(count(&db, "user")?, 2);
+                assert_eq!(count(&db, "order:")?, 10);
+                assert_eq!(db.delete_prefix(Bytes::from("nothing"))?, 0);
+                assert_eq!(db.delete_prefix(Bytes::from("user:"))?, 0);
+                assert_eq!(db.list_keys()?.len(), 12);
+                assert_eq!(db.delete_prefix(Bytes::new())?, 12);
+                assert!(db.list_keys()?.is_empty());
+            }
+            Ok(())
+        })?;
+
+        // The tombstones survive a reopen
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        fill(&Db::open(&opts)?)?;
+        let mut db = Db::open(&opts)?;
+        db.delete_prefix(Bytes::from("order:"))?;
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert_eq!(count(&db, "order:")?, 0);
+        assert_eq!(count(&db, "user")?, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tolerate_missing_files() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_tolerate_missing_files".to_string(),
+            64,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..12 {
+            db.put(
+                Bytes::from(format!("key{:02}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        let file_of = (0..12)
+            .map(|i| {
+                db.locate(format!("key{:02}", i).as_bytes())
+                    .unwrap()
+                    .get_file_id()
+            })
+            .collect::<Vec<_>>();
+        let newest = *file_of.last().unwrap();
+        assert!(newest > 2);
+        drop(db);
+
+        // Can't be mapped, one that fails to replay, and the active file
+        let path = |file_id: u32| opts.dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+        fs::remove_file(path(1))?;
+        fs::create_dir(path(1))?;
+        let mut bytes = fs::read(path(2))?;
+        bytes[0] ^= 0xff;
+        fs::write(path(2), bytes)?;
+        fs::remove_file(path(newest))?;
+        fs::create_dir(path(newest))?;
+        assert!(Db::open(&opts).is_err());
+
+        opts.tolerate_missing_files = true;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().skipped_files, vec![1, 2, newest]);
+        for (i, file_id) in file_of.iter().enumerate() {
+            let value = db.get(Bytes::from(format!("key{:02}", i)));
+            if [1, 2, newest].contains(file_id) {
+                assert!(value.is_err());
+            } else {
+                assert_eq!(value?, format!("value{}", i).into_bytes());
+            }
+        }
+        // Writes go after the skipped active file
+        db.put(Bytes::from("new"), Bytes::from("value"))?;
+        assert_eq!(db.locate(b"new").unwrap().get_file_id(), newest + 1);
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().skipped_files, vec![1, 2, newest]);
+        assert_eq!(db.get(Bytes::from("new"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_reindex() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_reindex".to_string(),
+            256,
+        );
+        opts.inline_value_threshold = 4;
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..20 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        db.put(Bytes::from("small"), Bytes::from("abc"))?;
+        db.delete(Bytes::from("key3"))?;
+        let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: false,
+        })?;
+        batch.put(Bytes::from("batched"), Bytes::from("value"))?;
+        batch.commit()?;
+        db.merge()?;
+        drop(db);
+        let mut db = Db::open(&opts)?;
+        db.put(Bytes::from("key5"), Bytes::from("rewritten"))?;
+        assert!(db.data_file_ids().len() > 2);
+        let expected = db
+            .list_keys()?
+            .into_iter()
+            .map(|key| (key.clone(), db.locate(&key)))
+            .collect::<std::collections::BTreeMap<_, _>>();
+
+        // Repoint, drop and add keys behind the database's back
+        let index = &db.ctx.index;
+        index.put(b"key1".as_slice().into(), db.locate(b"key2").unwrap());
+        index.delete(b"key7");
+        index.delete(b"small");
+        index.put(b"key3".as_slice().into(), db.locate(b"key4").unwrap());
+        assert!(db.get(Bytes::from("key1")).is_err());
+
+        db.reindex()?;
+        let rebuilt = db
+            .list_keys()?
+            .into_iter()
+            .map(|key| (key.clone(), db.locate(&key)))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert_eq!(rebuilt, expected);
+        assert_eq!(db.get(Bytes::from("key1"))?, "value1");
+        assert_eq!(db.get(Bytes::from("key5"))?, "rewritten");
+        assert_eq!(db.get(Bytes::from("small"))?, "abc");
+        assert!(db.locate(b"key3").is_none());
+        assert!(opts.dir_path.join(HINT_FILE_NAME).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keydir_file_id_after_rotation() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_keydir_file_id_after_rotation".to_string(),
+            64,
+        );
+        on_both_backends(&opts, |db| {
+            // Every record fills most of a file, so nearly every put rotates
+            for i in 0..50 {
+                let key = Bytes::from(format!("key{}", i));
+                db.put(key.clone(), Bytes::from(format!("value-{:020}", i)))?;
+                let entry = db.locate(&key).unwrap();
+                assert_eq!(entry.get_file_id(), db.active_file.read().get_file_id());
+                assert_eq!(entry.get_offset(), 0);
+            }
+            for i in 0..50 {
+                assert_eq!(
+                    db.get(Bytes::from(format!("key{}", i)))?,
+                    format!("value-{:020}", i).into_bytes()
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_puts_during_concurrent_rotation() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_puts_during_concurrent_rotation".to_string(),
+            4096,
+        );
+        on_both_backends(&opts, |db| {
+            let done = std::sync::atomic::AtomicBool::new(false);
+
+            thread::scope(|s| {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        db.rotate_active_file().unwrap();
+                    }
+                });
+                for t in 0..2 {
+                    let db = &db;
+                    s.spawn(move || {
+                        for i in 0..500 {
+                            db.put(
+                                Bytes::from(format!("key-{}-{}", t, i)),
+                                Bytes::from(format!("value{}", i)),
+                            )
+                            .unwrap();
+                            // Give the rotating thread a chance to run in between
+                            thread::yield_now();
+                        }
+                    });
+                }
+                while db.metrics().puts < 1000 {
+                    thread::yield_now();
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+
+            for t in 0..2 {
+                for i in 0..500 {
+                    assert_eq!(
+                        db.get(Bytes::from(format!("key-{}-{}", t, i)))?,
+                        format!("value{}", i).into_bytes()
+                    );
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_concurrent_rotations_keep_file_ids_contiguous() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_concurrent_rotations_contiguous".to_string(),
+            200,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+
+        // Records of ~70 bytes rotate every third put, and one thread also
+        // rotates by hand
+        thread::scope(|s| {
+            for t in 0..8 {
+                let db = &db;
+                s.spawn(move || {
+                    for i in 0..200 {
+                        db.put(
+                            Bytes::from(format!("key-{}-{}", t, i)),
+                            Bytes::from(format!("value-{:040}", i)),
+                        )
+                        .unwrap();
+                        if t == 0 && i % 10 == 0 {
+                            db.rotate_active_file().unwrap();
+                        }
+                        thread::yield_now();
+                    }
+                });
+            }
+        });
+
+        let active_id = db.active_file.read().get_file_id();
+        let mut inactive_ids = db
+            .inactive_files
+            .iter()
+            .map(|file| file.get_file_id())
+            .collect::<Vec<_>>();
+        inactive_ids.sort();
+        assert_eq!(inactive_ids, (0..active_id).collect::<Vec<_>>());
+        assert_eq!(db.shards[0].get_file_id(), active_id);
+        for t in 0..8 {
+            for i in 0..200 {
+                assert_eq!(
+                    db.get(Bytes::from(format!("key-{}-{}", t, i)))?,
+                    format!("value-{:040}", i).into_bytes()
+                );
+            }
+        }
+        drop(db);
+
+        let files_on_disk = fs::read_dir(&opts.dir_path)?
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(FILE_SUFFIX)
+            })
+            .count();
+        assert_eq!(files_on_disk as u32, active_id + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_during_rotation() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_reads_during_rotation".to_string(),
+            512,
+        );
+        on_both_backends(&opts, |db| {
+            let written = std::sync::atomic::AtomicU32::new(0);
+
+            // Readers chase the writer, so most reads hit a recently rotated or
+            // just-published active file
+            thread::scope(|s| {
+                s.spawn(|| {
+                    for i in 0..2000 {
+                        db.put(
+                            Bytes::from(format!("key{}", i)),
+                            Bytes::from(format!("value{}", i)),
+                        )
+                        .unwrap();
+                        written.store(i + 1, Ordering::Release);
+                    }
+                });
+                for _ in 0..2 {
+                    s.spawn(|| loop {
+                        let n = written.load(Ordering::Acquire);
+                        for i in n.saturating_sub(8)..n {
+                            assert_eq!(
+                                db.get(Bytes::from(format!("key{}", i))).unwrap(),
+                                format!("value{}", i).into_bytes()
+                            );
+                        }
+                        if n == 2000 {
+                            break;
+                        }
+                    });
+                }
+            });
+            assert!(db.inactive_files.len() > 10);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_gets_while_keys_move_between_files() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_gets_while_keys_move_between_files".to_string(),
+            1024,
+        );
+        on_both_backends(&opts, |db| {
+            for i in 0..16 {
+                db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+            }
+            let done = std::sync::atomic::AtomicBool::new(false);
+
+            // Every key is repointed again and again while files rotate and
+            // merges run under the readers; a live key must always be found
+            thread::scope(|s| {
+                s.spawn(|| {
+                    // Merges take reserved file ids until the next open
+                    for _ in 0..50 {
+                        db.merge().unwrap();
+                        if done.load(Ordering::Acquire) {
+                            break;
+                        }
+                    }
+                });
+                s.spawn(|| {
+                    for round in 0..200 {
+                        for i in 0..16 {
+                            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))
+                                .unwrap();
+                        }
+                        if round % 10 == 0 {
+                            db.rotate_active_file().unwrap();
+                        }
+                    }
+                    done.store(true, Ordering::Release);
+                });
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        while !done.load(Ordering::Acquire) {
+                            for i in 0..16 {
+                                let key = Bytes::from(format!("key{}", i));
+                                assert_eq!(db.get(key.clone()).unwrap(), "value");
+                            }
+                        }
+                    });
+                }
+            });
+
+            // A file that stays missing is reported once the retries run out
+            let missing = db.data_file_ids().into_iter().max().unwrap() + 1;
+            let mut entry = db.locate(b"key0").unwrap();
+            entry = KeyDirEntry::new(missing, entry.get_offset(), entry.get_size());
+            db.ctx.index.put(b"key0".as_slice().into(), entry);
+            assert!(matches!(
+                db.get(Bytes::from("key0")),
+                Err(Error::FileNotFound(file_id)) if file_id == missing
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_export_single_file() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_export_single_file".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let export_path = Path::new("/tmp/test_export_single_file-export");
+        let _ = fs::remove_dir_all(export_path);
+        let db = Db::open(&opts)?;
+        for round in 0..2 {
+            for i in 0..50 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("value{}-{}", i, round)),
+                )?;
+            }
+        }
+        for i in 0..10 {
+            db.delete(Bytes::from(format!("key{}", i)))?;
+        }
+        assert!(!db.inactive_files.is_empty());
+        db.export_single_file(export_path)?;
+        // The export directory has to be fresh
+        assert!(db.export_single_file(export_path).is_err());
 
-    Ok(())
-}
+        let data_files = fs::read_dir(export_path)?
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(FILE_SUFFIX)
+            })
+            .count();
+        assert_eq!(data_files, 1);
+        assert!(export_path.join(HINT_FILE_NAME).is_file());
 
-impl Drop for Db {
-    fn drop(&mut self) {
-        self.close().expect("failed to close db");
+        let mut export_opts = opts.clone();
+        export_opts.dir_path = export_path.to_path_buf();
+        let exported = Db::open(&export_opts)?;
+        assert_eq!(exported.list_keys()?.len(), 40);
+        for i in 0..50 {
+            let value = exported.get(Bytes::from(format!("key{}", i)));
+            if i < 10 {
+                assert!(value.is_err());
+            } else {
+                assert_eq!(value?, format!("value{}-1", i).into_bytes());
+            }
+        }
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::thread;
-
-    use super::*;
-    use bytes::Bytes;
 
     #[test]
-    fn test_open_db() -> Result<()> {
-        let opts = Opts::new(
+    fn test_sync_dir_after_creating_files() -> Result<()> {
+        let mut opts = Opts::new(
             256,
             1024,
             false,
             true,
-            "/tmp/open_db".to_string(),
-            1024 * 1024,
+            "/tmp/test_sync_dir_after_creating_files".to_string(),
+            128,
         );
-
+        let _ = fs::remove_dir_all(&opts.dir_path);
         let db = Db::open(&opts)?;
+        // The first data file of a new database
+        assert_eq!(take_synced_dirs(), vec![opts.dir_path.clone()]);
 
-        for i in 1..100 {
-            let key = Bytes::from(format!("key{}", i));
-            assert_eq!(
-                db.get(key.clone()).unwrap_err().to_string(),
-                Error::Unsupported("Db read error: Key not found".to_string()).to_string()
-            );
+        // Rotation, both from a full file and by hand
+        for i in 0..10 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
         }
+        let rotations = db.inactive_files.len();
+        assert!(rotations > 0);
+        db.rotate_active_file()?;
+        let synced = take_synced_dirs();
+        assert_eq!(synced.len(), rotations + 1);
+        assert!(synced.iter().all(|dir| *dir == opts.dir_path));
 
-        for i in 101..100000 {
-            let key = Bytes::from(format!("key{}", i));
-            let value = Bytes::from(format!("value{}", i));
-            match db.get(key.clone()) {
-                Ok(read_value) => assert_eq!(value, read_value),
-                Err(e) => {
-                    println!("read error: key: {:?}, error: {:?}", key, e);
-                }
-            }
+        // The merge output, then its install on the next open
+        db.merge()?;
+        let merge_dir = PathBuf::from(format!("{}-merge", opts.dir_path.display()));
+        assert!(take_synced_dirs().contains(&merge_dir));
+        drop(db);
+        let db = Db::open(&opts)?;
+        // Once for the files moved in, once for the files renumbered
+        assert_eq!(
+            take_synced_dirs(),
+            vec![opts.dir_path.clone(), opts.dir_path.clone()]
+        );
+        drop(db);
+
+        // Turned off, or without sync writes, nothing is synced
+        opts.sync_dir = false;
+        let db = Db::open(&opts)?;
+        db.rotate_active_file()?;
+        assert!(take_synced_dirs().is_empty());
+        drop(db);
+        opts.sync_dir = true;
+        opts.sync_writes = false;
+        let db = Db::open(&opts)?;
+        for i in 0..10 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
         }
+        db.rotate_active_file()?;
+        assert!(take_synced_dirs().is_empty());
         Ok(())
     }
 
+    #[cfg(not(unix))]
     #[test]
-    fn test_single_thread_put_and_read() -> Result<()> {
-        let opts = Opts::new(
+    fn test_sync_dir_is_a_no_op() -> Result<()> {
+        sync_dir(Path::new("/tmp/test_sync_dir_missing"))
+    }
+
+    #[test]
+    fn test_lock_file_across_close_and_reopen() -> Result<()> {
+        let mut opts = Opts::new(
             256,
             1024,
             false,
-            true,
-            "/tmp/put_and_read".to_string(),
+            false,
+            "/tmp/test_lock_file_across_close_and_reopen".to_string(),
             1024 * 1024,
         );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let lock_path = opts.dir_path.join(LOCK_FILE_NAME);
+
         let mut db = Db::open(&opts)?;
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+        assert!(lock_path.is_file());
+        assert!(Db::open(&opts).is_err());
+        // Closing twice, then dropping, releases the lock once
+        db.close()?;
+        assert!(!lock_path.exists());
+        db.close()?;
+        let other = Db::open(&opts)?;
+        drop(db);
+        assert!(lock_path.is_file());
+        assert!(Db::open(&opts).is_err());
+        drop(other);
+        assert!(!lock_path.exists());
 
-        for i in 1..100000 {
-            let key = Bytes::from(format!("key{}", i));
-            let value = Bytes::from(format!("value{}", i));
-            match db.put(key.clone(), value.clone()) {
-                Ok(_) => println!("put success: key: {:?}, value: {:?}", key, value),
-                Err(e) => return Err(e),
-            }
-            assert_eq!(db.get(key.clone()).unwrap(), value);
-        }
+        // A lock left behind by a process that is gone
+        fs::write(&lock_path, crate::storage::stale_lock_contents())?;
+        let stale = fs::File::open(&lock_path)?;
+        fs2::FileExt::lock_exclusive(&stale)?;
+        assert!(Db::open(&opts).is_err());
+        opts.lock_policy = crate::LockPolicy::BreakStale;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.get(Bytes::from("key"))?, "value");
         Ok(())
     }
 
     #[test]
-    fn test_concurrent_read() -> anyhow::Result<()> {
+    fn test_open_over_leftover_lock_file() -> Result<()> {
         let opts = Opts::new(
             256,
             1024,
             false,
-            true,
-            "/tmp/concurrent_read".to_string(),
+            false,
+            "/tmp/test_open_over_leftover_lock_file".to_string(),
             1024 * 1024,
         );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let lock_path = opts.dir_path.join(LOCK_FILE_NAME);
         let db = Db::open(&opts)?;
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+        drop(db);
 
-        // Create shared DB reference
-        let db = Arc::new(db);
-        let start = std::time::Instant::now();
-
-        // Spawn multiple reader threads
-        let mut handles = vec![];
-        for i in 1..1000 {
-            let db = db.clone();
-            let key = Bytes::from(format!("key{}", i));
-            let value = Bytes::from(format!("value{}", i));
+        // A crashed process leaves its lock file but not its OS lock, so the
+        // contents don't matter, even naming a live process
+        for contents in [
+            crate::storage::stale_lock_contents(),
+            format!("{}\nlocalhost\n0\n", std::process::id()),
+            "garbage".to_string(),
+        ] {
+            fs::write(&lock_path, contents)?;
+            let db = Db::open(&opts)?;
+            assert_eq!(db.get(Bytes::from("key"))?, "value");
+            assert!(Db::open(&opts).is_err());
+        }
+        Ok(())
+    }
 
-            let handle = thread::spawn(move || match db.get(key.clone()) {
-                Ok(read_value) => {
-                    assert_eq!(read_value, value, "Read value mismatch in thread {}", i)
-                }
-                Err(e) => println!("read error: key: {:?}, error: {:?}", key, e),
-            });
-            handles.push(handle);
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_open_of_read_only_files() -> Result<()> {
+        if skip_as_root("test_read_only_open_of_read_only_files") {
+            return Ok(());
+        }
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_read_only_open_of_read_only_files".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..20 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
         }
+        db.merge()?;
+        drop(db);
+        // Installs the merge, leaving a hint file
+        drop(Db::open(&opts)?);
 
-        // Wait for all reads to complete
-        for handle in handles {
-            handle
-                .join()
-                .map_err(|e| anyhow::anyhow!("Thread panicked: {:?}", e))?;
+        for entry in fs::read_dir(&opts.dir_path)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if name.ends_with(FILE_SUFFIX) || name == HINT_FILE_NAME {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o444))?;
+            }
+        }
+        opts.read_only = true;
+        let db = Db::open(&opts)?;
+        for i in 0..20 {
+            assert_eq!(db.get(Bytes::from(format!("key{}", i)))?, "value");
         }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_open_writes_nothing() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_read_only_open_writes_nothing".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(merge_dir_path(&opts.dir_path)?);
+        opts.read_only = true;
+        let list = |dir: &Path| -> Result<Vec<String>> {
+            let mut names = fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
 
-        let duration = start.elapsed();
-        println!("All concurrent reads completed in {:?}", duration);
+        assert!(matches!(Db::open(&opts), Err(Error::Io(e)) if e.kind() == ErrorKind::NotFound));
+        assert!(!opts.dir_path.exists());
 
+        fs::create_dir_all(&opts.dir_path)?;
+        let db = Db::open(&opts)?;
+        assert!(db.get(Bytes::from("key")).is_err());
+        assert_eq!(list(&opts.dir_path)?, vec![LOCK_FILE_NAME.to_string()]);
+        drop(db);
+        assert!(list(&opts.dir_path)?.is_empty());
+
+        // Nor does it install a finished merge
+        opts.read_only = false;
+        let db = Db::open(&opts)?;
+        for i in 0..20 {
+            db.put(Bytes::from(format!("key{}", i % 5)), Bytes::from("value"))?;
+        }
+        db.merge()?;
+        drop(db);
+        let before = list(&opts.dir_path)?;
+        opts.read_only = true;
+        let db = Db::open(&opts)?;
+        for i in 0..5 {
+            assert_eq!(db.get(Bytes::from(format!("key{}", i)))?, "value");
+        }
+        drop(db);
+        assert_eq!(list(&opts.dir_path)?, before);
+        assert!(merge_dir_path(&opts.dir_path)?
+            .join(MERGE_FINISHED_FILE)
+            .is_file());
         Ok(())
     }
 
     #[test]
-    fn test_delete() -> Result<()> {
-        let opts = Opts::new(
+    fn test_replay_bounds_uncommitted_batches() -> Result<()> {
+        let mut opts = Opts::new(
             256,
             1024,
             false,
-            true,
-            "/tmp/delete".to_string(),
-            1024 * 1024,
+            false,
+            "/tmp/test_replay_bounds_uncommitted_batches".to_string(),
+            64 * 1024 * 1024,
         );
-        let mut db = Db::open(&opts)?;
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.max_buffered_batch_entries = 100;
+        let db = Db::open(&opts)?;
 
-        for i in 1..10000 {
-            let key = Bytes::from(format!("key{}", i));
-            let value = Bytes::from(format!("value{}", i));
-            match db.put(key.clone(), value.clone()) {
-                Ok(_) => println!("put success: key: {:?}, value: {:?}", key, value),
-                Err(e) => return Err(e),
-            }
+        // A batch that crashed before its marker, far larger than the buffer
+        for i in 0..10000 {
+            let key = encode_transaction_key(format!("lost{}", i).into_bytes(), 1000);
+            db.append_entry(&DataEntry::new(key, b"value".to_vec(), State::Active))?;
         }
+        let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: false,
+        })?;
+        batch.put(Bytes::from("batched"), Bytes::from("value"))?;
+        batch.commit()?;
+        db.put(Bytes::from("after"), Bytes::from("value"))?;
 
-        for i in 1..100 {
-            let key = Bytes::from(format!("key{}", i));
-            match db.delete(key.clone()) {
-                Ok(_) => println!("delete success: key: {:?}", key),
-                Err(e) => return Err(e),
-            }
-            assert_eq!(
-                db.get(key.clone()).unwrap_err().to_string(),
-                Error::Unsupported("Db read error: Key not found".to_string()).to_string()
-            );
-        }
+        let file = db.active_file.read().clone();
+        let replay = Db::process_file_handle(&file, &opts)?;
+        assert!(replay.peak_buffered <= 100);
+        assert_eq!(replay.oversized_batch_entries, 10000);
+        drop(db);
 
-        for i in 1..100 {
-            let key = Bytes::from(format!("key{}", i));
-            assert_eq!(
-                db.get(key.clone()).unwrap_err().to_string(),
-                Error::Unsupported("Db read error: Key not found".to_string()).to_string()
-            );
-        }
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().oversized_batch_entries, 10000);
+        assert!(db.get(Bytes::from("lost0")).is_err());
+        assert_eq!(db.get(Bytes::from("batched"))?, "value");
+        assert_eq!(db.get(Bytes::from("after"))?, "value");
         Ok(())
     }
+
+    // Writes `before`, a two-entry batch and its marker, then `after`.
+    // Returns the data file and the marker's position.
+    fn write_batch_fixture(opts: &Opts) -> Result<(PathBuf, KeyDirEntry)> {
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(opts)?;
+        db.put(Bytes::from("before"), Bytes::from("value"))?;
+        for key in ["batched1", "batched2"] {
+            let key = encode_transaction_key(key.as_bytes().to_vec(), 7);
+            db.append_entry(&DataEntry::new(key, b"value".to_vec(), State::Active))?;
+        }
+        let marker = db.append_entry(&DataEntry::new(
+            encode_transaction_key(crate::batch::COMMITTED_KEY.to_vec(), 7),
+            Vec::new(),
+            State::Committed,
+        ))?;
+        db.put(Bytes::from("after"), Bytes::from("value"))?;
+        let path = opts
+            .dir_path
+            .join(format!("{}{}", INITIAL_FILE_ID, FILE_SUFFIX));
+        Ok((path, marker))
+    }
+
     #[test]
-    fn test_sync() -> Result<()> {
-        let opts = Opts::new(256, 1024, false, true, "/tmp/sync".to_string(), 1024 * 1024);
-        let mut db = Db::open(&opts).expect("failed to open engine");
-        println!("db: {:?}", db);
-        let key = Bytes::from("key");
-        let value = Bytes::from("value");
-        db.put(key.clone(), value)?;
+    fn test_replay_crash_points() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_replay_crash_points".to_string(),
+            64 * 1024 * 1024,
+        );
+        let has = |db: &Db, key: &'static str| db.get(Bytes::from(key)).is_ok();
 
-        let close_res = db.sync();
-        assert!(close_res.is_ok());
+        // Intact
+        write_batch_fixture(&opts)?;
+        let db = Db::open(&opts)?;
+        assert!(has(&db, "before") && has(&db, "batched1") && has(&db, "after"));
+        assert_eq!(db.open_report().uncommitted_batch_entries, 0);
+        drop(db);
+
+        // Crash before the marker
+        let (path, marker) = write_batch_fixture(&opts)?;
+        let file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(marker.get_offset())?;
+        let db = Db::open(&opts)?;
+        assert!(has(&db, "before"));
+        assert!(!has(&db, "batched1") && !has(&db, "batched2"));
+        assert_eq!(db.open_report().uncommitted_batch_entries, 2);
+        drop(db);
 
+        // Crash halfway through the marker: the torn tail is cut off
+        let (path, marker) = write_batch_fixture(&opts)?;
+        let file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(marker.get_offset() + marker.get_size() as u64 / 2)?;
+        let db = Db::open(&opts)?;
+        assert!(has(&db, "before"));
+        assert!(!has(&db, "batched1"));
+        assert_eq!(db.open_report().uncommitted_batch_entries, 2);
+        assert_eq!(db.open_report().skipped_corrupt_records, 0);
+        assert_eq!(fs::metadata(&path)?.len(), marker.get_offset());
+        drop(db);
+
+        // Corrupt marker with later writes after it
+        let (path, marker) = write_batch_fixture(&opts)?;
+        let mut bytes = fs::read(&path)?;
+        let crc_end = (marker.get_offset() + marker.get_size() as u64) as usize;
+        bytes[crc_end - 1] ^= 0xff;
+        fs::write(&path, &bytes)?;
+        match Db::open(&opts) {
+            Err(Error::Corrupted { file_id, offset }) => {
+                assert_eq!(file_id, INITIAL_FILE_ID);
+                assert_eq!(offset, marker.get_offset());
+            }
+            other => panic!("expected a corrupt record, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(fs::metadata(&path)?.len(), bytes.len() as u64);
+
+        opts.skip_corrupt_records = true;
+        let db = Db::open(&opts)?;
+        assert!(has(&db, "before") && has(&db, "after"));
+        assert!(!has(&db, "batched1"));
+        assert_eq!(db.open_report().skipped_corrupt_records, 1);
+        assert_eq!(db.open_report().uncommitted_batch_entries, 2);
+        drop(db);
+        assert_eq!(fs::metadata(&path)?.len(), bytes.len() as u64);
         Ok(())
     }
+
     #[test]
-    fn test_close() -> Result<()> {
-        let opts = Opts::new(
+    fn test_corrupt_length_before_intact_records() -> Result<()> {
+        let mut opts = Opts::new(
             256,
             1024,
             false,
-            true,
-            "/tmp/close".to_string(),
-            1024 * 1024,
+            false,
+            "/tmp/test_corrupt_length_before_intact_records".to_string(),
+            64 * 1024 * 1024,
         );
-        let mut db = Db::open(&opts)?;
-
-        let key = Bytes::from("key");
-        let value = Bytes::from("value");
-        db.put(key, value)?;
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..10 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        let corrupt = db.locate(b"key3").unwrap();
+        drop(db);
 
-        let close_res = db.close();
-        assert!(close_res.is_ok());
+        // The value length of one record now runs past where it ends
+        let path = opts
+            .dir_path
+            .join(format!("{}{}", INITIAL_FILE_ID, FILE_SUFFIX));
+        let mut bytes = fs::read(&path)?;
+        bytes[corrupt.get_offset() as usize + 2] = 0x7f;
+        fs::write(&path, &bytes)?;
+        match Db::open(&opts) {
+            Err(Error::Corrupted { offset, .. }) => assert_eq!(offset, corrupt.get_offset()),
+            other => panic!("expected a corrupt record, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(fs::metadata(&path)?.len(), bytes.len() as u64);
 
-        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        // Stepping over it keeps every record after it
+        opts.skip_corrupt_records = true;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.open_report().skipped_corrupt_records, 1);
+        assert!(db.get(Bytes::from("key3")).is_err());
+        for i in (0..10).filter(|i| *i != 3) {
+            assert_eq!(
+                db.get(Bytes::from(format!("key{}", i)))?,
+                format!("value{}", i).as_bytes()
+            );
+        }
+        drop(db);
+        assert_eq!(fs::metadata(&path)?.len(), bytes.len() as u64);
         Ok(())
     }
 
@@ -750,7 +4969,7 @@ mod tests {
             "/tmp/test_back_up".to_string(),
             1024 * 1024,
         );
-        let mut db = Db::open(&opts)?;
+        let db = Db::open(&opts)?;
 
         let key = Bytes::from("key");
         let value = Bytes::from("value");
@@ -762,4 +4981,343 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_back_up_during_writes() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_back_up_during_writes".to_string(),
+            2048,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("before"), Bytes::from("value"))?;
+        let written = std::sync::atomic::AtomicU32::new(0);
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|s| -> Result<()> {
+            s.spawn(|| {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    db.put(
+                        Bytes::from(format!("key{}", i)),
+                        Bytes::from(format!("value{}", i)),
+                    )
+                    .unwrap();
+                    i += 1;
+                    written.store(i, Ordering::Release);
+                    thread::yield_now();
+                }
+            });
+
+            for round in 0..10 {
+                let acknowledged = written.load(Ordering::Acquire);
+                let back_up_path =
+                    PathBuf::from(format!("/tmp/test_back_up_during_writes-{}", round));
+                let _ = fs::remove_dir_all(&back_up_path);
+                let report = db.back_up(&back_up_path)?;
+                assert!(report.files.iter().all(|file| file.name != LOCK_FILE_NAME));
+                if round > 0 {
+                    assert!(report.files.iter().any(|file| file.hard_linked));
+                }
+
+                let sizes = |dir: &Path| -> Vec<(PathBuf, u64)> {
+                    let mut sizes = fs::read_dir(dir)
+                        .unwrap()
+                        .map(|entry| {
+                            let path = entry.unwrap().path();
+                            let len = fs::metadata(&path).unwrap().len();
+                            (path, len)
+                        })
+                        .filter(|(path, _)| path.extension().is_some_and(|ext| ext == "db"))
+                        .collect::<Vec<_>>();
+                    sizes.sort();
+                    sizes
+                };
+                let copied = sizes(&back_up_path);
+
+                let mut backup_opts = opts.clone();
+                backup_opts.dir_path = back_up_path.clone();
+                let backup = Db::open(&backup_opts)?;
+                // Opening didn't have to cut off a torn record
+                assert_eq!(sizes(&back_up_path), copied);
+                assert_eq!(backup.get(Bytes::from("before"))?, "value");
+                for i in 0..acknowledged {
+                    assert_eq!(
+                        backup.get(Bytes::from(format!("key{}", i)))?,
+                        format!("value{}", i).into_bytes()
+                    );
+                }
+                drop(backup);
+                fs::remove_dir_all(&back_up_path)?;
+                thread::sleep(std::time::Duration::from_millis(5));
+            }
+            done.store(true, Ordering::Relaxed);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_values() -> Result<()> {
+        let check = |db: &Db| -> Result<()> {
+            for key in ["put", "batched", "inserted"] {
+                assert_eq!(db.get(Bytes::from(key))?, "");
+            }
+            // Deleted, unlike the empty values
+            assert!(db.get(Bytes::from("deleted")).is_err());
+            assert_eq!(db.list_keys()?.len(), 5);
+            Ok(())
+        };
+        for (inline_value_threshold, file_manifest) in [(0, false), (8, false), (8, true)] {
+            let mut opts = Opts::new(
+                256,
+                1024,
+                false,
+                true,
+                "/tmp/test_empty_values".to_string(),
+                256,
+            );
+            let _ = fs::remove_dir_all(&opts.dir_path);
+            opts.inline_value_threshold = inline_value_threshold;
+            opts.file_manifest = file_manifest;
+            let mut db = Db::open(&opts)?;
+            db.put(Bytes::from("put"), Bytes::new())?;
+            db.put(Bytes::from("deleted"), Bytes::new())?;
+            db.delete(Bytes::from("deleted"))?;
+            let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+                max_batch_num: 10,
+                sync_writes: true,
+            })?;
+            batch.put(Bytes::from("batched"), Bytes::new())?;
+            batch.commit()?;
+            assert_eq!(
+                db.get_or_insert_with(Bytes::from("inserted"), Bytes::new)?,
+                Bytes::new()
+            );
+            assert_eq!(
+                db.get_or_insert_with(Bytes::from("put"), || Bytes::from("x"))?,
+                Bytes::new()
+            );
+            // Push the empty values into inactive files
+            for i in 0..20 {
+                db.put(
+                    Bytes::from(format!("filler{}", i % 2)),
+                    Bytes::from("value"),
+                )?;
+            }
+            check(&db)?;
+            let transaction = db.iter_transactions().next().unwrap();
+            assert_eq!(
+                transaction.get_entries(),
+                &[(b"batched".to_vec(), Some(Vec::new()))]
+            );
+            db.close()?;
+            drop(db);
+
+            // Replayed, from mapped files and from the manifest
+            for _ in 0..2 {
+                let db = Db::open(&opts)?;
+                check(&db)?;
+                if inline_value_threshold > 0 {
+                    let entry = db.ctx.index.get(b"put").unwrap();
+                    assert_eq!(entry.get_inline_value(), Some(b"".as_slice()));
+                }
+                if let Some(slice) = db.get_mmap_slice(b"put")? {
+                    assert!(slice.is_empty());
+                }
+            }
+
+            let db = Db::open(&opts)?;
+            db.merge()?;
+            check(&db)?;
+            drop(db);
+            // Installs the merge, then again from its hint file
+            for _ in 0..2 {
+                check(&Db::open(&opts)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_raw() -> Result<()> {
+        let primary_opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_apply_raw_primary".to_string(),
+            256,
+        );
+        let follower_opts = Opts {
+            dir_path: PathBuf::from("/tmp/test_apply_raw_follower"),
+            inline_value_threshold: 4,
+            ..primary_opts.clone()
+        };
+        let _ = fs::remove_dir_all(&primary_opts.dir_path);
+        let _ = fs::remove_dir_all(&follower_opts.dir_path);
+        let primary = Db::open(&primary_opts)?;
+        for i in 0..20 {
+            primary.put(Bytes::from(format!("key{}", i)), Bytes::from(i.to_string()))?;
+        }
+        primary.delete(Bytes::from("key3"))?;
+        primary.put_all(&[
+            (Bytes::from("key4"), Bytes::from("batch")),
+            (Bytes::from("new"), Bytes::from("batch")),
+        ])?;
+
+        // Ship every record of the primary's files as it is
+        let follower = Db::open(&follower_opts)?;
+        for file_id in primary.data_file_ids() {
+            let file = primary.data_file(file_id)?;
+            let mut offset = 0;
+            while offset < file.get_offset() {
+                let mut record = vec![0; file.record_len_at(offset)?];
+                file.read(&mut record, offset)?;
+                let applied = follower.apply_raw(file_id, &record)?;
+                assert_eq!(
+                    (applied.get_file_id(), applied.get_offset()),
+                    (file_id, offset)
+                );
+                offset += record.len() as u64;
+            }
+        }
+        assert!(primary.data_file_ids().len() > 1);
+        assert_eq!(follower.data_file_ids(), primary.data_file_ids());
+        assert!(follower.apply_raw(0, &[]).is_err());
+
+        let check = |db: &Db| -> Result<()> {
+            let mut keys = db.list_keys()?;
+            keys.sort();
+            let mut expected = primary.list_keys()?;
+            expected.sort();
+            assert_eq!(keys, expected);
+            for key in keys {
+                assert_eq!(db.get(key.clone())?, primary.get(key)?);
+            }
+            Ok(())
+        };
+        check(&follower)?;
+        assert!(follower
+            .locate(b"key1")
+            .unwrap()
+            .get_inline_value()
+            .is_some());
+        assert_eq!(follower.get(Bytes::from("new"))?, "batch");
+        // Records go to the file they were written to on the primary
+        let record = DataEntry::new(
+            encode_transaction_key(b"late".to_vec(), NON_COMMITTED),
+            b"late".to_vec(),
+            State::Active,
+        )
+        .encode()?;
+        assert!(follower.apply_raw(0, &record).is_err());
+        drop(follower);
+
+        check(&Db::open(&follower_opts)?)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    mod tracing_spans {
+        use super::*;
+        use std::fmt::Debug;
+        use std::sync::atomic::AtomicU64;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        // Records every span as its name and fields, and every event's
+        // message
+        #[derive(Default)]
+        struct Collector {
+            next_id: AtomicU64,
+            spans: Mutex<Vec<String>>,
+            events: Mutex<Vec<String>>,
+        }
+
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                self.0.push_str(&format!(" {}={:?}", field.name(), value));
+            }
+        }
+
+        impl Subscriber for &'static Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields(span.metadata().name().to_string());
+                span.record(&mut fields);
+                self.spans.lock().push(fields.0);
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(String::new());
+                event.record(&mut fields);
+                self.events.lock().push(fields.0);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        #[test]
+        fn test_spans() -> Result<()> {
+            let collector: &'static Collector = Box::leak(Box::default());
+            let opts = Opts::new(
+                256,
+                1024,
+                false,
+                false,
+                "/tmp/test_tracing_spans".to_string(),
+                128,
+            );
+            let _ = fs::remove_dir_all(&opts.dir_path);
+            tracing::subscriber::with_default(collector, || -> Result<()> {
+                let db = Db::open(&opts)?;
+                for i in 0..10 {
+                    db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+                }
+                db.get(Bytes::from("key0"))?;
+                db.put_all(&[(Bytes::from("a"), Bytes::from("1"))])?;
+                db.merge()?;
+                drop(db);
+                Db::open(&opts)?;
+                Ok(())
+            })?;
+
+            let spans = collector.spans.lock();
+            let has_span = |prefix: &str| spans.iter().any(|span| span.starts_with(prefix));
+            assert!(has_span("open dir=/tmp/test_tracing_spans"));
+            assert!(has_span("append file_id=0 offset=0 size="));
+            assert!(has_span("read_record file_id=0 offset=0 size="));
+            assert!(has_span("batch_commit seq=1 entries=1"));
+            assert!(has_span("merge"));
+            assert!(has_span("load_hint_file"));
+            assert!(has_span("replay_file file_id=0"));
+
+            let events = collector.events.lock();
+            let has_event = |part: &str| events.iter().any(|event| event.contains(part));
+            assert!(has_event("message=rotated from=0 to=1"));
+            assert!(has_event("message=merging file file_id=0"));
+            assert!(has_event("message=merged files="));
+            assert!(has_event("message=replayed records="));
+            assert!(has_event("message=loaded hint file entries=11"));
+            Ok(())
+        }
+    }
 }
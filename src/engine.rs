@@ -0,0 +1,294 @@
+use crate::db::Db;
+use crate::index::{IndexIterator, Indexer};
+use crate::{Error, Result, Stat};
+use bytes::Bytes;
+use dashmap::DashMap;
+
+/// One write of a `KvEngine::batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Bytes, Bytes),
+    Delete(Bytes),
+}
+
+/// The operations a key-value store has to offer to stand in for zap, so
+/// code embedding it can be written against the trait and run against
+/// `MemEngine` in tests.
+///
+/// `Error` takes zap's own errors, so an engine wrapping a `Db` can report
+/// them in the embedding system's error type.
+pub trait KvEngine {
+    type Error: std::error::Error + From<Error> + Send + Sync + 'static;
+
+    /// The value stored under `key`, `None` if there is none.
+    fn get(&self, key: Bytes) -> std::result::Result<Option<Bytes>, Self::Error>;
+
+    fn put(&self, key: Bytes, value: Bytes) -> std::result::Result<(), Self::Error>;
+
+    /// Deleting a key that isn't there is not an error.
+    fn delete(&self, key: Bytes) -> std::result::Result<(), Self::Error>;
+
+    /// The pairs whose keys start with `prefix`, sorted by key.
+    fn scan_prefix(&self, prefix: &[u8]) -> std::result::Result<Vec<(Bytes, Bytes)>, Self::Error>;
+
+    /// Applies `ops` all together or not at all. Where a key is written
+    /// more than once the last write wins.
+    fn batch(&self, ops: Vec<BatchOp>) -> std::result::Result<(), Self::Error>;
+
+    /// Waits until every write made so far is durable.
+    fn flush(&self) -> std::result::Result<(), Self::Error>;
+
+    fn stat(&self) -> std::result::Result<Stat, Self::Error>;
+}
+
+impl KvEngine for Db {
+    type Error = Error;
+
+    fn get(&self, key: Bytes) -> Result<Option<Bytes>> {
+        Ok(self.get_seq(key)?.map(|(value, _)| value))
+    }
+
+    fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        Db::put(self, key, value)
+    }
+
+    fn delete(&self, key: Bytes) -> Result<()> {
+        Db::delete(self, key)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Bytes, Bytes)>> {
+        let mut iter = self.ctx.index.iter();
+        iter.seek(prefix);
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            keys.push(Bytes::copy_from_slice(key));
+        }
+        drop(iter);
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            // Deleted since the index was scanned
+            if let Some((value, _)) = self.get_seq(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let batch = self.new_write_batch(crate::batch::WriteBatchOptions {
+            max_batch_num: ops.len(),
+            sync_writes: self.ctx.opts.sync_writes,
+        })?;
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    self.counters.count_put();
+                    self.validate_put(&key, &value)?;
+                    batch.put(key, value)?;
+                }
+                BatchOp::Delete(key) => {
+                    self.counters.count_delete();
+                    batch.delete(key)?;
+                }
+            }
+        }
+        batch.commit()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sync()
+    }
+
+    fn stat(&self) -> Result<Stat> {
+        Db::stat(self)
+    }
+}
+
+/// An in-memory `KvEngine` over a `DashMap`, for tests of code written
+/// against the trait and as a model to check `Db` against.
+///
+/// Nothing is persisted and `stat` only counts keys. A batch isn't isolated
+/// from readers on other threads, which may see part of it.
+#[derive(Debug, Default)]
+pub struct MemEngine {
+    map: DashMap<Bytes, Bytes>,
+}
+
+impl MemEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvEngine for MemEngine {
+    type Error = Error;
+
+    fn get(&self, key: Bytes) -> Result<Option<Bytes>> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        Ok(self.map.get(&key).map(|value| value.clone()))
+    }
+
+    fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&self, key: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        self.map.remove(&key);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Bytes, Bytes)>> {
+        let mut pairs = self
+            .map
+            .iter()
+            .filter(|pair| pair.key().starts_with(prefix))
+            .map(|pair| (pair.key().clone(), pair.value().clone()))
+            .collect::<Vec<_>>();
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let valid = ops.iter().all(|op| match op {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) => !key.is_empty(),
+        });
+        if !valid {
+            return Err(Error::EmptyKey);
+        }
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => self.put(key, value)?,
+                BatchOp::Delete(key) => self.delete(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stat(&self) -> Result<Stat> {
+        Ok(Stat {
+            key_num: self.map.len(),
+            ..Stat::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Checks `Db` against `MemEngine` over random operations on a small key
+    // space, reopening the database now and then so replay is covered too
+    #[test]
+    fn test_differential_against_mem_engine() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_engine_differential".to_string(),
+            4096,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+        let model = MemEngine::new();
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let random_key = |rng: &mut StdRng| {
+            let key = format!(
+                "{}{}",
+                ["a", "ab", "b"][rng.gen_range(0..3)],
+                rng.gen_range(0..20)
+            );
+            Bytes::from(key)
+        };
+
+        for step in 0..5000 {
+            match rng.gen_range(0..100) {
+                0..=29 => {
+                    let key = random_key(&mut rng);
+                    assert_eq!(
+                        KvEngine::get(&db, key.clone())?,
+                        model.get(key)?,
+                        "step {}",
+                        step
+                    );
+                }
+                30..=59 => {
+                    let key = random_key(&mut rng);
+                    let value = Bytes::from(format!("value{}", step));
+                    KvEngine::put(&db, key.clone(), value.clone())?;
+                    model.put(key, value)?;
+                }
+                60..=74 => {
+                    let key = random_key(&mut rng);
+                    KvEngine::delete(&db, key.clone())?;
+                    model.delete(key)?;
+                }
+                75..=84 => {
+                    let prefix = ["", "a", "ab", "b1"][rng.gen_range(0..4)].as_bytes();
+                    assert_eq!(
+                        db.scan_prefix(prefix)?,
+                        model.scan_prefix(prefix)?,
+                        "step {}",
+                        step
+                    );
+                }
+                85..=96 => {
+                    let ops = (0..rng.gen_range(1..6))
+                        .map(|i| {
+                            let key = random_key(&mut rng);
+                            if rng.gen_bool(0.7) {
+                                BatchOp::Put(key, Bytes::from(format!("batch{}-{}", step, i)))
+                            } else {
+                                BatchOp::Delete(key)
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    db.batch(ops.clone())?;
+                    model.batch(ops)?;
+                }
+                97 => {
+                    db.flush()?;
+                    model.flush()?;
+                }
+                _ => {
+                    db.close()?;
+                    db = Db::open(&opts)?;
+                }
+            }
+            assert_eq!(KvEngine::stat(&db)?.key_num, model.stat()?.key_num);
+        }
+        assert!(db.data_file_ids().len() > 1);
+        assert_eq!(db.scan_prefix(b"")?, model.scan_prefix(b"")?);
+
+        // Both refuse a batch with an empty key and apply none of it
+        let ops = vec![
+            BatchOp::Put(Bytes::from("fresh"), Bytes::from("value")),
+            BatchOp::Put(Bytes::new(), Bytes::from("value")),
+        ];
+        assert!(matches!(db.batch(ops.clone()), Err(Error::EmptyKey)));
+        assert!(matches!(model.batch(ops), Err(Error::EmptyKey)));
+        assert_eq!(KvEngine::get(&db, Bytes::from("fresh"))?, None);
+        assert_eq!(model.get(Bytes::from("fresh"))?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+//! Cache mode: keeping the data files under `Opts::max_db_size`.
+//!
+//! A merge only reclaims space once it's installed on the next open, so
+//! eviction drops whole files instead, oldest first, on a thread of its own
+//! that writes going over the cap wake. Before a file goes, the
+//! live keys in it that were read since they were last written are copied
+//! forward to the active file and get a second chance; the rest are evicted
+//! with the file. Nothing older of a key is left in its shard to replay once
+//! its oldest file is gone, so evicted keys need no tombstones. Merge output
+//! waiting to be installed still holds them, so it's discarded on the first
+//! eviction, as if that merge had failed.
+
+use crate::batch::{decode_transaction_key, encode_transaction_key};
+use crate::db::{sync_dir, Db, NON_COMMITTED};
+use crate::index::Indexer;
+use crate::merge::merge_dir_path;
+use crate::shard::{shard_of, SHARD_FILE_IDS};
+use crate::{Result, State};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::fs;
+use std::sync::{mpsc, Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Default)]
+struct EvictState {
+    // A write went over the cap since the last pass began
+    pending: bool,
+    running: bool,
+    shutdown: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<EvictState>,
+    // Wakes the evictor thread
+    work: Condvar,
+    // Wakes `settle` once a pass is over
+    #[cfg(test)]
+    done: Condvar,
+}
+
+/// Evicts under `Opts::max_db_size` in the background, so that writes going
+/// over the cap only wake it rather than wait for files to be evicted.
+///
+/// The thread holds a weak reference to the database, which has to be shared
+/// for it to be started, see `Db::start_evictor`.
+#[derive(Debug, Default)]
+pub(crate) struct Evictor {
+    shared: Arc<Shared>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Evictor {
+    fn wake(&self) {
+        let mut state = self.shared.state.lock();
+        state.pending = true;
+        self.shared.work.notify_one();
+    }
+
+    /// Stops the thread once the pass under way, if any, is over.
+    pub(crate) fn stop(&self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.work.notify_one();
+        if let Some(worker) = self.worker.lock().take() {
+            // The database is dropped on the evictor thread if a pass held
+            // the last reference to it
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
+        }
+    }
+
+    // Waits for the passes woken so far to be over
+    #[cfg(test)]
+    fn settle(&self) {
+        let mut state = self.shared.state.lock();
+        while state.pending || state.running {
+            self.shared.done.wait(&mut state);
+        }
+    }
+}
+
+impl Drop for Evictor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(db: &Weak<Db>, shared: &Shared) {
+    let mut state = shared.state.lock();
+    while !state.shutdown {
+        if !state.pending {
+            shared.work.wait(&mut state);
+            continue;
+        }
+        state.pending = false;
+        state.running = true;
+        let dropped = MutexGuard::unlocked(&mut state, || {
+            let Some(db) = db.upgrade() else {
+                return true;
+            };
+            // The next write over the cap wakes the thread to try again
+            if let Err(_e) = db.evict() {
+                trace_event!(error = %_e, "eviction failed");
+            }
+            false
+        });
+        state.running = false;
+        #[cfg(test)]
+        shared.done.notify_all();
+        if dropped {
+            return;
+        }
+    }
+}
+
+impl Db {
+    /// A receiver of the keys evicted under `Opts::max_db_size` from now
+    /// on. Dropping it unsubscribes.
+    pub fn subscribe_evictions(&self) -> mpsc::Receiver<Bytes> {
+        let (sender, receiver) = mpsc::channel();
+        self.eviction_listeners.lock().push(sender);
+        receiver
+    }
+
+    /// Starts evicting under `Opts::max_db_size` on a thread of its own,
+    /// which writes that take the database over the cap wake from now on.
+    /// The thread stops when the database is closed or dropped. Does nothing
+    /// without a cap, or if it's started already.
+    pub fn start_evictor(self: &Arc<Self>) -> Result<()> {
+        if self.ctx.opts.max_db_size.is_none() {
+            return Ok(());
+        }
+        let mut worker = self.evictor.worker.lock();
+        if worker.is_some() || self.evictor.shared.state.lock().shutdown {
+            return Ok(());
+        }
+        let db = Arc::downgrade(self);
+        let shared = self.evictor.shared.clone();
+        *worker = Some(
+            thread::Builder::new()
+                .name("zap-evict".to_string())
+                .spawn(move || run(&db, &shared))?,
+        );
+        drop(worker);
+        // Writes may have gone over the cap before it started
+        self.wake_evictor();
+        Ok(())
+    }
+
+    /// Evicts keys until the data files fit under `Opts::max_db_size`,
+    /// returning how many were evicted. The thread of `start_evictor` runs
+    /// it when writes take the database over the cap; without it, or after
+    /// lowering the cap, it has to be called. Does nothing without a cap, or while a merge runs, as the merge
+    /// would bring evicted keys back; the output of a finished merge not
+    /// installed yet is discarded for the same reason.
+    pub fn evict(&self) -> Result<usize> {
+        let Some(max_db_size) = self.ctx.opts.max_db_size else {
+            return Ok(0);
+        };
+        let Some(_merge_guard) = self.merge_lock.try_lock() else {
+            return Ok(0);
+        };
+        let _eviction_guard = self.eviction_lock.lock();
+        let mut evicted = 0;
+        while self.data_size() > max_db_size {
+            let Some(file_id) = self.oldest_inactive_file() else {
+                break;
+            };
+            self.discard_merge_output()?;
+            evicted += self.evict_file(file_id)?;
+        }
+        Ok(evicted)
+    }
+
+    // Wakes the evictor thread after a write if the write took the
+    // database over the cap
+    pub(crate) fn wake_evictor(&self) {
+        let Some(max_db_size) = self.ctx.opts.max_db_size else {
+            return;
+        };
+        if self.data_size() > max_db_size {
+            self.evictor.wake();
+        }
+    }
+
+    // Bytes written to the data files
+    pub(crate) fn data_size(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.active_file_size())
+            .sum::<u64>()
+            + self
+                .inactive_files
+                .iter()
+                .map(|file| file.get_offset())
+                .sum::<u64>()
+    }
+
+    // The inactive file written to longest ago: the shards number their
+    // files alike, so the one furthest into its shard's ids is newest
+    fn oldest_inactive_file(&self) -> Option<u32> {
+        self.inactive_files
+            .iter()
+            .map(|file| file.get_file_id())
+            .min_by_key(|file_id| (file_id % SHARD_FILE_IDS, *file_id))
+    }
+
+    // Removes the output of a finished merge waiting for the next open. The
+    // files it would replace stay, under the ids they have.
+    fn discard_merge_output(&self) -> Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        let merge_dir = merge_dir_path(&self.ctx.opts.dir_path)?;
+        if merge_dir.is_dir() {
+            trace_event!("discarding merge output");
+            fs::remove_dir_all(merge_dir)?;
+        }
+        Ok(())
+    }
+
+    // Copies the read keys of inactive file `file_id` forward, then deletes
+    // it with the rest. Returns how many keys were evicted.
+    fn evict_file(&self, file_id: u32) -> Result<usize> {
+        trace_span!("evict_file", file_id);
+        // Every write appended so far has to be in the index to tell which
+        // entries are live, and none may land while they're moved
+        let commit_lock = self.batch_commit_lock.write();
+        self.check_open()?;
+        self.index_sequencer.wait_all();
+        let Some(file) = self.inactive_files.get(&file_id).map(|file| file.clone()) else {
+            return Ok(0);
+        };
+        let shard_index = shard_of(file_id, self.shards.len());
+        let shard = &self.shards[shard_index];
+
+        let mut evicted = Vec::new();
+        // Index updates for the hint log
+        let mut logged = Vec::new();
+        let mut offset = 0;
+        while offset < file.get_offset() {
+            let (mut entry, size) = file.extract_stored_entry(offset)?;
+            let record_offset = offset;
+            offset += size as u64;
+            if entry.get_state() != State::Active {
+                continue;
+            }
+            let (key, _) = decode_transaction_key(entry.get_key().clone());
+            let Some(keydir_entry) = self.ctx.index.get(&key) else {
+                continue;
+            };
+            if (keydir_entry.get_file_id(), keydir_entry.get_offset()) != (file_id, record_offset) {
+                continue;
+            }
+            if keydir_entry.is_referenced() {
+                entry.set_key(encode_transaction_key(key.clone(), NON_COMMITTED));
+                let mut write_guard = shard.active_file.write();
+                let mut moved = self.append_locked(shard, &mut write_guard, &entry)?;
+                if let Some(value) = keydir_entry.get_inline_value() {
+                    moved.set_inline_value(value);
+                }
+                moved.track_references();
+                logged.push((key.clone(), Some(moved.clone())));
+                self.ctx.index.put(key.into(), moved);
+            } else {
+                self.ctx.index.delete(&key);
+                self.secondary_indexes.remove(&key);
+                logged.push((key.clone(), None));
+                evicted.push(Bytes::from(key));
+            }
+        }
+        if let Some(hint_log) = &self.hint_log {
+            let active_file = shard.active_file.read();
+            hint_log.append(
+                &logged,
+                &[(active_file.get_file_id(), active_file.get_offset())],
+            );
+        }
+
+        // The copies have to be durable before the file they were copied
+        // from goes
+        shard.active_file.write().sync()?;
+        self.inactive_files.remove(&file_id);
+        drop(commit_lock);
+        self.remove_data_file(file_id)?;
+        if self.ctx.opts.should_sync_dir() {
+            sync_dir(&self.ctx.opts.dir_path)?;
+        }
+
+        trace_event!(keys = evicted.len(), "evicted file");
+        let count = evicted.len();
+        let mut listeners = self.eviction_listeners.lock();
+        for key in evicted {
+            listeners.retain(|listener| listener.send(key.clone()).is_ok());
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Opts};
+
+    fn cache_opts(name: &str) -> Opts {
+        let mut opts = Opts::new(256, 1024, false, false, format!("/tmp/{}", name), 4096);
+        opts.max_db_size = Some(32 * 1024);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts
+    }
+
+    #[test]
+    fn test_cache_stays_under_cap() -> Result<()> {
+        let opts = cache_opts("test_cache_stays_under_cap");
+        let db = Arc::new(Db::open(&opts)?);
+        db.start_evictor()?;
+        let evictions = db.subscribe_evictions();
+        let value = Bytes::from(vec![b'v'; 100]);
+        for i in 0..10 {
+            db.put(Bytes::from(format!("hot{}", i)), value.clone())?;
+        }
+        for i in 0..2000 {
+            db.put(Bytes::from(format!("cold{}", i)), value.clone())?;
+            db.evictor.settle();
+            for j in 0..10 {
+                assert_eq!(db.get(Bytes::from(format!("hot{}", j)))?, value);
+            }
+            assert!(db.data_size() <= opts.max_db_size.unwrap());
+        }
+
+        let evicted = evictions.try_iter().collect::<Vec<_>>();
+        assert!(evicted.len() > 1000);
+        assert!(evicted.iter().all(|key| key.starts_with(b"cold")));
+        assert_eq!(db.stat()?.key_num, 2010 - evicted.len());
+        assert!(db.get(Bytes::from("cold0")).is_err());
+        assert_eq!(db.get(Bytes::from("cold1999"))?, value);
+        drop(db);
+
+        // Evicted keys stay gone
+        let db = Db::open(&opts)?;
+        assert_eq!(db.stat()?.key_num, 2010 - evicted.len());
+        for i in 0..10 {
+            assert_eq!(db.get(Bytes::from(format!("hot{}", i)))?, value);
+        }
+        assert!(db.get(Bytes::from("cold0")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eviction_discards_merge_output() -> Result<()> {
+        let opts = cache_opts("test_eviction_discards_merge_output");
+        let db = Arc::new(Db::open(&opts)?);
+        db.start_evictor()?;
+        let evictions = db.subscribe_evictions();
+        let value = Bytes::from(vec![b'v'; 100]);
+        for i in 0..150 {
+            db.put(Bytes::from(format!("old{}", i)), value.clone())?;
+        }
+        db.merge()?;
+        for i in 0..400 {
+            db.put(Bytes::from(format!("new{}", i)), value.clone())?;
+        }
+        db.evictor.settle();
+        let evicted = evictions.try_iter().collect::<Vec<_>>();
+        assert!(evicted.contains(&Bytes::from("old0")));
+        let key_num = db.stat()?.key_num;
+        assert_eq!(key_num, 550 - evicted.len());
+        drop(db);
+
+        // The merged copies of evicted keys aren't installed
+        let db = Db::open(&opts)?;
+        assert_eq!(db.stat()?.key_num, key_num);
+        assert!(db.get(Bytes::from("old0")).is_err());
+        assert_eq!(db.get(Bytes::from("new399"))?, value);
+        assert!(db.data_size() <= opts.max_db_size.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_without_evictor() -> Result<()> {
+        let opts = cache_opts("test_evict_without_evictor");
+        let db = Db::open(&opts)?;
+        let value = Bytes::from(vec![b'v'; 100]);
+        for i in 0..400 {
+            db.put(Bytes::from(format!("key{}", i)), value.clone())?;
+        }
+        assert!(db.data_size() > opts.max_db_size.unwrap());
+
+        let evicted = db.evict()?;
+        assert!(evicted > 0);
+        assert!(db.data_size() <= opts.max_db_size.unwrap());
+        assert_eq!(db.stat()?.key_num, 400 - evicted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_cap_limits() -> Result<()> {
+        let mut opts = cache_opts("test_cache_cap_limits");
+        opts.max_value_size = 64 * 1024;
+        let db = Db::open(&opts)?;
+        let too_large = Bytes::from(vec![0; 33 * 1024]);
+        assert!(matches!(
+            db.put(Bytes::from("key"), too_large),
+            Err(Error::Unsupported(_))
+        ));
+        drop(db);
+
+        // The active file alone has to fit
+        opts.max_db_size = Some(4096);
+        assert!(Db::open(&opts).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! The metrics of a shared `Db` as a Prometheus collector, for the embedding
+//! application to register with the registry it serves.
+//!
+//! Values are read when the registry is gathered: the counters of
+//! `Db::metrics`, as `zap_*_total` counters and the `zap_get_latency_seconds`
+//! histogram, and gauges from `Db::stat`. Rates such as puts per second or
+//! the inline read ratio are left to queries. `Db::stat` walks the index, so
+//! a scrape costs time in proportion to the number of keys.
+
+use crate::db::Db;
+use crate::metrics::GET_LATENCY_BUCKETS_US;
+use crate::{Error, LatencyHistogram, Result};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, MetricFamily, MetricType};
+use prometheus::Registry;
+use std::sync::Arc;
+
+// Name and help of each family, in the order `DbCollector::samples` reads
+// them
+const FAMILIES: [(&str, &str); 14] = [
+    ("zap_puts_total", "Puts, batch writes included."),
+    ("zap_gets_total", "Gets, misses included."),
+    ("zap_deletes_total", "Deletes, of missing keys included."),
+    (
+        "zap_inline_reads_total",
+        "Gets answered from a value inlined in the index.",
+    ),
+    ("zap_bytes_written_total", "Bytes appended to data files."),
+    (
+        "zap_bytes_read_total",
+        "Bytes of records read from data files.",
+    ),
+    ("zap_rotations_total", "Active files retired for new ones."),
+    ("zap_merges_total", "Merges completed."),
+    ("zap_get_latency_seconds", "How long gets took."),
+    ("zap_active_file_bytes", "Bytes in the active files."),
+    ("zap_disk_bytes", "Bytes in all data files."),
+    (
+        "zap_reclaimable_bytes",
+        "Bytes of data files a merge would drop.",
+    ),
+    ("zap_keys", "Live keys."),
+    (
+        "zap_merge_recommended",
+        "1 once the reclaimable share reaches the merge threshold.",
+    ),
+];
+
+enum Sample {
+    Counter(u64),
+    Gauge(u64),
+    Histogram(LatencyHistogram),
+}
+
+/// Collects the metrics of one database on every gather.
+#[derive(Debug)]
+pub struct DbCollector {
+    db: Arc<Db>,
+    descs: Vec<Desc>,
+}
+
+impl DbCollector {
+    pub fn new(db: Arc<Db>) -> Result<Self> {
+        let descs = FAMILIES
+            .iter()
+            .map(|(name, help)| {
+                Desc::new(
+                    name.to_string(),
+                    help.to_string(),
+                    Vec::new(),
+                    Default::default(),
+                )
+            })
+            .collect::<prometheus::Result<Vec<_>>>()
+            .map_err(|e| Error::Unsupported(e.to_string()))?;
+        Ok(DbCollector { db, descs })
+    }
+
+    /// Registers a collector for `db` with `registry`.
+    pub fn register(db: Arc<Db>, registry: &Registry) -> Result<()> {
+        registry
+            .register(Box::new(DbCollector::new(db)?))
+            .map_err(|e| Error::Unsupported(e.to_string()))
+    }
+
+    fn samples(&self) -> [Sample; FAMILIES.len()] {
+        let metrics = self.db.metrics();
+        let stat = self.db.stat().unwrap_or_default();
+        [
+            Sample::Counter(metrics.puts),
+            Sample::Counter(metrics.gets),
+            Sample::Counter(metrics.deletes),
+            Sample::Counter(metrics.inline_reads),
+            Sample::Counter(metrics.bytes_written),
+            Sample::Counter(metrics.bytes_read),
+            Sample::Counter(metrics.rotations),
+            Sample::Counter(metrics.merges),
+            Sample::Histogram(metrics.get_latency),
+            Sample::Gauge(metrics.active_file_bytes),
+            Sample::Gauge(stat.disk_size),
+            Sample::Gauge(stat.reclaimable_bytes),
+            Sample::Gauge(stat.key_num as u64),
+            Sample::Gauge(stat.merge_recommended as u64),
+        ]
+    }
+}
+
+impl Collector for DbCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        FAMILIES
+            .iter()
+            .zip(self.samples())
+            .map(|((name, help), sample)| family(name, help, sample))
+            .collect()
+    }
+}
+
+fn family(name: &str, help: &str, sample: Sample) -> MetricFamily {
+    let mut metric = proto::Metric::default();
+    let metric_type = match sample {
+        Sample::Counter(value) => {
+            let mut counter = proto::Counter::default();
+            counter.set_value(value as f64);
+            metric.set_counter(counter);
+            MetricType::COUNTER
+        }
+        Sample::Gauge(value) => {
+            let mut gauge = proto::Gauge::default();
+            gauge.set_value(value as f64);
+            metric.set_gauge(gauge);
+            MetricType::GAUGE
+        }
+        Sample::Histogram(latency) => {
+            metric.set_histogram(histogram(&latency));
+            MetricType::HISTOGRAM
+        }
+    };
+    let mut family = MetricFamily::default();
+    family.set_name(name.to_string());
+    family.set_help(help.to_string());
+    family.set_field_type(metric_type);
+    family.mut_metric().push(metric);
+    family
+}
+
+// Prometheus buckets count everything up to their bound, in seconds
+fn histogram(latency: &LatencyHistogram) -> proto::Histogram {
+    let mut histogram = proto::Histogram::default();
+    let mut cumulative = 0;
+    for (bound, count) in GET_LATENCY_BUCKETS_US.iter().zip(&latency.buckets) {
+        cumulative += count;
+        let mut bucket = proto::Bucket::default();
+        bucket.set_upper_bound(*bound as f64 / 1e6);
+        bucket.set_cumulative_count(cumulative);
+        histogram.mut_bucket().push(bucket);
+    }
+    histogram.set_sample_count(latency.count());
+    histogram.set_sample_sum(latency.sum_us as f64 / 1e6);
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use bytes::Bytes;
+    use prometheus::{Encoder, TextEncoder};
+    use std::fs;
+
+    fn render(registry: &Registry) -> String {
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // The value of the sample line starting with `series`
+    fn value(text: &str, series: &str) -> f64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {} in\n{}", series, text))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_exposition() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_exporter".to_string(),
+            64,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Arc::new(Db::open(&opts)?);
+        let registry = Registry::new();
+        DbCollector::register(db.clone(), &registry)?;
+
+        let text = render(&registry);
+        assert!(text.contains("# HELP zap_puts_total Puts, batch writes included.\n"));
+        assert!(text.contains("# TYPE zap_get_latency_seconds histogram\n"));
+        assert!(text.contains("# TYPE zap_keys gauge\n"));
+        assert_eq!(value(&text, "zap_puts_total"), 0.0);
+
+        for i in 0..10 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        db.put(Bytes::from("key0"), Bytes::from("again"))?;
+        for i in 0..3 {
+            db.get(Bytes::from(format!("key{}", i)))?;
+        }
+        db.delete(Bytes::from("key9"))?;
+
+        let text = render(&registry);
+        assert_eq!(value(&text, "zap_puts_total"), 11.0);
+        assert_eq!(value(&text, "zap_gets_total"), 3.0);
+        assert_eq!(value(&text, "zap_deletes_total"), 1.0);
+        assert_eq!(value(&text, "zap_keys"), 9.0);
+        assert!(value(&text, "zap_rotations_total") > 0.0);
+        assert!(value(&text, "zap_reclaimable_bytes") > 0.0);
+        assert_eq!(
+            value(&text, "zap_bytes_written_total"),
+            value(&text, "zap_disk_bytes")
+        );
+        assert_eq!(value(&text, "zap_get_latency_seconds_count"), 3.0);
+        assert_eq!(
+            value(&text, "zap_get_latency_seconds_bucket{le=\"+Inf\"}"),
+            3.0
+        );
+        assert!(text.contains("zap_get_latency_seconds_bucket{le=\"0.00001\"}"));
+
+        // A second collector for the same names is refused
+        assert!(DbCollector::register(db, &registry).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+//! Named hooks at the points where the order of writes decides what survives
+//! a crash, for testing recovery from outside the crate.
+//!
+//! A hook is armed for one database directory, so tests running side by side
+//! don't trip each other's hooks. Without the `failpoints` feature the hooks
+//! compile to nothing.
+
+use crate::db::copy_recursive;
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A record has been appended to an active file but not yet fsynced.
+pub const APPEND_BEFORE_SYNC: &str = "append-before-sync";
+/// A write is durable but not yet in the index.
+pub const SYNC_BEFORE_INDEX_UPDATE: &str = "sync-before-index-update";
+/// A batch's entries have been appended but not its commit markers.
+pub const BATCH_BEFORE_MARKER: &str = "batch-before-marker";
+/// A batch's commit markers have been appended but not yet fsynced.
+pub const BATCH_AFTER_MARKER: &str = "batch-after-marker";
+/// A merge has written and synced its output but not its finished record.
+pub const MERGE_BEFORE_FINISHED: &str = "merge-before-finished";
+
+/// What an armed hook does when it is reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailAction {
+    /// Panics, abandoning the operation midway as a crash would. At
+    /// `SYNC_BEFORE_INDEX_UPDATE` it leaves later writes waiting for the
+    /// abandoned one, so only the database's files are of use afterwards.
+    Panic,
+    /// Fails the operation with an IO error.
+    Error,
+    /// Copies the database directory to the given path, replacing what's
+    /// there, and carries on. Opening the copy is opening the database as a
+    /// crash at that point would have left it.
+    Snapshot(PathBuf),
+}
+
+static HOOKS: Mutex<Vec<(PathBuf, &'static str, FailAction)>> = Mutex::new(Vec::new());
+
+/// Arms the hook `name` for the database in `dir_path`. It stays armed until
+/// `remove` or `clear`.
+pub fn configure(dir_path: &Path, name: &'static str, action: FailAction) {
+    remove(dir_path, name);
+    HOOKS.lock().push((dir_path.to_path_buf(), name, action));
+}
+
+/// Disarms the hook `name` for the database in `dir_path`.
+pub fn remove(dir_path: &Path, name: &str) {
+    HOOKS
+        .lock()
+        .retain(|(dir, hook, _)| !(dir == dir_path && *hook == name));
+}
+
+/// Disarms every hook of the database in `dir_path`.
+pub fn clear(dir_path: &Path) {
+    HOOKS.lock().retain(|(dir, _, _)| dir != dir_path);
+}
+
+/// Runs the hook `name` of the database in `dir_path`, if armed.
+pub(crate) fn hit(dir_path: &Path, name: &str) -> Result<()> {
+    let action = HOOKS
+        .lock()
+        .iter()
+        .find(|(dir, hook, _)| dir == dir_path && *hook == name)
+        .map(|(_, _, action)| action.clone());
+    match action {
+        None => Ok(()),
+        Some(FailAction::Panic) => panic!("failpoint {} hit", name),
+        Some(FailAction::Error) => Err(Error::Io(io::Error::other(format!(
+            "failpoint {} hit",
+            name
+        )))),
+        Some(FailAction::Snapshot(target)) => {
+            if target.is_dir() {
+                fs::remove_dir_all(&target)?;
+            }
+            copy_recursive(dir_path, &target)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatchOptions;
+    use crate::db::Db;
+    use crate::Opts;
+    use bytes::Bytes;
+    use std::fs::OpenOptions;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    fn opts(name: &str) -> Opts {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            format!("/tmp/{}", name),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts
+    }
+
+    // Options for opening the snapshot taken into `dir_path`
+    fn snapshot_opts(opts: &Opts) -> Opts {
+        let mut snapshot = opts.clone();
+        snapshot.dir_path = PathBuf::from(format!("{}-crash", opts.dir_path.display()));
+        let _ = fs::remove_dir_all(&snapshot.dir_path);
+        snapshot
+    }
+
+    fn batch(db: &Db, keys: &[&'static str]) -> Result<()> {
+        let batch = db.new_write_batch(WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: true,
+        })?;
+        for key in keys {
+            batch.put(Bytes::from(*key), Bytes::from("value"))?;
+        }
+        batch.commit()
+    }
+
+    #[test]
+    fn test_uncommitted_batches_are_dropped() -> Result<()> {
+        let opts = opts("test_failpoints_uncommitted");
+        let crash = snapshot_opts(&opts);
+        let db = Db::open(&opts)?;
+        batch(&db, &["first"])?;
+
+        configure(
+            &opts.dir_path,
+            BATCH_BEFORE_MARKER,
+            FailAction::Snapshot(crash.dir_path.clone()),
+        );
+        batch(&db, &["a", "b"])?;
+        clear(&opts.dir_path);
+
+        let crashed = Db::open(&crash)?;
+        assert_eq!(crashed.get(Bytes::from("first"))?, "value");
+        assert!(crashed.get(Bytes::from("a")).is_err());
+        assert!(crashed.get(Bytes::from("b")).is_err());
+        assert_eq!(crashed.open_report().uncommitted_batch_entries, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_committed_batches_survive() -> Result<()> {
+        let opts = opts("test_failpoints_committed");
+        let crash = snapshot_opts(&opts);
+        let db = Db::open(&opts)?;
+
+        // Crashing once the marker is written keeps the whole batch
+        configure(
+            &opts.dir_path,
+            BATCH_AFTER_MARKER,
+            FailAction::Snapshot(crash.dir_path.clone()),
+        );
+        batch(&db, &["a", "b"])?;
+        clear(&opts.dir_path);
+        let crashed = Db::open(&crash)?;
+        assert_eq!(crashed.get(Bytes::from("a"))?, "value");
+        assert_eq!(crashed.get(Bytes::from("b"))?, "value");
+        drop(crashed);
+
+        // A commit that fails after its marker isn't acknowledged, but the
+        // batch is on disk and comes back on open
+        configure(&opts.dir_path, BATCH_AFTER_MARKER, FailAction::Error);
+        assert!(batch(&db, &["c"]).is_err());
+        clear(&opts.dir_path);
+        assert!(db.get(Bytes::from("c")).is_err());
+        drop(db);
+        assert_eq!(Db::open(&opts)?.get(Bytes::from("c"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_sync_writes_stay_invisible() -> Result<()> {
+        let opts = opts("test_failpoints_sync");
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("key"), Bytes::from("old"))?;
+
+        for hook in [APPEND_BEFORE_SYNC, SYNC_BEFORE_INDEX_UPDATE] {
+            configure(&opts.dir_path, hook, FailAction::Error);
+            assert!(db.put(Bytes::from("key"), Bytes::from("new")).is_err());
+            assert!(db.delete(Bytes::from("key")).is_err());
+            clear(&opts.dir_path);
+            assert_eq!(db.get(Bytes::from("key"))?, "old");
+        }
+        // Later writes aren't held up by the failed ones
+        db.put(Bytes::from("other"), Bytes::from("value"))?;
+        assert_eq!(db.get(Bytes::from("other"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_tail_is_truncated() -> Result<()> {
+        let opts = opts("test_failpoints_torn_tail");
+        let crash = snapshot_opts(&opts);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("kept"), Bytes::from("value"))?;
+
+        configure(
+            &opts.dir_path,
+            APPEND_BEFORE_SYNC,
+            FailAction::Snapshot(crash.dir_path.clone()),
+        );
+        db.put(Bytes::from("torn"), Bytes::from("value"))?;
+        clear(&opts.dir_path);
+        let end = db.active_file.read().get_offset();
+        drop(db);
+
+        // Only part of the last record made it to disk
+        let active = crash.dir_path.join("0.db");
+        OpenOptions::new()
+            .write(true)
+            .open(&active)?
+            .set_len(end - 3)?;
+        let crashed = Db::open(&crash)?;
+        assert_eq!(crashed.get(Bytes::from("kept"))?, "value");
+        assert!(crashed.get(Bytes::from("torn")).is_err());
+        // Appends continue where the intact records end
+        crashed.put(Bytes::from("after"), Bytes::from("value"))?;
+        drop(crashed);
+        let reopened = Db::open(&crash)?;
+        assert_eq!(reopened.get(Bytes::from("kept"))?, "value");
+        assert_eq!(reopened.get(Bytes::from("after"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_without_finished_record_is_ignored() -> Result<()> {
+        let opts = opts("test_failpoints_merge");
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(
+                Bytes::from(format!("key{}", i % 10)),
+                Bytes::from(i.to_string()),
+            )?;
+        }
+        let check = |db: &Db| -> Result<()> {
+            for i in 90..100 {
+                assert_eq!(
+                    db.get(Bytes::from(format!("key{}", i % 10)))?,
+                    i.to_string().into_bytes()
+                );
+            }
+            Ok(())
+        };
+
+        // Both leave the merge output on disk without its finished record
+        configure(&opts.dir_path, MERGE_BEFORE_FINISHED, FailAction::Error);
+        assert!(db.merge().is_err());
+        configure(&opts.dir_path, MERGE_BEFORE_FINISHED, FailAction::Panic);
+        assert!(catch_unwind(AssertUnwindSafe(|| db.merge())).is_err());
+        clear(&opts.dir_path);
+        let merge_dir = PathBuf::from(format!("{}-merge", opts.dir_path.display()));
+        assert!(merge_dir.is_dir());
+        check(&db)?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        check(&db)?;
+        // The next merge starts over and is installed
+        db.merge()?;
+        drop(db);
+        check(&Db::open(&opts)?)
+    }
+}
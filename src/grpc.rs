@@ -0,0 +1,389 @@
+//! A gRPC service over a shared `Db`, with the Get, Put, Delete, MultiGet,
+//! Scan, BatchCommit and Stats calls defined in `proto/zap.proto`.
+//!
+//! Database calls block, so every call runs on tokio's blocking threads.
+//! Errors map to status codes by their `Error` variant: bad keys and values
+//! are INVALID_ARGUMENT, a closed database UNAVAILABLE, a full disk
+//! RESOURCE_EXHAUSTED and corruption DATA_LOSS.
+
+use crate::db::Db;
+use crate::{Error, Result};
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+/// Messages and client and server stubs generated from `proto/zap.proto`.
+pub mod proto {
+    tonic::include_proto!("zap.v1");
+}
+
+use proto::zap_server::{Zap, ZapServer};
+use proto::{
+    BatchCommitRequest, BatchCommitResponse, DeleteRequest, DeleteResponse, GetRequest,
+    GetResponse, KeyValue, MultiGetRequest, MultiGetResponse, OptionalValue, PutRequest,
+    PutResponse, ScanRequest, StatsRequest, StatsResponse,
+};
+
+// Key/value pairs a scan reads ahead of the client
+const SCAN_BUFFER: usize = 64;
+
+/// Serves `db` to connections on `listener`. Only returns if the server
+/// fails.
+pub async fn serve_grpc(db: Arc<Db>, listener: TcpListener) -> Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(ZapService::new(db).into_server())
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e)))
+}
+
+/// The `Zap` service, for adding to a tonic server of one's own.
+#[derive(Debug, Clone)]
+pub struct ZapService {
+    db: Arc<Db>,
+}
+
+impl ZapService {
+    pub fn new(db: Arc<Db>) -> Self {
+        ZapService { db }
+    }
+
+    pub fn into_server(self) -> ZapServer<Self> {
+        ZapServer::new(self)
+    }
+
+    // Runs `f` on the blocking threads
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Db) -> Result<T> + Send + 'static,
+    ) -> std::result::Result<T, Status> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(&db))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(Status::from)
+    }
+}
+
+#[tonic::async_trait]
+impl Zap for ZapService {
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> std::result::Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        match self.run(move |db| db.get_seq(key)).await? {
+            Some((value, _)) => Ok(Response::new(GetResponse { value })),
+            None => Err(Status::not_found("Key not found")),
+        }
+    }
+
+    async fn put(
+        &self,
+        request: Request<PutRequest>,
+    ) -> std::result::Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        self.run(move |db| db.put(key, value)).await?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> std::result::Result<Response<DeleteResponse>, Status> {
+        let key = request.into_inner().key;
+        self.run(move |db| db.delete(key)).await?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn multi_get(
+        &self,
+        request: Request<MultiGetRequest>,
+    ) -> std::result::Result<Response<MultiGetResponse>, Status> {
+        let keys = request.into_inner().keys;
+        let values = self
+            .run(move |db| {
+                keys.into_iter()
+                    .map(|key| {
+                        Ok(match db.get_seq(key)? {
+                            Some((value, _)) => OptionalValue { found: true, value },
+                            None => OptionalValue::default(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await?;
+        Ok(Response::new(MultiGetResponse { values }))
+    }
+
+    type ScanStream = ReceiverStream<std::result::Result<KeyValue, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { prefix, limit } = request.into_inner();
+        let keys = self.run(move |db| db.scan_prefix_keys(&prefix)).await?;
+        let limit = if limit == 0 {
+            usize::MAX
+        } else {
+            limit as usize
+        };
+
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            for key in keys.into_iter().take(limit) {
+                let pair = match db.get_seq(key.clone()) {
+                    Ok(Some((value, _))) => Ok(KeyValue { key, value }),
+                    // Deleted since the keys were listed
+                    Ok(None) => continue,
+                    Err(e) => Err(Status::from(e)),
+                };
+                let failed = pair.is_err();
+                // Stops once the client goes away
+                if sender.blocking_send(pair).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn batch_commit(
+        &self,
+        request: Request<BatchCommitRequest>,
+    ) -> std::result::Result<Response<BatchCommitResponse>, Status> {
+        let writes = request.into_inner().writes;
+        self.run(move |db| {
+            let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+                max_batch_num: writes.len(),
+                sync_writes: db.ctx.opts.sync_writes,
+            })?;
+            for write in writes {
+                if write.delete {
+                    batch.delete(write.key)?;
+                } else {
+                    db.validate_put(&write.key, &write.value)?;
+                    batch.put(write.key, write.value)?;
+                }
+            }
+            batch.commit()
+        })
+        .await?;
+        Ok(Response::new(BatchCommitResponse {}))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> std::result::Result<Response<StatsResponse>, Status> {
+        let stat = self.run(|db| db.stat()).await?;
+        Ok(Response::new(StatsResponse {
+            key_num: stat.key_num as u64,
+            data_file_num: stat.data_file_num as u64,
+            disk_size: stat.disk_size,
+            index_memory: stat.index_memory,
+            inline_value_bytes: stat.inline_value_bytes,
+        }))
+    }
+}
+
+impl From<Error> for Status {
+    fn from(e: Error) -> Self {
+        let message = e.to_string();
+        match e {
+            Error::EmptyKey | Error::Unsupported(_) => Status::invalid_argument(message),
+            Error::Closed => Status::unavailable(message),
+            Error::CursorExpired(_) => Status::out_of_range(message),
+            Error::DiskFull | Error::FileIdsExhausted(_) => Status::resource_exhausted(message),
+            Error::Corrupted { .. }
+            | Error::ChecksumMismatch(_)
+            | Error::ConflictingDataFiles { .. } => Status::data_loss(message),
+            Error::Io(_)
+            | Error::FileNotFound(_)
+            | Error::ReportableBug(_)
+            | Error::Decode(_)
+            | Error::Undecodable { .. } => Status::internal(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::zap_client::ZapClient;
+    use super::proto::Write;
+    use super::*;
+    use crate::Opts;
+    use bytes::Bytes;
+    use std::fs;
+    use tonic::Code;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpcs() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_grpc_rpcs".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.max_value_size = 64;
+        let db = Arc::new(Db::open(&opts)?);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve_grpc(db.clone(), listener));
+        let mut client = ZapClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let bytes = |s: &str| Bytes::copy_from_slice(s.as_bytes());
+
+        client
+            .put(PutRequest {
+                key: bytes("greeting"),
+                value: bytes("hello"),
+            })
+            .await
+            .unwrap();
+        let value = client
+            .get(GetRequest {
+                key: bytes("greeting"),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .value;
+        assert_eq!(value, bytes("hello"));
+        let status = client
+            .get(GetRequest {
+                key: bytes("missing"),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        let status = client
+            .put(PutRequest {
+                key: bytes("big"),
+                value: Bytes::from(vec![0u8; 65]),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = client
+            .get(GetRequest { key: Bytes::new() })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        client
+            .batch_commit(BatchCommitRequest {
+                writes: vec![
+                    Write {
+                        key: bytes("a"),
+                        value: bytes("1"),
+                        delete: false,
+                    },
+                    Write {
+                        key: bytes("greeting"),
+                        value: Bytes::new(),
+                        delete: true,
+                    },
+                ],
+            })
+            .await
+            .unwrap();
+        // A batch with an invalid write applies none of them
+        let status = client
+            .batch_commit(BatchCommitRequest {
+                writes: vec![
+                    Write {
+                        key: bytes("b"),
+                        value: bytes("1"),
+                        delete: false,
+                    },
+                    Write {
+                        key: bytes("c"),
+                        value: Bytes::from(vec![0u8; 65]),
+                        delete: false,
+                    },
+                ],
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let values = client
+            .multi_get(MultiGetRequest {
+                keys: vec![bytes("a"), bytes("greeting"), bytes("b")],
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .values;
+        assert_eq!(
+            values,
+            vec![
+                OptionalValue {
+                    found: true,
+                    value: bytes("1")
+                },
+                OptionalValue::default(),
+                OptionalValue::default(),
+            ]
+        );
+
+        client
+            .delete(DeleteRequest { key: bytes("a") })
+            .await
+            .unwrap();
+        assert!(db.locate(b"a").is_none());
+
+        for i in 0..3000 {
+            db.put(
+                Bytes::from(format!("scan:{:05}", i)),
+                Bytes::from(i.to_string()),
+            )?;
+        }
+        db.put(bytes("other"), bytes("value"))?;
+        let mut stream = client
+            .scan(ScanRequest {
+                prefix: bytes("scan:"),
+                limit: 0,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut scanned = Vec::new();
+        while let Some(pair) = stream.message().await.unwrap() {
+            scanned.push(pair);
+        }
+        assert_eq!(scanned.len(), 3000);
+        for (i, pair) in scanned.iter().enumerate() {
+            assert_eq!(pair.key, Bytes::from(format!("scan:{:05}", i)));
+            assert_eq!(pair.value, Bytes::from(i.to_string()));
+        }
+        let mut stream = client
+            .scan(ScanRequest {
+                prefix: bytes("scan:"),
+                limit: 10,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut limited = 0;
+        while stream.message().await.unwrap().is_some() {
+            limited += 1;
+        }
+        assert_eq!(limited, 10);
+
+        let stats = client.stats(StatsRequest {}).await.unwrap().into_inner();
+        assert_eq!(stats.key_num, 3001);
+        assert_eq!(stats.data_file_num, 1);
+        assert!(stats.disk_size > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+//! Serves a database over HTTP, for scripts without a client library:
+//!
+//! - `GET /kv/{key}` returns the value as the response body
+//! - `PUT /kv/{key}` stores the request body as the value
+//! - `DELETE /kv/{key}` removes the key
+//! - `GET /kv?prefix=...&limit=...` lists keys as JSON
+//! - `GET /stats` returns `Db::stat` as JSON
+//!
+//! Keys in paths and query strings are percent-decoded, and values are raw
+//! bytes. A key over `max_key_size` is refused with 400 and a value over
+//! `max_value_size` with 413 before its body is read. Each connection gets a
+//! thread, up to `MAX_CONNECTIONS`; past that, connections are turned away
+//! with 503.
+
+use crate::db::Db;
+use crate::{Error, Result};
+use bytes::Bytes;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Most connections served at once.
+pub const MAX_CONNECTIONS: usize = 256;
+// Longest request line or header line, and most header lines
+const MAX_LINE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Binds `addr` and serves `db` there. Only returns if the listener fails.
+pub fn serve_http(db: Arc<Db>, addr: impl ToSocketAddrs) -> Result<()> {
+    serve_http_on(db, TcpListener::bind(addr)?)
+}
+
+/// Like `serve_http`, on a listener that's already bound.
+pub fn serve_http_on(db: Arc<Db>, listener: TcpListener) -> Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = Response::error(503, "Too many connections").write(&mut stream, false);
+            continue;
+        }
+        let db = db.clone();
+        let connections = connections.clone();
+        thread::Builder::new()
+            .name("zap-http".to_string())
+            .spawn(move || {
+                let _ = handle(&db, stream);
+                connections.fetch_sub(1, Ordering::SeqCst);
+            })?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    content_length: Option<usize>,
+    chunked: bool,
+    keep_alive: bool,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Bytes,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: impl Into<Bytes>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn empty(status: u16) -> Self {
+        Response::new(status, "text/plain", Bytes::new())
+    }
+
+    fn json(body: String) -> Self {
+        Response::new(200, "application/json", body)
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::new(
+            status,
+            "application/json",
+            format!("{{\"error\":{}}}\n", json_string(message)),
+        )
+    }
+
+    fn write(&self, out: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        if !keep_alive {
+            write!(out, "Connection: close\r\n")?;
+        }
+        write!(out, "\r\n")?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::EmptyKey | Error::Unsupported(_) => 400,
+            Error::Closed => 503,
+            Error::DiskFull => 507,
+            _ => 500,
+        };
+        Response::error(status, &e.to_string())
+    }
+}
+
+fn handle(db: &Db, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Response::error(400, &e.to_string()).write(&mut writer, false)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (response, body_read) = route(db, &request, &mut reader);
+        // A body left unread would be taken for the next request
+        let keep_alive = request.keep_alive && body_read;
+        response.write(&mut writer, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Answers `request`, reading its body if it's a PUT. Also returns whether
+// the body, if any, was read.
+fn route(db: &Db, request: &Request, body: &mut impl BufRead) -> (Response, bool) {
+    let has_body = request.chunked || request.content_length.is_some_and(|len| len > 0);
+    if let Some(key) = request.path.strip_prefix("/kv/") {
+        let key = match percent_decode(key, false) {
+            Some(key) if key.len() <= db.ctx.opts.max_key_size => key,
+            Some(_) => return (Response::error(400, "Key too long"), !has_body),
+            None => return (Response::error(400, "Invalid percent-encoding"), !has_body),
+        };
+        return match request.method.as_str() {
+            "GET" => (get(db, key), !has_body),
+            "PUT" => put(db, key, request, body),
+            "DELETE" => (delete(db, key), !has_body),
+            _ => (Response::error(405, "Use GET, PUT or DELETE"), !has_body),
+        };
+    }
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/kv") => list(db, request.query.as_deref().unwrap_or("")),
+        ("GET", "/stats") => stats(db),
+        (_, "/kv" | "/stats") => Response::error(405, "Use GET"),
+        _ => Response::error(404, "No such route"),
+    };
+    (response, !has_body)
+}
+
+fn get(db: &Db, key: Bytes) -> Response {
+    match db.get_seq(key) {
+        Ok(Some((value, _))) => Response::new(200, "application/octet-stream", value),
+        Ok(None) => Response::error(404, "Key not found"),
+        Err(e) => e.into(),
+    }
+}
+
+fn put(db: &Db, key: Bytes, request: &Request, body: &mut impl BufRead) -> (Response, bool) {
+    if request.chunked {
+        return (Response::error(411, "Content-Length is required"), false);
+    }
+    let len = request.content_length.unwrap_or(0);
+    if len > db.ctx.opts.max_value_size {
+        return (Response::error(413, "Value too large"), false);
+    }
+    let mut value = vec![0; len];
+    if body.read_exact(&mut value).is_err() {
+        return (
+            Response::error(400, "Body shorter than Content-Length"),
+            false,
+        );
+    }
+    let response = match db.put(key, Bytes::from(value)) {
+        Ok(()) => Response::empty(204),
+        Err(e) => e.into(),
+    };
+    (response, true)
+}
+
+fn delete(db: &Db, key: Bytes) -> Response {
+    if !key.is_empty() && db.locate(&key).is_none() {
+        return Response::error(404, "Key not found");
+    }
+    match db.delete(key) {
+        Ok(()) => Response::empty(204),
+        Err(e) => e.into(),
+    }
+}
+
+fn list(db: &Db, query: &str) -> Response {
+    let mut prefix = Bytes::new();
+    let mut limit = usize::MAX;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let Some(value) = percent_decode(value, true) else {
+            return Response::error(400, "Invalid percent-encoding");
+        };
+        match name {
+            "prefix" => prefix = value,
+            "limit" => match std::str::from_utf8(&value)
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                Some(value) => limit = value,
+                None => return Response::error(400, "Invalid limit"),
+            },
+            _ => return Response::error(400, &format!("Unknown parameter {}", name)),
+        }
+    }
+    match db.scan_prefix_keys(&prefix) {
+        Ok(keys) => {
+            let keys = keys
+                .iter()
+                .take(limit)
+                .map(|key| json_string(&percent_encode(key)))
+                .collect::<Vec<_>>();
+            Response::json(format!("{{\"keys\":[{}]}}\n", keys.join(",")))
+        }
+        Err(e) => e.into(),
+    }
+}
+
+fn stats(db: &Db) -> Response {
+    match db.stat() {
+        Ok(stat) => Response::json(format!(
+            "{{\"key_num\":{},\"data_file_num\":{},\"disk_size\":{},\"index_memory\":{},\"inline_value_bytes\":{}}}\n",
+            stat.key_num,
+            stat.data_file_num,
+            stat.disk_size,
+            stat.index_memory,
+            stat.inline_value_bytes
+        )),
+        Err(e) => e.into(),
+    }
+}
+
+// Reads a request line and headers, leaving the body. `None` once the client
+// has hung up.
+fn read_request(input: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let Some(line) = read_line(input)? else {
+        return Ok(None);
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("unsupported HTTP version"));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        content_length: None,
+        chunked: false,
+        keep_alive: version != "HTTP/1.0",
+    };
+
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(input)?.ok_or_else(|| invalid("unexpected end of stream"))?;
+        if line.is_empty() {
+            return Ok(Some(request));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let len = value
+                    .parse()
+                    .map_err(|_| invalid("invalid Content-Length"))?;
+                request.content_length = Some(len);
+            }
+            "transfer-encoding" => request.chunked = !value.eq_ignore_ascii_case("identity"),
+            "connection" if value.eq_ignore_ascii_case("close") => request.keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => request.keep_alive = true,
+            _ => {}
+        }
+    }
+    Err(invalid("too many headers"))
+}
+
+fn read_line(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = input
+        .by_ref()
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(invalid("line too long"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("non-UTF-8 request line or header"))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Decodes `%XX` escapes, and in query strings `+` as a space. `None` if an
+// escape is malformed.
+fn percent_decode(s: &str, query: bool) -> Option<Bytes> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(decoded.into())
+}
+
+// Encodes `key` for use in a `/kv/{key}` path. Bytes allowed in a path
+// segment are kept, so readable keys stay readable.
+fn percent_encode(key: &[u8]) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*,;=:@".contains(b) {
+            encoded.push(*b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        507 => "Insufficient Storage",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn start(name: &str) -> Result<(Arc<Db>, SocketAddr)> {
+        let opts = Opts::new(16, 64, false, false, format!("/tmp/{}", name), 1024 * 1024);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Arc::new(Db::open(&opts)?);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server_db = db.clone();
+        thread::spawn(move || serve_http_on(server_db, listener));
+        Ok((db, addr))
+    }
+
+    // A keep-alive HTTP client, returning the status and body
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Result<Self> {
+            let writer = TcpStream::connect(addr)?;
+            writer.set_read_timeout(Some(Duration::from_secs(10)))?;
+            Ok(Client {
+                reader: BufReader::new(writer.try_clone()?),
+                writer,
+            })
+        }
+
+        fn call(&mut self, method: &str, target: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+            let head = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                method,
+                target,
+                body.len()
+            );
+            self.writer.write_all(head.as_bytes())?;
+            self.writer.write_all(body)?;
+            self.response()
+        }
+
+        fn response(&mut self) -> Result<(u16, Vec<u8>)> {
+            let status_line = read_line(&mut self.reader)?.unwrap();
+            let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+            let mut len = 0;
+            while let Some(line) = read_line(&mut self.reader)? {
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    len = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; len];
+            self.reader.read_exact(&mut body)?;
+            Ok((status, body))
+        }
+    }
+
+    #[test]
+    fn test_routes() -> Result<()> {
+        let (db, addr) = start("test_http_routes")?;
+        let mut client = Client::connect(addr)?;
+
+        assert_eq!(client.call("PUT", "/kv/user:1", b"alice")?.0, 204);
+        assert_eq!(client.call("PUT", "/kv/user%3A2", b"\x00\xffbob")?.0, 204);
+        assert_eq!(client.call("PUT", "/kv/with%20space", b"")?.0, 204);
+        assert_eq!(
+            client.call("GET", "/kv/user:1", b"")?,
+            (200, b"alice".to_vec())
+        );
+        assert_eq!(db.get(Bytes::from("user:2"))?, &b"\x00\xffbob"[..]);
+        assert_eq!(db.get(Bytes::from("with space"))?, "");
+        assert_eq!(client.call("GET", "/kv/missing", b"")?.0, 404);
+
+        assert_eq!(
+            client.call("GET", "/kv?prefix=user%3A", b"")?,
+            (200, b"{\"keys\":[\"user:1\",\"user:2\"]}\n".to_vec())
+        );
+        assert_eq!(
+            client.call("GET", "/kv?limit=1", b"")?,
+            (200, b"{\"keys\":[\"user:1\"]}\n".to_vec())
+        );
+        assert_eq!(
+            client.call("GET", "/kv?prefix=with+", b"")?,
+            (200, b"{\"keys\":[\"with%20space\"]}\n".to_vec())
+        );
+
+        assert_eq!(client.call("DELETE", "/kv/user:1", b"")?.0, 204);
+        assert_eq!(client.call("DELETE", "/kv/user:1", b"")?.0, 404);
+        let (status, body) = client.call("GET", "/stats", b"")?;
+        assert_eq!(status, 200);
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .starts_with("{\"key_num\":2,\"data_file_num\":1,"));
+
+        assert_eq!(client.call("POST", "/kv/key", b"")?.0, 405);
+        assert_eq!(client.call("GET", "/nothing", b"")?.0, 404);
+        assert_eq!(client.call("GET", "/kv/%zz", b"")?.0, 400);
+        assert_eq!(client.call("GET", "/kv?limit=many", b"")?.0, 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_limits() -> Result<()> {
+        let (db, addr) = start("test_http_size_limits")?;
+        let mut client = Client::connect(addr)?;
+
+        // Over max_key_size once decoded
+        let long_key = "%41".repeat(17);
+        let (status, _) = client.call("PUT", &format!("/kv/{}", long_key), b"value")?;
+        assert_eq!(status, 400);
+        let mut client = Client::connect(addr)?;
+        assert_eq!(client.call("PUT", "/kv/empty/", b"")?.0, 204);
+        assert_eq!(client.call("PUT", "/kv/", b"value")?.0, 400);
+
+        // Refused from the headers alone, without sending the body
+        let mut client = Client::connect(addr)?;
+        client
+            .writer
+            .write_all(b"PUT /kv/big HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n")?;
+        assert_eq!(client.response()?.0, 413);
+        assert!(db.locate(b"big").is_none());
+
+        let mut client = Client::connect(addr)?;
+        assert_eq!(client.call("PUT", "/kv/fits", &[b'x'; 64])?.0, 204);
+        assert_eq!(client.call("PUT", "/kv/fits", &[b'x'; 65])?.0, 413);
+
+        let mut client = Client::connect(addr)?;
+        client.writer.write_all(b"not a request\r\n\r\n")?;
+        assert_eq!(client.response()?.0, 400);
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+//! Migrating data out of bitcask stores written by other implementations.
+//!
+//! The layout read is the one of bitcask and its Go ports. Data files are
+//! named `<id>.bitcask.data` and hold records, all integers big-endian:
+//!
+//! ```text
+//! crc32: u32 | tstamp: u32 | ksz: u16 | vsz: u32 | key | value
+//! ```
+//!
+//! with the CRC taken over everything after it. A value starting with
+//! `bitcask_tombstone` marks a delete. A `<id>.bitcask.hint` file next to a
+//! data file lists its records as
+//!
+//! ```text
+//! tstamp: u32 | ksz: u16 | total_sz: u32 | offset: u64 | key
+//! ```
+//!
+//! and is read instead of scanning the data file. Files with higher ids
+//! are newer, as are records further into a file.
+
+use crate::db::Db;
+use crate::{Error, Opts, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const DATA_SUFFIX: &str = ".bitcask.data";
+const HINT_SUFFIX: &str = ".bitcask.hint";
+const TOMBSTONE_PREFIX: &[u8] = b"bitcask_tombstone";
+// crc32, tstamp, ksz, vsz
+const RECORD_HEADER_LEN: usize = 4 + 4 + 2 + 4;
+// tstamp, ksz, total_sz, offset
+const HINT_HEADER_LEN: usize = 4 + 2 + 4 + 8;
+// Most live pairs written in one `put_all`
+const IMPORT_BATCH_LEN: usize = 1024;
+
+/// What `from_bitcask` read and wrote.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Data files read.
+    pub data_files: usize,
+    /// Data files whose hint file was read instead of the data file.
+    pub hint_files: usize,
+    /// Records read, whether live, overwritten, deleted or corrupt.
+    pub records: u64,
+    /// Keys written into the new database.
+    pub keys: usize,
+    /// Keys whose latest record is a delete.
+    pub tombstones: usize,
+    /// Records skipped because they failed their CRC or couldn't be
+    /// parsed. Nothing after an unparsable record in a file can be read.
+    pub corrupt_records: u64,
+}
+
+// Where the latest record of a key is
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    file_id: u32,
+    offset: u64,
+}
+
+type KeyLocations = Vec<(Vec<u8>, Location)>;
+
+/// Imports the latest value of every key in the bitcask directory `dir`
+/// into a new database at `opts.dir_path`, which must be new or empty.
+///
+/// Records that fail their CRC or can't be parsed are counted in
+/// `ImportReport::corrupt_records` and skipped with
+/// `Opts::skip_corrupt_records`, and fail the import with
+/// `Error::Corrupted` otherwise. The pairs are written in batches through
+/// `Db::put_all`, so only the keys' locations are held in memory.
+pub fn from_bitcask(dir: &Path, opts: &Opts) -> Result<ImportReport> {
+    if opts.dir_path.exists() && fs::read_dir(&opts.dir_path)?.next().is_some() {
+        return Err(Error::Unsupported(format!(
+            "Import directory {} is not empty",
+            opts.dir_path.display()
+        )));
+    }
+
+    let mut data_files = Vec::new();
+    for dentry in fs::read_dir(dir)? {
+        let name = dentry?.file_name().to_string_lossy().into_owned();
+        if let Some(file_id) = name
+            .strip_suffix(DATA_SUFFIX)
+            .and_then(|id| id.parse().ok())
+        {
+            data_files.push(file_id);
+        }
+    }
+    data_files.sort_unstable();
+
+    let mut report = ImportReport {
+        data_files: data_files.len(),
+        ..Default::default()
+    };
+    let mut latest = HashMap::new();
+    for file_id in data_files.iter().copied() {
+        let hint_path = dir.join(format!("{}{}", file_id, HINT_SUFFIX));
+        match read_hint_file(&hint_path, file_id) {
+            Ok(Some(locations)) => {
+                report.hint_files += 1;
+                report.records += locations.len() as u64;
+                latest.extend(locations);
+            }
+            // A hint that can't be read is no reason to skip the data
+            Ok(None) | Err(_) => scan_data_file(
+                &data_path(dir, file_id),
+                file_id,
+                opts,
+                &mut report,
+                &mut latest,
+            )?,
+        }
+    }
+
+    // Read the values back in file order, so each file is read front to back
+    let mut locations = latest.into_iter().collect::<Vec<_>>();
+    locations.sort_unstable_by_key(|(_, location)| (location.file_id, location.offset));
+
+    let db = Db::open(opts)?;
+    let mut file: Option<(u32, File)> = None;
+    let mut pairs = Vec::with_capacity(IMPORT_BATCH_LEN);
+    for (key, location) in locations {
+        let data_file = match file {
+            Some((file_id, ref data_file)) if file_id == location.file_id => data_file,
+            _ => {
+                let data_file = File::open(data_path(dir, location.file_id))?;
+                &file.insert((location.file_id, data_file)).1
+            }
+        };
+        let record = match read_record_at(data_file, location.offset)? {
+            // A hint can point at a record that doesn't match its key
+            Some((record_key, value)) if record_key == key => value,
+            _ => {
+                skip_corrupt(opts, &mut report, location)?;
+                continue;
+            }
+        };
+        if record.starts_with(TOMBSTONE_PREFIX) {
+            report.tombstones += 1;
+            continue;
+        }
+        pairs.push((Bytes::from(key), Bytes::from(record)));
+        if pairs.len() == IMPORT_BATCH_LEN {
+            db.put_all(&pairs)?;
+            report.keys += pairs.len();
+            pairs.clear();
+        }
+    }
+    db.put_all(&pairs)?;
+    report.keys += pairs.len();
+    db.sync()?;
+    Ok(report)
+}
+
+fn data_path(dir: &Path, file_id: u32) -> PathBuf {
+    dir.join(format!("{}{}", file_id, DATA_SUFFIX))
+}
+
+// Counts a corrupt record, or fails on it without `skip_corrupt_records`
+fn skip_corrupt(opts: &Opts, report: &mut ImportReport, location: Location) -> Result<()> {
+    if !opts.skip_corrupt_records {
+        return Err(Error::Corrupted {
+            file_id: location.file_id,
+            offset: location.offset,
+        });
+    }
+    report.corrupt_records += 1;
+    Ok(())
+}
+
+// The locations in a hint file, `None` if there isn't one
+fn read_hint_file(path: &Path, file_id: u32) -> Result<Option<KeyLocations>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut locations = Vec::new();
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < HINT_HEADER_LEN {
+            return Err(Error::Corrupted {
+                file_id,
+                offset: (bytes.len() - rest.len()) as u64,
+            });
+        }
+        let key_len = u16::from_be_bytes([rest[4], rest[5]]) as usize;
+        let offset = u64::from_be_bytes(rest[10..18].try_into().unwrap());
+        // bitcask ends its hint files with a CRC entry of an empty key
+        if key_len == 0 {
+            break;
+        }
+        let Some(key) = rest.get(HINT_HEADER_LEN..HINT_HEADER_LEN + key_len) else {
+            return Err(Error::Corrupted {
+                file_id,
+                offset: (bytes.len() - rest.len()) as u64,
+            });
+        };
+        locations.push((key.to_vec(), Location { file_id, offset }));
+        rest = &rest[HINT_HEADER_LEN + key_len..];
+    }
+    Ok(Some(locations))
+}
+
+// Records the location of every intact record of a data file in `latest`
+fn scan_data_file(
+    path: &Path,
+    file_id: u32,
+    opts: &Opts,
+    report: &mut ImportReport,
+    latest: &mut HashMap<Vec<u8>, Location>,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut offset = 0;
+    loop {
+        let location = Location { file_id, offset };
+        let mut header = [0; RECORD_HEADER_LEN];
+        match read_full(&mut reader, &mut header)? {
+            0 => return Ok(()),
+            RECORD_HEADER_LEN => {}
+            // A torn header, the file can't be read past it
+            _ => return skip_corrupt(opts, report, location),
+        }
+        let (key_len, value_len) = record_lens(&header);
+        let mut body = vec![0; key_len + value_len];
+        if key_len == 0 || read_full(&mut reader, &mut body)? < body.len() {
+            return skip_corrupt(opts, report, location);
+        }
+        report.records += 1;
+        offset += (RECORD_HEADER_LEN + body.len()) as u64;
+        if !record_crc_matches(&header, &body) {
+            skip_corrupt(opts, report, location)?;
+            continue;
+        }
+        latest.insert(body[..key_len].to_vec(), location);
+    }
+}
+
+// Reads the intact record at `offset` as its key and value
+fn read_record_at(file: &File, offset: u64) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    if let Err(e) = file.read_exact_at(&mut header, offset) {
+        return match e.kind() {
+            ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e.into()),
+        };
+    }
+    let (key_len, value_len) = record_lens(&header);
+    let mut body = vec![0; key_len + value_len];
+    if let Err(e) = file.read_exact_at(&mut body, offset + RECORD_HEADER_LEN as u64) {
+        return match e.kind() {
+            ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e.into()),
+        };
+    }
+    if !record_crc_matches(&header, &body) {
+        return Ok(None);
+    }
+    let value = body.split_off(key_len);
+    Ok(Some((body, value)))
+}
+
+fn record_lens(header: &[u8; RECORD_HEADER_LEN]) -> (usize, usize) {
+    let key_len = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let value_len = u32::from_be_bytes(header[10..14].try_into().unwrap()) as usize;
+    (key_len, value_len)
+}
+
+fn record_crc_matches(header: &[u8; RECORD_HEADER_LEN], body: &[u8]) -> bool {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(body);
+    hasher.finalize() == u32::from_be_bytes(header[..4].try_into().unwrap())
+}
+
+// Fills `buf` as far as the reader goes, returning how much was read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/bitcask")
+    }
+
+    fn import_opts(name: &str, skip_corrupt_records: bool) -> Opts {
+        let mut opts = Opts::new(256, 1024, false, false, format!("/tmp/{}", name), 4096);
+        opts.skip_corrupt_records = skip_corrupt_records;
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts
+    }
+
+    #[test]
+    fn test_import_bitcask_fixture() -> Result<()> {
+        let opts = import_opts("test_import_bitcask", true);
+        let report = from_bitcask(&fixture_dir(), &opts)?;
+        assert_eq!(
+            report,
+            ImportReport {
+                data_files: 2,
+                hint_files: 1,
+                records: 150 + 100 + 10 + 1,
+                keys: 190,
+                tombstones: 10,
+                corrupt_records: 1,
+            }
+        );
+
+        let db = Db::open(&opts)?;
+        assert_eq!(db.list_keys()?.len(), 190);
+        for i in 0..10 {
+            assert_eq!(db.get_seq(Bytes::from(format!("key{}", i)))?, None);
+        }
+        assert_eq!(db.get(Bytes::from("key10"))?, "value10-v1");
+        assert_eq!(db.get(Bytes::from("key99"))?, "value99-v1");
+        assert_eq!(db.get(Bytes::from("key100"))?, "value100-v2");
+        // The newer record of key199 fails its CRC
+        assert_eq!(db.get(Bytes::from("key199"))?, "value199-v2");
+
+        // The target has to be fresh
+        assert!(matches!(
+            from_bitcask(&fixture_dir(), &opts),
+            Err(Error::Unsupported(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_fails_on_corrupt_record() -> Result<()> {
+        let opts = import_opts("test_import_bitcask_strict", false);
+        assert!(matches!(
+            from_bitcask(&fixture_dir(), &opts),
+            Err(Error::Corrupted { file_id: 2, .. })
+        ));
+
+        // A torn record at the end of a file is skipped too
+        let dir = PathBuf::from("/tmp/test_import_bitcask_torn_src");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let data = fs::read(fixture_dir().join("1.bitcask.data"))?;
+        fs::write(dir.join("1.bitcask.data"), &data[..data.len() - 3])?;
+        let opts = import_opts("test_import_bitcask_torn", true);
+        let report = from_bitcask(&dir, &opts)?;
+        assert_eq!(report.keys, 149);
+        assert_eq!(report.corrupt_records, 1);
+        Ok(())
+    }
+}
@@ -1,21 +1,21 @@
-use super::{IndexIterator, IndexIteratorMode, Indexer};
+use super::{IndexIterator, IndexIteratorMode, IndexKey, Indexer};
 use crate::{KeyDirEntry, Result};
 use bytes::Bytes;
 use parking_lot::RwLock;
 use std::{collections::BTreeMap, sync::Arc};
 
 #[derive(Debug, Clone)]
-pub struct BTree(Arc<RwLock<BTreeMap<Vec<u8>, KeyDirEntry>>>);
+pub struct BTree(Arc<RwLock<BTreeMap<IndexKey, KeyDirEntry>>>);
 
 impl Indexer for BTree {
-    fn put(&self, key: Vec<u8>, entry: KeyDirEntry) -> Option<KeyDirEntry> {
+    fn put(&self, key: IndexKey, entry: KeyDirEntry) -> Option<KeyDirEntry> {
         let mut write_guard = self.0.write();
         write_guard.insert(key, entry)
     }
 
     fn get(&self, key: &[u8]) -> Option<KeyDirEntry> {
         let read_guard = self.0.read();
-        read_guard.get(key).copied()
+        read_guard.get(key).cloned()
     }
 
     fn delete(&self, key: &[u8]) -> Option<KeyDirEntry> {
@@ -27,8 +27,8 @@ impl Indexer for BTree {
         Ok(self
             .0
             .read()
-            .iter()
-            .map(|(k, _)| Bytes::copy_from_slice(k))
+            .keys()
+            .map(|k| Bytes::copy_from_slice(k))
             .collect::<Vec<Bytes>>())
     }
 
@@ -39,14 +39,14 @@ impl Indexer for BTree {
             .read()
             .iter()
             .map(|(key, value)| (key.clone(), value.clone()))
-            .collect::<Vec<(Vec<u8>, KeyDirEntry)>>();
+            .collect::<Vec<(IndexKey, KeyDirEntry)>>();
         BTreeIterator { items, index: 0 }.into()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct BTreeIterator {
-    items: Vec<(Vec<u8>, KeyDirEntry)>,
+    items: Vec<(IndexKey, KeyDirEntry)>,
     index: usize,
 }
 
@@ -55,14 +55,14 @@ impl IndexIterator for BTreeIterator {
         self.index = 0;
     }
 
-    fn seek(&mut self, key: Vec<u8>) {
-        self.index = match self.items.binary_search_by(|(k, _)| k.cmp(&key)) {
+    fn seek(&mut self, key: &[u8]) {
+        self.index = match self.items.binary_search_by(|(k, _)| (**k).cmp(key)) {
             Ok(equal_val) => equal_val,
             Err(insert_val) => insert_val,
         };
     }
 
-    fn next(&mut self) -> Option<(&Vec<u8>, &KeyDirEntry)> {
+    fn next(&mut self) -> Option<(&[u8], &KeyDirEntry)> {
         if self.index >= self.items.len() {
             return None;
         }
@@ -74,13 +74,18 @@ impl IndexIterator for BTreeIterator {
     }
 }
 
-#[allow(dead_code)]
 impl BTree {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self(Arc::new(RwLock::new(BTreeMap::new())))
     }
 }
 
+impl Default for BTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::all)]
 mod tests {
@@ -104,7 +109,7 @@ mod tests {
         let key = b"key".to_vec();
         let value = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        let result = map.put(key.clone(), value);
+        let result = map.put(key.clone().into(), value.clone());
         assert!(result.is_none(), "Expected None, got {:?}", result);
 
         let retrieved = map.get(&key).unwrap();
@@ -123,8 +128,8 @@ mod tests {
 
         let value2 = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(key.clone(), value1);
-        let result = map.put(key.clone(), value2);
+        map.put(key.clone().into(), value1.clone());
+        let result = map.put(key.clone().into(), value2);
         assert!(result.is_some(), "Expected Some, got None");
 
         let retrieved = result.unwrap();
@@ -143,8 +148,8 @@ mod tests {
         let banana = b"banana".to_vec();
         let banana_entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(apple.clone(), apple_entry);
-        map.put(banana.clone(), banana_entry);
+        map.put(apple.clone().into(), apple_entry.clone());
+        map.put(banana.clone().into(), banana_entry.clone());
 
         match map.get(&apple) {
             Some(retrieved) => {
@@ -185,8 +190,8 @@ mod tests {
         let banana = b"banana".to_vec();
         let banana_entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(apple.clone(), apple_entry);
-        map.put(banana.clone(), banana_entry);
+        map.put(apple.clone().into(), apple_entry.clone());
+        map.put(banana.clone().into(), banana_entry.clone());
 
         match map.delete(&apple) {
             Some(deleted_entry) => {
@@ -227,8 +232,8 @@ mod tests {
         let banana = b"banana".to_vec();
         let banana_entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        btree.put(apple.clone(), apple_entry.clone());
-        btree.put(banana.clone(), banana_entry.clone());
+        btree.put(apple.clone().into(), apple_entry.clone());
+        btree.put(banana.clone().into(), banana_entry.clone());
 
         let mut iterator = match btree.iter() {
             IndexIteratorMode::BTree(iter) => iter,
@@ -237,7 +242,7 @@ mod tests {
 
         let mut results = Vec::new();
         while let Some((key, entry)) = iterator.next() {
-            results.push((key.clone(), entry.clone()));
+            results.push((key.to_vec(), entry.clone()));
         }
 
         assert_eq!(results.len(), 2);
@@ -252,7 +257,7 @@ mod tests {
         let key = b"key".to_vec();
         let entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        btree.put(key.clone(), entry.clone());
+        btree.put(key.clone().into(), entry.clone());
 
         let mut iterator = match btree.iter() {
             IndexIteratorMode::BTree(iter) => iter,
@@ -284,16 +289,16 @@ mod tests {
         let key3 = b"cherry".to_vec();
         let entry3 = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        btree.put(key1.clone(), entry1.clone());
-        btree.put(key2.clone(), entry2.clone());
-        btree.put(key3.clone(), entry3.clone());
+        btree.put(key1.clone().into(), entry1.clone());
+        btree.put(key2.clone().into(), entry2.clone());
+        btree.put(key3.clone().into(), entry3.clone());
 
         let mut iterator = match btree.iter() {
             IndexIteratorMode::BTree(iter) => iter,
             _ => panic!("Unexpected iterator type"),
         };
 
-        iterator.seek(b"banana".to_vec());
+        iterator.seek(b"banana");
 
         if let Some((iter_key, iter_entry)) = iterator.next() {
             assert_eq!(iter_key, &key2);
@@ -311,4 +316,54 @@ mod tests {
 
         assert!(iterator.next().is_none());
     }
+
+    #[test]
+    fn test_btree_iterator_seek_borrowed() {
+        let index = BTree::new();
+        for key in ["apple", "cherry"] {
+            index.put(
+                key.as_bytes().into(),
+                KeyDirEntry::new(random_u32(), random_u64(), random_u32()),
+            );
+        }
+
+        let mut iterator = match index.iter() {
+            IndexIteratorMode::BTree(iter) => iter,
+            _ => panic!("Unexpected iterator type"),
+        };
+
+        // Seeking between keys lands on the next one
+        let target = String::from("banana");
+        iterator.seek(target.as_bytes());
+        assert_eq!(
+            iterator.next().map(|(k, _)| k.to_vec()),
+            Some(b"cherry".to_vec())
+        );
+        iterator.seek(&target.as_bytes()[..0]);
+        assert_eq!(
+            iterator.next().map(|(k, _)| k.to_vec()),
+            Some(b"apple".to_vec())
+        );
+        iterator.seek(b"date");
+        assert!(iterator.next().is_none());
+    }
+
+    #[test]
+    fn test_btree_iterates_u64_keys_numerically() {
+        let map = BTree::new();
+        let numbers = [1000u64, 3, u64::MAX, 0, 256, 255, 1 << 32];
+        for n in numbers {
+            let entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
+            map.put(crate::encode_u64_key(n).to_vec().into(), entry);
+        }
+
+        let mut iter = map.iter();
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(crate::decode_u64_key(key).unwrap());
+        }
+        let mut sorted = numbers.to_vec();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
 }
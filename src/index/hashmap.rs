@@ -1,28 +1,32 @@
-use super::{IndexIterator, IndexIteratorMode, Indexer};
+use super::{IndexIterator, IndexIteratorMode, IndexKey, Indexer, KeyComparator};
 use crate::{KeyDirEntry, Result};
 use bytes::Bytes;
 use dashmap::DashMap;
-use std::sync::Arc;
+use std::{cmp::Ordering, sync::Arc};
 
 #[derive(Debug, Clone)]
-pub struct HashMap(Arc<DashMap<Vec<u8>, KeyDirEntry>>);
+pub struct HashMap {
+    map: Arc<DashMap<IndexKey, KeyDirEntry>>,
+    // The order `iter` sorts its snapshot in, byte order if `None`
+    comparator: Option<KeyComparator>,
+}
 
 impl Indexer for HashMap {
-    fn put(&self, key: Vec<u8>, entry: KeyDirEntry) -> Option<KeyDirEntry> {
-        self.0.insert(key, entry)
+    fn put(&self, key: IndexKey, entry: KeyDirEntry) -> Option<KeyDirEntry> {
+        self.map.insert(key, entry)
     }
 
     fn get(&self, key: &[u8]) -> Option<KeyDirEntry> {
-        self.0.get(key).map(|r| *r.value())
+        self.map.get(key).map(|r| r.value().clone())
     }
 
     fn delete(&self, key: &[u8]) -> Option<KeyDirEntry> {
-        self.0.remove(key).map(|(_, v)| v)
+        self.map.remove(key).map(|(_, v)| v)
     }
 
     fn list_keys(&self) -> Result<Vec<Bytes>> {
         Ok(self
-            .0
+            .map
             .iter()
             .map(|r| Bytes::copy_from_slice(r.key()))
             .collect::<Vec<Bytes>>())
@@ -31,12 +35,18 @@ impl Indexer for HashMap {
     #[allow(clippy::clone_on_copy)]
     fn iter(&self) -> IndexIteratorMode {
         let mut items = self
-            .0
+            .map
             .iter()
-            .map(|r| (r.key().clone(), *r.value()))
-            .collect::<Vec<(Vec<u8>, KeyDirEntry)>>();
-        items.sort_by(|a, b| a.0.cmp(&b.0));
-        HashMapIterator { items, index: 0 }.into()
+            .map(|r| (r.key().clone(), r.value().clone()))
+            .collect::<Vec<(IndexKey, KeyDirEntry)>>();
+        let comparator = self.comparator.clone();
+        items.sort_by(|a, b| compare(&comparator, &a.0, &b.0));
+        HashMapIterator {
+            items,
+            index: 0,
+            comparator,
+        }
+        .into()
     }
 }
 
@@ -45,14 +55,17 @@ impl IndexIterator for HashMapIterator {
         self.index = 0;
     }
 
-    fn seek(&mut self, key: Vec<u8>) {
-        self.index = match self.items.binary_search_by(|(k, _)| k.cmp(&key)) {
+    fn seek(&mut self, key: &[u8]) {
+        self.index = match self
+            .items
+            .binary_search_by(|(k, _)| compare(&self.comparator, k, key))
+        {
             Ok(equal_val) => equal_val,
             Err(insert_val) => insert_val,
         };
     }
 
-    fn next(&mut self) -> Option<(&Vec<u8>, &KeyDirEntry)> {
+    fn next(&mut self) -> Option<(&[u8], &KeyDirEntry)> {
         if self.index >= self.items.len() {
             return None;
         }
@@ -66,13 +79,33 @@ impl IndexIterator for HashMapIterator {
 
 #[derive(Debug, Clone)]
 pub struct HashMapIterator {
-    items: Vec<(Vec<u8>, KeyDirEntry)>,
+    items: Vec<(IndexKey, KeyDirEntry)>,
     index: usize,
+    comparator: Option<KeyComparator>,
 }
 
 impl HashMap {
     pub fn new() -> Self {
-        Self(Arc::new(DashMap::new()))
+        Self {
+            map: Arc::new(DashMap::new()),
+            comparator: None,
+        }
+    }
+
+    /// An empty index whose iterators order keys by `comparator`, so range
+    /// scans over it agree with an index kept in that order.
+    pub fn with_comparator(comparator: KeyComparator) -> Self {
+        Self {
+            map: Arc::new(DashMap::new()),
+            comparator: Some(comparator),
+        }
+    }
+}
+
+fn compare(comparator: &Option<KeyComparator>, a: &[u8], b: &[u8]) -> Ordering {
+    match comparator {
+        Some(comparator) => comparator.compare(a, b),
+        None => a.cmp(b),
     }
 }
 
@@ -104,7 +137,7 @@ mod tests {
         let key = b"key".to_vec();
         let value = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        let result = map.put(key.clone(), value);
+        let result = map.put(key.clone().into(), value.clone());
         assert!(result.is_none(), "Expected None, got {:?}", result);
 
         let retrieved = map.get(&key).unwrap();
@@ -123,8 +156,8 @@ mod tests {
 
         let value2 = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(key.clone(), value1);
-        let result = map.put(key.clone(), value2);
+        map.put(key.clone().into(), value1.clone());
+        let result = map.put(key.clone().into(), value2);
         assert!(result.is_some(), "Expected Some, got None");
 
         let retrieved = result.unwrap();
@@ -143,8 +176,8 @@ mod tests {
         let banana = b"banana".to_vec();
         let banana_entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(apple.clone(), apple_entry);
-        map.put(banana.clone(), banana_entry);
+        map.put(apple.clone().into(), apple_entry.clone());
+        map.put(banana.clone().into(), banana_entry.clone());
 
         match map.get(&apple) {
             Some(retrieved) => {
@@ -185,8 +218,8 @@ mod tests {
         let banana = b"banana".to_vec();
         let banana_entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(apple.clone(), apple_entry);
-        map.put(banana.clone(), banana_entry);
+        map.put(apple.clone().into(), apple_entry.clone());
+        map.put(banana.clone().into(), banana_entry.clone());
 
         match map.delete(&apple) {
             Some(deleted_entry) => {
@@ -227,8 +260,8 @@ mod tests {
         let banana = b"banana".to_vec();
         let banana_entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(apple.clone(), apple_entry.clone());
-        map.put(banana.clone(), banana_entry.clone());
+        map.put(apple.clone().into(), apple_entry.clone());
+        map.put(banana.clone().into(), banana_entry.clone());
 
         let mut iterator = match map.iter() {
             IndexIteratorMode::HashMap(iter) => iter,
@@ -237,7 +270,7 @@ mod tests {
 
         let mut results = Vec::new();
         while let Some((key, entry)) = iterator.next() {
-            results.push((key.clone(), entry.clone()));
+            results.push((key.to_vec(), entry.clone()));
         }
 
         assert_eq!(results.len(), 2);
@@ -252,7 +285,7 @@ mod tests {
         let key = b"key".to_vec();
         let entry = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(key.clone(), entry.clone());
+        map.put(key.clone().into(), entry.clone());
 
         let mut iterator = match map.iter() {
             IndexIteratorMode::HashMap(iter) => iter,
@@ -284,16 +317,16 @@ mod tests {
         let key3 = b"cherry".to_vec();
         let entry3 = KeyDirEntry::new(random_u32(), random_u64(), random_u32());
 
-        map.put(key1.clone(), entry1.clone());
-        map.put(key2.clone(), entry2.clone());
-        map.put(key3.clone(), entry3.clone());
+        map.put(key1.clone().into(), entry1.clone());
+        map.put(key2.clone().into(), entry2.clone());
+        map.put(key3.clone().into(), entry3.clone());
 
         let mut iterator = match map.iter() {
             IndexIteratorMode::HashMap(iter) => iter,
             _ => panic!("Unexpected iterator type"),
         };
 
-        iterator.seek(b"banana".to_vec());
+        iterator.seek(b"banana");
 
         if let Some((iter_key, iter_entry)) = iterator.next() {
             assert_eq!(iter_key, &key2);
@@ -311,4 +344,87 @@ mod tests {
 
         assert!(iterator.next().is_none());
     }
+
+    #[test]
+    fn test_hashmap_iterator_seek_borrowed() {
+        let index = HashMap::new();
+        for key in ["apple", "cherry"] {
+            index.put(
+                key.as_bytes().into(),
+                KeyDirEntry::new(random_u32(), random_u64(), random_u32()),
+            );
+        }
+
+        let mut iterator = match index.iter() {
+            IndexIteratorMode::HashMap(iter) => iter,
+            _ => panic!("Unexpected iterator type"),
+        };
+
+        // Seeking between keys lands on the next one
+        let target = String::from("banana");
+        iterator.seek(target.as_bytes());
+        assert_eq!(
+            iterator.next().map(|(k, _)| k.to_vec()),
+            Some(b"cherry".to_vec())
+        );
+        iterator.seek(&target.as_bytes()[..0]);
+        assert_eq!(
+            iterator.next().map(|(k, _)| k.to_vec()),
+            Some(b"apple".to_vec())
+        );
+        iterator.seek(b"date");
+        assert!(iterator.next().is_none());
+    }
+
+    // The keys in `[start, end)` of the ordered `index`, by `less`
+    fn range(index: &impl Indexer, start: &[u8], less: impl Fn(&[u8]) -> bool) -> Vec<Vec<u8>> {
+        let mut iter = index.iter();
+        iter.seek(start);
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            if !less(key) {
+                break;
+            }
+            keys.push(key.to_vec());
+        }
+        keys
+    }
+
+    #[test]
+    fn test_hashmap_iterator_comparator() {
+        let number = |key: &[u8]| std::str::from_utf8(key).unwrap().parse::<u32>().unwrap();
+        let numeric = KeyComparator::new(move |a, b| number(a).cmp(&number(b)));
+        let hashmap = HashMap::with_comparator(numeric.clone());
+        let unordered = HashMap::new();
+        // Zero-padded, so byte order is numeric order
+        let btree = crate::index::BTree::new();
+        for i in 0..50u32 {
+            let entry = KeyDirEntry::new(0, i as u64, 1);
+            hashmap.put(i.to_string().as_bytes().into(), entry.clone());
+            unordered.put(i.to_string().as_bytes().into(), entry.clone());
+            btree.put(format!("{:03}", i).as_bytes().into(), entry);
+        }
+
+        let ranged = range(&hashmap, b"7", |key| number(key) < 23);
+        let from_btree = range(&btree, b"007", |key| key < b"023".as_slice());
+        assert_eq!(ranged.len(), 16);
+        assert_eq!(
+            ranged.iter().map(|key| number(key)).collect::<Vec<_>>(),
+            from_btree.iter().map(|key| number(key)).collect::<Vec<_>>()
+        );
+        // In byte order "10" to "22" sort before "7" and are missed
+        assert_eq!(
+            range(&unordered, b"7", |key| number(key) < 23),
+            vec![b"7".to_vec(), b"8".to_vec(), b"9".to_vec()]
+        );
+
+        // Seeking a key that isn't there lands on the next one in its order
+        hashmap.delete(b"7");
+        let mut iter = hashmap.iter();
+        iter.seek(b"7");
+        assert_eq!(
+            iter.next().map(|(key, _)| key.to_vec()),
+            Some(b"8".to_vec())
+        );
+    }
 }
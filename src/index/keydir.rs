@@ -1,18 +1,40 @@
 use bytes::BytesMut;
 use prost::encoding::encode_varint;
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
 pub struct KeyDirEntry {
     file_id: u32,
     offset: u64,
     size: u32,
+    // Copy of a small value kept in memory so `get` can skip the data file.
+    // The data file still holds the authoritative record.
+    inline_value: Option<Arc<[u8]>>,
+    // Under `Opts::max_db_size`, whether the key was read since it was
+    // written. Shared with the entry's clones, so a read through the copy
+    // the index hands out marks the index's own.
+    referenced: Option<Arc<AtomicBool>>,
+}
+
+// Whether a key was read says nothing about where it's stored
+impl PartialEq for KeyDirEntry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.file_id, self.offset, self.size, &self.inline_value)
+            == (other.file_id, other.offset, other.size, &other.inline_value)
+    }
 }
 
+impl Eq for KeyDirEntry {}
+
 impl KeyDirEntry {
     pub fn new(file_id: u32, offset: u64, size: u32) -> Self {
         Self {
             file_id,
             offset,
             size,
+            inline_value: None,
+            referenced: None,
         }
     }
 
@@ -35,4 +57,30 @@ impl KeyDirEntry {
     pub fn get_size(&self) -> u32 {
         self.size
     }
+
+    pub fn set_inline_value(&mut self, value: &[u8]) {
+        self.inline_value = Some(Arc::from(value));
+    }
+
+    pub fn get_inline_value(&self) -> Option<&[u8]> {
+        self.inline_value.as_deref()
+    }
+
+    /// Starts tracking reads of the entry, as unread.
+    pub(crate) fn track_references(&mut self) {
+        self.referenced = Some(Arc::new(AtomicBool::new(false)));
+    }
+
+    /// Records a read of the entry, if reads of it are tracked.
+    pub(crate) fn mark_referenced(&self) {
+        if let Some(referenced) = &self.referenced {
+            referenced.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn is_referenced(&self) -> bool {
+        self.referenced
+            .as_ref()
+            .is_some_and(|referenced| referenced.load(Ordering::Relaxed))
+    }
 }
@@ -1,37 +1,89 @@
+//! The in-memory indexes mapping keys to where their values are stored.
+//!
+//! They can be used on their own, or behind other storage:
+//!
+//! ```
+//! use zap::index::{BTree, IndexIterator, Indexer};
+//! use zap::KeyDirEntry;
+//!
+//! let index = BTree::new();
+//! index.put(b"b".as_slice().into(), KeyDirEntry::new(0, 40, 20));
+//! index.put(b"a".as_slice().into(), KeyDirEntry::new(0, 0, 20));
+//! assert_eq!(index.get(b"a").unwrap().get_offset(), 0);
+//!
+//! let mut iter = index.iter();
+//! let mut keys = Vec::new();
+//! while let Some((key, entry)) = iter.next() {
+//!     keys.push((key.to_vec(), entry.get_offset()));
+//! }
+//! assert_eq!(keys, vec![(b"a".to_vec(), 0), (b"b".to_vec(), 40)]);
+//! ```
+
 mod btree;
 mod hashmap;
 mod keydir;
-pub use btree::BTree;
-use btree::BTreeIterator;
-pub use hashmap::HashMap;
-use hashmap::HashMapIterator;
+pub use btree::{BTree, BTreeIterator};
+pub use hashmap::{HashMap, HashMapIterator};
 pub use keydir::KeyDirEntry;
 
 use crate::Result;
 use bytes::Bytes;
 use enum_dispatch::enum_dispatch;
+use std::{cmp::Ordering, fmt, sync::Arc};
 
-#[allow(dead_code)]
+/// Keys are stored as boxed slices, which are two words smaller than a
+/// `Vec<u8>` and carry no spare capacity.
+pub type IndexKey = Box<[u8]>;
+
+/// An order for keys other than byte order, for index iterators to walk
+/// them in.
+#[derive(Clone)]
+pub struct KeyComparator(Arc<CompareFn>);
+
+type CompareFn = dyn Fn(&[u8], &[u8]) -> Ordering + Send + Sync;
+
+impl KeyComparator {
+    pub fn new(compare: impl Fn(&[u8], &[u8]) -> Ordering + Send + Sync + 'static) -> Self {
+        KeyComparator(Arc::new(compare))
+    }
+
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        (self.0)(a, b)
+    }
+}
+
+impl fmt::Debug for KeyComparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyComparator")
+    }
+}
+
+/// A map from keys to their positions, shared between threads.
 #[enum_dispatch(IndexMode)]
-pub(crate) trait Indexer: Send + Sync {
-    fn put(&self, key: Vec<u8>, entry: KeyDirEntry) -> Option<KeyDirEntry>;
+pub trait Indexer: Send + Sync {
+    /// Sets the position of `key`, returning the one it replaces.
+    fn put(&self, key: IndexKey, entry: KeyDirEntry) -> Option<KeyDirEntry>;
 
     fn get(&self, key: &[u8]) -> Option<KeyDirEntry>;
 
+    /// Removes `key`, returning its position.
     fn delete(&self, key: &[u8]) -> Option<KeyDirEntry>;
 
+    /// Every key, in no particular order.
     fn list_keys(&self) -> Result<Vec<Bytes>>;
 
+    /// An iterator over a snapshot of the index, in key order.
     fn iter(&self) -> IndexIteratorMode;
 }
 
+#[allow(dead_code)]
 #[enum_dispatch(IndexIteratorMode)]
 pub trait IndexIterator: Sync + Send {
     fn rewind(&mut self);
 
-    fn seek(&mut self, key: Vec<u8>);
+    fn seek(&mut self, key: &[u8]);
 
-    fn next(&mut self) -> Option<(&Vec<u8>, &KeyDirEntry)>;
+    fn next(&mut self) -> Option<(&[u8], &KeyDirEntry)>;
 }
 
 #[enum_dispatch]
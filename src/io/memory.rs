@@ -0,0 +1,69 @@
+use crate::{Error, Result};
+use parking_lot::RwLock;
+use std::{io::ErrorKind, sync::Arc};
+
+use super::IOHandler;
+
+/// A data file held in a growable buffer instead of on disk, for databases
+/// opened with `Db::open_in_memory`. Clones share the buffer, as clones of
+/// the other backends share their file.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryIO {
+    data: Arc<RwLock<Vec<u8>>>,
+}
+
+impl IOHandler for MemoryIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let data = self.data.read();
+        if offset >= data.len() as u64 {
+            return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+        }
+        // A read running past the end is short, as it is for the others
+        let end = (offset + buf.len() as u64).min(data.len() as u64);
+        let val = &data[offset as usize..end as usize];
+        buf[..val.len()].copy_from_slice(val);
+        Ok(val.len())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.data.write().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.read().len() as u64)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.data.write().truncate(len as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_io() -> Result<()> {
+        let mut io = MemoryIO::default();
+        assert_eq!(io.write(b"hello world")?, 11);
+        let shared = io.clone();
+        assert_eq!(shared.size()?, 11);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(shared.read(&mut buf, 6)?, 5);
+        assert_eq!(&buf, b"world");
+        // Short at the end, and past it nothing to read
+        assert_eq!(shared.read(&mut buf, 8)?, 3);
+        assert!(shared.read(&mut buf, 11).is_err());
+
+        io.truncate(5)?;
+        assert_eq!(shared.size()?, 5);
+        Ok(())
+    }
+}
@@ -1,13 +1,12 @@
 use crate::{Error, Result};
 use memmap2::Mmap;
-use parking_lot::Mutex;
-use std::{fs::OpenOptions, io::ErrorKind, path::Path, sync::Arc};
+use std::{fs::OpenOptions, io::ErrorKind, ops::Deref, path::Path, sync::Arc};
 
 use super::IOHandler;
 
 #[derive(Debug, Clone)]
 pub struct MmapIO {
-    mmap: Arc<Mutex<Mmap>>,
+    mmap: Arc<Mmap>,
 }
 
 #[allow(dead_code)]
@@ -22,21 +21,80 @@ impl MmapIO {
         let mmap = unsafe { Mmap::map(&file)? };
 
         Ok(MmapIO {
-            mmap: Arc::new(Mutex::new(mmap)),
+            mmap: Arc::new(mmap),
         })
     }
+
+    /// Maps an existing file without asking for write access, for read-only
+    /// databases on read-only files or media.
+    pub fn open_read_only(file_name: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(file_name)?;
+
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        Ok(MmapIO {
+            mmap: Arc::new(mmap),
+        })
+    }
+
+    /// Asks the kernel to read the mapped file into the page cache ahead of
+    /// use.
+    pub fn will_need(&self) -> Result<()> {
+        if self.mmap.is_empty() {
+            return Ok(());
+        }
+        self.mmap.advise(memmap2::Advice::WillNeed)?;
+        Ok(())
+    }
+
+    /// Borrows `len` bytes at `offset` without copying them out of the mapping.
+    pub fn slice(&self, offset: u64, len: usize) -> Result<MmapSlice> {
+        let end = offset + len as u64;
+        if end > self.mmap.len() as u64 {
+            return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(MmapSlice {
+            mmap: self.mmap.clone(),
+            start: offset as usize,
+            end: end as usize,
+        })
+    }
+}
+
+/// A range of a mapped file. The mapping stays alive while the slice exists,
+/// even if the file itself is removed.
+#[derive(Debug, Clone)]
+pub struct MmapSlice {
+    mmap: Arc<Mmap>,
+    start: usize,
+    end: usize,
+}
+
+impl Deref for MmapSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.mmap[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for MmapSlice {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
 }
 
 #[allow(dead_code)]
 impl IOHandler for MmapIO {
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        let mmap_buffer = self.mmap.lock();
-        let end = offset + buf.len() as u64;
-        if end > mmap_buffer.len() as u64 {
+        let mmap_buffer = &self.mmap;
+        if offset >= mmap_buffer.len() as u64 {
             return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
         }
+        // Like `read_at`, a read running past the end of the file is short
+        let end = (offset + buf.len() as u64).min(mmap_buffer.len() as u64);
         let val = &mmap_buffer[offset as usize..end as usize];
-        buf.copy_from_slice(val);
+        buf[..val.len()].copy_from_slice(val);
 
         Ok(val.len())
     }
@@ -49,7 +107,13 @@ impl IOHandler for MmapIO {
         unimplemented!()
     }
 
-    fn get_file_id(&self) -> u32 {
-        unimplemented!()
+    fn size(&self) -> Result<u64> {
+        Ok(self.mmap.len() as u64)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<()> {
+        Err(Error::Unsupported(
+            "Mmap does not support truncate".to_string(),
+        ))
     }
 }
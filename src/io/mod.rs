@@ -1,8 +1,10 @@
+mod memory;
 mod mmap;
 mod standard;
 use crate::result::Result;
 use enum_dispatch::enum_dispatch;
-pub use mmap::MmapIO;
+pub use memory::MemoryIO;
+pub use mmap::{MmapIO, MmapSlice};
 pub use standard::StandardIO;
 
 #[derive(Debug, Clone)]
@@ -10,6 +12,7 @@ pub use standard::StandardIO;
 pub enum IO {
     Standard(StandardIO),
     Mmap(MmapIO),
+    Memory(MemoryIO),
 }
 
 #[enum_dispatch(IO)]
@@ -17,5 +20,8 @@ pub trait IOHandler: Send + Sync {
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
     fn sync(&self) -> Result<()>;
-    fn get_file_id(&self) -> u32;
+    /// Current length of the underlying file.
+    fn size(&self) -> Result<u64>;
+    /// Cuts the file back to `len` bytes.
+    fn truncate(&self, len: u64) -> Result<()>;
 }
@@ -9,9 +9,20 @@ use std::{
     sync::Arc,
 };
 
+#[cfg(feature = "sim")]
+use crate::sim::{self, Op, SimFile};
+#[cfg(test)]
+use std::time::Duration;
+
 #[derive(Debug, Clone)]
 pub struct StandardIO {
     fd: Arc<RwLock<File>>,
+    // Slept before every read, to stand in for a slow disk
+    #[cfg(test)]
+    read_delay: Option<Duration>,
+    // Set for files in a directory under `Simulation`
+    #[cfg(feature = "sim")]
+    sim: Option<SimFile>,
 }
 
 #[allow(dead_code)]
@@ -24,37 +35,160 @@ impl StandardIO {
             .open(path)?;
         Ok(StandardIO {
             fd: Arc::new(RwLock::new(file)),
+            #[cfg(test)]
+            read_delay: None,
+            #[cfg(feature = "sim")]
+            sim: sim::track(path),
         })
     }
+
+    /// Opens an existing file for reading only; writes to it fail.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(StandardIO {
+            fd: Arc::new(RwLock::new(file)),
+            #[cfg(test)]
+            read_delay: None,
+            #[cfg(feature = "sim")]
+            sim: sim::track(path),
+        })
+    }
+
+    /// Asks the kernel to read the file into the page cache ahead of use.
+    pub fn will_need(&self) -> Result<()> {
+        let read_guard = self.fd.read();
+        let ret =
+            unsafe { libc::posix_fadvise(read_guard.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+        if ret != 0 {
+            return Err(Error::Io(std::io::Error::from_raw_os_error(ret)));
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_read_delay(mut self, delay: Duration) -> Self {
+        self.read_delay = Some(delay);
+        self
+    }
 }
 
 impl IOHandler for StandardIO {
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        let read_guard = self.fd.read();
-        read_guard.read_at(buf, offset).map_err(Error::from)
+        #[cfg(test)]
+        if let Some(delay) = self.read_delay {
+            std::thread::sleep(delay);
+        }
+        #[cfg(feature = "sim")]
+        let len = buf.len();
+        let mut read = || {
+            let read_guard = self.fd.read();
+            read_guard.read_at(buf, offset).map_err(Error::from)
+        };
+        #[cfg(feature = "sim")]
+        if let Some(sim) = &self.sim {
+            return sim.run(Op::Read { offset, len }, read);
+        }
+        read()
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let mut write_guard = self.fd.write();
-        write_guard.write(buf).map_err(Error::from)
+        // Appends go through `&File` so reads of earlier records don't wait
+        // on them; writers to a file are already serialized by its owner
+        let write = || {
+            let read_guard = self.fd.read();
+            write_full(&mut &*read_guard, buf)
+        };
+        #[cfg(feature = "sim")]
+        if let Some(sim) = &self.sim {
+            return sim.run(Op::Write { len: buf.len() }, write);
+        }
+        write()
     }
 
     fn sync(&self) -> Result<()> {
-        let read_guard = self.fd.read();
-        read_guard.sync_all().map_err(Error::from)
+        let sync = || {
+            let read_guard = self.fd.read();
+            read_guard.sync_all().map_err(Error::from)
+        };
+        #[cfg(feature = "sim")]
+        if let Some(sim) = &self.sim {
+            return sim.run(Op::Sync, sync);
+        }
+        sync()
     }
-    fn get_file_id(&self) -> u32 {
+
+    fn size(&self) -> Result<u64> {
         let read_guard = self.fd.read();
-        read_guard.as_raw_fd() as u32
+        Ok(read_guard.metadata()?.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        let truncate = || {
+            let read_guard = self.fd.read();
+            read_guard.set_len(len).map_err(Error::from)
+        };
+        #[cfg(feature = "sim")]
+        if let Some(sim) = &self.sim {
+            return sim.run(Op::Truncate { len }, truncate);
+        }
+        truncate()
     }
 }
 
+// A single `write` may be short or interrupted; keep going until the whole
+// record lands so callers can advance their offset by the full length.
+fn write_full<W: Write>(writer: &mut W, buf: &[u8]) -> Result<usize> {
+    writer.write_all(buf).map_err(Error::from)?;
+    Ok(buf.len())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use std::{
+        fs,
+        io::{self, ErrorKind},
+    };
 
     use super::*;
 
+    // A writer that accepts at most `chunk` bytes per call and reports an
+    // interruption on every other call.
+    struct ShortWriter {
+        data: Vec<u8>,
+        chunk: usize,
+        calls: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(self.chunk);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_full_handles_short_writes() {
+        let mut writer = ShortWriter {
+            data: Vec::new(),
+            chunk: 3,
+            calls: 0,
+        };
+        let buf = b"a record that needs several short writes";
+        let written = write_full(&mut writer, buf);
+        assert!(written.is_ok());
+        assert_eq!(buf.len(), written.ok().unwrap());
+        assert_eq!(writer.data.as_slice(), buf.as_slice());
+    }
+
     fn check_and_delete(path: &Path) {
         if path.exists() {
             fs::remove_file(path).unwrap()
@@ -0,0 +1,356 @@
+use crate::db::{Db, ENTRY_REMOVED};
+use crate::index::{IndexIterator, IndexIteratorMode, Indexer};
+use crate::{Error, Result};
+use bytes::Bytes;
+
+/// Iterator over key-value pairs in key order, returned by `Db::iter` and
+/// `Db::iter_from`.
+///
+/// The keys are taken from the index when it's created; keys deleted since
+/// are skipped, as are keys whose index entry points at a tombstone, and
+/// values are read as they are when reached. `position` is the last key
+/// yielded, which `Db::iter_from` resumes after, so a long scan can be
+/// checkpointed and picked up again after a restart.
+///
+/// ```
+/// use bytes::Bytes;
+/// use zap::{db::Db, Opts};
+///
+/// # fn main() -> zap::Result<()> {
+/// let opts = Opts::new(256, 1024, false, false, "/tmp/doc_db_iter".to_string(), 1 << 20);
+/// # let _ = std::fs::remove_dir_all(&opts.dir_path);
+/// let db = Db::open(&opts)?;
+/// db.put(Bytes::from("b"), Bytes::from("2"))?;
+/// db.put(Bytes::from("a"), Bytes::from("1"))?;
+///
+/// let mut pairs = Vec::new();
+/// for pair in db.iter() {
+///     let (key, value) = pair?;
+///     pairs.push((key, value));
+/// }
+/// assert_eq!(pairs, [(Bytes::from("a"), Bytes::from("1")), (Bytes::from("b"), Bytes::from("2"))]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct DbIter<'a> {
+    db: &'a Db,
+    index_iter: IndexIteratorMode,
+    position: Option<Bytes>,
+}
+
+impl Db {
+    /// Iterates over every key and its value, in the index's key order.
+    pub fn iter(&self) -> DbIter<'_> {
+        DbIter {
+            db: self,
+            index_iter: self.ctx.index.iter(),
+            position: None,
+        }
+    }
+
+    pub fn keys(&self) -> KeysIter {
+        KeysIter {
+            index_iter: self.ctx.index.iter(),
+        }
+    }
+
+    /// Every key and its value, in the index's key order, for databases
+    /// small enough to hold in memory.
+    ///
+    /// Unlike collecting `iter`, the index is walked once, which sorts a
+    /// hash index once and walks a BTree as it is, and the values are read
+    /// in file and offset order so the data files are read front to back. A
+    /// value moved since the walk is looked up again.
+    pub fn to_sorted_vec(&self) -> Result<Vec<(Bytes, Bytes)>> {
+        let mut entries = Vec::new();
+        let mut index_iter = self.ctx.index.iter();
+        while let Some((key, entry)) = index_iter.next() {
+            entries.push((Bytes::copy_from_slice(key), entry.clone()));
+        }
+        drop(index_iter);
+
+        let mut read_order = (0..entries.len()).collect::<Vec<_>>();
+        read_order.sort_by_key(|&i| (entries[i].1.get_file_id(), entries[i].1.get_offset()));
+        let mut values = vec![None; entries.len()];
+        for i in read_order {
+            let (key, entry) = &entries[i];
+            values[i] = match entry.get_inline_value() {
+                Some(value) => Some(Bytes::copy_from_slice(value)),
+                None => match self.read_data_entry(key, entry.clone()) {
+                    Ok(data_entry) => Some(Bytes::from(data_entry.into_value())),
+                    // Rewritten or deleted since the walk
+                    Err(Error::FileNotFound(_)) => self.get_seq(key.clone())?.map(|(v, _)| v),
+                    Err(e) => return Err(e),
+                },
+            };
+        }
+        Ok(entries
+            .into_iter()
+            .zip(values)
+            .filter_map(|((key, _), value)| Some((key, value?)))
+            .collect())
+    }
+
+    /// Iterates over the keys after `key`, as saved from `DbIter::position`.
+    pub fn iter_from(&self, key: Bytes) -> DbIter<'_> {
+        let mut index_iter = self.ctx.index.iter();
+        index_iter.seek(&key);
+        DbIter {
+            db: self,
+            index_iter,
+            position: Some(key),
+        }
+    }
+}
+
+/// Iterator over the keys in the index when it was created, in key order,
+/// returned by `Db::keys`. Reads no data files.
+///
+/// ```
+/// use bytes::Bytes;
+/// use zap::{db::Db, Opts};
+///
+/// # fn main() -> zap::Result<()> {
+/// let opts = Opts::new(256, 1024, false, false, "/tmp/doc_db_keys".to_string(), 1 << 20);
+/// # let _ = std::fs::remove_dir_all(&opts.dir_path);
+/// let db = Db::open(&opts)?;
+/// for key in ["user:2", "order:1", "user:1"] {
+///     db.put(Bytes::from(key), Bytes::from("value"))?;
+/// }
+/// let users = db.keys().filter(|key| key.starts_with(b"user:")).collect::<Vec<_>>();
+/// assert_eq!(users, ["user:1", "user:2"]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KeysIter {
+    index_iter: IndexIteratorMode,
+}
+
+impl Iterator for KeysIter {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        self.index_iter
+            .next()
+            .map(|(key, _)| Bytes::copy_from_slice(key))
+    }
+}
+
+impl DbIter<'_> {
+    /// The last key yielded, or the key the iterator was resumed from.
+    /// `None` before the first key.
+    pub fn position(&self) -> Option<&Bytes> {
+        self.position.as_ref()
+    }
+}
+
+impl Iterator for DbIter<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = Bytes::copy_from_slice(self.index_iter.next()?.0);
+            // `seek` stops on the resumed key itself
+            if self.position.as_ref() == Some(&key) {
+                continue;
+            }
+            let value = match self.db.get_seq(key.clone()) {
+                Ok(Some((value, _))) => value,
+                // Deleted since the iterator was created
+                Ok(None) => continue,
+                // An index keeping a delete, as replaying a replica's log
+                // may, points at the tombstone
+                Err(Error::Unsupported(message)) if message == ENTRY_REMOVED => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            self.position = Some(key.clone());
+            return Some(Ok((key, value)));
+        }
+    }
+}
+
+impl std::fmt::Debug for DbIter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DbIter")
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::KeyDirEntry;
+    use crate::Opts;
+
+    #[test]
+    fn test_to_sorted_vec() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_to_sorted_vec".to_string(),
+            4096,
+        );
+        opts.inline_value_threshold = 4;
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..500u32 {
+            // Keys out of write order, overwritten across files
+            let key = Bytes::from(format!("key{}", i.wrapping_mul(7919) % 300));
+            let value = Bytes::from(if i % 3 == 0 {
+                format!("{}", i % 100)
+            } else {
+                format!("value{}", i)
+            });
+            db.put(key.clone(), value.clone())?;
+            expected.insert(key, value);
+        }
+        for i in (0..300).step_by(11) {
+            let key = Bytes::from(format!("key{}", i));
+            db.delete(key.clone())?;
+            expected.remove(&key);
+        }
+        let expected = expected.into_iter().collect::<Vec<_>>();
+        assert!(db.data_file_ids().len() > 1);
+        assert_eq!(db.to_sorted_vec()?, expected);
+
+        // The ordered index gives the same
+        let index = crate::index::BTree::new();
+        for key in db.list_keys()? {
+            index.put(key.to_vec().into(), db.locate(&key).unwrap());
+        }
+        db.ctx.index = index.into();
+        assert_eq!(db.to_sorted_vec()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_skips_tombstones_in_the_index() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_iter_skips_tombstones".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for key in ["a", "b", "c"] {
+            db.put(Bytes::from(key), Bytes::from("value"))?;
+        }
+        let (file_id, start) = {
+            let active_file = db.shards[0].active_file.read();
+            (active_file.get_file_id(), active_file.get_offset())
+        };
+        db.delete(Bytes::from("b"))?;
+        let end = db.shards[0].active_file.read().get_offset();
+        // The delete kept in the index, pointing at its tombstone
+        db.ctx.index.put(
+            b"b".to_vec().into(),
+            KeyDirEntry::new(file_id, start, (end - start) as u32),
+        );
+        assert!(db.get(Bytes::from("b")).is_err());
+
+        let pairs = db.iter().collect::<Result<Vec<_>>>()?;
+        let keys = pairs.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_resumes_from_position() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_iter_resume".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(
+                Bytes::from(format!("key{:03}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        db.delete(Bytes::from("key010"))?;
+
+        let mut iter = db.iter();
+        assert_eq!(iter.position(), None);
+        let mut seen = iter.by_ref().take(50).collect::<Result<Vec<_>>>()?;
+        let saved = iter.position().unwrap().clone();
+        assert_eq!(saved, "key050");
+        drop(iter);
+
+        // Deleted and added keys after the save are reflected on resume
+        db.delete(Bytes::from("key060"))?;
+        db.put(Bytes::from("key0605"), Bytes::from("added"))?;
+        let rest = db.iter_from(saved).collect::<Result<Vec<_>>>()?;
+        assert_eq!(rest[0].0, "key051");
+        seen.extend(rest);
+
+        let mut expected = (0..100)
+            .filter(|i| *i != 10 && *i != 60)
+            .map(|i| format!("key{:03}", i))
+            .collect::<Vec<_>>();
+        expected.push("key0605".to_string());
+        expected.sort();
+        let keys = seen.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+        assert_eq!(seen[0].1, "value0");
+
+        // Resuming from a key that's gone starts at the next one
+        let rest = db.iter_from(Bytes::from("key0600")).next().unwrap()?;
+        assert_eq!(rest.0, "key0605");
+        assert_eq!(
+            db.iter_from(Bytes::from("key099")).next().transpose()?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_in_key_order() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_iter_in_key_order".to_string(),
+            1024 * 1024,
+        );
+        let number = |key: &[u8]| std::str::from_utf8(key).unwrap().parse::<u32>().unwrap();
+        opts.key_order = Some(crate::index::KeyComparator::new(move |a, b| {
+            number(a).cmp(&number(b))
+        }));
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+        for i in (0..30u32).rev() {
+            db.put(Bytes::from(i.to_string()), Bytes::from("value"))?;
+        }
+        let expected = (0..30u32).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(db.keys().collect::<Vec<_>>(), expected);
+        // "10" to "19" sort before "9" in byte order
+        let rest = db.iter_from(Bytes::from("9")).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            rest.first().map(|(key, _)| key.clone()),
+            Some(Bytes::from("10"))
+        );
+        assert_eq!(rest.len(), 20);
+
+        // As is the index rebuilt by replay
+        db.reindex()?;
+        assert_eq!(db.keys().collect::<Vec<_>>(), expected);
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert_eq!(db.keys().collect::<Vec<_>>(), expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Dumping a database as JSON lines and loading one back.
+//!
+//! Each line is an object holding a live key and its value, base64-encoded
+//! as they're arbitrary bytes:
+//!
+//! ```text
+//! {"key_b64":"dXNlcjox","value_b64":"YWxpY2U="}
+//! ```
+//!
+//! Records carry no write time, so there is no `ts`. Other fields, such as
+//! the readable `key` and `value` the CLI adds, are ignored on load.
+
+use crate::db::Db;
+use crate::{Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+// Most lines written in one `put_all` while loading
+const LOAD_BATCH_LEN: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonlRecord {
+    key_b64: String,
+    value_b64: String,
+    // The key and value decoded as UTF-8, for reading only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+impl Db {
+    /// Writes every live key and its value to `w` as a JSON object per line,
+    /// in key order, returning how many were written.
+    pub fn dump_jsonl<W: Write>(&self, w: W) -> Result<usize> {
+        self.write_jsonl(w, false)
+    }
+
+    // `dump_jsonl`, with `utf8_lossy` also writing the key and value as
+    // (lossily decoded) text
+    pub(crate) fn write_jsonl<W: Write>(&self, mut w: W, utf8_lossy: bool) -> Result<usize> {
+        let mut written = 0;
+        for pair in self.iter() {
+            let (key, value) = pair?;
+            let record = JsonlRecord {
+                key_b64: STANDARD.encode(&key),
+                value_b64: STANDARD.encode(&value),
+                key: utf8_lossy.then(|| String::from_utf8_lossy(&key).into_owned()),
+                value: utf8_lossy.then(|| String::from_utf8_lossy(&value).into_owned()),
+            };
+            serde_json::to_writer(&mut w, &record).map_err(|e| Error::Io(e.into()))?;
+            w.write_all(b"\n")?;
+            written += 1;
+        }
+        w.flush()?;
+        Ok(written)
+    }
+
+    /// Stores the pairs of a `dump_jsonl` dump read from `r`, returning how
+    /// many were stored. Blank lines are skipped.
+    ///
+    /// Lines are written in batches as they're read, so a malformed line,
+    /// reported as `Error::Decode` with its line number, fails the load with
+    /// the batches before it already stored.
+    pub fn load_jsonl<R: BufRead>(&mut self, r: R) -> Result<usize> {
+        let mut loaded = 0;
+        let mut pairs = Vec::with_capacity(LOAD_BATCH_LEN);
+        for (index, line) in r.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            pairs.push(
+                decode_line(&line)
+                    .map_err(|e| Error::Decode(format!("line {}: {}", index + 1, e)))?,
+            );
+            if pairs.len() == LOAD_BATCH_LEN {
+                self.put_all(&pairs)?;
+                loaded += pairs.len();
+                pairs.clear();
+            }
+        }
+        self.put_all(&pairs)?;
+        Ok(loaded + pairs.len())
+    }
+}
+
+fn decode_line(line: &str) -> std::result::Result<(Bytes, Bytes), String> {
+    let record: JsonlRecord = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let key = STANDARD
+        .decode(&record.key_b64)
+        .map_err(|e| format!("key_b64: {}", e))?;
+    let value = STANDARD
+        .decode(&record.value_b64)
+        .map_err(|e| format!("value_b64: {}", e))?;
+    Ok((Bytes::from(key), Bytes::from(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use std::fs;
+
+    fn jsonl_opts(name: &str) -> Opts {
+        let opts = Opts::new(256, 1024, false, false, format!("/tmp/{}", name), 4096);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() -> Result<()> {
+        let db = Db::open(&jsonl_opts("test_jsonl_dump"))?;
+        let mut pairs = vec![
+            (Bytes::from(&b"\x00\xffbinary\n"[..]), Bytes::from("value")),
+            (Bytes::from("newlines"), Bytes::from("line 1\nline 2\r\n")),
+            (Bytes::from("quote\"d"), Bytes::from(&b"\x00\x01\x02"[..])),
+            (Bytes::from("empty"), Bytes::new()),
+        ];
+        for i in 0..50 {
+            pairs.push((Bytes::from(format!("key{}", i)), Bytes::from(i.to_string())));
+        }
+        for (key, value) in pairs.iter() {
+            db.put(key.clone(), value.clone())?;
+        }
+        db.put(Bytes::from("deleted"), Bytes::from("value"))?;
+        db.delete(Bytes::from("deleted"))?;
+
+        let mut dump = Vec::new();
+        assert_eq!(db.dump_jsonl(&mut dump)?, pairs.len());
+        let dump = String::from_utf8(dump).unwrap();
+        assert_eq!(dump.lines().count(), pairs.len());
+        assert!(
+            dump.lines()
+                .any(|line| line
+                    == r#"{"key_b64":"bmV3bGluZXM=","value_b64":"bGluZSAxCmxpbmUgMg0K"}"#)
+        );
+
+        let mut copy = Db::open(&jsonl_opts("test_jsonl_load"))?;
+        assert_eq!(
+            copy.load_jsonl(format!("\n{}\n", dump).as_bytes())?,
+            pairs.len()
+        );
+        pairs.sort();
+        let copied = copy.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(copied, pairs);
+
+        // The readable fields don't get in the way of a load
+        let mut readable = Vec::new();
+        db.write_jsonl(&mut readable, true)?;
+        let readable = String::from_utf8(readable).unwrap();
+        assert!(readable.contains(r#""key":"newlines","value":"line 1\nline 2\r\n"}"#));
+        let mut copy = Db::open(&jsonl_opts("test_jsonl_load_readable"))?;
+        assert_eq!(copy.load_jsonl(readable.as_bytes())?, pairs.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_jsonl_reports_malformed_lines() -> Result<()> {
+        let mut db = Db::open(&jsonl_opts("test_jsonl_malformed"))?;
+        let good = r#"{"key_b64":"YQ==","value_b64":"MQ=="}"#;
+        for (input, line) in [
+            (format!("{}\n\n{{\"key_b64\": ", good), 3),
+            (
+                format!("{}\n{{\"key_b64\":\"!!\",\"value_b64\":\"\"}}", good),
+                2,
+            ),
+            (format!("{}\n{{\"value_b64\":\"MQ==\"}}", good), 2),
+        ] {
+            match db.load_jsonl(input.as_bytes()) {
+                Err(Error::Decode(message)) => {
+                    assert!(
+                        message.starts_with(&format!("line {}:", line)),
+                        "{}",
+                        message
+                    )
+                }
+                result => panic!("unexpected {:?}", result),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use crate::db::Db;
+use crate::{Error, Result};
+use bytes::Bytes;
+
+/// Encodes `n` as an 8-byte big-endian key, so numeric order and byte order
+/// agree and ordered scans return integer keys sorted numerically.
+pub fn encode_u64_key(n: u64) -> Bytes {
+    Bytes::copy_from_slice(&n.to_be_bytes())
+}
+
+/// Reverses `encode_u64_key`. Fails if `key` is not 8 bytes long.
+pub fn decode_u64_key(key: &[u8]) -> Result<u64> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| Error::Unsupported(format!("u64 key must be 8 bytes, got {}", key.len())))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl Db {
+    /// Stores `value` under the key `encode_u64_key(n)`.
+    pub fn put_u64(&self, n: u64, value: Bytes) -> Result<()> {
+        self.put(encode_u64_key(n), value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{IndexIterator, Indexer};
+    use crate::Opts;
+    use std::fs;
+
+    #[test]
+    fn test_u64_keys() -> Result<()> {
+        for n in [0, 1, 255, 256, u32::MAX as u64, u64::MAX] {
+            assert_eq!(decode_u64_key(&encode_u64_key(n))?, n);
+        }
+        assert!(decode_u64_key(b"short").is_err());
+
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_u64_keys".to_string(),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        let numbers = [300, 7, u64::MAX, 0, 256, 1 << 40, 255];
+        for n in numbers {
+            db.put_u64(n, Bytes::from(n.to_string()))?;
+        }
+        assert_eq!(db.get(encode_u64_key(256))?, "256");
+
+        let mut iter = db.ctx.index.iter();
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(decode_u64_key(key)?);
+        }
+        let mut sorted = numbers.to_vec();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        Ok(())
+    }
+}
@@ -1,14 +1,101 @@
+// Runs a `failpoints` hook, evaluating to its `Result`; always `Ok` without
+// the feature
+macro_rules! fail_point {
+    ($dir_path:expr, $name:ident) => {{
+        #[cfg(feature = "failpoints")]
+        let result = $crate::failpoints::hit($dir_path, $crate::failpoints::$name);
+        #[cfg(not(feature = "failpoints"))]
+        let result: $crate::Result<()> = Ok(());
+        result
+    }};
+}
+
+// Enters a `tracing` span for the rest of the enclosing block; nothing
+// without the feature
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::debug_span!($($args)*).entered();
+    };
+}
+
+// Emits a `tracing` event; nothing without the feature
+macro_rules! trace_event {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($args)*);
+    };
+}
+
+mod archive;
 mod batch;
+mod changes;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod codec;
+mod compact;
+mod compression;
 pub mod db;
-mod index;
+mod engine;
+mod evict;
+#[cfg(feature = "prometheus")]
+pub mod exporter;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod import;
+pub mod index;
 mod io;
+mod iter;
+#[cfg(feature = "serde")]
+mod jsonl;
+mod key;
+mod limiter;
+mod memoizer;
+mod memory;
 mod merge;
+mod metrics;
 pub mod options;
+mod replica;
 mod result;
+mod runtime;
+mod secondary;
+mod sequencer;
+#[cfg(feature = "server")]
+pub mod server;
+mod shard;
+mod shutdown;
+#[cfg(feature = "sim")]
+pub mod sim;
+mod sink;
+mod snapshot;
+mod stat;
 mod storage;
+mod syncer;
+#[cfg(feature = "serde")]
+pub mod typed;
+#[cfg(feature = "server")]
+pub use self::options::TtlClock;
 pub use self::{
+    batch::Transaction,
+    changes::{Change, ChangeCursor, ChangeIter},
+    compression::Compression,
+    engine::{BatchOp, KvEngine, MemEngine},
     index::KeyDirEntry,
-    options::Opts,
+    io::MmapSlice,
+    iter::{DbIter, KeysIter},
+    key::{decode_u64_key, encode_u64_key},
+    metrics::{LatencyHistogram, Metrics, GET_LATENCY_BUCKETS_US},
+    options::{LockPolicy, Opts},
+    replica::{ReplicaDb, Replicator, SyncReport},
     result::{Error, Result},
-    storage::State,
+    runtime::SharedRuntime,
+    secondary::Extractor,
+    shutdown::{CloseStats, ShutdownGuard},
+    sink::{BackupSink, DirSink, BACKUP_MANIFEST_NAME},
+    stat::Stat,
+    storage::{EntryIter, FileRecord, State, CRC_LEN, HEADER_MAX_LEN},
 };
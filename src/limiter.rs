@@ -0,0 +1,131 @@
+use parking_lot::{Condvar, Mutex};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A counting semaphore over reads of data files, for
+/// `Opts::max_concurrent_reads`.
+#[derive(Debug)]
+pub(crate) struct ReadLimiter {
+    limit: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+    // Most reads in flight at once
+    #[cfg(test)]
+    peak: std::sync::atomic::AtomicUsize,
+}
+
+/// A read let through by `ReadLimiter::acquire`, until it's dropped. It
+/// can be handed to the thread doing the read.
+pub(crate) struct ReadPermit {
+    limiter: Arc<ReadLimiter>,
+}
+
+impl ReadLimiter {
+    pub(crate) fn new(limit: usize) -> Self {
+        ReadLimiter {
+            limit,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+            #[cfg(test)]
+            peak: Default::default(),
+        }
+    }
+
+    /// Waits until fewer than the limit of reads are in flight.
+    pub(crate) fn acquire(self: &Arc<Self>) -> ReadPermit {
+        let mut in_flight = self.in_flight.lock();
+        while *in_flight >= self.limit {
+            self.released.wait(&mut in_flight);
+        }
+        self.let_through(&mut in_flight)
+    }
+
+    /// Like `acquire`, but gives up at `deadline`.
+    pub(crate) fn acquire_until(self: &Arc<Self>, deadline: Instant) -> Option<ReadPermit> {
+        let mut in_flight = self.in_flight.lock();
+        while *in_flight >= self.limit {
+            if self
+                .released
+                .wait_until(&mut in_flight, deadline)
+                .timed_out()
+                && *in_flight >= self.limit
+            {
+                return None;
+            }
+        }
+        Some(self.let_through(&mut in_flight))
+    }
+
+    fn let_through(self: &Arc<Self>, in_flight: &mut usize) -> ReadPermit {
+        *in_flight += 1;
+        #[cfg(test)]
+        self.peak
+            .fetch_max(*in_flight, std::sync::atomic::Ordering::SeqCst);
+        ReadPermit {
+            limiter: self.clone(),
+        }
+    }
+}
+
+impl Drop for ReadPermit {
+    fn drop(&mut self) {
+        *self.limiter.in_flight.lock() -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Db, FILE_SUFFIX};
+    use crate::io::StandardIO;
+    use crate::{Error, Opts, Result};
+    use bytes::Bytes;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_max_concurrent_reads() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_max_concurrent_reads".to_string(),
+            256,
+        );
+        opts.max_concurrent_reads = Some(2);
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..20 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from(i.to_string()))?;
+        }
+        assert!(db.data_file_ids().len() > 1);
+
+        // Stall reads of the first file so they overlap
+        let path = opts.dir_path.join(format!("0{}", FILE_SUFFIX));
+        let slow_io = StandardIO::open_read_only(&path)?.with_read_delay(Duration::from_millis(5));
+        db.inactive_files.get_mut(&0).unwrap().io = slow_io.into();
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| -> Result<()> {
+                    for i in 0..20 {
+                        assert_eq!(
+                            db.get(Bytes::from(format!("key{}", i)))?,
+                            i.to_string().as_bytes()
+                        );
+                    }
+                    Ok(())
+                });
+            }
+        });
+        let limiter = db.read_limiter.as_ref().unwrap();
+        assert_eq!(limiter.peak.load(Ordering::SeqCst), 2);
+        assert_eq!(*limiter.in_flight.lock(), 0);
+
+        opts.max_concurrent_reads = Some(0);
+        assert!(matches!(Db::open(&opts), Err(Error::Unsupported(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use crate::db::{Db, FILE_SUFFIX};
+use crate::runtime::SharedRuntime;
+use crate::storage::{FileHandle, Manifest};
+use crate::{Opts, Result};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Default)]
+struct MemoizeState {
+    manifest: Manifest,
+    // Files rotated out and not yet recorded
+    retired: VecDeque<FileHandle>,
+    // Files were dropped since the manifest was saved
+    unsaved: bool,
+    shutdown: bool,
+    // On a runtime: a pass is queued or running
+    scheduled: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<MemoizeState>,
+    // Wakes the memoizer thread
+    work: Condvar,
+    // Wakes `stop` once a pass is over
+    done: Condvar,
+}
+
+/// Records data files in the manifest as they're rotated out under
+/// `Opts::file_manifest`, so the next open can skip replaying them even if
+/// the database isn't closed cleanly.
+///
+/// Recording a file means replaying it, so it happens on a thread of its own,
+/// or on a `SharedRuntime`, rather than under the shard lock.
+#[derive(Debug)]
+pub(crate) struct Memoizer {
+    shared: Arc<Shared>,
+    opts: Opts,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    runtime: Option<SharedRuntime>,
+}
+
+impl Memoizer {
+    /// Starts recording into `manifest`, what open left behind, on a thread
+    /// of its own or on `runtime`.
+    pub(crate) fn start(
+        manifest: Manifest,
+        opts: &Opts,
+        runtime: Option<&SharedRuntime>,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(MemoizeState {
+                manifest,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let worker = match runtime {
+            Some(_) => None,
+            None => {
+                let shared = shared.clone();
+                let opts = opts.clone();
+                Some(
+                    thread::Builder::new()
+                        .name("zap-memoize".to_string())
+                        .spawn(move || run(&shared, &opts))?,
+                )
+            }
+        };
+        Ok(Self {
+            shared,
+            opts: opts.clone(),
+            worker: Mutex::new(worker),
+            runtime: runtime.cloned(),
+        })
+    }
+
+    /// Queues `file`, just rotated out, to be recorded.
+    pub(crate) fn retire(&self, file: FileHandle) {
+        let mut state = self.shared.state.lock();
+        if state.shutdown {
+            return;
+        }
+        state.retired.push_back(file);
+        self.schedule(&mut state);
+    }
+
+    /// Drops data file `file_id`, once removed, from the manifest.
+    pub(crate) fn forget(&self, file_id: u32) {
+        let mut state = self.shared.state.lock();
+        state.retired.retain(|file| file.get_file_id() != file_id);
+        if state.manifest.remove(file_id) && !state.shutdown {
+            state.unsaved = true;
+            self.schedule(&mut state);
+        }
+    }
+
+    fn schedule(&self, state: &mut MemoizeState) {
+        match &self.runtime {
+            Some(runtime) if !state.scheduled => {
+                state.scheduled = true;
+                let shared = self.shared.clone();
+                let opts = self.opts.clone();
+                runtime.spawn(move || run_pass(&shared, &opts));
+            }
+            Some(_) => {}
+            None => {
+                self.shared.work.notify_one();
+            }
+        }
+    }
+
+    /// Records the files still queued and stops. Nothing is written after it
+    /// returns.
+    pub(crate) fn stop(&self) {
+        let mut state = self.shared.state.lock();
+        state.shutdown = true;
+        while state.scheduled {
+            self.shared.done.wait(&mut state);
+        }
+        drop(state);
+        self.shared.work.notify_one();
+        if let Some(worker) = self.worker.lock().take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for Memoizer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(shared: &Shared, opts: &Opts) {
+    let mut state = shared.state.lock();
+    loop {
+        if !state.retired.is_empty() || state.unsaved {
+            memoize_retired(&mut state, opts);
+            continue;
+        }
+        if state.shutdown {
+            return;
+        }
+        shared.work.wait(&mut state);
+    }
+}
+
+fn run_pass(shared: &Shared, opts: &Opts) {
+    let mut state = shared.state.lock();
+    while !state.retired.is_empty() || state.unsaved {
+        memoize_retired(&mut state, opts);
+    }
+    state.scheduled = false;
+    shared.done.notify_all();
+}
+
+// Records the files queued so far and saves the manifest. A file that can't
+// be replayed is left out and replays on the next open.
+fn memoize_retired(state: &mut MutexGuard<'_, MemoizeState>, opts: &Opts) {
+    let files = state.retired.drain(..).collect::<Vec<_>>();
+    let replays = MutexGuard::unlocked(state, || {
+        files
+            .into_iter()
+            .filter_map(|file| {
+                let replay = Db::process_file_handle(&file, opts).ok()?;
+                Some((file, replay))
+            })
+            .collect::<Vec<_>>()
+    });
+    for (file, replay) in replays {
+        // Removed while it replayed
+        let path = opts
+            .dir_path
+            .join(format!("{}{}", file.get_file_id(), FILE_SUFFIX));
+        if !path.is_file() {
+            continue;
+        }
+        if let Err(_e) = Db::memoize_file(&file, &replay, &mut state.manifest, &opts.dir_path) {
+            trace_event!(file_id = file.get_file_id(), error = %_e, "not memoized");
+        }
+    }
+    state.unsaved = false;
+    if let Err(_e) = state.manifest.save(&opts.dir_path) {
+        trace_event!(error = %_e, "manifest not saved");
+    }
+}
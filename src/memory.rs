@@ -0,0 +1,349 @@
+//! Databases whose data files are buffers in memory, for tests and for data
+//! that needn't outlive the process.
+
+use crate::batch::{decode_transaction_key, encode_transaction_key};
+use crate::db::{
+    backup_extent, validate_options, BackupExtent, BackupFile, BackupReport, Db, OpenReport,
+    FILE_SUFFIX, INITIAL_FILE_ID, NON_COMMITTED,
+};
+use crate::evict::Evictor;
+use crate::index::Indexer;
+use crate::io::MemoryIO;
+use crate::metrics::Counters;
+use crate::options::Context;
+use crate::secondary::SecondaryIndexes;
+use crate::sequencer::IndexSequencer;
+use crate::shard::{shard_count, shard_of, WriteShard, SHARD_FILE_IDS};
+use crate::storage::{FileHandle, LockFile};
+use crate::syncer::Position;
+use crate::{Error, KeyDirEntry, Opts, Result};
+use dashmap::{DashMap, DashSet};
+use parking_lot::{Mutex, RwLock};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::Arc;
+
+impl Db {
+    /// Opens an empty database whose data files are `MemoryIO` buffers
+    /// rather than files in `opts.dir_path`, which is left alone. It takes
+    /// no lock and keeps no hint file. Writes, batches and rotation work as
+    /// they do on disk and syncing does nothing; what's written is gone once
+    /// the database is dropped, unless `back_up` wrote it out first.
+    ///
+    /// `merge` has no directory to leave its output in for the next open,
+    /// so it rewrites the live entries into the shards' new files and drops
+    /// the merged ones before returning, with writes waiting meanwhile.
+    ///
+    /// The options only meaningful for files on disk, `read_only`,
+    /// `key_file`, `file_manifest`, `incremental_hint` and `file_crc`, are
+    /// refused.
+    pub fn open_in_memory(opts: &Opts) -> Result<Db> {
+        validate_options(opts)?;
+        let on_disk_only = [
+            ("read_only", opts.read_only),
+            ("key_file", opts.key_file),
+            ("file_manifest", opts.file_manifest),
+            ("incremental_hint", opts.incremental_hint),
+            ("file_crc", opts.file_crc),
+        ];
+        if let Some((name, _)) = on_disk_only.iter().find(|(_, set)| *set) {
+            return Err(Error::Unsupported(format!(
+                "Opts::{} on a database in memory",
+                name
+            )));
+        }
+        let mut opts = opts.clone();
+        opts.sync_dir = false;
+
+        let shards = (0..shard_count(&opts))
+            .map(|shard| {
+                let file_id = INITIAL_FILE_ID + shard as u32 * SHARD_FILE_IDS;
+                let active_file = FileHandle::new(file_id, MemoryIO::default().into());
+                WriteShard::start(active_file, &opts, None)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Db {
+            ctx: Context::new(&opts, opts.new_index()),
+            active_file: shards[0].active_file.clone(),
+            inactive_files: Arc::new(DashMap::new()),
+            shards,
+            sequence_number: Arc::new(AtomicU32::new(NON_COMMITTED + 1)),
+            batch_commit_lock: RwLock::new(()),
+            index_sequencer: IndexSequencer::default(),
+            merge_lock: Mutex::new(()),
+            closed: AtomicBool::new(false),
+            lock_file: Mutex::new(LockFile::unlocked(&opts.dir_path)),
+            open_report: OpenReport::default(),
+            counters: Counters::default(),
+            runtime: None,
+            generation: 0,
+            raw_batches: Mutex::new(std::collections::HashMap::new()),
+            read_limiter: opts
+                .max_concurrent_reads
+                .map(|limit| Arc::new(crate::limiter::ReadLimiter::new(limit))),
+            read_workers: Mutex::new(None),
+            eviction_lock: Mutex::new(()),
+            evictor: Evictor::default(),
+            eviction_listeners: Mutex::new(Vec::new()),
+            hint_log: None,
+            memoizer: None,
+            large_keys: DashSet::new(),
+            in_memory: true,
+            secondary_indexes: SecondaryIndexes::default(),
+        })
+    }
+
+    // `merge_with` for a database in memory. Every shard's active file is
+    // retired, the live entries of the retired files are appended to the
+    // new ones and the retired files dropped, all under the commit lock. A
+    // read that looked a key up in a dropped file finds it gone and looks
+    // the key up again.
+    pub(crate) fn merge_in_memory(
+        &self,
+        on_relocate: &mut impl FnMut(&[u8], KeyDirEntry, KeyDirEntry),
+    ) -> Result<()> {
+        let _commit_lock = self.batch_commit_lock.write();
+        self.index_sequencer.wait_all();
+        let mut active_files = self
+            .shards
+            .iter()
+            .map(|shard| shard.active_file.write())
+            .collect::<Vec<_>>();
+        if active_files.iter().all(|file| file.get_offset() == 0) && self.inactive_files.is_empty()
+        {
+            return Err(Error::Unsupported("Merge when db is empty".to_string()));
+        }
+
+        let mut merged_files = self
+            .inactive_files
+            .iter()
+            .map(|file| file.clone())
+            .collect::<Vec<_>>();
+        for (shard, active_file) in self.shards.iter().zip(active_files.iter_mut()) {
+            merged_files.push(active_file.freeze());
+            self.rotate_locked(shard, active_file)?;
+        }
+        merged_files.sort_by_key(|file| file.get_file_id());
+
+        for file in merged_files.iter() {
+            let file_id = file.get_file_id();
+            // Entries stay in their shard, and so in their size class
+            let shard_index = shard_of(file_id, self.shards.len());
+            let mut offset = 0;
+            while offset < file.get_offset() {
+                let (mut entry, size) = file.extract_stored_entry(offset)?;
+                let (key, _) = decode_transaction_key(entry.get_key().clone());
+                let live = self.ctx.index.get(&key).filter(|keydir_entry| {
+                    keydir_entry.get_file_id() == file_id && keydir_entry.get_offset() == offset
+                });
+                if let Some(keydir_entry) = live {
+                    entry.set_key(encode_transaction_key(key.clone(), NON_COMMITTED));
+                    let mut moved = self.append_locked(
+                        &self.shards[shard_index],
+                        &mut active_files[shard_index],
+                        &entry,
+                    )?;
+                    if let Some(value) = keydir_entry.get_inline_value() {
+                        moved.set_inline_value(value);
+                    }
+                    if self.ctx.opts.max_db_size.is_some() {
+                        moved.track_references();
+                    }
+                    on_relocate(&key, keydir_entry, moved.clone());
+                    self.ctx.index.put(key.into(), moved);
+                }
+                offset += size as u64;
+            }
+        }
+        for file in merged_files.iter() {
+            self.inactive_files.remove(&file.get_file_id());
+        }
+
+        self.counters.count_merge();
+        trace_event!(files = merged_files.len(), "merged in memory");
+        Ok(())
+    }
+
+    // `back_up` for a database in memory: every data file up to the backup
+    // point `ends` is written to `dir_path` as `<id>.db`
+    pub(crate) fn write_out_memory_files(
+        &self,
+        dir_path: &Path,
+        ends: &[Position],
+    ) -> Result<BackupReport> {
+        let mut files = self
+            .inactive_files
+            .iter()
+            .map(|file| file.clone())
+            .collect::<Vec<_>>();
+        files.extend(
+            self.shards
+                .iter()
+                .map(|shard| shard.active_file.read().clone()),
+        );
+
+        fs::create_dir_all(dir_path)?;
+        let mut report = BackupReport::default();
+        for file in files {
+            let name = format!("{}{}", file.get_file_id(), FILE_SUFFIX);
+            let len = match backup_extent(ends, &name) {
+                Some(BackupExtent::Prefix(end)) => end,
+                Some(BackupExtent::Whole) => file.get_offset(),
+                // Created after the backup point
+                None => continue,
+            };
+            let mut buf = vec![0u8; len as usize];
+            let mut read = 0;
+            while read < buf.len() {
+                read += file.read(&mut buf[read..], read as u64)?;
+            }
+            let mut dst = File::create(dir_path.join(&name))?;
+            dst.write_all(&buf)?;
+            dst.sync_all()?;
+            report.files.push(BackupFile {
+                name,
+                bytes: len,
+                hard_linked: false,
+            });
+        }
+        report.files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::thread;
+
+    fn memory_opts(name: &str) -> Opts {
+        Opts::new(256, 1024, false, true, format!("/tmp/{}", name), 1024)
+    }
+
+    #[test]
+    fn test_open_in_memory() -> Result<()> {
+        let mut opts = memory_opts("test_open_in_memory");
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open_in_memory(&opts)?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        db.put_all(&[
+            (Bytes::from("a"), Bytes::from("value")),
+            (Bytes::from("b"), Bytes::from("value")),
+        ])?;
+        db.delete(Bytes::from("key0"))?;
+        db.put(Bytes::from("a"), Bytes::from("newer"))?;
+        db.sync_all()?;
+        assert!(db.data_file_ids().len() > 1);
+        assert!(db.compact_active()? > 0);
+        for i in 1..100 {
+            assert_eq!(db.get(Bytes::from(format!("key{}", i)))?, "value");
+        }
+        assert!(db.get(Bytes::from("key0")).is_err());
+        assert_eq!(db.get(Bytes::from("a"))?, "newer");
+        assert_eq!(db.get(Bytes::from("b"))?, "value");
+        assert!(matches!(
+            db.backup_to(Vec::new()),
+            Err(Error::Unsupported(_))
+        ));
+        drop(db);
+        assert!(!opts.dir_path.exists());
+
+        opts.file_crc = true;
+        assert!(matches!(
+            Db::open_in_memory(&opts),
+            Err(Error::Unsupported(m)) if m == "Opts::file_crc on a database in memory"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_in_memory() -> Result<()> {
+        let opts = memory_opts("test_merge_in_memory");
+        let db = Db::open_in_memory(&opts)?;
+        for round in 0..3 {
+            for i in 0..100 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("value{}-{}", i, round)),
+                )?;
+            }
+        }
+        for i in 0..10 {
+            db.delete(Bytes::from(format!("key{}", i)))?;
+        }
+        let merged_ids = db.data_file_ids();
+
+        // Readers keep finding every key while it moves
+        let mut relocated = Vec::new();
+        thread::scope(|s| -> Result<()> {
+            let reader = s.spawn(|| -> Result<()> {
+                for _ in 0..20 {
+                    for i in 10..100 {
+                        let value = db.get(Bytes::from(format!("key{}", i)))?;
+                        assert_eq!(value, format!("value{}-2", i).as_bytes());
+                    }
+                }
+                Ok(())
+            });
+            db.merge_with(|key, old, new| relocated.push((key.to_vec(), old, new)))?;
+            reader.join().unwrap()
+        })?;
+
+        assert_eq!(relocated.len(), 90);
+        let file_ids = db.data_file_ids();
+        assert!(merged_ids.iter().all(|id| !file_ids.contains(id)));
+        assert!(file_ids.len() < merged_ids.len());
+        for (key, _, new) in relocated {
+            assert_eq!(db.locate(&key), Some(new));
+        }
+
+        // Replaying the merged files lands on the same state
+        let mut db = db;
+        db.put(Bytes::from("key50"), Bytes::from("after"))?;
+        db.reindex()?;
+        for i in 0..100 {
+            let value = db.get(Bytes::from(format!("key{}", i)));
+            match i {
+                0..10 => assert!(value.is_err()),
+                50 => assert_eq!(value?, "after"),
+                _ => assert_eq!(value?, format!("value{}-2", i).as_bytes()),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_back_up_in_memory() -> Result<()> {
+        let opts = memory_opts("test_back_up_in_memory");
+        let backup_dir = opts.dir_path.join("backup");
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open_in_memory(&opts)?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))?;
+        }
+        db.delete(Bytes::from("key0"))?;
+        db.merge()?;
+        db.put(Bytes::from("key1"), Bytes::from("newer"))?;
+
+        let report = db.back_up(&backup_dir)?;
+        assert_eq!(report.files.len(), db.data_file_ids().len());
+        assert!(report.files.iter().all(|file| !file.hard_linked));
+        drop(db);
+
+        let restored = Db::open(&Opts {
+            dir_path: backup_dir,
+            ..opts
+        })?;
+        assert!(restored.get(Bytes::from("key0")).is_err());
+        assert_eq!(restored.get(Bytes::from("key1"))?, "newer");
+        for i in 2..100 {
+            assert_eq!(restored.get(Bytes::from(format!("key{}", i)))?, "value");
+        }
+        Ok(())
+    }
+}
@@ -1,74 +1,194 @@
 use crate::batch::{decode_transaction_key, encode_transaction_key};
-use crate::db::{Db, NON_COMMITTED};
+use crate::changes::write_generation;
+use crate::db::{next_reserved_file_id, sync_dir, Db, NON_COMMITTED};
 use crate::index::Indexer;
 use crate::io::StandardIO;
-use crate::storage::{DataEntry, FileHandle, HintFile};
-use crate::{Error, Result, State};
+use crate::shard::size_class_of;
+use crate::storage::{DataEntry, FileHandle, HintFile, KeyFile};
+use crate::{Error, KeyDirEntry, Result, State};
+use bytes::Bytes;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
 
 pub(crate) const MERGE_FINISHED_FILE: &str = "merge_finished";
-pub(crate) const MERGE_FINISHED_KEY: &str = "__MERGE_FINISHED__";
+// The merge finished record is alone in its file, so it needs no key
+pub(crate) const MERGE_FINISHED_KEY: &str = "";
+// Merge output of a database whose directory has no name, such as `/`
+const MERGE_CHILD_DIR: &str = ".merge";
+// Entries buffered between the merge reader and writer
+const MERGE_CHANNEL_CAPACITY: usize = 1024;
 
 #[allow(dead_code)]
 impl Db {
-    pub fn merge(&mut self) -> Result<()> {
-        let read_guard = self.active_file.read();
-        if read_guard.get_offset() == 0 && self.inactive_files.len() == 0 {
-            return Err(Error::Unsupported("Merge when db is empty".to_string()));
+    /// Rewrites the live entries of every file up to the current active files
+    /// into a `-merge` directory next to the database.
+    ///
+    /// The output replaces the merged files on the next `open`, before the
+    /// database serves any reads, so no data file is removed while handles
+    /// to it may still be in use. Reads and writes carry on during the merge;
+    /// writes go to new files that the merge leaves in place. A database in
+    /// memory merges in place instead; see `open_in_memory`.
+    pub fn merge(&self) -> Result<()> {
+        self.merge_with(|_, _, _| {})
+    }
+
+    /// Like `merge`, but calls `on_relocate` with the key, the old and the
+    /// new index entry of every live entry the merge moves, for keeping an
+    /// external secondary index in step. The new entries take effect once
+    /// the output is installed on the next open; until then reads go to the
+    /// old ones, and a merge that fails leaves them in place.
+    pub fn merge_with(
+        &self,
+        mut on_relocate: impl FnMut(&[u8], KeyDirEntry, KeyDirEntry),
+    ) -> Result<()> {
+        trace_span!("merge");
+        // Two merges would write to the same `-merge` directory
+        let Some(_merge_guard) = self.merge_lock.try_lock() else {
+            return Err(Error::Unsupported("Merge already in progress".to_string()));
+        };
+        self.check_open()?;
+        if self.in_memory {
+            return self.merge_in_memory(&mut on_relocate);
         }
 
-        let mut opts = self.ctx.opts.clone();
-        let filename = opts.dir_path.file_name().unwrap();
-        opts.dir_path
-            .set_file_name(format!("{}-merge", filename.to_string_lossy()));
-        let merge_db = Db::open(&opts)?;
+        // Writes appended before the merge boundary have to be in the index
+        // when it's checked below, or they'd be dropped with their files
+        let commit_lock = self.batch_commit_lock.write();
+        self.index_sequencer.wait_all();
+        let mut active_files = self
+            .shards
+            .iter()
+            .map(|shard| shard.active_file.write())
+            .collect::<Vec<_>>();
+        if active_files.iter().all(|file| file.get_offset() == 0) && self.inactive_files.is_empty()
+        {
+            return Err(Error::Unsupported("Merge when db is empty".to_string()));
+        }
 
         // Get Filehandles that need to be merged
-        let mut file_handles = Vec::new();
-
-        self.inactive_files.iter().for_each(|file| {
-            file_handles.push((file.get_file_id(), file.clone()));
-        });
+        let mut file_handles = self
+            .inactive_files
+            .iter()
+            .map(|file| (file.get_file_id(), file.freeze()))
+            .collect::<Vec<_>>();
 
-        file_handles.push((read_guard.get_file_id(), read_guard.clone()));
+        // Every shard's current file is merged. It's retired while still
+        // locked, so writes made during the merge all go to newer files
+        let mut unmerged_file_ids = Vec::new();
+        for (shard, active_file) in self.shards.iter().zip(active_files.iter_mut()) {
+            file_handles.push((active_file.get_file_id(), active_file.freeze()));
+            // Merging is how used up ids are freed, so it may take a reserved one
+            let new_file_id = next_reserved_file_id(active_file.get_file_id(), self.shards.len())?;
+            self.rotate_to_locked(shard, active_file, new_file_id)?;
+            unmerged_file_ids.push(new_file_id);
+        }
+        drop(active_files);
+        drop(commit_lock);
 
-        file_handles.sort_by(|a, b| a.0.cmp(&b.0));
+        file_handles.sort_by_key(|a| a.0);
 
-        drop(read_guard);
-        self.rotate_active_file()?;
+        let mut opts = self.ctx.opts.clone();
+        opts.dir_path = merge_dir_path(&opts.dir_path)?;
+        // Only the database itself evicts, the merge's hint file is written
+        // as it goes and merged files go without CRC sidecars
+        opts.max_db_size = None;
+        opts.incremental_hint = false;
+        opts.file_crc = false;
+        // Output of an earlier merge that was never installed is superseded
+        if opts.dir_path.is_dir() {
+            fs::remove_dir_all(&opts.dir_path)?;
+        }
+        let merge_db = Db::open_on(&opts, self.runtime.clone())?;
 
         let mut hint_file = HintFile::new(&merge_db.ctx.opts.dir_path);
-        for (_, file) in file_handles.iter() {
-            let mut offset = 0;
-            loop {
-                let (mut entry, size) = match file.extract_data_entry(offset) {
-                    Ok((entry, size)) => (entry, size),
-                    Err(_) => {
-                        //FIXME: == cannot be applied to result::Error
-                        // if e == Error::Io(ErrorKind::UnexpectedEof.into()) {
-                        //     break;
-                        // };
-                        break;
+        let mut merged_keys = Vec::new();
+
+        // A reader thread streams entries in file order while this thread
+        // filters them against the index and writes the live ones out
+        let (sender, receiver) = mpsc::sync_channel(MERGE_CHANNEL_CAPACITY);
+        let input_files = &file_handles;
+        thread::scope(|s| -> Result<()> {
+            s.spawn(move || {
+                for (file_id, file) in input_files.iter() {
+                    let mut offset = 0;
+                    while let Ok((entry, size)) = file.extract_stored_entry(offset) {
+                        // The writer hung up after an error
+                        if sender.send((*file_id, offset, entry)).is_err() {
+                            return;
+                        }
+                        offset += size as u64;
                     }
-                };
+                }
+            });
+
+            #[cfg(feature = "tracing")]
+            let mut merging_file = None;
+            for (file_id, offset, mut entry) in receiver {
+                #[cfg(feature = "tracing")]
+                if merging_file.replace(file_id) != Some(file_id) {
+                    tracing::debug!(file_id, "merging file");
+                }
                 let (key, _) = decode_transaction_key(entry.get_key().clone());
                 if let Some(keydir_entry) = self.ctx.index.get(&key) {
-                    if keydir_entry.get_file_id() == file.get_file_id()
-                        && keydir_entry.get_offset() == offset
+                    if keydir_entry.get_file_id() == file_id && keydir_entry.get_offset() == offset
                     {
-                        let key = encode_transaction_key(key, NON_COMMITTED);
-                        entry.set_key(key.clone());
-                        let keydir_entry = merge_db.append_entry(&entry)?;
-                        hint_file.write_entry(key, &keydir_entry)?;
+                        if self.ctx.opts.key_file {
+                            merged_keys.push(Bytes::copy_from_slice(&key));
+                        }
+                        entry.set_key(encode_transaction_key(key.clone(), NON_COMMITTED));
+                        // Records keep the size class they were written to
+                        let new_entry = match size_class_of(file_id, &self.ctx.opts) {
+                            Some(class) => merge_db.append_entry_to(class, &entry)?,
+                            None => merge_db.append_entry(&entry)?,
+                        };
+                        hint_file.write_entry(entry.get_key().clone(), &new_entry)?;
+                        if (new_entry.get_file_id(), new_entry.get_offset()) != (file_id, offset) {
+                            on_relocate(&key, keydir_entry, new_entry);
+                        }
                     }
                 }
-                offset += size as u64;
             }
-        }
+            Ok(())
+        })?;
 
         merge_db.sync()?;
+        let mut coverage = merge_db
+            .inactive_files
+            .iter()
+            .map(|file| (file.get_file_id(), file.get_offset()))
+            .collect::<Vec<_>>();
+        for shard in merge_db.shards.iter() {
+            let active_file = shard.active_file.read();
+            coverage.push((active_file.get_file_id(), active_file.get_offset()));
+        }
+        coverage.sort();
+        hint_file.write_coverage(&coverage)?;
         hint_file.sync()?;
+        if self.ctx.opts.key_file {
+            merged_keys.sort();
+            KeyFile::write_all(&merge_db.ctx.opts.dir_path, &merged_keys)?;
+        }
+
+        // Per shard, the first file not merged and the id after the output.
+        // Files from the first one up are renumbered from there on install
+        let mut boundaries = Vec::new();
+        for (shard, unmerged_file_id) in merge_db.shards.iter().zip(unmerged_file_ids) {
+            let renumber_from = shard.get_file_id() + 1;
+            if renumber_from > unmerged_file_id {
+                return Err(Error::Unsupported(format!(
+                    "Merge output runs into file {}",
+                    unmerged_file_id
+                )));
+            }
+            boundaries.push(format!("{}:{}", unmerged_file_id, renumber_from));
+        }
+
+        // Cursors into the merged files expire once the output is installed
+        write_generation(&merge_db.ctx.opts.dir_path, self.generation + 1)?;
 
-        let unmerged_file_id = file_handles.last().unwrap().0 + 1;
+        fail_point!(&self.ctx.opts.dir_path, MERGE_BEFORE_FINISHED)?;
         let mut merge_finished_file = FileHandle::new(
             0,
             StandardIO::new(&merge_db.ctx.opts.dir_path.join(MERGE_FINISHED_FILE))
@@ -78,23 +198,50 @@ impl Db {
 
         let entry = DataEntry::new(
             MERGE_FINISHED_KEY,
-            unmerged_file_id.to_string().into_bytes(),
+            boundaries.join(",").into_bytes(),
             State::Active,
         );
 
         let enc_record = entry.encode()?;
         merge_finished_file.write(&enc_record)?;
         merge_finished_file.sync()?;
+        if self.ctx.opts.should_sync_dir() {
+            sync_dir(&merge_db.ctx.opts.dir_path)?;
+        }
 
+        self.counters.count_merge();
+        trace_event!(files = file_handles.len(), "merged");
         Ok(())
     }
 }
 
+/// Where `merge` writes its output for the database in `dir_path`: a `-merge`
+/// directory next to it, or a `.merge` directory inside it if it has no name
+/// to go next to, as with `/`.
+pub(crate) fn merge_dir_path(dir_path: &Path) -> Result<PathBuf> {
+    // `.` and `..` have no name of their own, the directories they stand
+    // for do
+    let dir_path = match dir_path.file_name() {
+        Some(_) => dir_path.to_path_buf(),
+        None => dir_path.canonicalize()?,
+    };
+    Ok(match dir_path.file_name() {
+        Some(name) => {
+            let mut name = name.to_os_string();
+            name.push("-merge");
+            dir_path.with_file_name(name)
+        }
+        None => dir_path.join(MERGE_CHILD_DIR),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
 
     use super::*;
+    use crate::db::parse_data_file_id;
+    use crate::storage::HINT_FILE_NAME;
     use crate::*;
     #[test]
     fn test_merge() -> Result<()> {
@@ -157,4 +304,304 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_merge_dir_path() -> Result<()> {
+        for (dir_path, merge_dir) in [
+            ("/tmp/db", "/tmp/db-merge"),
+            ("/tmp/db/", "/tmp/db-merge"),
+            ("/data/", "/data-merge"),
+            ("db", "db-merge"),
+            ("nested/db/", "nested/db-merge"),
+        ] {
+            assert_eq!(merge_dir_path(Path::new(dir_path))?, Path::new(merge_dir));
+        }
+        let cwd = std::env::current_dir()?;
+        assert_eq!(merge_dir_path(Path::new("."))?, merge_dir_path(&cwd)?);
+        assert_eq!(merge_dir_path(Path::new("/"))?, Path::new("/.merge"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_with_unusual_dir_paths() -> Result<()> {
+        // A trailing slash, and a path relative to the working directory
+        for dir_path in [
+            "/tmp/test_merge_trailing_slash/",
+            "target/test_merge_relative_dir",
+        ] {
+            let opts = Opts::new(256, 1024, false, true, dir_path.to_string(), 1024);
+            let _ = fs::remove_dir_all(&opts.dir_path);
+            let db = Db::open(&opts)?;
+            for i in 0..100 {
+                db.put(
+                    Bytes::from(format!("key{}", i % 10)),
+                    Bytes::from(i.to_string()),
+                )?;
+            }
+            db.merge()?;
+            assert!(merge_dir_path(&opts.dir_path)?.is_dir());
+            drop(db);
+
+            let db = Db::open(&opts)?;
+            assert!(!merge_dir_path(&opts.dir_path)?.exists());
+            assert!(!db.open_report().hint_file_ignored);
+            for i in 90..100 {
+                assert_eq!(
+                    db.get(Bytes::from(format!("key{}", i % 10)))?,
+                    i.to_string().into_bytes()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_writes_after_merge_win_over_hint() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_writes_after_merge".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+        for i in 0..100 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("old"))?;
+        }
+        db.merge()?;
+        db.put(Bytes::from("key1"), Bytes::from("new"))?;
+        db.delete(Bytes::from("key2"))?;
+        db.close()?;
+        drop(db);
+
+        // Installs the merge, then again with the hint file in place
+        for _ in 0..2 {
+            let db = Db::open(&opts)?;
+            assert_eq!(db.get(Bytes::from("key0"))?, "old");
+            assert_eq!(db.get(Bytes::from("key1"))?, "new");
+            assert!(db.get(Bytes::from("key2")).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_hint_is_ignored() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_stale_hint_is_ignored".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let hint_path = opts.dir_path.join(HINT_FILE_NAME);
+        let stale_hint = PathBuf::from("/tmp/test_stale_hint_is_ignored-hint");
+
+        let db = Db::open(&opts)?;
+        for i in 0..50 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("old"))?;
+        }
+        db.put(Bytes::from("gone"), Bytes::from("old"))?;
+        db.merge()?;
+        drop(db);
+        let db = Db::open(&opts)?;
+        assert!(!db.open_report().hint_file_ignored);
+        fs::copy(&hint_path, &stale_hint)?;
+
+        for i in 0..50 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("new"))?;
+        }
+        db.delete(Bytes::from("gone"))?;
+        db.merge()?;
+        drop(db);
+        drop(Db::open(&opts)?);
+
+        // An older hint kept with newer data files
+        fs::copy(&stale_hint, &hint_path)?;
+        let db = Db::open(&opts)?;
+        assert!(db.open_report().hint_file_ignored);
+        for i in 0..50 {
+            assert_eq!(db.get(Bytes::from(format!("key{}", i)))?, "new");
+        }
+        assert!(db.locate(b"gone").is_none());
+        drop(db);
+
+        // A hint pointing into a file that isn't there
+        fs::remove_file(&hint_path)?;
+        let mut hint_file = HintFile::new(&opts.dir_path);
+        hint_file.write_entry(
+            encode_transaction_key(b"ghost".to_vec(), NON_COMMITTED),
+            &KeyDirEntry::new(42, 0, 20),
+        )?;
+        hint_file.sync()?;
+        drop(hint_file);
+        let db = Db::open(&opts)?;
+        assert!(db.open_report().hint_file_ignored);
+        assert!(db.locate(b"ghost").is_none());
+        assert_eq!(db.get(Bytes::from("key0"))?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_while_writing() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_merge_while_writing".to_string(),
+            4096,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let mut db = Db::open(&opts)?;
+        for i in 0..500 {
+            db.put(Bytes::from(format!("old{}", i)), Bytes::from("old"))?;
+        }
+
+        thread::scope(|s| -> Result<()> {
+            let writer = s.spawn(|| -> Result<()> {
+                for i in 0..1000 {
+                    db.put(Bytes::from(format!("new{}", i)), Bytes::from("new"))?;
+                    // Some keys are moved by the merge and written again
+                    if i % 10 == 0 {
+                        db.put(Bytes::from(format!("old{}", i / 10)), Bytes::from("new"))?;
+                    }
+                }
+                Ok(())
+            });
+            for _ in 0..3 {
+                db.merge()?;
+                thread::yield_now();
+            }
+            writer.join().unwrap()
+        })?;
+        db.close()?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        for i in 0..500 {
+            let expected = if i < 100 { "new" } else { "old" };
+            assert_eq!(
+                db.get(Bytes::from(format!("old{}", i)))?,
+                expected.as_bytes()
+            );
+        }
+        for i in 0..1000 {
+            assert_eq!(db.get(Bytes::from(format!("new{}", i)))?, "new");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_resets_exhausted_file_ids() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_merge_resets_file_ids".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..20 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("old"))?;
+        }
+        db.seed_file_id(u32::MAX - 1030)?;
+
+        // Rotation stops short of the reserved ids
+        let mut written = 0;
+        let exhausted = loop {
+            match db.put(Bytes::from(format!("key{}", written)), Bytes::from("new")) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(exhausted, Error::FileIdsExhausted(id) if id == u32::MAX - 1024));
+        assert!(matches!(
+            db.rotate_active_file(),
+            Err(Error::FileIdsExhausted(_))
+        ));
+
+        // Merging still has ids to rotate into
+        db.merge()?;
+        db.put(Bytes::from("after"), Bytes::from("merge"))?;
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        let active_id = db.shards[0].get_file_id();
+        assert!(active_id < 20);
+        let mut file_ids = fs::read_dir(&opts.dir_path)?
+            .flatten()
+            .filter_map(|file| file.file_name().to_str().and_then(parse_data_file_id))
+            .collect::<Vec<_>>();
+        file_ids.sort();
+        assert_eq!(file_ids, (0..=active_id).collect::<Vec<_>>());
+        assert!(!opts.dir_path.join(MERGE_FINISHED_FILE).exists());
+        for i in 0..20 {
+            let expected = if i < written { "new" } else { "old" };
+            assert_eq!(
+                db.get(Bytes::from(format!("key{}", i)))?,
+                expected.as_bytes()
+            );
+        }
+        assert_eq!(db.get(Bytes::from("after"))?, "merge");
+
+        // Files rotate again from the reset id
+        for i in 0..100 {
+            db.put(Bytes::from(format!("more{}", i)), Bytes::from("value"))?;
+        }
+        assert!(db.shards[0].get_file_id() > active_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_reports_relocations() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_merge_reports_relocations".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(merge_dir_path(&opts.dir_path)?);
+        let db = Db::open(&opts)?;
+        for round in 0..3 {
+            for i in 0..100 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("value{}-{}", i, round)),
+                )?;
+            }
+        }
+        for i in 0..10 {
+            db.delete(Bytes::from(format!("key{}", i)))?;
+        }
+
+        let mut relocations = std::collections::HashMap::new();
+        db.merge_with(|key, old, new| {
+            assert!(relocations.insert(key.to_vec(), (old, new)).is_none());
+        })?;
+        assert_eq!(relocations.len(), 90);
+        for (key, (old, _)) in relocations.iter() {
+            assert_eq!(db.locate(key).as_ref(), Some(old));
+        }
+        drop(db);
+
+        // The new entries are where the keys are once the output is installed
+        let db = Db::open(&opts)?;
+        for (key, (_, new)) in relocations.iter() {
+            let entry = db.locate(key).unwrap();
+            assert_eq!(
+                (entry.get_file_id(), entry.get_offset(), entry.get_size()),
+                (new.get_file_id(), new.get_offset(), new.get_size())
+            );
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,246 @@
+use crate::db::Db;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// Upper bounds, in microseconds, of the buckets `Metrics::get_latency`
+/// sorts reads into. Slower reads land in one more bucket after these.
+pub const GET_LATENCY_BUCKETS_US: [u64; 10] =
+    [10, 25, 50, 100, 250, 500, 1_000, 10_000, 100_000, 1_000_000];
+
+/// Operation and IO counters since the database was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Bytes appended to data files.
+    pub bytes_written: u64,
+    /// Bytes of records read back from data files.
+    pub bytes_read: u64,
+    pub puts: u64,
+    pub gets: u64,
+    /// Gets answered from a value inlined in the index, without reading a
+    /// data file.
+    pub inline_reads: u64,
+    pub deletes: u64,
+    pub merges: u64,
+    /// Times an active file filled up and a new one was started.
+    pub rotations: u64,
+    /// How long gets took.
+    pub get_latency: LatencyHistogram,
+    /// Bytes in the active files, as of the call.
+    pub active_file_bytes: u64,
+}
+
+/// Counts of durations by bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// How many fell in each bucket: up to each bound of
+    /// `GET_LATENCY_BUCKETS_US` and over the last one. Not cumulative.
+    pub buckets: Vec<u64>,
+    /// Sum of all durations, in microseconds.
+    pub sum_us: u64,
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    bytes_written: AtomicU64,
+    bytes_read: AtomicU64,
+    puts: AtomicU64,
+    gets: AtomicU64,
+    inline_reads: AtomicU64,
+    deletes: AtomicU64,
+    merges: AtomicU64,
+    rotations: AtomicU64,
+    get_latency: [AtomicU64; GET_LATENCY_BUCKETS_US.len() + 1],
+    get_latency_sum_us: AtomicU64,
+}
+
+/// Times a get from `Counters::start_get` until it's dropped.
+pub(crate) struct GetTimer<'a> {
+    counters: &'a Counters,
+    start: Instant,
+}
+
+impl Drop for GetTimer<'_> {
+    fn drop(&mut self) {
+        let us = self.start.elapsed().as_micros() as u64;
+        let bucket = GET_LATENCY_BUCKETS_US.partition_point(|&bound| bound < us);
+        self.counters.get_latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .get_latency_sum_us
+            .fetch_add(us, Ordering::Relaxed);
+    }
+}
+
+impl Counters {
+    pub(crate) fn add_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_put(&self) {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a get, timing it until the returned guard is dropped.
+    pub(crate) fn start_get(&self) -> GetTimer<'_> {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        GetTimer {
+            counters: self,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn count_inline_read(&self) {
+        self.inline_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_merge(&self) {
+        self.merges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_rotation(&self) {
+        self.rotations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Db {
+    pub fn metrics(&self) -> Metrics {
+        let counters = &self.counters;
+        Metrics {
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+            bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+            puts: counters.puts.load(Ordering::Relaxed),
+            gets: counters.gets.load(Ordering::Relaxed),
+            inline_reads: counters.inline_reads.load(Ordering::Relaxed),
+            deletes: counters.deletes.load(Ordering::Relaxed),
+            merges: counters.merges.load(Ordering::Relaxed),
+            rotations: counters.rotations.load(Ordering::Relaxed),
+            get_latency: LatencyHistogram {
+                buckets: counters
+                    .get_latency
+                    .iter()
+                    .map(|bucket| bucket.load(Ordering::Relaxed))
+                    .collect(),
+                sum_us: counters.get_latency_sum_us.load(Ordering::Relaxed),
+            },
+            active_file_bytes: self
+                .shards
+                .iter()
+                .map(|shard| shard.active_file_size())
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::batch::encode_transaction_key;
+    use crate::storage::DataEntry;
+    use crate::*;
+
+    #[test]
+    fn test_metrics_count_operations() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_metrics".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        // An earlier run's merge would otherwise be installed on open
+        let _ = std::fs::remove_dir_all(crate::merge::merge_dir_path(&opts.dir_path)?);
+        let db = Db::open(&opts)?;
+
+        let key0 = encode_transaction_key(b"key0".to_vec(), 0);
+        let record = DataEntry::new(key0.clone(), b"value0".to_vec(), State::Active);
+        let record_len = record.encoded_len() as u64;
+        for i in 0..10 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        for i in 0..4 {
+            db.get(Bytes::from(format!("key{}", i)))?;
+        }
+        db.delete(Bytes::from("key0"))?;
+        // Deleting a missing key writes nothing
+        db.delete(Bytes::from("missing"))?;
+
+        let tombstone = DataEntry::new(key0, Vec::new(), State::Inactive);
+        let metrics = db.metrics();
+        assert_eq!(metrics.puts, 10);
+        assert_eq!(metrics.gets, 4);
+        assert_eq!(metrics.deletes, 2);
+        assert_eq!(metrics.merges, 0);
+        assert_eq!(
+            metrics.bytes_written,
+            10 * record_len + tombstone.encoded_len() as u64
+        );
+        assert_eq!(metrics.bytes_read, 4 * record_len);
+
+        assert_eq!(metrics.get_latency.count(), 4);
+        assert_eq!(
+            metrics.get_latency.buckets.len(),
+            GET_LATENCY_BUCKETS_US.len() + 1
+        );
+        assert_eq!(metrics.active_file_bytes, metrics.bytes_written);
+        assert_eq!(metrics.rotations, 0);
+        assert_eq!(metrics.inline_reads, 0);
+
+        db.merge()?;
+        let metrics = db.metrics();
+        assert_eq!(metrics.merges, 1);
+        // Merge moves the database on to a new active file
+        assert_eq!(metrics.rotations, 1);
+        assert_eq!(metrics.active_file_bytes, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_count_inline_reads() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_metrics_inline".to_string(),
+            1024 * 1024,
+        );
+        opts.inline_value_threshold = 4;
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("small"), Bytes::from("abc"))?;
+        db.put(Bytes::from("large"), Bytes::from("abcdef"))?;
+        db.get(Bytes::from("small"))?;
+        db.get(Bytes::from("small"))?;
+        db.get(Bytes::from("large"))?;
+        // A miss is timed too
+        assert!(db.get(Bytes::from("missing")).is_err());
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.gets, 4);
+        assert_eq!(metrics.inline_reads, 2);
+        assert_eq!(metrics.get_latency.count(), 4);
+        Ok(())
+    }
+}
@@ -1,15 +1,179 @@
-use std::path::PathBuf;
+#[cfg(feature = "server")]
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+use std::{path::PathBuf, time::Duration};
 
-use crate::index::{HashMap, IndexMode};
+use crate::index::{HashMap, IndexMode, KeyComparator};
 
 #[derive(Debug, Clone)]
 pub struct Opts {
     pub max_key_size: usize,
     pub max_value_size: usize,
+    /// Serve reads only. Open writes nothing but the lock file: a missing
+    /// directory fails it, and a finished merge is left for the next open
+    /// that may write.
     pub read_only: bool,
     pub sync_writes: bool,
+    /// Also fsync the data directory after creating or renaming data files,
+    /// so a crash can't lose a synced file's directory entry. Only applies
+    /// with `sync_writes`.
+    pub sync_dir: bool,
     pub dir_path: PathBuf,
     pub data_file_size: u64,
+    /// Values up to this many bytes are also kept in the index so `get` can
+    /// skip the data file. `0` disables inlining.
+    pub inline_value_threshold: usize,
+    /// Keep a sorted key-only file next to the data files, rewritten on
+    /// merge and checkpoint, for key scans that don't read the data files.
+    pub key_file: bool,
+    /// Record a summary and index snapshot of each immutable data file, so
+    /// open can skip replaying files that haven't changed since. Files are
+    /// recorded in the background as they rotate out, and by open for the
+    /// files it replays anyway, such as those a merge installed there. A file
+    /// whose size or checksum no longer matches is replayed. Read-only opens
+    /// use the manifest without writing it.
+    pub file_manifest: bool,
+    /// Log every index update to a hint file as it's applied, so open loads
+    /// the index from it and only replays what was appended after its last
+    /// write instead of every data file.
+    pub incremental_hint: bool,
+    /// Keep the length and a CRC32 of each data file in a `{file_id}.crc`
+    /// sidecar, kept up as it's appended to and written as it's retired and
+    /// on close, and check the files against them on open. A file that
+    /// doesn't match, say one truncated or with records dropped, is listed in
+    /// `OpenReport::crc_mismatches` and replayed as it is. Files a merge
+    /// wrote have none.
+    pub file_crc: bool,
+    /// Under `file_crc`, fail open with `Error::ChecksumMismatch` for a file
+    /// that doesn't match its sidecar instead of replaying it.
+    pub strict_file_crc: bool,
+    /// Flush unsynced writes to disk at least this often.
+    pub sync_interval: Option<Duration>,
+    /// Unix permission mode for the data directory when open creates it.
+    #[cfg(unix)]
+    pub dir_mode: Option<u32>,
+    /// Unix permission mode for data files as they're created.
+    #[cfg(unix)]
+    pub file_mode: Option<u32>,
+    /// Which locks on the directory held by another process open takes over.
+    pub lock_policy: LockPolicy,
+    /// Open without taking the directory lock, alongside the process holding
+    /// it, to inspect a live database. Only allowed with `read_only`; the
+    /// files are read as they are at open, and a finished merge is left for
+    /// the lock holder to install.
+    pub skip_lock: bool,
+    /// Most entries of uncommitted batches held in memory while replaying a
+    /// data file. A batch that would go over is dropped on open, so keep
+    /// this above the largest `max_batch_num` in use.
+    pub max_buffered_batch_entries: usize,
+    /// Step over a corrupt record that has intact records after it instead
+    /// of failing open. A corrupt record at the end of a file is a torn
+    /// write and is always dropped.
+    pub skip_corrupt_records: bool,
+    /// Leave out data files that can't be opened or replayed, listing them
+    /// in `OpenReport::skipped_files`, instead of failing open. Their keys
+    /// read as missing, or as an older value from another file.
+    pub tolerate_missing_files: bool,
+    /// Have open start reading every data file into the page cache once the
+    /// index is loaded, so the first reads don't each wait on the disk.
+    pub warmup: bool,
+    /// Share of the data files' bytes that must be reclaimable before
+    /// `Stat::merge_recommended` is set.
+    pub merge_stale_ratio: f64,
+    /// Most reads of data files in flight at once; more wait for one to
+    /// finish. Values inlined in the index aren't counted. `None` for no
+    /// limit.
+    pub max_concurrent_reads: Option<usize>,
+    /// Values at least this many bytes long are appended to an active file
+    /// of their own, so files of small values merge without copying large
+    /// ones around. A key moves between them with its value's size.
+    /// `None` keeps a single active file.
+    /// Fixed once data is written.
+    pub size_class_boundary: Option<u64>,
+    /// Most bytes the data files may take up, for using the database as a
+    /// cache. Once writes go over it, keys are evicted in the background by
+    /// `Db::start_evictor`, or by calling `Db::evict`, oldest written first,
+    /// but keys read since they were written are kept. A value larger than
+    /// the cap can't be written. `None` for no cap.
+    pub max_db_size: Option<u64>,
+    /// The order `Db::iter`, `Db::keys` and `Db::iter_from` walk keys in.
+    /// Prefix scans still match on bytes. `None` for byte order.
+    pub key_order: Option<KeyComparator>,
+    /// Number of independent active files puts are spread over by key hash.
+    /// `0` or `1` keeps a single active file. Fixed once data is written.
+    #[cfg(feature = "write-shards")]
+    pub write_shards: usize,
+    /// The time the server's TTL deadlines are set from and checked
+    /// against. Deadlines are stored as absolute times, so a clock that
+    /// jumps moves every deadline with it.
+    #[cfg(feature = "server")]
+    pub ttl_clock: TtlClock,
+}
+
+/// Which locks on the data directory `Db::open` takes over, for filesystems
+/// where locks outlive their holder (NFS). A lock file nobody holds a lock
+/// on, as a crashed process leaves behind, is taken whatever the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Fail while another process holds the lock.
+    #[default]
+    Respect,
+    /// Take over a lock left by a process that no longer exists on this
+    /// host.
+    BreakStale,
+    /// Also take over a lock whose holder can't be checked: one recorded on
+    /// another host, or with unreadable contents. A holder alive on this host
+    /// is never overridden. Only for when nothing else can be using the
+    /// database, say after its host crashed.
+    Force,
+}
+
+/// A source of wall-clock time, `Fn() -> SystemTime`, for `Opts::ttl_clock`.
+/// Defaults to the system clock.
+#[cfg(feature = "server")]
+#[derive(Clone)]
+pub struct TtlClock(Arc<dyn Fn() -> SystemTime + Send + Sync>);
+
+#[cfg(feature = "server")]
+impl TtlClock {
+    pub fn new(now: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        TtlClock(Arc::new(now))
+    }
+
+    /// The system clock, as is.
+    pub fn system() -> Self {
+        TtlClock::new(SystemTime::now)
+    }
+
+    /// The system time when called, advanced from then on by the monotonic
+    /// clock. Later jumps of the system clock, say a correction backward,
+    /// don't make entries outlive their TTL or expire early; time lost while
+    /// the machine sleeps may not be counted.
+    pub fn monotonic() -> Self {
+        let (anchor, start) = (SystemTime::now(), Instant::now());
+        TtlClock::new(move || anchor + start.elapsed())
+    }
+
+    pub fn now(&self) -> SystemTime {
+        (self.0)()
+    }
+}
+
+#[cfg(feature = "server")]
+impl Default for TtlClock {
+    fn default() -> Self {
+        TtlClock::system()
+    }
+}
+
+#[cfg(feature = "server")]
+impl fmt::Debug for TtlClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TtlClock").field(&self.now()).finish()
+    }
 }
 
 #[derive(Debug)]
@@ -25,8 +189,35 @@ impl Default for Opts {
             max_value_size: 2048,
             read_only: false,
             sync_writes: true,
+            sync_dir: true,
             dir_path: PathBuf::from("/tmp"),
             data_file_size: 256 * 1024 * 1024,
+            inline_value_threshold: 0,
+            key_file: false,
+            file_manifest: false,
+            incremental_hint: false,
+            file_crc: false,
+            strict_file_crc: false,
+            sync_interval: None,
+            #[cfg(unix)]
+            dir_mode: None,
+            #[cfg(unix)]
+            file_mode: None,
+            lock_policy: LockPolicy::default(),
+            skip_lock: false,
+            max_buffered_batch_entries: 1 << 20,
+            skip_corrupt_records: false,
+            tolerate_missing_files: false,
+            warmup: false,
+            merge_stale_ratio: 0.5,
+            max_concurrent_reads: None,
+            size_class_boundary: None,
+            max_db_size: None,
+            key_order: None,
+            #[cfg(feature = "write-shards")]
+            write_shards: 0,
+            #[cfg(feature = "server")]
+            ttl_clock: TtlClock::default(),
         }
     }
 }
@@ -45,10 +236,53 @@ impl Opts {
             max_value_size,
             read_only,
             sync_writes,
+            sync_dir: true,
             dir_path: PathBuf::from(dir_path),
             data_file_size,
+            inline_value_threshold: 0,
+            key_file: false,
+            file_manifest: false,
+            incremental_hint: false,
+            file_crc: false,
+            strict_file_crc: false,
+            sync_interval: None,
+            #[cfg(unix)]
+            dir_mode: None,
+            #[cfg(unix)]
+            file_mode: None,
+            lock_policy: LockPolicy::default(),
+            skip_lock: false,
+            max_buffered_batch_entries: 1 << 20,
+            skip_corrupt_records: false,
+            tolerate_missing_files: false,
+            warmup: false,
+            merge_stale_ratio: 0.5,
+            max_concurrent_reads: None,
+            size_class_boundary: None,
+            max_db_size: None,
+            key_order: None,
+            #[cfg(feature = "write-shards")]
+            write_shards: 0,
+            #[cfg(feature = "server")]
+            ttl_clock: TtlClock::default(),
         }
     }
+
+    pub(crate) fn should_sync_dir(&self) -> bool {
+        self.sync_writes && self.sync_dir
+    }
+
+    // An empty index walked in `key_order`
+    pub(crate) fn new_index(&self) -> HashMap {
+        match &self.key_order {
+            Some(comparator) => HashMap::with_comparator(comparator.clone()),
+            None => HashMap::new(),
+        }
+    }
+
+    pub(crate) fn should_inline(&self, value_len: usize) -> bool {
+        self.inline_value_threshold > 0 && value_len <= self.inline_value_threshold
+    }
 }
 
 impl Default for Context {
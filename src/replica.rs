@@ -0,0 +1,356 @@
+//! Read-only replicas kept up to date by copying a primary's data files.
+//!
+//! A `Replicator` copies the data files of a primary to a replica directory:
+//! files the replica doesn't have yet, and what was appended to the ones it
+//! has, up to where every batch written so far is whole. A `ReplicaDb`
+//! serves reads from that directory and `catch_up` replays just what the
+//! last sync added.
+//!
+//! Merges rewrite the data files on the primary's next open, starting a new
+//! generation (see `Db::changes_since`). A sync that finds the replica on an
+//! older generation replaces all its files, and a replica that finds its
+//! files replaced reopens. A replica only reads once a sync is done, so
+//! `catch_up` shouldn't run while a sync to its directory is under way.
+
+use crate::changes::{read_generation, write_generation};
+use crate::db::{parse_data_file_id, sync_dir, Db, FILE_SUFFIX};
+use crate::io::{MmapIO, StandardIO};
+use crate::options::Opts;
+use crate::shard::shard_of;
+use crate::storage::{FileHandle, HINT_FILE_NAME};
+use crate::Result;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+/// What `Replicator::sync_to` changed in the replica directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Files copied whole, because the replica didn't have them or had a
+    /// different version.
+    pub copied_files: Vec<u32>,
+    /// Files the replica had a start of, which got the rest appended.
+    pub extended_files: Vec<u32>,
+    /// Files the primary no longer has.
+    pub removed_files: Vec<u32>,
+    /// Bytes of data files written.
+    pub bytes: u64,
+}
+
+/// Copies the data files of a primary to replica directories.
+#[derive(Debug)]
+pub struct Replicator<'a> {
+    primary: &'a Db,
+}
+
+impl<'a> Replicator<'a> {
+    pub fn new(primary: &'a Db) -> Self {
+        Replicator { primary }
+    }
+
+    /// Brings the replica in `dir_path` up to date with the primary's data
+    /// files as they are when called, creating the directory if needed.
+    ///
+    /// Only writes that are durable on the primary are copied, so the
+    /// replica never has one the primary could lose in a crash.
+    pub fn sync_to(&self, dir_path: &Path) -> Result<SyncReport> {
+        let db = self.primary;
+        // The ends of the active files are read under their locks, which
+        // batches hold from their first entry to their marker
+        let ends = db
+            .shards
+            .iter()
+            .map(|shard| {
+                let read_guard = shard.active_file.read();
+                (read_guard.get_file_id(), read_guard.get_offset())
+            })
+            .collect::<Vec<_>>();
+        for end in ends.iter() {
+            db.wait_durable(*end)?;
+        }
+        // Files retired since the ends were read are copied whole. Their
+        // successors aren't, so the copy still ends on a whole batch
+        let mut sizes = ends.into_iter().collect::<BTreeMap<u32, u64>>();
+        for file in db.inactive_files.iter() {
+            sizes.insert(file.get_file_id(), file.get_offset());
+        }
+
+        fs::create_dir_all(dir_path)?;
+        let mut report = SyncReport::default();
+        let new_generation = read_generation(dir_path)? != db.generation;
+        let replica_files = data_files(dir_path)?;
+        for file_id in replica_files.keys() {
+            if new_generation || !sizes.contains_key(file_id) {
+                fs::remove_file(dir_path.join(format!("{}{}", file_id, FILE_SUFFIX)))?;
+                report.removed_files.push(*file_id);
+            }
+        }
+
+        let src_dir = &db.ctx.opts.dir_path;
+        for (file_id, size) in sizes {
+            let name = format!("{}{}", file_id, FILE_SUFFIX);
+            let (src, dst) = (src_dir.join(&name), dir_path.join(&name));
+            let copied = match replica_files.get(&file_id) {
+                Some(len) if !new_generation && *len == size => continue,
+                // What the replica has of the active file is a prefix of it
+                Some(len) if !new_generation && *len < size => {
+                    report.extended_files.push(file_id);
+                    copy_range(&src, &dst, *len, size)?
+                }
+                _ => {
+                    report.copied_files.push(file_id);
+                    let _ = fs::remove_file(&dst);
+                    copy_range(&src, &dst, 0, size)?
+                }
+            };
+            report.bytes += copied;
+        }
+
+        // Hint files are only written by merges
+        if new_generation {
+            let hint_file = src_dir.join(HINT_FILE_NAME);
+            let _ = fs::remove_file(dir_path.join(HINT_FILE_NAME));
+            if hint_file.is_file() {
+                fs::copy(&hint_file, dir_path.join(HINT_FILE_NAME))?;
+                File::open(dir_path.join(HINT_FILE_NAME))?.sync_all()?;
+            }
+        }
+        // The generation goes last: a replica on it has all its files
+        sync_dir(dir_path)?;
+        write_generation(dir_path, db.generation)?;
+        sync_dir(dir_path)?;
+        Ok(report)
+    }
+}
+
+/// A read-only database on a replica directory kept up to date with
+/// `Replicator::sync_to`. Reads go through `Deref` to the `Db`.
+#[derive(Debug)]
+pub struct ReplicaDb {
+    db: Db,
+    opts: Opts,
+}
+
+impl ReplicaDb {
+    /// Opens the replica in `opts.dir_path`, read only whatever
+    /// `opts.read_only` says. Sync to the directory first.
+    pub fn open_following(opts: &Opts) -> Result<Self> {
+        let mut opts = opts.clone();
+        opts.read_only = true;
+        Ok(ReplicaDb {
+            db: Db::open(&opts)?,
+            opts,
+        })
+    }
+
+    /// Replays what syncs have added to the data files since the replica
+    /// was opened or last caught up. Reopens instead if a sync replaced or
+    /// removed files, after a merge or a compaction on the primary.
+    pub fn catch_up(&mut self) -> Result<()> {
+        let dir_path = &self.opts.dir_path;
+        let files = data_files(dir_path)?;
+        let known = self.db.data_file_ids();
+        let replaced = read_generation(dir_path)? != self.db.generation
+            || known.iter().any(|file_id| !files.contains_key(file_id))
+            || self.db.shards.iter().any(|shard| {
+                let active_file = shard.active_file.read();
+                files[&active_file.get_file_id()] < active_file.get_offset()
+            });
+        if replaced {
+            // The lock has to be free for the new handle
+            self.db.close()?;
+            self.db = Db::open(&self.opts)?;
+            return Ok(());
+        }
+
+        let db = &self.db;
+        let shard_count = db.shards.len();
+        let mut current_sequence_number = 0;
+        // In id order, so each key's writes replay in the order they were made
+        for (file_id, len) in files {
+            let shard = &db.shards[shard_of(file_id, shard_count)];
+            let mut active_file = shard.active_file.write();
+            if file_id == active_file.get_file_id() {
+                if len > active_file.get_offset() {
+                    let replay =
+                        Db::process_file_from(&active_file, active_file.get_offset(), &self.opts)?;
+                    replay.apply(&db.ctx.index, &mut current_sequence_number);
+                    active_file.set_offset(replay.size);
+                }
+                continue;
+            }
+            if known.contains(&file_id) || file_id < active_file.get_file_id() {
+                continue;
+            }
+
+            // A file after the active one: it becomes the active file
+            let path = dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+            let new_file = FileHandle::new(file_id, StandardIO::open_read_only(&path)?.into());
+            let replay = Db::process_file_handle(&new_file, &self.opts)?;
+            replay.apply(&db.ctx.index, &mut current_sequence_number);
+            new_file.set_offset(replay.size);
+
+            let old_file = std::mem::replace(&mut *active_file, new_file);
+            let old_path = dir_path.join(format!("{}{}", old_file.get_file_id(), FILE_SUFFIX));
+            let retired = FileHandle::new(
+                old_file.get_file_id(),
+                MmapIO::open_read_only(&old_path)?.into(),
+            );
+            retired.set_offset(old_file.get_offset());
+            db.inactive_files
+                .insert(retired.get_file_id(), retired.freeze());
+            shard.file_id.store(file_id, Ordering::SeqCst);
+            shard.publish(&active_file);
+        }
+        db.sequence_number
+            .fetch_max(current_sequence_number + 1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Deref for ReplicaDb {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+// Ids and sizes of the data files in `dir_path`
+fn data_files(dir_path: &Path) -> Result<BTreeMap<u32, u64>> {
+    let mut files = BTreeMap::new();
+    for dentry in fs::read_dir(dir_path)? {
+        let dentry = dentry?;
+        if let Some(file_id) = dentry.file_name().to_str().and_then(parse_data_file_id) {
+            files.insert(file_id, dentry.metadata()?.len());
+        }
+    }
+    Ok(files)
+}
+
+// Copies bytes `from..to` of `src` to the same place in `dst`, which has
+// the bytes before them, and syncs it
+fn copy_range(src: &Path, dst: &Path, from: u64, to: u64) -> Result<u64> {
+    let mut src = File::open(src)?;
+    src.seek(SeekFrom::Start(from))?;
+    let mut dst = OpenOptions::new().create(true).append(true).open(dst)?;
+    let copied = io::copy(&mut src.take(to - from), &mut dst)?;
+    dst.sync_all()?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::merge_dir_path;
+    use bytes::Bytes;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn opts(dir_path: &str) -> Result<Opts> {
+        let opts = Opts::new(256, 1024, false, false, dir_path.to_string(), 4096);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(merge_dir_path(&opts.dir_path)?);
+        Ok(opts)
+    }
+
+    fn key(i: usize) -> Bytes {
+        Bytes::from(format!("key{:05}", i))
+    }
+
+    #[test]
+    fn test_replica_follows_writer() -> Result<()> {
+        let primary_opts = opts("/tmp/test_replica_primary")?;
+        let replica_opts = opts("/tmp/test_replica_follower")?;
+        let primary = Arc::new(Db::open(&primary_opts)?);
+        let committed = Arc::new(AtomicUsize::new(0));
+        let writer = {
+            let (primary, committed) = (primary.clone(), committed.clone());
+            thread::spawn(move || -> Result<()> {
+                let mut i = 0;
+                while i < 2000 {
+                    if i % 10 == 8 {
+                        primary.put_all(&[(key(i), key(i)), (key(i + 1), key(i + 1))])?;
+                        i += 2;
+                    } else {
+                        primary.put(key(i), key(i))?;
+                        i += 1;
+                    }
+                    committed.store(i, Ordering::SeqCst);
+                    thread::sleep(Duration::from_micros(50));
+                }
+                Ok(())
+            })
+        };
+
+        let replicator = Replicator::new(&primary);
+        replicator.sync_to(&replica_opts.dir_path)?;
+        let mut replica = ReplicaDb::open_following(&replica_opts)?;
+        let opened = replica.open_report().clone();
+        let mut syncs = 0;
+        while !writer.is_finished() {
+            thread::sleep(Duration::from_millis(5));
+            let before = committed.load(Ordering::SeqCst);
+            replicator.sync_to(&replica_opts.dir_path)?;
+            replica.catch_up()?;
+            for i in 0..before {
+                assert_eq!(replica.get(key(i))?, key(i));
+            }
+            syncs += 1;
+        }
+        writer.join().unwrap()?;
+        assert!(syncs > 1);
+        // Caught up without reopening
+        assert_eq!(replica.open_report(), &opened);
+        let written = committed.load(Ordering::SeqCst);
+        assert!(primary.data_file_ids().len() > 1);
+
+        // Everything once the writer has stopped
+        let report = replicator.sync_to(&replica_opts.dir_path)?;
+        assert!(report.copied_files.is_empty() || report.bytes > 0);
+        replica.catch_up()?;
+        assert_eq!(replica.list_keys()?.len(), written);
+        assert!(replica.put(key(0), key(0)).is_err());
+        assert_eq!(replicator.sync_to(&replica_opts.dir_path)?.bytes, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replica_swaps_merged_files() -> Result<()> {
+        let primary_opts = opts("/tmp/test_replica_merge_primary")?;
+        let replica_opts = opts("/tmp/test_replica_merge_follower")?;
+        let primary = Db::open(&primary_opts)?;
+        for round in 0..5 {
+            for i in 0..100 {
+                primary.put(key(i), Bytes::from(format!("{}", round)))?;
+            }
+        }
+        Replicator::new(&primary).sync_to(&replica_opts.dir_path)?;
+        let mut replica = ReplicaDb::open_following(&replica_opts)?;
+
+        primary.merge()?;
+        drop(primary);
+        // Installs the merge
+        let primary = Db::open(&primary_opts)?;
+        for i in 0..10 {
+            primary.put(key(i), Bytes::from("after"))?;
+        }
+        let report = Replicator::new(&primary).sync_to(&replica_opts.dir_path)?;
+        assert!(!report.removed_files.is_empty());
+        assert!(report.extended_files.is_empty());
+        replica.catch_up()?;
+
+        assert_eq!(replica.data_file_ids(), primary.data_file_ids());
+        assert!(!replica.open_report().hint_file_ignored);
+        for i in 0..100 {
+            let value = if i < 10 { "after" } else { "4" };
+            assert_eq!(replica.get(key(i))?, value.as_bytes());
+        }
+        Ok(())
+    }
+}
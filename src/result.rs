@@ -1,4 +1,5 @@
-use std::io;
+use crate::ChangeCursor;
+use std::{io, path::PathBuf};
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -15,4 +16,47 @@ pub enum Error {
     /// system.
     #[error("IO Error")]
     Io(#[from] io::Error),
+    /// An index entry points at a data file the database doesn't have.
+    #[error("Db read error: File {0} not found")]
+    FileNotFound(u32),
+    /// A key was empty. Every read and write needs one.
+    #[error("Key is required")]
+    EmptyKey,
+    /// An append ran out of disk space. The partial record was cut off, so
+    /// appending can resume once space is freed.
+    #[error("No space left on device")]
+    DiskFull,
+    /// A data file holds a record that fails to decode with intact records
+    /// after it, so it isn't a torn write at the end of the file.
+    #[error("Corrupt record in data file {file_id} at offset {offset}")]
+    Corrupted { file_id: u32, offset: u64 },
+    /// A data file no longer matches the length and CRC32 recorded for it
+    /// under `Opts::file_crc`, say because it was truncated.
+    #[error("Data file {0} doesn't match its recorded checksum")]
+    ChecksumMismatch(u32),
+    /// Several names in the data directory parse to the same data file id,
+    /// or one does with leading zeros, so it isn't clear which file holds
+    /// the id's data.
+    #[error("Conflicting data files for id {file_id}: {paths:?}")]
+    ConflictingDataFiles { file_id: u32, paths: Vec<PathBuf> },
+    /// Rotation has used up the data file ids. Merging renumbers the files
+    /// on the next open, so the ids can be used again.
+    #[error("Data file ids exhausted after file {0}, merge to renumber the data files")]
+    FileIdsExhausted(u32),
+    /// A stored key or value couldn't be decoded as the type it was read
+    /// as, say after the type's definition changed.
+    #[error("Decode error: {0}")]
+    Decode(String),
+    /// A codec was handed bytes it can't decode, such as a key of the
+    /// wrong length.
+    #[error("Can't decode {bytes:?}: {reason}")]
+    Undecodable { bytes: Vec<u8>, reason: String },
+    /// A change cursor points into data files that have since been merged
+    /// or compacted away. The changes after it are gone, so the consumer
+    /// has to resync from a full scan.
+    #[error("Change cursor {0:?} has expired, resync from a full scan")]
+    CursorExpired(ChangeCursor),
+    /// The database has been shut down.
+    #[error("Database is closed")]
+    Closed,
 }
@@ -0,0 +1,256 @@
+use crate::{Error, Result};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send>;
+pub(crate) type Task = dyn Fn() + Send + Sync;
+
+struct Timer {
+    due: Instant,
+    interval: Duration,
+    // Dropped along with whatever registered it
+    task: Weak<Task>,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    timers: Vec<Timer>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    work: Condvar,
+}
+
+struct Pool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+/// A pool of threads that the databases opened on it with
+/// `Db::open_with_runtime` run their background work on, such as flushing
+/// and fsyncing writes, instead of each starting threads of their own.
+///
+/// Clones share the pool. Its threads stop once the last clone is dropped,
+/// including those the databases hold.
+#[derive(Clone)]
+pub struct SharedRuntime(Arc<Pool>);
+
+impl SharedRuntime {
+    /// Starts a pool of `threads` threads.
+    pub fn new(threads: usize) -> Result<Self> {
+        if threads == 0 {
+            return Err(Error::Unsupported(
+                "A shared runtime needs at least one thread".to_string(),
+            ));
+        }
+        let shared = Arc::new(Shared::default());
+        let workers = (0..threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name("zap-runtime".to_string())
+                    .spawn(move || work(&shared))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(SharedRuntime(Arc::new(Pool { shared, workers })))
+    }
+
+    /// Number of threads in the pool.
+    pub fn threads(&self) -> usize {
+        self.0.workers.len()
+    }
+
+    /// Runs `job` on one of the pool's threads.
+    pub(crate) fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        self.0.shared.queue.lock().jobs.push_back(Box::new(job));
+        self.0.shared.work.notify_one();
+    }
+
+    /// Runs `task` every `interval` for as long as it is alive.
+    pub(crate) fn every(&self, interval: Duration, task: &Arc<Task>) {
+        self.0.shared.queue.lock().timers.push(Timer {
+            due: Instant::now() + interval,
+            interval,
+            task: Arc::downgrade(task),
+        });
+        self.0.shared.work.notify_one();
+    }
+}
+
+impl fmt::Debug for SharedRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedRuntime")
+            .field("threads", &self.threads())
+            .finish()
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().shutdown = true;
+        self.shared.work.notify_all();
+        let current = thread::current().id();
+        for worker in self.workers.drain(..) {
+            // Dropped by one of its own jobs, the thread finishes by itself
+            if worker.thread().id() != current {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+// Runs jobs as they come and timers as they fall due. Queued jobs are still
+// run once the pool is shutting down.
+fn work(shared: &Shared) {
+    let mut queue = shared.queue.lock();
+    loop {
+        if let Some(job) = queue.jobs.pop_front() {
+            MutexGuard::unlocked(&mut queue, job);
+            continue;
+        }
+        if queue.shutdown {
+            return;
+        }
+
+        let now = Instant::now();
+        queue.timers.retain(|timer| timer.task.strong_count() > 0);
+        if let Some(timer) = queue
+            .timers
+            .iter_mut()
+            .filter(|timer| timer.due <= now)
+            .min_by_key(|timer| timer.due)
+        {
+            timer.due = now + timer.interval;
+            let task = timer.task.clone();
+            MutexGuard::unlocked(&mut queue, || {
+                if let Some(task) = task.upgrade() {
+                    task();
+                }
+            });
+            continue;
+        }
+        match queue.timers.iter().map(|timer| timer.due).min() {
+            Some(due) => {
+                shared.work.wait_until(&mut queue, due);
+            }
+            None => shared.work.wait(&mut queue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::Opts;
+    use bytes::Bytes;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_databases_share_a_runtime() -> Result<()> {
+        let runtime = SharedRuntime::new(2)?;
+        let dbs = (0..3)
+            .map(|i| {
+                let mut opts = Opts::new(
+                    256,
+                    1024,
+                    false,
+                    i != 0,
+                    format!("/tmp/test_shared_runtime_{}", i),
+                    1024,
+                );
+                let _ = fs::remove_dir_all(&opts.dir_path);
+                opts.sync_interval = Some(Duration::from_millis(5));
+                Db::open_with_runtime(&opts, &runtime)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // None of them started sync threads of their own
+        assert!(dbs
+            .iter()
+            .flat_map(|db| db.shards.iter())
+            .all(|shard| !shard.syncer.has_thread()));
+
+        thread::scope(|s| {
+            for db in dbs.iter() {
+                s.spawn(move || {
+                    for i in 0..200 {
+                        db.put(Bytes::from(format!("key{}", i)), Bytes::from("value"))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        // Sync writes were all acknowledged, so the runtime synced them; the
+        // interval flush syncs the database without sync writes
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while dbs[0].shards[0].syncer.sync_count() == 0 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(5));
+        }
+        for db in dbs.iter() {
+            assert!(db.shards[0].syncer.sync_count() > 0);
+            assert_eq!(db.get(Bytes::from("key199"))?, "value");
+        }
+
+        // Merges run on it too, and the databases keep it running
+        dbs[1].merge()?;
+        assert_eq!(runtime.threads(), 2);
+        drop(runtime);
+        drop(dbs);
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_shared_runtime_1".to_string(),
+            1024,
+        );
+        opts.sync_interval = None;
+        assert_eq!(Db::open(&opts)?.get(Bytes::from("key0"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_timers_stop_with_their_task() -> Result<()> {
+        let runtime = SharedRuntime::new(1)?;
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        let task: Arc<Task> = Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        runtime.every(Duration::from_millis(1), &task);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while ticks.load(Ordering::SeqCst) < 3 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(task);
+        // A run under way when it was dropped may still finish
+        thread::sleep(Duration::from_millis(20));
+        let stopped_at = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(ticks.load(Ordering::SeqCst), stopped_at);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        runtime.spawn(move || {
+            sender
+                .send(thread::current().name().map(str::to_string))
+                .unwrap()
+        });
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(10)).unwrap(),
+            Some("zap-runtime".to_string())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,349 @@
+//! Secondary indexes over values, for finding keys by a field of their
+//! value. They're kept in memory next to the primary index and built from it
+//! when created, so they are created again after every open.
+
+use crate::db::Db;
+use crate::index::{IndexIterator, Indexer};
+use crate::sequencer::IndexUpdate;
+use crate::storage::DataEntry;
+use crate::{Error, KeyDirEntry, Result};
+use bytes::Bytes;
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Pulls the secondary key out of a value, `None` to leave the value's key
+/// out of the index.
+pub type Extractor = fn(&[u8]) -> Option<Vec<u8>>;
+
+/// A key's new secondary key in the named index, `None` if it has none
+/// anymore
+pub(crate) type SecondaryUpdate = (String, Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Debug, Default)]
+pub(crate) struct SecondaryIndexes {
+    indexes: RwLock<HashMap<String, SecondaryIndex>>,
+}
+
+#[derive(Debug)]
+struct SecondaryIndex {
+    extractor: Extractor,
+    // Secondary key to the primary keys that have it
+    entries: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    // Primary key to its secondary key, for moving it on an update
+    secondary_keys: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl SecondaryIndex {
+    fn set(&mut self, primary: Vec<u8>, secondary: Option<Vec<u8>>) {
+        if let Some(old) = self.secondary_keys.remove(&primary) {
+            if let Some(primaries) = self.entries.get_mut(&old) {
+                primaries.remove(&primary);
+                if primaries.is_empty() {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+        if let Some(secondary) = secondary {
+            self.entries
+                .entry(secondary.clone())
+                .or_default()
+                .insert(primary.clone());
+            self.secondary_keys.insert(primary, secondary);
+        }
+    }
+}
+
+impl SecondaryIndexes {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.read().is_empty()
+    }
+
+    // What every index makes of `key` now holding `value`, or being deleted
+    fn extract(&self, key: &[u8], value: Option<&[u8]>, updates: &mut Vec<SecondaryUpdate>) {
+        for (name, index) in self.indexes.read().iter() {
+            let secondary = value.and_then(index.extractor);
+            updates.push((name.clone(), key.to_vec(), secondary));
+        }
+    }
+
+    /// Applies the updates of one write at once, so lookups see all of a
+    /// batch or none of it.
+    pub(crate) fn apply(&self, updates: Vec<SecondaryUpdate>) {
+        if updates.is_empty() {
+            return;
+        }
+        let mut indexes = self.indexes.write();
+        for (name, primary, secondary) in updates {
+            // Extracted for an index dropped since
+            if let Some(index) = indexes.get_mut(&name) {
+                index.set(primary, secondary);
+            }
+        }
+    }
+
+    // The name and extractor of every index, to create them again with
+    pub(crate) fn extractors(&self) -> Vec<(String, Extractor)> {
+        self.indexes
+            .read()
+            .iter()
+            .map(|(name, index)| (name.clone(), index.extractor))
+            .collect()
+    }
+
+    // Takes `key` out of every index, as when it's evicted
+    pub(crate) fn remove(&self, key: &[u8]) {
+        if self.is_empty() {
+            return;
+        }
+        for index in self.indexes.write().values_mut() {
+            index.set(key.to_vec(), None);
+        }
+    }
+}
+
+impl Db {
+    /// Starts keeping the secondary index `name`, mapping what `extractor`
+    /// pulls out of each value to the keys holding it. It's built from
+    /// every live key and updated by each write in the order writes become
+    /// visible, a batch's writes all at once.
+    ///
+    /// Indexes live in memory only: they're gone once the database closes
+    /// and are rebuilt by creating them again after the next open.
+    pub fn create_index(&self, name: &str, extractor: Extractor) -> Result<()> {
+        // Writes made after this wait for the index; earlier ones are in the
+        // primary index it's built from
+        let _commit_lock = self.batch_commit_lock.write();
+        self.check_open()?;
+        self.index_sequencer.wait_all();
+        if self.secondary_indexes.indexes.read().contains_key(name) {
+            return Err(Error::Unsupported(format!("Index {} already exists", name)));
+        }
+
+        let mut index = SecondaryIndex {
+            extractor,
+            entries: BTreeMap::new(),
+            secondary_keys: HashMap::new(),
+        };
+        let mut iter = self.ctx.index.iter();
+        while let Some((key, entry)) = iter.next() {
+            let value = self.value_at(key, entry.clone())?;
+            index.set(key.to_vec(), extractor(&value));
+        }
+        self.secondary_indexes
+            .indexes
+            .write()
+            .insert(name.to_string(), index);
+        Ok(())
+    }
+
+    /// Stops keeping the secondary index `name`.
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        match self.secondary_indexes.indexes.write().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(no_such_index(name)),
+        }
+    }
+
+    /// The keys whose values have `secondary_key` in the index `name`, in
+    /// key order.
+    pub fn get_by_index(&self, name: &str, secondary_key: &[u8]) -> Result<Vec<Bytes>> {
+        let indexes = self.secondary_indexes.indexes.read();
+        let index = indexes.get(name).ok_or_else(|| no_such_index(name))?;
+        Ok(index
+            .entries
+            .get(secondary_key)
+            .map(|primaries| primaries.iter().cloned().map(Bytes::from).collect())
+            .unwrap_or_default())
+    }
+
+    /// Every secondary key in the index `name` starting with `prefix`,
+    /// paired with each key holding it, in secondary key order.
+    pub fn scan_index_prefix(&self, name: &str, prefix: &[u8]) -> Result<Vec<(Bytes, Bytes)>> {
+        let indexes = self.secondary_indexes.indexes.read();
+        let index = indexes.get(name).ok_or_else(|| no_such_index(name))?;
+        Ok(index
+            .entries
+            .range(prefix.to_vec()..)
+            .take_while(|(secondary, _)| secondary.starts_with(prefix))
+            .flat_map(|(secondary, primaries)| {
+                primaries.iter().map(|primary| {
+                    (
+                        Bytes::copy_from_slice(secondary),
+                        Bytes::copy_from_slice(primary),
+                    )
+                })
+            })
+            .collect())
+    }
+
+    // The secondary index updates for the index `updates` of a write,
+    // reading back the values it wrote. A value that can't be read leaves
+    // its key out of the indexes rather than under a stale secondary key.
+    pub(crate) fn secondary_updates(&self, updates: &[IndexUpdate]) -> Vec<SecondaryUpdate> {
+        let mut secondary_updates = Vec::new();
+        if self.secondary_indexes.is_empty() {
+            return secondary_updates;
+        }
+        for (key, entry) in updates {
+            let value = entry
+                .as_ref()
+                .and_then(|entry| self.value_at(key, entry.clone()).ok());
+            self.secondary_indexes
+                .extract(key, value.as_deref(), &mut secondary_updates);
+        }
+        secondary_updates
+    }
+
+    // Builds every secondary index again from the primary index
+    pub(crate) fn rebuild_secondary_indexes(&self) -> Result<()> {
+        let mut indexes = self.secondary_indexes.indexes.write();
+        for index in indexes.values_mut() {
+            index.entries.clear();
+            index.secondary_keys.clear();
+        }
+        let mut iter = self.ctx.index.iter();
+        while let Some((key, entry)) = iter.next() {
+            let value = self.value_at(key, entry.clone())?;
+            for index in indexes.values_mut() {
+                index.set(key.to_vec(), (index.extractor)(&value));
+            }
+        }
+        Ok(())
+    }
+
+    // The value `entry` holds for `key`
+    fn value_at(&self, key: &[u8], entry: KeyDirEntry) -> Result<Vec<u8>> {
+        match entry.get_inline_value() {
+            Some(value) => Ok(value.to_vec()),
+            None => self.read_data_entry(key, entry).map(DataEntry::into_value),
+        }
+    }
+}
+
+fn no_such_index(name: &str) -> Error {
+    Error::Unsupported(format!("No index named {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    // Values are `user|rest`; the index is over `user`
+    fn user_of(value: &[u8]) -> Option<Vec<u8>> {
+        let end = value.iter().position(|b| *b == b'|')?;
+        Some(value[..end].to_vec())
+    }
+
+    fn keys(keys: &[&str]) -> Vec<Bytes> {
+        keys.iter()
+            .map(|key| Bytes::copy_from_slice(key.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_secondary_index() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_secondary_index".to_string(),
+            1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("session1"), Bytes::from("alice|a"))?;
+        db.put(Bytes::from("session2"), Bytes::from("alice|b"))?;
+        db.put(Bytes::from("session3"), Bytes::from("bob|c"))?;
+
+        // Built from what's there, then kept up by writes
+        db.create_index("user", user_of)?;
+        assert_eq!(
+            db.get_by_index("user", b"alice")?,
+            keys(&["session1", "session2"])
+        );
+        db.put(Bytes::from("session2"), Bytes::from("bob|d"))?;
+        db.delete(Bytes::from("session1"))?;
+        db.put_all(&[
+            (Bytes::from("session3"), Bytes::from("alice|e")),
+            (Bytes::from("session4"), Bytes::from("bert|f")),
+            (Bytes::from("session5"), Bytes::from("no user")),
+        ])?;
+        let check = |db: &Db| -> Result<()> {
+            assert_eq!(db.get_by_index("user", b"alice")?, keys(&["session3"]));
+            assert_eq!(db.get_by_index("user", b"bob")?, keys(&["session2"]));
+            assert!(db.get_by_index("user", b"carol")?.is_empty());
+            assert_eq!(
+                db.scan_index_prefix("user", b"b")?,
+                vec![
+                    (Bytes::from("bert"), Bytes::from("session4")),
+                    (Bytes::from("bob"), Bytes::from("session2")),
+                ]
+            );
+            assert_eq!(db.scan_index_prefix("user", b"")?.len(), 3);
+            Ok(())
+        };
+        check(&db)?;
+        assert!(matches!(
+            db.create_index("user", user_of),
+            Err(Error::Unsupported(_))
+        ));
+
+        // Merging moves entries, not what they hold
+        db.merge()?;
+        check(&db)?;
+        drop(db);
+
+        // Created again after reopening, the index comes out the same
+        let mut db = Db::open(&opts)?;
+        assert!(db.get_by_index("user", b"alice").is_err());
+        db.create_index("user", user_of)?;
+        check(&db)?;
+        db.reindex()?;
+        check(&db)?;
+
+        db.drop_index("user")?;
+        assert!(db.get_by_index("user", b"alice").is_err());
+        assert!(db.drop_index("user").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_moves_keys_at_once() -> Result<()> {
+        let opts = Opts::new(256, 1024, false, false, "/tmp/unused".to_string(), 4096);
+        let db = Db::open_in_memory(&opts)?;
+        db.create_index("user", user_of)?;
+        db.put_all(&[
+            (Bytes::from("a"), Bytes::from("x|")),
+            (Bytes::from("b"), Bytes::from("x|")),
+        ])?;
+
+        let done = AtomicBool::new(false);
+        thread::scope(|s| -> Result<()> {
+            let writer = s.spawn(|| -> Result<()> {
+                for i in 0..500 {
+                    let user = if i % 2 == 0 { "y|" } else { "x|" };
+                    db.put_all(&[
+                        (Bytes::from("a"), Bytes::from(user)),
+                        (Bytes::from("b"), Bytes::from(user)),
+                    ])?;
+                }
+                done.store(true, Ordering::SeqCst);
+                Ok(())
+            });
+            while !done.load(Ordering::SeqCst) {
+                // Both keys under one user, never split across two
+                let entries = db.scan_index_prefix("user", b"")?;
+                assert_eq!(entries.len(), 2);
+                assert_eq!(entries[0].0, entries[1].0, "{:?}", entries);
+            }
+            writer.join().unwrap()
+        })?;
+        assert_eq!(db.get_by_index("user", b"x")?, keys(&["a", "b"]));
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::index::{IndexMode, Indexer};
+use crate::secondary::{SecondaryIndexes, SecondaryUpdate};
+use crate::storage::HintLog;
+use crate::syncer::Position;
+use crate::KeyDirEntry;
+use parking_lot::{Condvar, Mutex};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// A key and its new position, `None` if it was deleted
+pub(crate) type IndexUpdate = (Vec<u8>, Option<KeyDirEntry>);
+
+/// Applies index updates in the order their writes were appended.
+///
+/// A writer takes a ticket while it still holds the append lock and, once its
+/// write is durable, hands the ticket back with its index updates. Updates are
+/// applied in ticket order, so a sync write only becomes visible after its
+/// fsync and nothing appended after it becomes visible before it, while
+/// writers still wait on their fsyncs concurrently.
+#[derive(Debug, Default)]
+pub(crate) struct IndexSequencer {
+    state: Mutex<SequencerState>,
+    // Wakes writers waiting for their ticket to be applied
+    applied: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SequencerState {
+    issued: u64,
+    // Every ticket below this has been applied
+    applied: u64,
+    // Handed back, waiting on an earlier ticket
+    ready: BTreeMap<u64, ReadyTicket>,
+}
+
+// A ticket's index updates, the ends of its records and its secondary index
+// updates
+type ReadyTicket = (Vec<IndexUpdate>, Vec<Position>, Vec<SecondaryUpdate>);
+
+impl IndexSequencer {
+    /// Takes the next ticket. Callers hold the lock of every shard they
+    /// appended to, or `Db::batch_commit_lock` exclusively, across the append
+    /// and this call, so tickets follow each shard's log order.
+    pub(crate) fn issue(&self) -> u64 {
+        let mut state = self.state.lock();
+        state.issued += 1;
+        state.issued - 1
+    }
+
+    /// Hands back `ticket` with its updates and the ends of the records it
+    /// appended, and blocks until they're applied. Every issued ticket must
+    /// be completed, with no updates if its write failed, or later writers
+    /// wait forever.
+    ///
+    /// Updates reach `hint_log` in the order they're applied, and so do the
+    /// secondary index updates handed back with them.
+    pub(crate) fn complete(
+        &self,
+        ticket: u64,
+        updates: Vec<IndexUpdate>,
+        positions: &[Position],
+        index: &IndexMode,
+        hint_log: Option<&HintLog>,
+        secondary: (&SecondaryIndexes, Vec<SecondaryUpdate>),
+    ) {
+        let (secondary_indexes, secondary_updates) = secondary;
+        let mut state = self.state.lock();
+        state
+            .ready
+            .insert(ticket, (updates, positions.to_vec(), secondary_updates));
+        let mut progressed = false;
+        loop {
+            let next = state.applied;
+            let Some((updates, positions, secondary_updates)) = state.ready.remove(&next) else {
+                break;
+            };
+            if let Some(hint_log) = hint_log {
+                hint_log.append(&updates, &positions);
+            }
+            secondary_indexes.apply(secondary_updates);
+            for (key, position) in updates {
+                match position {
+                    Some(keydir_entry) => {
+                        index.put(key.into(), keydir_entry);
+                    }
+                    None => {
+                        index.delete(&key);
+                    }
+                }
+            }
+            state.applied += 1;
+            progressed = true;
+        }
+        if progressed {
+            self.applied.notify_all();
+        }
+        while state.applied <= ticket {
+            self.applied.wait(&mut state);
+        }
+    }
+
+    /// Blocks until every ticket issued so far has been applied.
+    pub(crate) fn wait_all(&self) {
+        let mut state = self.state.lock();
+        while state.applied < state.issued {
+            self.applied.wait(&mut state);
+        }
+    }
+
+    /// Like `wait_all`, but gives up at `deadline`. Returns whether every
+    /// ticket was applied.
+    pub(crate) fn wait_all_until(&self, deadline: Instant) -> bool {
+        let mut state = self.state.lock();
+        while state.applied < state.issued {
+            if self.applied.wait_until(&mut state, deadline).timed_out() {
+                return state.applied >= state.issued;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,976 @@
+//! Serves a database over the Redis protocol (RESP), enough of it for Redis
+//! clients to GET, SET, DEL, EXISTS, MGET, SCAN, TTL and EXPIRE, and to group
+//! writes with MULTI/EXEC.
+//!
+//! Each connection gets a thread sharing the one `Db`. Expiry deadlines are
+//! stored in the database itself, as milliseconds since the Unix epoch under
+//! a reserved key prefix, and an expired key is removed when it is next read.
+//! Time is read from the database's `Opts::ttl_clock`.
+
+use crate::batch::{WriteBatch, WriteBatchOptions};
+use crate::db::Db;
+use crate::{Error, Result};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+// Keys holding expiry deadlines start with this, and are hidden from SCAN
+const EXPIRY_PREFIX: &[u8] = b"\0zap-server:expiry:";
+// Most elements in one command
+const MAX_ARGS: usize = 1024 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Accepts connections on `listener` and serves `db` to each on its own
+/// thread. Only returns if the listener fails.
+pub fn serve(db: Arc<Db>, listener: TcpListener) -> Result<()> {
+    let server = Arc::new(Server {
+        db,
+        write_lock: Mutex::new(()),
+    });
+    for stream in listener.incoming() {
+        // A connection that failed to be set up doesn't affect the others
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let server = server.clone();
+        thread::Builder::new()
+            .name("zap-server".to_string())
+            .spawn(move || server.handle(stream))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    Array(Vec<Reply>),
+}
+
+impl From<Error> for Reply {
+    fn from(e: Error) -> Self {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+impl Reply {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(out, "+{}\r\n", s),
+            Reply::Error(e) => write!(out, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                write!(out, "${}\r\n", value.len())?;
+                out.write_all(value)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write(out))
+            }
+        }
+    }
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+fn arity_error(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+// Writes queued by MULTI
+#[derive(Debug, Default)]
+struct Transaction {
+    commands: Vec<Vec<Bytes>>,
+    // A command was refused while queueing, so EXEC fails
+    aborted: bool,
+}
+
+struct Server {
+    db: Arc<Db>,
+    // Serializes writes, so expiring a key can't remove a value that
+    // replaced it
+    write_lock: Mutex<()>,
+}
+
+impl Server {
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        // No argument needs to be longer than the largest key or value
+        let max_len = self
+            .db
+            .ctx
+            .opts
+            .max_key_size
+            .max(self.db.ctx.opts.max_value_size);
+        let mut transaction = None;
+        loop {
+            let args = match read_command(&mut reader, max_len) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    Reply::Error(format!("ERR Protocol error: {}", e)).write(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let Some(name) = args.first().map(|name| name.to_ascii_uppercase()) else {
+                continue;
+            };
+            let reply = match name.as_slice() {
+                b"QUIT" => {
+                    Reply::Simple("OK".into()).write(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                b"MULTI" if transaction.is_some() => {
+                    Reply::Error("ERR MULTI calls can not be nested".into())
+                }
+                b"MULTI" => {
+                    transaction = Some(Transaction::default());
+                    Reply::Simple("OK".into())
+                }
+                b"EXEC" => match transaction.take() {
+                    None => Reply::Error("ERR EXEC without MULTI".into()),
+                    Some(queued) if queued.aborted => Reply::Error(
+                        "EXECABORT Transaction discarded because of previous errors.".into(),
+                    ),
+                    Some(queued) => self.exec(queued.commands),
+                },
+                b"DISCARD" => match transaction.take() {
+                    None => Reply::Error("ERR DISCARD without MULTI".into()),
+                    Some(_) => Reply::Simple("OK".into()),
+                },
+                _ => match &mut transaction {
+                    Some(queued) => match check_queued(&name, &args) {
+                        Ok(()) => {
+                            queued.commands.push(args);
+                            Reply::Simple("QUEUED".into())
+                        }
+                        Err(reply) => {
+                            queued.aborted = true;
+                            reply
+                        }
+                    },
+                    None => self.run(&name, &args),
+                },
+            };
+            reply.write(&mut writer)?;
+            // Replies to pipelined commands go out together
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    fn run(&self, name: &[u8], args: &[Bytes]) -> Reply {
+        let name = String::from_utf8_lossy(name);
+        let result = match (name.as_ref(), args.len()) {
+            ("PING", 1) => Ok(Reply::Simple("PONG".into())),
+            ("PING", 2) => Ok(Reply::Bulk(Some(args[1].clone()))),
+            ("GET", 2) => self.get(&args[1]).map(Reply::Bulk),
+            ("SET", 3..) => self.set(&args[1..]),
+            ("DEL", 2..) => self.del(&args[1..]),
+            ("EXISTS", 2..) => self.exists(&args[1..]),
+            ("MGET", 2..) => args[1..]
+                .iter()
+                .map(|key| self.get(key).map(Reply::Bulk))
+                .collect::<Result<Vec<_>>>()
+                .map(Reply::Array),
+            ("SCAN", 2..) => self.scan(&args[1..]),
+            ("TTL", 2) => self.ttl(&args[1]),
+            ("EXPIRE", 3) => self.expire(&args[1], &args[2]),
+            ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "SCAN" | "TTL" | "EXPIRE", _) => {
+                return arity_error(&name)
+            }
+            _ => {
+                return Reply::Error(format!(
+                    "ERR unknown command '{}'",
+                    String::from_utf8_lossy(&args[0])
+                ))
+            }
+        };
+        result.unwrap_or_else(Reply::from)
+    }
+
+    // The value of `key`, or `None` if it's missing or expired
+    fn get(&self, key: &Bytes) -> Result<Option<Bytes>> {
+        if self.expire_if_due(key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get_seq(key.clone())?.map(|(value, _)| value))
+    }
+
+    fn set(&self, args: &[Bytes]) -> Result<Reply> {
+        let deadline = match parse_set_options(&args[2..]) {
+            Ok(ttl) => ttl.map(|ttl| self.now_ms().saturating_add(ttl)),
+            Err(reply) => return Ok(reply),
+        };
+        let _guard = self.write_lock.lock();
+        let batch = self.db.new_write_batch(self.batch_options(2))?;
+        put_with_deadline(&batch, &args[0], &args[1], deadline)?;
+        batch.commit()?;
+        Ok(Reply::Simple("OK".into()))
+    }
+
+    fn del(&self, keys: &[Bytes]) -> Result<Reply> {
+        let _guard = self.write_lock.lock();
+        let batch = self
+            .db
+            .new_write_batch(self.batch_options(2 * keys.len()))?;
+        let mut deleted = 0;
+        for key in keys.iter().collect::<HashSet<_>>() {
+            if self.live_locked(key)? {
+                deleted += 1;
+            }
+            batch.delete(key.clone())?;
+            batch.delete(expiry_key(key))?;
+        }
+        batch.commit()?;
+        Ok(Reply::Integer(deleted))
+    }
+
+    fn exists(&self, keys: &[Bytes]) -> Result<Reply> {
+        let mut count = 0;
+        for key in keys {
+            if self.get(key)?.is_some() {
+                count += 1;
+            }
+        }
+        Ok(Reply::Integer(count))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]. The cursor is a position in
+    // the sorted keys, so keys added or removed between calls can shift it
+    fn scan(&self, args: &[Bytes]) -> Result<Reply> {
+        let Some(cursor) = parse_int::<usize>(&args[0]) else {
+            return Ok(Reply::Error("ERR invalid cursor".into()));
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let value = options.next();
+            match (option.to_ascii_uppercase().as_slice(), value) {
+                (b"MATCH", Some(value)) => pattern = Some(value.clone()),
+                (b"COUNT", Some(value)) => match parse_int::<usize>(value) {
+                    Some(n) if n > 0 => count = n,
+                    _ => return Ok(syntax_error()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+
+        let mut keys = self
+            .db
+            .list_keys()?
+            .into_iter()
+            .filter(|key| !key.starts_with(EXPIRY_PREFIX))
+            .collect::<Vec<_>>();
+        keys.sort();
+        let end = cursor.saturating_add(count).min(keys.len());
+        let mut page = Vec::new();
+        for key in keys.get(cursor..end).unwrap_or_default() {
+            let matched = pattern
+                .as_ref()
+                .is_none_or(|pattern| glob_match(pattern, key));
+            if matched && !self.expire_if_due(key)? {
+                page.push(Reply::Bulk(Some(key.clone())));
+            }
+        }
+        let next = if end < keys.len() { end } else { 0 };
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(Bytes::from(next.to_string()))),
+            Reply::Array(page),
+        ]))
+    }
+
+    fn ttl(&self, key: &Bytes) -> Result<Reply> {
+        if !self.live(key)? {
+            return Ok(Reply::Integer(-2));
+        }
+        Ok(Reply::Integer(match self.deadline(key)? {
+            // Rounded to the nearest second
+            Some(deadline) => (deadline.saturating_sub(self.now_ms()) as i64 + 500) / 1000,
+            None => -1,
+        }))
+    }
+
+    fn expire(&self, key: &Bytes, seconds: &Bytes) -> Result<Reply> {
+        let Some(seconds) = parse_int::<i64>(seconds) else {
+            return Ok(Reply::Error(
+                "ERR value is not an integer or out of range".into(),
+            ));
+        };
+        if !self.live(key)? {
+            return Ok(Reply::Integer(0));
+        }
+        let _guard = self.write_lock.lock();
+        let batch = self.db.new_write_batch(self.batch_options(2))?;
+        if seconds <= 0 {
+            batch.delete(key.clone())?;
+            batch.delete(expiry_key(key))?;
+        } else {
+            let deadline = self
+                .now_ms()
+                .saturating_add((seconds as u64).saturating_mul(1000));
+            batch.put(
+                expiry_key(key),
+                Bytes::copy_from_slice(&deadline.to_be_bytes()),
+            )?;
+        }
+        batch.commit()?;
+        Ok(Reply::Integer(1))
+    }
+
+    // Applies writes queued by MULTI as one batch
+    fn exec(&self, commands: Vec<Vec<Bytes>>) -> Reply {
+        let result = (|| -> Result<Reply> {
+            let _guard = self.write_lock.lock();
+            let entries = commands.iter().map(|args| 2 * (args.len() - 1)).sum();
+            let batch = self.db.new_write_batch(self.batch_options(entries))?;
+            // Keys this transaction has set (true) or deleted (false)
+            let mut written = HashMap::new();
+            let mut replies = Vec::new();
+            for args in commands.iter() {
+                if args[0].eq_ignore_ascii_case(b"SET") {
+                    let deadline = parse_set_options(&args[3..])
+                        .ok()
+                        .flatten()
+                        .map(|ttl| self.now_ms().saturating_add(ttl));
+                    put_with_deadline(&batch, &args[1], &args[2], deadline)?;
+                    written.insert(args[1].clone(), true);
+                    replies.push(Reply::Simple("OK".into()));
+                    continue;
+                }
+                let mut deleted = 0;
+                for key in args[1..].iter().collect::<HashSet<_>>() {
+                    let live = match written.get(key) {
+                        Some(live) => *live,
+                        None => self.live_locked(key)?,
+                    };
+                    if live {
+                        deleted += 1;
+                    }
+                    batch.delete(key.clone())?;
+                    batch.delete(expiry_key(key))?;
+                    written.insert(key.clone(), false);
+                }
+                replies.push(Reply::Integer(deleted));
+            }
+            batch.commit()?;
+            Ok(Reply::Array(replies))
+        })();
+        result.unwrap_or_else(Reply::from)
+    }
+
+    fn live(&self, key: &Bytes) -> Result<bool> {
+        Ok(!self.expire_if_due(key)? && self.db.locate(key).is_some())
+    }
+
+    // `live` for callers holding `write_lock`, which can't expire the key
+    // under it. They delete the key and its deadline either way.
+    fn live_locked(&self, key: &Bytes) -> Result<bool> {
+        if self.db.locate(key).is_none() {
+            return Ok(false);
+        }
+        Ok(!matches!(self.deadline(key)?, Some(deadline) if deadline <= self.now_ms()))
+    }
+
+    fn deadline(&self, key: &[u8]) -> Result<Option<u64>> {
+        let Some((value, _)) = self.db.get_seq(expiry_key(key))? else {
+            return Ok(None);
+        };
+        let deadline = value.as_ref().try_into().map_err(|_| {
+            Error::Unsupported(format!(
+                "Invalid expiry for key {}",
+                String::from_utf8_lossy(key)
+            ))
+        })?;
+        Ok(Some(u64::from_be_bytes(deadline)))
+    }
+
+    // Removes `key` if its deadline has passed, returning whether it did
+    fn expire_if_due(&self, key: &Bytes) -> Result<bool> {
+        match self.deadline(key)? {
+            Some(deadline) if deadline <= self.now_ms() => {}
+            _ => return Ok(false),
+        }
+        let _guard = self.write_lock.lock();
+        // It may have been written again before the lock was taken
+        match self.deadline(key)? {
+            Some(deadline) if deadline <= self.now_ms() => {}
+            _ => return Ok(false),
+        }
+        let batch = self.db.new_write_batch(self.batch_options(2))?;
+        batch.delete(key.clone())?;
+        batch.delete(expiry_key(key))?;
+        batch.commit()?;
+        Ok(true)
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.db
+            .ctx
+            .opts
+            .ttl_clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+
+    fn batch_options(&self, entries: usize) -> WriteBatchOptions {
+        WriteBatchOptions {
+            max_batch_num: entries,
+            sync_writes: self.db.ctx.opts.sync_writes,
+        }
+    }
+}
+
+// Writes `key` and its deadline, or clears the deadline it had
+fn put_with_deadline(
+    batch: &WriteBatch<'_>,
+    key: &Bytes,
+    value: &Bytes,
+    deadline: Option<u64>,
+) -> Result<()> {
+    batch.put(key.clone(), value.clone())?;
+    match deadline {
+        Some(deadline) => batch.put(
+            expiry_key(key),
+            Bytes::copy_from_slice(&deadline.to_be_bytes()),
+        ),
+        None => batch.delete(expiry_key(key)),
+    }
+}
+
+// Whether a command can be queued by MULTI. Only writes are, as they're
+// applied as one batch
+fn check_queued(name: &[u8], args: &[Bytes]) -> std::result::Result<(), Reply> {
+    match name {
+        b"SET" if args.len() >= 3 => parse_set_options(&args[3..]).map(|_| ()),
+        b"DEL" if args.len() >= 2 => Ok(()),
+        b"SET" | b"DEL" => Err(arity_error(&String::from_utf8_lossy(name))),
+        _ => Err(Reply::Error(format!(
+            "ERR {} is not supported inside MULTI",
+            String::from_utf8_lossy(&args[0])
+        ))),
+    }
+}
+
+// The TTL in milliseconds set by SET's `EX seconds` or `PX milliseconds`
+// option
+fn parse_set_options(options: &[Bytes]) -> std::result::Result<Option<u64>, Reply> {
+    match options {
+        [] => Ok(None),
+        [unit, ttl] => {
+            let millis = match (unit.to_ascii_uppercase().as_slice(), parse_int::<u64>(ttl)) {
+                (b"EX", Some(seconds)) if seconds > 0 => seconds.saturating_mul(1000),
+                (b"PX", Some(millis)) if millis > 0 => millis,
+                (b"EX" | b"PX", _) => {
+                    return Err(Reply::Error(
+                        "ERR invalid expire time in 'set' command".into(),
+                    ))
+                }
+                _ => return Err(syntax_error()),
+            };
+            Ok(Some(millis))
+        }
+        _ => Err(syntax_error()),
+    }
+}
+
+fn expiry_key(key: &[u8]) -> Bytes {
+    [EXPIRY_PREFIX, key].concat().into()
+}
+
+fn parse_int<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// Redis-style glob: `*` matches any run of bytes, `?` any one byte, and `\`
+// escapes the next byte
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*`, and the text it has taken so far
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'\\') if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some(b) if *b != b'\\' && *b == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|b| *b == b'*')
+}
+
+// Reads one command: a RESP array of bulk strings, or an inline command of
+// space separated words. `None` once the client has hung up.
+fn read_command(input: &mut impl BufRead, max_len: usize) -> io::Result<Option<Vec<Bytes>>> {
+    let Some(line) = read_line(input)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(Bytes::copy_from_slice)
+            .collect();
+        return Ok(Some(args));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(input)?.ok_or_else(|| invalid("unexpected end of stream"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| invalid("expected '$'"))?;
+        let len = parse_len(len, max_len)?;
+        let mut arg = vec![0; len + 2];
+        input.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(Bytes::from(arg));
+    }
+    Ok(Some(args))
+}
+
+fn read_line(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // Lines only hold lengths and inline commands, so they're kept short
+    let read = input
+        .by_ref()
+        .take(64 * 1024)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(invalid("line too long"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(len: &[u8], max: usize) -> io::Result<usize> {
+    match parse_int::<usize>(len) {
+        Some(len) if len <= max => Ok(len),
+        Some(_) => Err(invalid("length over the limit")),
+        None => Err(invalid("invalid length")),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Opts, TtlClock};
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    fn start(name: &str) -> Result<(Arc<Db>, SocketAddr)> {
+        start_with_clock(name, TtlClock::default())
+    }
+
+    fn start_with_clock(name: &str, ttl_clock: TtlClock) -> Result<(Arc<Db>, SocketAddr)> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            format!("/tmp/{}", name),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.ttl_clock = ttl_clock;
+        let db = Arc::new(Db::open(&opts)?);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server_db = db.clone();
+        thread::spawn(move || serve(server_db, listener));
+        Ok((db, addr))
+    }
+
+    // A plain RESP client
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Result<Self> {
+            let writer = TcpStream::connect(addr)?;
+            writer.set_read_timeout(Some(Duration::from_secs(10)))?;
+            Ok(Client {
+                reader: BufReader::new(writer.try_clone()?),
+                writer,
+            })
+        }
+
+        fn send(&mut self, args: &[&str]) -> Result<()> {
+            let mut request = format!("*{}\r\n", args.len()).into_bytes();
+            for arg in args {
+                request.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+            }
+            self.writer.write_all(&request)?;
+            Ok(())
+        }
+
+        fn call(&mut self, args: &[&str]) -> Result<Reply> {
+            self.send(args)?;
+            self.reply()
+        }
+
+        fn reply(&mut self) -> Result<Reply> {
+            let line = read_line(&mut self.reader)?.unwrap();
+            let (kind, rest) = (line[0], String::from_utf8(line[1..].to_vec()).unwrap());
+            Ok(match kind {
+                b'+' => Reply::Simple(rest),
+                b'-' => Reply::Error(rest),
+                b':' => Reply::Integer(rest.parse().unwrap()),
+                b'$' if rest == "-1" => Reply::Bulk(None),
+                b'$' => {
+                    let mut value = vec![0; rest.parse::<usize>().unwrap() + 2];
+                    self.reader.read_exact(&mut value)?;
+                    value.truncate(value.len() - 2);
+                    Reply::Bulk(Some(Bytes::from(value)))
+                }
+                b'*' => Reply::Array(
+                    (0..rest.parse::<usize>().unwrap())
+                        .map(|_| self.reply())
+                        .collect::<Result<_>>()?,
+                ),
+                _ => panic!("unexpected reply {:?}", line),
+            })
+        }
+    }
+
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(Some(Bytes::copy_from_slice(value.as_bytes())))
+    }
+
+    fn is_error(reply: &Reply) -> bool {
+        matches!(reply, Reply::Error(_))
+    }
+
+    #[test]
+    fn test_commands() -> Result<()> {
+        let (db, addr) = start("test_server_commands")?;
+        let mut client = Client::connect(addr)?;
+
+        assert_eq!(client.call(&["PING"])?, Reply::Simple("PONG".into()));
+        assert_eq!(client.call(&["SET", "a", "1"])?, Reply::Simple("OK".into()));
+        assert_eq!(client.call(&["set", "b", "2"])?, Reply::Simple("OK".into()));
+        assert_eq!(client.call(&["GET", "a"])?, bulk("1"));
+        assert_eq!(client.call(&["GET", "missing"])?, Reply::Bulk(None));
+        assert_eq!(
+            client.call(&["MGET", "a", "missing", "b"])?,
+            Reply::Array(vec![bulk("1"), Reply::Bulk(None), bulk("2")])
+        );
+        assert_eq!(
+            client.call(&["EXISTS", "a", "b", "missing"])?,
+            Reply::Integer(2)
+        );
+        assert_eq!(
+            client.call(&["DEL", "a", "a", "missing"])?,
+            Reply::Integer(1)
+        );
+        assert_eq!(client.call(&["EXISTS", "a"])?, Reply::Integer(0));
+        assert_eq!(db.get(Bytes::from("b"))?, "2");
+
+        assert!(is_error(&client.call(&["GET"])?));
+        assert!(is_error(&client.call(&["SET", "", "value"])?));
+        assert!(is_error(&client.call(&["SET", "a", "1", "EX"])?));
+        assert!(is_error(&client.call(&["FLUSHALL"])?));
+
+        // Pipelined, and inline
+        client.send(&["SET", "c", "3"])?;
+        client.send(&["GET", "c"])?;
+        client.writer.write_all(b"GET b\r\n")?;
+        assert_eq!(client.reply()?, Reply::Simple("OK".into()));
+        assert_eq!(client.reply()?, bulk("3"));
+        assert_eq!(client.reply()?, bulk("2"));
+
+        // Connections share the database
+        let handles = (0..4)
+            .map(|t| {
+                thread::spawn(move || -> Result<()> {
+                    let mut client = Client::connect(addr)?;
+                    for i in 0..50 {
+                        let key = format!("key-{}-{}", t, i);
+                        assert_eq!(
+                            client.call(&["SET", &key, "value"])?,
+                            Reply::Simple("OK".into())
+                        );
+                        assert_eq!(client.call(&["GET", &key])?, bulk("value"));
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(db.get(Bytes::from("key-3-49"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_expiry() -> Result<()> {
+        let (_db, addr) = start("test_server_expiry")?;
+        let mut client = Client::connect(addr)?;
+
+        client.call(&["SET", "forever", "value"])?;
+        client.call(&["SET", "short", "value", "PX", "100"])?;
+        client.call(&["SET", "long", "value", "EX", "100"])?;
+        assert_eq!(client.call(&["TTL", "forever"])?, Reply::Integer(-1));
+        assert_eq!(client.call(&["TTL", "long"])?, Reply::Integer(100));
+        assert_eq!(client.call(&["TTL", "missing"])?, Reply::Integer(-2));
+        assert_eq!(
+            client.call(&["EXPIRE", "missing", "10"])?,
+            Reply::Integer(0)
+        );
+        assert_eq!(
+            client.call(&["EXPIRE", "forever", "10"])?,
+            Reply::Integer(1)
+        );
+        assert_eq!(client.call(&["TTL", "forever"])?, Reply::Integer(10));
+        // Setting a key again clears its deadline
+        client.call(&["SET", "forever", "value"])?;
+        assert_eq!(client.call(&["TTL", "forever"])?, Reply::Integer(-1));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(client.call(&["GET", "short"])?, Reply::Bulk(None));
+        assert_eq!(client.call(&["TTL", "short"])?, Reply::Integer(-2));
+        assert_eq!(
+            client.call(&["EXISTS", "short", "long"])?,
+            Reply::Integer(1)
+        );
+
+        // A deadline in the past removes the key at once
+        assert_eq!(client.call(&["EXPIRE", "long", "0"])?, Reply::Integer(1));
+        assert_eq!(client.call(&["GET", "long"])?, Reply::Bulk(None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expiry_follows_ttl_clock() -> Result<()> {
+        let now_ms = Arc::new(AtomicU64::new(1_000_000_000_000));
+        let clock = now_ms.clone();
+        let (_db, addr) = start_with_clock(
+            "test_server_ttl_clock",
+            TtlClock::new(move || UNIX_EPOCH + Duration::from_millis(clock.load(Ordering::SeqCst))),
+        )?;
+        let mut client = Client::connect(addr)?;
+        client.call(&["SET", "a", "value", "EX", "10"])?;
+        client.call(&["SET", "b", "value", "EX", "20"])?;
+
+        now_ms.fetch_add(9_000, Ordering::SeqCst);
+        assert_eq!(client.call(&["TTL", "a"])?, Reply::Integer(1));
+        assert_eq!(
+            client.call(&["GET", "a"])?,
+            Reply::Bulk(Some(Bytes::from("value")))
+        );
+        now_ms.fetch_add(1_000, Ordering::SeqCst);
+        assert_eq!(client.call(&["GET", "a"])?, Reply::Bulk(None));
+        assert_eq!(client.call(&["TTL", "b"])?, Reply::Integer(10));
+
+        // Going back in time brings nothing back, and pushes the remaining
+        // deadline further away
+        now_ms.fetch_sub(60_000, Ordering::SeqCst);
+        assert_eq!(client.call(&["GET", "a"])?, Reply::Bulk(None));
+        assert_eq!(client.call(&["TTL", "b"])?, Reply::Integer(70));
+        Ok(())
+    }
+
+    #[test]
+    fn test_del_of_expired_key() -> Result<()> {
+        let now_ms = Arc::new(AtomicU64::new(1_000_000_000_000));
+        let clock = now_ms.clone();
+        let (db, addr) = start_with_clock(
+            "test_server_del_expired",
+            TtlClock::new(move || UNIX_EPOCH + Duration::from_millis(clock.load(Ordering::SeqCst))),
+        )?;
+        let mut client = Client::connect(addr)?;
+        client.call(&["SET", "a", "value", "PX", "10"])?;
+        client.call(&["SET", "b", "value", "PX", "10"])?;
+        client.call(&["SET", "c", "value"])?;
+        now_ms.fetch_add(50, Ordering::SeqCst);
+
+        // Expired keys aren't counted, and deleting them doesn't hang
+        assert_eq!(client.call(&["DEL", "a", "c"])?, Reply::Integer(1));
+        client.call(&["MULTI"])?;
+        client.call(&["DEL", "b"])?;
+        assert_eq!(
+            client.call(&["EXEC"])?,
+            Reply::Array(vec![Reply::Integer(0)])
+        );
+        assert!(db.locate(&expiry_key(&Bytes::from("b"))).is_none());
+
+        // Later writes still go through
+        assert_eq!(client.call(&["SET", "a", "1"])?, Reply::Simple("OK".into()));
+        assert_eq!(client.call(&["GET", "a"])?, bulk("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let (_db, addr) = start("test_server_scan")?;
+        let mut client = Client::connect(addr)?;
+        for i in 0..25 {
+            client.call(&["SET", &format!("key{:02}", i), "value"])?;
+        }
+        client.call(&["SET", "other", "value", "EX", "100"])?;
+
+        let mut cursor = "0".to_string();
+        let mut keys = Vec::new();
+        loop {
+            let Reply::Array(reply) = client.call(&["SCAN", &cursor, "COUNT", "10"])? else {
+                panic!("SCAN didn't reply with an array");
+            };
+            let [Reply::Bulk(Some(next)), Reply::Array(page)] = &reply[..] else {
+                panic!("unexpected SCAN reply {:?}", reply);
+            };
+            keys.extend(page.iter().cloned());
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        // Expiry deadlines aren't listed
+        assert_eq!(keys.len(), 26);
+
+        let Reply::Array(reply) = client.call(&["SCAN", "0", "MATCH", "key1?", "COUNT", "100"])?
+        else {
+            panic!("SCAN didn't reply with an array");
+        };
+        let expected = (10..20)
+            .map(|i| bulk(&format!("key{}", i)))
+            .collect::<Vec<_>>();
+        assert_eq!(reply, vec![bulk("0"), Reply::Array(expected)]);
+
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"k*y*", b"key"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(!glob_match(b"k?", b"key"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_exec() -> Result<()> {
+        let (db, addr) = start("test_server_multi_exec")?;
+        let mut client = Client::connect(addr)?;
+        client.call(&["SET", "old", "value"])?;
+
+        assert_eq!(client.call(&["MULTI"])?, Reply::Simple("OK".into()));
+        assert_eq!(
+            client.call(&["SET", "a", "1"])?,
+            Reply::Simple("QUEUED".into())
+        );
+        assert_eq!(
+            client.call(&["SET", "b", "2", "EX", "100"])?,
+            Reply::Simple("QUEUED".into())
+        );
+        assert_eq!(
+            client.call(&["DEL", "a", "old", "missing"])?,
+            Reply::Simple("QUEUED".into())
+        );
+        // Nothing is applied before EXEC
+        assert!(db.get(Bytes::from("b")).is_err());
+        assert_eq!(
+            client.call(&["EXEC"])?,
+            Reply::Array(vec![
+                Reply::Simple("OK".into()),
+                Reply::Simple("OK".into()),
+                Reply::Integer(2)
+            ])
+        );
+        assert_eq!(
+            client.call(&["MGET", "a", "b", "old"])?,
+            Reply::Array(vec![Reply::Bulk(None), bulk("2"), Reply::Bulk(None)])
+        );
+        assert_eq!(client.call(&["TTL", "b"])?, Reply::Integer(100));
+
+        // Discarded, and aborted by a command that can't be queued
+        client.call(&["MULTI"])?;
+        client.call(&["SET", "c", "3"])?;
+        assert_eq!(client.call(&["DISCARD"])?, Reply::Simple("OK".into()));
+        client.call(&["MULTI"])?;
+        client.call(&["SET", "c", "3"])?;
+        assert!(is_error(&client.call(&["GET", "c"])?));
+        assert!(is_error(&client.call(&["EXEC"])?));
+        assert_eq!(client.call(&["GET", "c"])?, Reply::Bulk(None));
+        assert!(is_error(&client.call(&["EXEC"])?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_protocol_errors() -> Result<()> {
+        let (_db, addr) = start("test_server_protocol_errors")?;
+        let mut client = Client::connect(addr)?;
+        // Longer than any key or value can be
+        client.writer.write_all(b"*2\r\n$3\r\nGET\r\n$100000\r\n")?;
+        assert!(is_error(&client.reply()?));
+        // The connection is closed after a protocol error
+        let mut rest = Vec::new();
+        client.reader.read_to_end(&mut rest)?;
+        assert!(rest.is_empty());
+
+        let mut client = Client::connect(addr)?;
+        assert_eq!(client.call(&["PING", "hello"])?, bulk("hello"));
+        assert_eq!(client.call(&["QUIT"])?, Reply::Simple("OK".into()));
+        Ok(())
+    }
+}
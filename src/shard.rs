@@ -0,0 +1,458 @@
+use crate::options::Opts;
+use crate::runtime::SharedRuntime;
+use crate::storage::FileHandle;
+use crate::syncer::SyncCoordinator;
+use crate::{Error, Result};
+use arc_swap::ArcSwap;
+use parking_lot::RwLock;
+use prost::{decode_length_delimiter, length_delimiter_len};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// File ids reserved for each write shard: shard `s` numbers its data files
+/// from `s * SHARD_FILE_IDS`.
+pub(crate) const SHARD_FILE_IDS: u32 = 1_000_000;
+/// Records the shard count a sharded database was written with.
+pub(crate) const SHARDS_FILE_NAME: &str = "write_shards";
+/// Under `Opts::size_class_boundary`, the shard of the values below the
+/// boundary and the one of the rest. Small values replay first.
+pub(crate) const SMALL_VALUES: usize = 0;
+pub(crate) const LARGE_VALUES: usize = 1;
+
+/// One independently appended active file, with its own rotation and fsync
+/// thread, or fsync work on a shared runtime. Every write of a key goes to
+/// the same shard, so per-key order is the order of the shard's files. Under
+/// size classes a key changes shard with its value's size, leaving a
+/// tombstone behind, see `FileReplay::apply`.
+#[derive(Debug)]
+pub(crate) struct WriteShard {
+    pub(crate) active_file: Arc<RwLock<FileHandle>>,
+    pub(crate) file_id: AtomicU32,
+    pub(crate) syncer: SyncCoordinator,
+    // A clone of the active file for readers, swapped in on rotation, so
+    // gets never wait behind appends or rotation.
+    published: ArcSwap<FileHandle>,
+}
+
+impl WriteShard {
+    pub(crate) fn start(
+        active_file: FileHandle,
+        opts: &Opts,
+        runtime: Option<&SharedRuntime>,
+    ) -> Result<Self> {
+        let file_id = AtomicU32::new(active_file.get_file_id());
+        let published = ArcSwap::from_pointee(active_file.clone());
+        let active_file = Arc::new(RwLock::new(active_file));
+        let syncer = match runtime {
+            Some(runtime) => {
+                SyncCoordinator::start_on(active_file.clone(), opts.sync_interval, runtime)
+            }
+            None => SyncCoordinator::start(active_file.clone(), opts.sync_interval)?,
+        };
+        Ok(Self {
+            active_file,
+            file_id,
+            syncer,
+            published,
+        })
+    }
+
+    pub(crate) fn get_file_id(&self) -> u32 {
+        self.file_id.load(Ordering::SeqCst)
+    }
+
+    /// The published active file, if it is still `file_id`.
+    pub(crate) fn published_file(&self, file_id: u32) -> Option<FileHandle> {
+        let published = self.published.load();
+        (published.get_file_id() == file_id).then(|| FileHandle::clone(&published))
+    }
+
+    /// Bytes written to the active file. Reads the published clone, which
+    /// shares its offset, so it doesn't wait on appends.
+    pub(crate) fn active_file_size(&self) -> u64 {
+        self.published.load().get_offset()
+    }
+
+    /// Makes a newly rotated-in active file visible to readers.
+    pub(crate) fn publish(&self, active_file: &FileHandle) {
+        self.published.store(Arc::new(active_file.clone()));
+    }
+}
+
+/// The shard whose ids `file_id` falls in. Unsharded databases put every file
+/// in shard 0, however many there are.
+pub(crate) fn shard_of(file_id: u32, shard_count: usize) -> usize {
+    if shard_count <= 1 {
+        return 0;
+    }
+    (file_id / SHARD_FILE_IDS) as usize
+}
+
+/// The shard a user key is written to. Uses crc32 so the mapping is stable
+/// across processes.
+pub(crate) fn shard_for_key(key: &[u8], shard_count: usize) -> usize {
+    if shard_count <= 1 {
+        return 0;
+    }
+    crc32fast::hash(key) as usize % shard_count
+}
+
+/// Like `shard_for_key`, for a key still carrying its transaction prefix.
+pub(crate) fn shard_for_transaction_key(key: &[u8], shard_count: usize) -> usize {
+    if shard_count <= 1 {
+        return 0;
+    }
+    let prefix = decode_length_delimiter(key).map_or(0, length_delimiter_len);
+    shard_for_key(&key[prefix.min(key.len())..], shard_count)
+}
+
+/// The size class a value of `value_len` bytes is written to.
+pub(crate) fn size_class(value_len: usize, boundary: u64) -> usize {
+    if value_len as u64 >= boundary {
+        LARGE_VALUES
+    } else {
+        SMALL_VALUES
+    }
+}
+
+/// The size class of data file `file_id`, `None` without size classes.
+pub(crate) fn size_class_of(file_id: u32, opts: &Opts) -> Option<usize> {
+    opts.size_class_boundary
+        .map(|_| shard_of(file_id, LARGE_VALUES + 1))
+}
+
+/// Number of shards configured in `opts`.
+pub(crate) fn shard_count(opts: &Opts) -> usize {
+    if opts.size_class_boundary.is_some() {
+        return LARGE_VALUES + 1;
+    }
+    #[cfg(feature = "write-shards")]
+    {
+        opts.write_shards.max(1)
+    }
+    #[cfg(not(feature = "write-shards"))]
+    {
+        let _ = opts;
+        1
+    }
+}
+
+/// Checks `shard_count` against the count the directory was written with and
+/// records it.
+///
+/// Keys map to shards by hash, so changing the count would split a key's
+/// history across shards and replay could apply it out of order. The only
+/// change allowed is from an unsharded directory, whose files all belong to
+/// shard 0 and so replay before any newer shard's.
+pub(crate) fn check_shard_count(
+    dir_path: &Path,
+    shard_count: usize,
+    file_ids: &[u32],
+) -> Result<()> {
+    let path = dir_path.join(SHARDS_FILE_NAME);
+    let recorded = match fs::read_to_string(&path) {
+        Ok(s) => s.trim().parse::<usize>().map_err(|_| {
+            Error::Unsupported(format!("Invalid shard count in {}", path.display()))
+        })?,
+        Err(_) => 1,
+    };
+    if recorded != shard_count && recorded != 1 && !file_ids.is_empty() {
+        return Err(Error::Unsupported(format!(
+            "Database was written with {} write shards, opened with {}",
+            recorded, shard_count
+        )));
+    }
+    if file_ids
+        .iter()
+        .any(|id| shard_of(*id, shard_count) >= shard_count)
+    {
+        return Err(Error::Unsupported(format!(
+            "Data files belong to more than {} write shards",
+            shard_count
+        )));
+    }
+    if shard_count > 1 && recorded != shard_count {
+        fs::write(path, shard_count.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_for_transaction_key() {
+        let key = b"some key".to_vec();
+        for seq in [0, 1, 300, 70_000] {
+            let enc_key = crate::batch::encode_transaction_key(key.clone(), seq);
+            assert_eq!(
+                shard_for_transaction_key(&enc_key, 7),
+                shard_for_key(&key, 7)
+            );
+        }
+        assert_eq!(shard_for_key(&key, 1), 0);
+    }
+
+    #[test]
+    fn test_size_classes() -> Result<()> {
+        use crate::db::Db;
+        use bytes::Bytes;
+
+        let mut opts = crate::Opts::new(
+            256,
+            4096,
+            false,
+            false,
+            "/tmp/test_size_classes".to_string(),
+            4096,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(crate::merge::merge_dir_path(&opts.dir_path)?);
+        opts.size_class_boundary = Some(100);
+        let small = Bytes::from("small");
+        let large = Bytes::from(vec![b'x'; 500]);
+        let class_of =
+            |db: &Db, key: &str| shard_of(db.locate(key.as_bytes()).unwrap().get_file_id(), 2);
+
+        let db = Db::open(&opts)?;
+        for i in 0..50 {
+            db.put(Bytes::from(format!("small{}", i)), small.clone())?;
+            db.put(Bytes::from(format!("large{}", i)), large.clone())?;
+        }
+        for i in 0..50 {
+            assert_eq!(class_of(&db, &format!("small{}", i)), SMALL_VALUES);
+            assert_eq!(class_of(&db, &format!("large{}", i)), LARGE_VALUES);
+        }
+        // A key takes its new value's class, live or deleted
+        db.put(Bytes::from("small0"), large.clone())?;
+        assert_eq!(class_of(&db, "small0"), LARGE_VALUES);
+        db.delete(Bytes::from("small1"))?;
+        db.put(Bytes::from("small1"), large.clone())?;
+        assert_eq!(class_of(&db, "small1"), LARGE_VALUES);
+        db.delete(Bytes::from("large1"))?;
+        db.put(Bytes::from("large1"), small.clone())?;
+        assert_eq!(class_of(&db, "large1"), SMALL_VALUES);
+        db.delete(Bytes::from("large2"))?;
+        db.put(Bytes::from("large2"), small.clone())?;
+        db.delete(Bytes::from("large2"))?;
+        // Back and forth, directly and in batches
+        db.put(Bytes::from("bounce"), large.clone())?;
+        db.put(Bytes::from("bounce"), small.clone())?;
+        db.put(Bytes::from("bounce"), large.clone())?;
+        db.put(Bytes::from("to_small"), large.clone())?;
+        db.put(Bytes::from("to_large"), small.clone())?;
+        let batch = db.new_write_batch(crate::batch::WriteBatchOptions {
+            max_batch_num: 10,
+            sync_writes: false,
+        })?;
+        batch.put(Bytes::from("to_small"), small.clone())?;
+        batch.put(Bytes::from("to_large"), large.clone())?;
+        batch.commit()?;
+        assert_eq!(class_of(&db, "to_small"), SMALL_VALUES);
+        assert_eq!(class_of(&db, "to_large"), LARGE_VALUES);
+
+        let check = |db: &Db| -> Result<()> {
+            assert_eq!(db.get(Bytes::from("bounce"))?, large);
+            assert_eq!(db.get(Bytes::from("to_small"))?, small);
+            assert_eq!(db.get(Bytes::from("to_large"))?, large);
+            assert_eq!(db.get(Bytes::from("small0"))?, large);
+            assert_eq!(db.get(Bytes::from("small1"))?, large);
+            assert_eq!(db.get(Bytes::from("large1"))?, small);
+            assert!(db.get(Bytes::from("large2")).is_err());
+            for i in 2..50 {
+                assert_eq!(db.get(Bytes::from(format!("small{}", i)))?, small);
+            }
+            for i in 3..50 {
+                assert_eq!(db.get(Bytes::from(format!("large{}", i)))?, large);
+            }
+            Ok(())
+        };
+        check(&db)?;
+        drop(db);
+        let db = Db::open(&opts)?;
+        check(&db)?;
+        db.merge()?;
+        drop(db);
+        let db = Db::open(&opts)?;
+        check(&db)?;
+        assert_eq!(class_of(&db, "small0"), LARGE_VALUES);
+        assert_eq!(class_of(&db, "small1"), LARGE_VALUES);
+        drop(db);
+
+        // The classes can't be dropped once written
+        opts.size_class_boundary = None;
+        assert!(Db::open(&opts).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "write-shards")]
+    fn sharded_opts(name: &str) -> crate::Opts {
+        let mut opts = crate::Opts::new(256, 1024, false, false, format!("/tmp/{}", name), 512);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.write_shards = 4;
+        opts
+    }
+
+    #[cfg(feature = "write-shards")]
+    #[test]
+    fn test_size_classes_reject_write_shards() {
+        let mut opts = sharded_opts("test_size_classes_reject_write_shards");
+        opts.size_class_boundary = Some(100);
+        assert!(crate::db::Db::open(&opts).is_err());
+    }
+
+    #[cfg(feature = "write-shards")]
+    #[test]
+    fn test_sharded_writes_survive_reopen() -> Result<()> {
+        use crate::batch::WriteBatchOptions;
+        use crate::db::Db;
+        use bytes::Bytes;
+
+        let opts = sharded_opts("test_sharded_writes_survive_reopen");
+        let db = Db::open(&opts)?;
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let db = &db;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let key = Bytes::from(format!("key-{}-{}", t, i));
+                        db.put(key, Bytes::from(format!("value-{}", i))).unwrap();
+                    }
+                });
+            }
+        });
+        for i in 0..100 {
+            db.delete(Bytes::from(format!("key-0-{}", i)))?;
+        }
+        let batch = db.new_write_batch(WriteBatchOptions {
+            max_batch_num: 100,
+            sync_writes: true,
+        })?;
+        for i in 0..20 {
+            batch.put(Bytes::from(format!("batch-{}", i)), Bytes::from("batched"))?;
+        }
+        batch.commit()?;
+
+        let shards = db
+            .inactive_files
+            .iter()
+            .map(|file| shard_of(file.get_file_id(), 4))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(shards.len(), 4);
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        for t in 0..4 {
+            for i in 0..100 {
+                let value = db.get(Bytes::from(format!("key-{}-{}", t, i)));
+                if t == 0 {
+                    assert!(value.is_err());
+                } else {
+                    assert_eq!(value?, format!("value-{}", i).into_bytes());
+                }
+            }
+        }
+        for i in 0..20 {
+            assert_eq!(db.get(Bytes::from(format!("batch-{}", i)))?, "batched");
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "write-shards")]
+    #[test]
+    fn test_sharded_merge() -> Result<()> {
+        use crate::db::Db;
+        use bytes::Bytes;
+
+        let opts = sharded_opts("test_sharded_merge");
+        let db = Db::open(&opts)?;
+        for round in 0..3 {
+            for i in 0..100 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("value{}-{}", i, round)),
+                )?;
+            }
+        }
+        db.merge()?;
+        for i in 0..10 {
+            db.put(Bytes::from(format!("key{}", i)), Bytes::from("after"))?;
+        }
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        for i in 0..100 {
+            let expected = if i < 10 {
+                "after".to_string()
+            } else {
+                format!("value{}-2", i)
+            };
+            assert_eq!(
+                db.get(Bytes::from(format!("key{}", i)))?,
+                expected.into_bytes()
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "write-shards")]
+    #[test]
+    fn test_shard_count_is_fixed() -> Result<()> {
+        use crate::db::Db;
+        use bytes::Bytes;
+
+        let mut opts = sharded_opts("test_shard_count_is_fixed");
+        opts.write_shards = 0;
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+        drop(db);
+
+        // An unsharded directory can become sharded, but not change again
+        opts.write_shards = 4;
+        let db = Db::open(&opts)?;
+        assert_eq!(db.get(Bytes::from("key"))?, "value");
+        drop(db);
+        opts.write_shards = 2;
+        assert!(Db::open(&opts).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "write-shards")]
+    #[test]
+    fn test_puts_to_other_shards_dont_wait() -> Result<()> {
+        use crate::db::Db;
+        use bytes::Bytes;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let opts = sharded_opts("test_puts_to_other_shards_dont_wait");
+        let db = Db::open(&opts)?;
+        let key_in = |shard: usize| {
+            (0..)
+                .map(|i| Bytes::from(format!("key{}", i)))
+                .find(|key| shard_for_key(key, 4) == shard)
+                .unwrap()
+        };
+
+        let (key0, key1) = (key_in(0), key_in(1));
+
+        // A put waiting on shard 0 doesn't hold up one to shard 1
+        let write_guard = db.shards[0].active_file.write();
+        let db = &db;
+        std::thread::scope(|s| {
+            let blocked = s.spawn(|| db.put(key0.clone(), Bytes::from("value")));
+            std::thread::sleep(Duration::from_millis(50));
+            let (tx, rx) = mpsc::channel();
+            s.spawn(move || tx.send(db.put(key1, Bytes::from("value"))));
+            let put = rx.recv_timeout(Duration::from_secs(5));
+            drop(write_guard);
+            assert!(matches!(put, Ok(Ok(()))));
+            blocked.join().unwrap()
+        })?;
+        assert_eq!(db.get(key0)?, "value");
+        Ok(())
+    }
+}
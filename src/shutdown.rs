@@ -0,0 +1,218 @@
+use crate::db::Db;
+use crate::{Error, Result};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+/// What `Db::shutdown` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloseStats {
+    /// Time spent waiting for writes and merges already under way.
+    pub drain_time: Duration,
+    /// Background sync threads stopped.
+    pub stopped_threads: usize,
+}
+
+/// Shuts a shared database down from elsewhere, such as a signal handler.
+/// It doesn't keep the database alive.
+#[derive(Debug, Clone)]
+pub struct ShutdownGuard {
+    db: Weak<Db>,
+}
+
+impl ShutdownGuard {
+    /// Runs `Db::shutdown`, or fails with `Error::Closed` if the database
+    /// has already been dropped.
+    pub fn trigger(&self, deadline: Duration) -> Result<CloseStats> {
+        match self.db.upgrade() {
+            Some(db) => db.shutdown(deadline),
+            None => Err(Error::Closed),
+        }
+    }
+}
+
+impl Db {
+    /// Flushes and closes the database for a clean exit.
+    ///
+    /// Operations that start once this is called fail with `Error::Closed`.
+    /// Writes and merges already under way get until `deadline` to finish; if
+    /// they don't, this fails and can be called again. Everything written is
+    /// then synced, the sync threads are stopped and the directory is
+    /// unlocked.
+    pub fn shutdown(&self, deadline: Duration) -> Result<CloseStats> {
+        let started = Instant::now();
+        let deadline = started + deadline;
+        self.closed.store(true, Ordering::SeqCst);
+
+        let timed_out = || Error::Unsupported("Shutdown timed out on writes in flight".to_string());
+        let _merge_guard = self
+            .merge_lock
+            .try_lock_until(deadline)
+            .ok_or_else(timed_out)?;
+        let _commit_lock = self
+            .batch_commit_lock
+            .try_write_until(deadline)
+            .ok_or_else(timed_out)?;
+        // Appended writes may still be waiting on their fsync
+        if !self.index_sequencer.wait_all_until(deadline) {
+            return Err(timed_out());
+        }
+        let drain_time = started.elapsed();
+
+        self.sync_all()?;
+        for shard in self.shards.iter() {
+            self.write_file_crc(&shard.active_file.read())?;
+        }
+        let stopped_threads = self
+            .shards
+            .iter()
+            .filter(|shard| shard.syncer.stop())
+            .count();
+        if let Some(memoizer) = &self.memoizer {
+            memoizer.stop();
+        }
+        self.evictor.stop();
+        self.lock_file.lock().release()?;
+        Ok(CloseStats {
+            drain_time,
+            stopped_threads,
+        })
+    }
+
+    /// A handle that shuts this database down when triggered.
+    pub fn shutdown_guard(self: &Arc<Self>) -> ShutdownGuard {
+        ShutdownGuard {
+            db: Arc::downgrade(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatchOptions;
+    use crate::storage::FileCrc;
+    use crate::Opts;
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use std::fs;
+    use std::thread;
+
+    fn opts(name: &str) -> Opts {
+        let mut opts = Opts::new(256, 1024, false, true, format!("/tmp/{}", name), 4096);
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        opts.sync_interval = Some(Duration::from_millis(5));
+        opts
+    }
+
+    #[test]
+    fn test_shutdown_with_writers() -> Result<()> {
+        let opts = opts("test_shutdown_with_writers");
+        let db = Db::open(&opts)?;
+        let acknowledged = Mutex::new(Vec::new());
+
+        let stats = thread::scope(|s| {
+            for t in 0..4 {
+                let db = &db;
+                let acknowledged = &acknowledged;
+                s.spawn(move || {
+                    for i in 0.. {
+                        let key = Bytes::from(format!("key-{}-{}", t, i));
+                        let written = if t % 2 == 0 {
+                            db.put(key.clone(), Bytes::from("value"))
+                        } else {
+                            let batch = db
+                                .new_write_batch(WriteBatchOptions {
+                                    max_batch_num: 10,
+                                    sync_writes: true,
+                                })
+                                .unwrap();
+                            batch.put(key.clone(), Bytes::from("value")).unwrap();
+                            batch.commit()
+                        };
+                        match written {
+                            Ok(()) => acknowledged.lock().push(key),
+                            Err(Error::Closed) => return,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                });
+            }
+            while acknowledged.lock().len() < 200 {
+                thread::yield_now();
+            }
+            db.shutdown(Duration::from_secs(10))
+        })?;
+        assert_eq!(stats.stopped_threads, db.shards.len());
+
+        assert!(matches!(db.get(Bytes::from("key-0-0")), Err(Error::Closed)));
+        assert!(matches!(
+            db.put(Bytes::from("late"), Bytes::from("value")),
+            Err(Error::Closed)
+        ));
+        assert!(matches!(db.merge(), Err(Error::Closed)));
+        // Shutting down again finds nothing left to do
+        assert_eq!(db.shutdown(Duration::ZERO)?.stopped_threads, 0);
+        drop(db);
+
+        let db = Db::open(&opts)?;
+        for key in acknowledged.into_inner() {
+            assert_eq!(db.get(key)?, "value");
+        }
+        assert!(db.get(Bytes::from("late")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_times_out_on_writes_in_flight() -> Result<()> {
+        let opts = opts("test_shutdown_times_out");
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+
+        // Stands in for a commit that doesn't finish in time
+        let commit_lock = db.batch_commit_lock.write();
+        assert!(db.shutdown(Duration::from_millis(20)).is_err());
+        drop(commit_lock);
+        assert!(matches!(db.get(Bytes::from("key")), Err(Error::Closed)));
+        db.shutdown(Duration::from_millis(20))?;
+        drop(db);
+
+        assert_eq!(Db::open(&opts)?.get(Bytes::from("key"))?, "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_guard() -> Result<()> {
+        let mut opts = opts("test_shutdown_guard");
+        opts.file_crc = true;
+        let db = Arc::new(Db::open(&opts)?);
+        let guard = db.shutdown_guard();
+        db.put(Bytes::from("key"), Bytes::from("value"))?;
+
+        let trigger = guard.clone();
+        thread::spawn(move || trigger.trigger(Duration::from_secs(1)))
+            .join()
+            .unwrap()?;
+        assert!(matches!(
+            db.put(Bytes::from("key"), Bytes::from("value")),
+            Err(Error::Closed)
+        ));
+        // The directory was unlocked, so it opens while the handle lives on
+        let reopened = Db::open(&opts)?;
+        assert_eq!(reopened.get(Bytes::from("key"))?, "value");
+        reopened.put(Bytes::from("other"), Bytes::from("value"))?;
+        let file_id = reopened.active_file.read().get_file_id();
+        drop(reopened);
+
+        // Dropping the old handle leaves the sidecars the new one wrote alone
+        let crc = FileCrc::read(&opts.dir_path, file_id)?;
+        assert!(crc.is_some());
+        drop(db);
+        assert_eq!(FileCrc::read(&opts.dir_path, file_id)?, crc);
+        assert!(matches!(
+            guard.trigger(Duration::from_secs(1)),
+            Err(Error::Closed)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+//! Backups uploaded file by file to wherever a `BackupSink` puts them.
+
+use crate::archive::list_files;
+use crate::db::{backup_extent, BackupExtent, BackupFile, BackupReport, Db};
+use crate::{Error, Result};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the object `Db::backup_with` sends last, listing the others.
+pub const BACKUP_MANIFEST_NAME: &str = "BACKUP_MANIFEST";
+
+/// Where `Db::backup_with` sends a backup, one named object per file.
+///
+/// Names are `/`-separated paths relative to the database directory. `data`
+/// yields exactly the length listed for the object in the manifest, so an
+/// uploader that needs the length up front, as S3's `PutObject` does, can
+/// take it from there or read `data` into a buffer. A sink for S3 is a
+/// thin wrapper around a client:
+///
+/// ```ignore
+/// struct S3Sink { client: s3::Client, bucket: String, prefix: String }
+///
+/// impl BackupSink for S3Sink {
+///     fn put_object(&mut self, name: &str, data: &mut dyn Read) -> zap::Result<()> {
+///         let mut body = Vec::new();
+///         data.read_to_end(&mut body)?;
+///         self.client
+///             .put_object(&self.bucket, &format!("{}/{}", self.prefix, name), body)
+///             .map_err(|e| zap::Error::Io(std::io::Error::other(e)))
+///     }
+/// }
+/// ```
+pub trait BackupSink {
+    /// Stores `data` as object `name`, replacing any object of that name.
+    fn put_object(&mut self, name: &str, data: &mut dyn Read) -> Result<()>;
+}
+
+/// A `BackupSink` writing each object to a file under a directory.
+#[derive(Debug, Clone)]
+pub struct DirSink {
+    dir_path: PathBuf,
+}
+
+impl DirSink {
+    pub fn new(dir_path: impl Into<PathBuf>) -> Self {
+        Self {
+            dir_path: dir_path.into(),
+        }
+    }
+}
+
+impl BackupSink for DirSink {
+    fn put_object(&mut self, name: &str, data: &mut dyn Read) -> Result<()> {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Error::Unsupported(format!("Bad object name {:?}", name)));
+        }
+        let path = self.dir_path.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&path)?;
+        io::copy(data, &mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Db {
+    /// Sends a backup to `sink`, taken at the same point as `back_up`: the
+    /// active files up to their ends once those are durable, older files
+    /// whole and files created since left out.
+    ///
+    /// Each file goes out as one object, its length fixed before it's sent.
+    /// A `BACKUP_MANIFEST` object follows with a line per file, `<len>
+    /// <crc32 in hex> <name>`, so a backup missing its manifest is known to
+    /// be incomplete.
+    pub fn backup_with(&self, sink: &mut dyn BackupSink) -> Result<BackupReport> {
+        let ends = self.backup_point()?;
+        let mut files = Vec::new();
+        list_files(&self.ctx.opts.dir_path, "", &mut files)?;
+        files.sort();
+
+        let mut report = BackupReport::default();
+        let mut manifest = String::new();
+        for (name, path) in files {
+            let file_name = name.rsplit('/').next().unwrap_or(&name);
+            let len = match backup_extent(&ends, file_name) {
+                Some(BackupExtent::Prefix(end)) => end,
+                Some(BackupExtent::Whole) => fs::metadata(&path)?.len(),
+                None => continue,
+            };
+            let mut data = CrcReader {
+                inner: File::open(&path)?.take(len),
+                hasher: crc32fast::Hasher::new(),
+                read: 0,
+            };
+            sink.put_object(&name, &mut data)?;
+            // The sink may stop short; a file that shrank ends early
+            io::copy(&mut data, &mut io::sink())?;
+            if data.read != len {
+                return Err(Error::Unsupported(format!(
+                    "{} shrank during the backup",
+                    name
+                )));
+            }
+            manifest.push_str(&format!(
+                "{} {:08x} {}\n",
+                len,
+                data.hasher.finalize(),
+                name
+            ));
+            report.files.push(BackupFile {
+                name,
+                bytes: len,
+                hard_linked: false,
+            });
+        }
+        sink.put_object(BACKUP_MANIFEST_NAME, &mut manifest.as_bytes())?;
+        Ok(report)
+    }
+}
+
+// Hashes and counts what's read through it
+struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    read: u64,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+
+    // Keeps every object in memory
+    #[derive(Default)]
+    struct RecordingSink {
+        objects: Vec<(String, Vec<u8>)>,
+    }
+
+    impl BackupSink for RecordingSink {
+        fn put_object(&mut self, name: &str, data: &mut dyn Read) -> Result<()> {
+            let mut bytes = Vec::new();
+            data.read_to_end(&mut bytes)?;
+            self.objects.push((name.to_string(), bytes));
+            Ok(())
+        }
+    }
+
+    fn sink_opts(name: &str) -> Opts {
+        let mut opts = Opts::new(256, 1024, false, false, format!("/tmp/{}", name), 1024);
+        opts.file_manifest = true;
+        opts
+    }
+
+    #[test]
+    fn test_backup_with_recording_sink() -> Result<()> {
+        let opts = sink_opts("test_backup_with");
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+        for i in 0..200 {
+            db.put(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            )?;
+        }
+        db.merge()?;
+        // Reopened to install the merge and its hint file
+        drop(db);
+        let db = Db::open(&opts)?;
+        db.put(Bytes::from("after"), Bytes::from("merge"))?;
+
+        let mut sink = RecordingSink::default();
+        let report = db.backup_with(&mut sink)?;
+        let (last, manifest) = sink.objects.pop().unwrap();
+        assert_eq!(last, BACKUP_MANIFEST_NAME);
+        let sent = sink
+            .objects
+            .iter()
+            .map(|(name, bytes)| {
+                let line = format!("{} {:08x} {}", bytes.len(), crc32fast::hash(bytes), name);
+                (name.clone(), line)
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(sent.len(), sink.objects.len());
+        let listed = String::from_utf8(manifest).unwrap();
+        assert_eq!(
+            listed.lines().collect::<Vec<_>>(),
+            sent.values().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            report
+                .files
+                .iter()
+                .map(|file| &file.name)
+                .collect::<Vec<_>>(),
+            sent.keys().collect::<Vec<_>>()
+        );
+        for name in ["hint", "MANIFEST"] {
+            assert!(sent.contains_key(name), "{} wasn't sent", name);
+        }
+
+        // Objects written by `DirSink` open as the database
+        let restore_opts = sink_opts("test_backup_with_dir_sink");
+        let _ = fs::remove_dir_all(&restore_opts.dir_path);
+        db.backup_with(&mut DirSink::new(&restore_opts.dir_path))?;
+        fs::remove_file(restore_opts.dir_path.join(BACKUP_MANIFEST_NAME))?;
+        let restored = Db::open(&restore_opts)?;
+        for i in 0..200 {
+            assert_eq!(
+                restored.get(Bytes::from(format!("key{}", i)))?,
+                format!("value{}", i).into_bytes()
+            );
+        }
+        assert_eq!(restored.get(Bytes::from("after"))?, "merge");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_sink_rejects_escaping_names() {
+        let mut sink = DirSink::new("/tmp/test_dir_sink_names");
+        for name in ["../outside", "/abs", "a/../../b"] {
+            assert!(sink.put_object(name, &mut &b"data"[..]).is_err());
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Read-only views of a database another process is writing to, for
+//! analytics jobs that can't stop the service owning it.
+
+use crate::changes::read_generation;
+use crate::db::{parse_data_file_id, Db, FILE_SUFFIX, NON_COMMITTED};
+use crate::index::Indexer;
+use crate::io::MmapIO;
+use crate::shard::shard_of;
+use crate::storage::FileHandle;
+use crate::{Error, Opts, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+// Opens started over because the writer removed files underneath them
+const SNAPSHOT_OPEN_ATTEMPTS: usize = 5;
+
+impl Db {
+    /// Opens a read-only view of the database in `dir_path` as it stands,
+    /// while another process may have it open for writing. Opened with
+    /// default options otherwise; see `open_snapshot`.
+    pub fn open_snapshot_of(dir_path: &Path) -> Result<Db> {
+        Self::open_snapshot(&Opts {
+            dir_path: dir_path.to_path_buf(),
+            ..Opts::default()
+        })
+    }
+
+    /// Like `open_snapshot_of`, with the options of `opts` the writer's
+    /// files depend on, such as `write_shards` or `size_class_boundary`.
+    ///
+    /// The writer locks the directory exclusively, so the view takes no lock
+    /// (`Opts::skip_lock`). It holds what replay finds: inactive files whole
+    /// and each active file up to its last intact record, less batches whose
+    /// marker isn't there yet, so every shard shows a prefix of its writes.
+    /// The view stays as it was opened while the writer carries on, until
+    /// `refresh`.
+    ///
+    /// A file the writer removes while the view opens, by compacting,
+    /// evicting or installing a merge, has the open start over.
+    pub fn open_snapshot(opts: &Opts) -> Result<Db> {
+        let mut opts = opts.clone();
+        opts.read_only = true;
+        opts.skip_lock = true;
+        opts.incremental_hint = false;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let generation = read_generation(&opts.dir_path)?;
+            match Db::open(&opts) {
+                // A merge installed meanwhile may have replaced some of the
+                // files read
+                Ok(_) if read_generation(&opts.dir_path)? != generation => {}
+                Ok(db) => return Ok(db),
+                Err(Error::Io(e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            if attempts == SNAPSHOT_OPEN_ATTEMPTS {
+                return Err(Error::Unsupported(format!(
+                    "Files of {} kept changing while opening a snapshot",
+                    opts.dir_path.display()
+                )));
+            }
+        }
+    }
+
+    /// Catches a view from `open_snapshot` up with its writer. Files the
+    /// writer rotated to since are replayed, and the active files again from
+    /// their start, as records appended to them may complete batches. A view
+    /// whose files the writer has since merged, compacted or evicted is
+    /// opened again instead. Secondary indexes of the view are caught up
+    /// along with it, or created again on the reopened view.
+    pub fn refresh(&mut self) -> Result<()> {
+        if !(self.ctx.opts.read_only && self.ctx.opts.skip_lock) {
+            return Err(Error::Unsupported(
+                "Only a view opened by Db::open_snapshot can be refreshed".to_string(),
+            ));
+        }
+        if read_generation(&self.ctx.opts.dir_path)? == self.generation {
+            match self.refresh_files() {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(Error::Io(e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let opts = self.ctx.opts.clone();
+        let extractors = self.secondary_indexes.extractors();
+        *self = Self::open_snapshot(&opts)?;
+        for (name, extractor) in extractors {
+            self.create_index(&name, extractor)?;
+        }
+        Ok(())
+    }
+
+    // Replays the files from each shard's active file on. Returns `false`
+    // if a file of the view is gone, leaving the index part way.
+    fn refresh_files(&mut self) -> Result<bool> {
+        let dir_path = self.ctx.opts.dir_path.clone();
+        let mut file_ids = fs::read_dir(&dir_path)?
+            .flatten()
+            .filter_map(|file| file.file_name().to_str().and_then(parse_data_file_id))
+            .collect::<Vec<u32>>();
+        file_ids.sort();
+        let still_there = self
+            .inactive_files
+            .iter()
+            .all(|file| file_ids.binary_search(&file.get_file_id()).is_ok());
+        if !still_there {
+            return Ok(false);
+        }
+
+        let shard_count = self.shards.len();
+        let mut current_sequence_number = NON_COMMITTED;
+        // Keys the replays touched, for the secondary indexes
+        let mut replayed_keys = HashSet::new();
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let active_id = shard.file_id.load(Ordering::SeqCst);
+            let ids = file_ids
+                .iter()
+                .filter(|id| shard_of(**id, shard_count) == shard_index && **id >= active_id)
+                .collect::<Vec<_>>();
+            if ids.first() != Some(&&active_id) {
+                return Ok(false);
+            }
+            // Every file replays before any of them is applied
+            let mut replayed = Vec::with_capacity(ids.len());
+            for file_id in ids {
+                let path = dir_path.join(format!("{}{}", file_id, FILE_SUFFIX));
+                let file = FileHandle::new(*file_id, MmapIO::open_read_only(&path)?.into());
+                let replay = Self::process_file_handle(&file, &self.ctx.opts)?;
+                replayed.push((file, replay));
+            }
+            for (file, replay) in replayed.iter() {
+                replay.apply(&self.ctx.index, &mut current_sequence_number);
+                if !self.secondary_indexes.is_empty() {
+                    replayed_keys.extend(replay.keys().cloned());
+                }
+                file.set_offset(replay.size);
+            }
+            let (mut active_file, _) = replayed.pop().expect("the active file replayed");
+            for (file, _) in replayed {
+                self.inactive_files
+                    .insert(file.get_file_id(), file.freeze());
+            }
+            active_file.set_io(&dir_path, true)?;
+            shard
+                .file_id
+                .store(active_file.get_file_id(), Ordering::SeqCst);
+            shard.publish(&active_file);
+            *shard.active_file.write() = active_file;
+        }
+
+        // Read back once every shard's files are in place
+        let updates = replayed_keys
+            .into_iter()
+            .map(|key| {
+                let entry = self.ctx.index.get(&key);
+                (key, entry)
+            })
+            .collect::<Vec<_>>();
+        let secondary_updates = self.secondary_updates(&updates);
+        self.secondary_indexes.apply(secondary_updates);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    // The writes a view holds: `key{i}` for every `i` below the count, each
+    // batch `a{i}`/`b{i}` whole or not at all
+    fn check_prefix(view: &Db, writes: usize) -> Result<usize> {
+        let mut seen = 0;
+        while seen < writes && view.get(Bytes::from(format!("key{}", seen))).is_ok() {
+            assert_eq!(
+                view.get(Bytes::from(format!("key{}", seen)))?,
+                format!("value{}", seen).as_bytes()
+            );
+            seen += 1;
+        }
+        for i in seen..writes {
+            assert!(view.get(Bytes::from(format!("key{}", i))).is_err());
+        }
+        for i in 0..writes {
+            let a = view.get(Bytes::from(format!("a{}", i))).is_ok();
+            let b = view.get(Bytes::from(format!("b{}", i))).is_ok();
+            assert_eq!(a, b, "batch {} is torn", i);
+        }
+        Ok(seen)
+    }
+
+    #[test]
+    fn test_snapshot_of_live_writer() -> Result<()> {
+        const WRITES: usize = 3000;
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_snapshot_of_live_writer".to_string(),
+            16 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let writer = Db::open(&opts)?;
+        writer.put(Bytes::from("key0"), Bytes::from("value0"))?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let writing = thread::spawn({
+            let done = done.clone();
+            move || -> Result<()> {
+                for i in 1..WRITES {
+                    writer.put(
+                        Bytes::from(format!("key{}", i)),
+                        Bytes::from(format!("value{}", i)),
+                    )?;
+                    writer.put_all(&[
+                        (Bytes::from(format!("a{}", i)), Bytes::from("value")),
+                        (Bytes::from(format!("b{}", i)), Bytes::from("value")),
+                    ])?;
+                }
+                done.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+
+        let mut view = Db::open_snapshot_of(&opts.dir_path)?;
+        let mut seen = check_prefix(&view, WRITES)?;
+        assert!(seen >= 1);
+        // Frozen until refreshed
+        assert_eq!(check_prefix(&view, WRITES)?, seen);
+        while !done.load(Ordering::SeqCst) {
+            view.refresh()?;
+            let now = check_prefix(&view, WRITES)?;
+            assert!(now >= seen);
+            seen = now;
+        }
+        writing.join().unwrap()?;
+
+        view.refresh()?;
+        assert_eq!(check_prefix(&view, WRITES)?, WRITES);
+        assert!(view.data_file_ids().len() > 1);
+        assert!(view.put(Bytes::from("key0"), Bytes::from("value")).is_err());
+
+        // A database opened normally can't be refreshed
+        let mut writer = Db::open(&opts)?;
+        assert!(matches!(writer.refresh(), Err(Error::Unsupported(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_updates_secondary_indexes() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_refresh_updates_secondary_indexes".to_string(),
+            256,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        let _ = fs::remove_dir_all(crate::merge::merge_dir_path(&opts.dir_path)?);
+        // Values are `user|rest`; the index is over `user`
+        let user_of = |value: &[u8]| {
+            let end = value.iter().position(|b| *b == b'|')?;
+            Some(value[..end].to_vec())
+        };
+        let users = |view: &Db, user: &str| view.get_by_index("user", user.as_bytes());
+        let writer = Db::open(&opts)?;
+        writer.put(Bytes::from("session1"), Bytes::from("alice|a"))?;
+        let mut view = Db::open_snapshot_of(&opts.dir_path)?;
+        view.create_index("user", user_of)?;
+        assert_eq!(users(&view, "alice")?, vec![Bytes::from("session1")]);
+
+        writer.put(Bytes::from("session2"), Bytes::from("alice|b"))?;
+        writer.put(Bytes::from("session1"), Bytes::from("bob|a"))?;
+        writer.put(Bytes::from("session3"), Bytes::from("carol|c"))?;
+        writer.delete(Bytes::from("session3"))?;
+        // Rotates, so the view replays new files as well
+        for i in 0..10 {
+            writer.put(Bytes::from(format!("filler{}", i)), Bytes::from("value"))?;
+        }
+        assert!(writer.data_file_ids().len() > 1);
+        view.refresh()?;
+        assert_eq!(users(&view, "alice")?, vec![Bytes::from("session2")]);
+        assert_eq!(users(&view, "bob")?, vec![Bytes::from("session1")]);
+        assert!(users(&view, "carol")?.is_empty());
+
+        // Installing a merge has the view opened again
+        writer.merge()?;
+        drop(writer);
+        let writer = Db::open(&opts)?;
+        writer.put(Bytes::from("session4"), Bytes::from("dave|d"))?;
+        view.refresh()?;
+        assert_eq!(users(&view, "dave")?, vec![Bytes::from("session4")]);
+        assert_eq!(users(&view, "alice")?, vec![Bytes::from("session2")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use crate::db::Db;
+use crate::index::{IndexIterator, IndexKey, Indexer};
+use crate::{KeyDirEntry, Result};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stat {
+    /// Number of live keys in the index.
+    pub key_num: usize,
+    /// Number of data files, including the active ones.
+    pub data_file_num: usize,
+    /// Bytes written across all data files.
+    pub disk_size: u64,
+    /// Approximate memory held by the index, inlined values included.
+    pub index_memory: u64,
+    /// Bytes of values copied into the index.
+    pub inline_value_bytes: u64,
+    /// Bytes of the data files not taken up by live records: overwritten
+    /// values, tombstones and batch markers, which a merge would drop.
+    pub reclaimable_bytes: u64,
+    /// `reclaimable_bytes` as a share of `disk_size`, `0.0` when empty.
+    pub stale_ratio: f64,
+    /// Whether `stale_ratio` has reached `Opts::merge_stale_ratio`.
+    pub merge_recommended: bool,
+}
+
+#[allow(dead_code)]
+impl Db {
+    pub fn stat(&self) -> Result<Stat> {
+        let mut stat = Stat::default();
+        let mut live_bytes = 0;
+
+        let mut iter = self.ctx.index.iter();
+        while let Some((key, entry)) = iter.next() {
+            let inline_len = entry.get_inline_value().map_or(0, |v| v.len()) as u64;
+            stat.key_num += 1;
+            live_bytes += entry.get_size() as u64;
+            stat.inline_value_bytes += inline_len;
+            stat.index_memory += (key.len()
+                + std::mem::size_of::<IndexKey>()
+                + std::mem::size_of::<KeyDirEntry>()) as u64
+                + inline_len;
+        }
+
+        stat.data_file_num = self.inactive_files.len() + self.shards.len();
+        stat.disk_size = self
+            .shards
+            .iter()
+            .map(|shard| shard.active_file.read().get_offset())
+            .sum::<u64>()
+            + self
+                .inactive_files
+                .iter()
+                .map(|file| file.get_offset())
+                .sum::<u64>();
+        stat.reclaimable_bytes = stat.disk_size.saturating_sub(live_bytes);
+        if stat.disk_size > 0 {
+            stat.stale_ratio = stat.reclaimable_bytes as f64 / stat.disk_size as f64;
+            stat.merge_recommended = stat.stale_ratio >= self.ctx.opts.merge_stale_ratio;
+        }
+
+        Ok(stat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_stat_counts_inline_values() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_stat_inline".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        opts.inline_value_threshold = 8;
+        let db = Db::open(&opts)?;
+
+        db.put(Bytes::from("small"), Bytes::from("12345678"))?;
+        db.put(Bytes::from("large"), Bytes::from("123456789"))?;
+
+        let stat = db.stat()?;
+        assert_eq!(stat.key_num, 2);
+        assert_eq!(stat.data_file_num, 1);
+        assert_eq!(stat.inline_value_bytes, 8);
+        assert!(stat.index_memory >= stat.inline_value_bytes + 10);
+        assert!(stat.disk_size > 0);
+        assert_eq!(stat.reclaimable_bytes, 0);
+
+        // The overwritten record and the tombstone are both dead weight
+        db.put(Bytes::from("large"), Bytes::from("987654321"))?;
+        db.delete(Bytes::from("small"))?;
+        let stat = db.stat()?;
+        let live = db.locate(b"large").unwrap().get_size() as u64;
+        assert_eq!(stat.reclaimable_bytes, stat.disk_size - live);
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_memory_per_key() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_stat_index_memory".to_string(),
+            1024 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let db = Db::open(&opts)?;
+
+        for i in 0..1000 {
+            db.put(Bytes::from(format!("{:016}", i)), Bytes::from("value"))?;
+        }
+
+        // A boxed key costs 8 bytes less per entry than a Vec<u8> did
+        let per_key = 16 + std::mem::size_of::<IndexKey>() + std::mem::size_of::<KeyDirEntry>();
+        assert!(std::mem::size_of::<IndexKey>() < std::mem::size_of::<Vec<u8>>());
+        assert_eq!(db.stat()?.index_memory, 1000 * per_key as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_recommended() -> Result<()> {
+        let opts = Opts::new(
+            256,
+            1024,
+            false,
+            false,
+            "/tmp/test_stat_merge_recommended".to_string(),
+            4096,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        let _ = std::fs::remove_dir_all(crate::merge::merge_dir_path(&opts.dir_path)?);
+        let mut db = Db::open(&opts)?;
+        let stat = db.stat()?;
+        assert_eq!(stat.stale_ratio, 0.0);
+        assert!(!stat.merge_recommended);
+
+        for round in 0..10 {
+            for i in 0..20 {
+                db.put(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("value{}", round)),
+                )?;
+            }
+        }
+        let stat = db.stat()?;
+        assert!(stat.stale_ratio > 0.8);
+        assert!(stat.merge_recommended);
+
+        // The merged files are installed on the next open
+        db.merge()?;
+        db.close()?;
+        let db = Db::open(&opts)?;
+        let stat = db.stat()?;
+        assert_eq!(stat.key_num, 20);
+        assert!(stat.stale_ratio < 0.5);
+        assert!(!stat.merge_recommended);
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::Result;
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const CRC_SUFFIX: &str = ".crc";
+const CRC_TMP_SUFFIX: &str = ".crc.tmp";
+// `len: u64 | crc: u32 | crc32 of both`, big-endian
+const CRC_FILE_LEN: usize = 16;
+const READ_BUF_LEN: usize = 64 * 1024;
+
+/// The length of a data file and the CRC32 of its bytes up to it, kept in
+/// `{file_id}.crc` next to the file under `Opts::file_crc`. Data files only
+/// grow, so the file still matches as long as it starts with those bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileCrc {
+    pub len: u64,
+    pub crc: u32,
+}
+
+impl FileCrc {
+    fn path(dir_path: &Path, file_id: u32) -> PathBuf {
+        dir_path.join(format!("{}{}", file_id, CRC_SUFFIX))
+    }
+
+    /// Replaces the sidecar of `file_id`.
+    pub fn write(&self, dir_path: &Path, file_id: u32) -> Result<()> {
+        let mut encoded = Vec::with_capacity(CRC_FILE_LEN);
+        encoded.extend_from_slice(&self.len.to_be_bytes());
+        encoded.extend_from_slice(&self.crc.to_be_bytes());
+        encoded.extend_from_slice(&crc32fast::hash(&encoded).to_be_bytes());
+
+        let tmp_path = dir_path.join(format!("{}{}", file_id, CRC_TMP_SUFFIX));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, Self::path(dir_path, file_id))?;
+        Ok(())
+    }
+
+    /// The sidecar of `file_id`, `None` if there is none or it's damaged,
+    /// which leaves the file unchecked.
+    pub fn read(dir_path: &Path, file_id: u32) -> Result<Option<FileCrc>> {
+        let encoded = match fs::read(Self::path(dir_path, file_id)) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if encoded.len() != CRC_FILE_LEN
+            || crc32fast::hash(&encoded[..12]).to_be_bytes() != encoded[12..]
+        {
+            return Ok(None);
+        }
+        Ok(Some(FileCrc {
+            len: u64::from_be_bytes(encoded[..8].try_into().unwrap()),
+            crc: u32::from_be_bytes(encoded[8..12].try_into().unwrap()),
+        }))
+    }
+
+    /// Whether the data file at `path` still starts with the bytes recorded.
+    pub fn matches(&self, path: &Path) -> Result<bool> {
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() < self.len {
+            return Ok(false);
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; READ_BUF_LEN];
+        let mut left = self.len;
+        while left > 0 {
+            let chunk = &mut buf[..left.min(READ_BUF_LEN as u64) as usize];
+            file.read_exact(chunk)?;
+            hasher.update(chunk);
+            left -= chunk.len() as u64;
+        }
+        Ok(hasher.finalize() == self.crc)
+    }
+
+    /// Removes the sidecar of `file_id`, if any.
+    pub fn remove(dir_path: &Path, file_id: u32) -> Result<()> {
+        match fs::remove_file(Self::path(dir_path, file_id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes every sidecar in `dir_path`, for when data files are replaced
+    /// or renumbered.
+    pub fn remove_all(dir_path: &Path) -> Result<()> {
+        for dentry in fs::read_dir(dir_path)? {
+            let dentry = dentry?;
+            let file_name = dentry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.ends_with(CRC_SUFFIX) || file_name.ends_with(CRC_TMP_SUFFIX) {
+                fs::remove_file(dentry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::io::ErrorKind;
 
 use bytes::{Buf, BufMut, BytesMut};
@@ -5,15 +6,28 @@ use prost::{
     decode_length_delimiter, encode_length_delimiter, encoding::decode_varint, length_delimiter_len,
 };
 
+use crate::Compression;
 use crate::Error;
 use crate::KeyDirEntry;
 use crate::Result;
 
+/// Upper bound of an encoded header: the state byte plus two varint lengths.
+pub const HEADER_MAX_LEN: usize = std::mem::size_of::<u8>() + MAX_VARINT_LEN * 2;
+/// Size of the CRC32 suffix written after every entry.
+pub const CRC_LEN: usize = std::mem::size_of::<u32>();
+// A u32 length never needs more than 5 varint bytes.
+const MAX_VARINT_LEN: usize = 5;
+// The low four bits of the state byte hold the state, the high four the
+// value's `Compression`
+const STATE_MASK: u8 = 0x0f;
+
 #[derive(Debug)]
 pub struct DataEntry {
     key: Vec<u8>,
     value: Vec<u8>,
     state: State,
+    // How `value` is compressed
+    compression: Compression,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -26,7 +40,7 @@ pub enum State {
 impl TryFrom<u8> for State {
     type Error = Error;
     fn try_from(v: u8) -> Result<Self> {
-        match v {
+        match v & STATE_MASK {
             0 => Ok(State::Active),
             1 => Ok(State::Inactive),
             2 => Ok(State::Committed),
@@ -41,8 +55,31 @@ impl DataEntry {
             key: key.into(),
             value: value.into(),
             state,
+            compression: Compression::None,
         }
     }
+
+    /// Marks the value as already compressed with `compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn get_compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// The entry with its value decompressed.
+    pub fn decompress(self) -> Result<Self> {
+        if self.compression == Compression::None {
+            return Ok(self);
+        }
+        Ok(Self {
+            value: self.compression.decompress(&self.value)?,
+            compression: Compression::None,
+            ..self
+        })
+    }
     pub fn set_key(&mut self, key: impl Into<Vec<u8>>) {
         self.key = key.into();
     }
@@ -59,6 +96,10 @@ impl DataEntry {
         &self.value
     }
 
+    pub fn into_value(self) -> Vec<u8> {
+        self.value
+    }
+
     pub fn set_state(&mut self, state: State) {
         self.state = state;
     }
@@ -67,9 +108,20 @@ impl DataEntry {
         self.state.clone()
     }
 
+    /// Length of the record produced by `encode`, without encoding it.
+    pub fn encoded_len(&self) -> usize {
+        std::mem::size_of::<u8>()
+            + length_delimiter_len(self.key.len())
+            + length_delimiter_len(self.value.len())
+            + self.key.len()
+            + self.value.len()
+            + CRC_LEN
+    }
+
     pub fn get_crc(&self) -> Result<u32> {
-        let (_, crc) = self.encode_and_get_crc()?;
-        Ok(crc)
+        self.validate_len()?;
+        let (header, header_len) = self.encode_header();
+        Ok(self.crc_with_header(&header[..header_len]))
     }
     pub fn encode(&self) -> Result<Vec<u8>> {
         let (data_entry, _) = self.encode_and_get_crc()?;
@@ -77,38 +129,49 @@ impl DataEntry {
     }
 
     pub fn encode_and_get_crc(&self) -> Result<(Vec<u8>, u32)> {
-        let key_size = self.key.len();
-        let value_size = self.value.len();
-        // If key_size and value_size are both 0, it means invalid data
-        if key_size == 0 && value_size == 0 {
+        self.validate_len()?;
+        let (header, header_len) = self.encode_header();
+        let header = &header[..header_len];
+        let crc = self.crc_with_header(header);
+
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        buf.extend_from_slice(header);
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+        buf.put_u32(crc);
+        Ok((buf, crc))
+    }
+
+    fn validate_len(&self) -> Result<()> {
+        // If key_size and value_size are both 0, it means invalid data. Keys
+        // written to data files carry their transaction prefix, so a record
+        // with an empty value still has a key
+        if self.key.is_empty() && self.value.is_empty() {
             return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
         }
-        let mut buf = BytesMut::new();
-        buf.reserve(
-            std::mem::size_of::<u8>()
-                + length_delimiter_len(self.key.len())
-                + length_delimiter_len(self.value.len())
-                + self.key.len()
-                + self.value.len()
-                + 4,
-        );
-
-        buf.put_u8(self.state.clone() as u8);
+        Ok(())
+    }
 
-        // Store key size and value size
+    /// The state byte and varint key and value lengths, and how many bytes of
+    /// the buffer they take.
+    pub fn encode_header(&self) -> ([u8; HEADER_MAX_LEN], usize) {
+        let mut header = [0u8; HEADER_MAX_LEN];
+        let mut buf = &mut header[..];
+        buf.put_u8(self.state.clone() as u8 | self.compression.state_byte_bits());
+        // The buffer always fits two u32 varints
         encode_length_delimiter(self.key.len(), &mut buf).unwrap();
         encode_length_delimiter(self.value.len(), &mut buf).unwrap();
+        let len = HEADER_MAX_LEN - buf.len();
+        (header, len)
+    }
 
-        // Store key and value data
-        buf.extend_from_slice(&self.key);
-        buf.extend_from_slice(&self.value);
-
-        // Calculate crc
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(&buf);
-        let crc = hasher.finalize();
-        buf.put_u32(crc);
-        Ok((buf.into(), crc))
+    /// CRC over the header, key and value, hashed slice by slice so the
+    /// entry never has to be laid out contiguously.
+    pub fn crc_with_header(&self, header: &[u8]) -> u32 {
+        let mut hasher = header_hasher(header);
+        hasher.update(&self.key);
+        hasher.update(&self.value);
+        hasher.finalize()
     }
 
     pub fn decode_header(mut header_buf: BytesMut) -> Result<(usize, usize, usize, u8)> {
@@ -116,10 +179,13 @@ impl DataEntry {
 
         // Get actual header size
         // Read key_size and value_size
-        let key_size = decode_length_delimiter(&mut header_buf).unwrap();
-        let value_size = decode_length_delimiter(&mut header_buf).unwrap();
+        let key_size = decode_length_delimiter(&mut header_buf)
+            .map_err(|e| Error::Unsupported(format!("decode data entry key size err: {}", e)))?;
+        let value_size = decode_length_delimiter(&mut header_buf)
+            .map_err(|e| Error::Unsupported(format!("decode data entry value size err: {}", e)))?;
 
-        // If key_size and value_size are both 0, it means the end of the file
+        // If key_size and value_size are both 0, it means the end of the file;
+        // no record has an empty key, whether or not its value is empty
         if key_size == 0 && value_size == 0 {
             return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
         }
@@ -130,6 +196,15 @@ impl DataEntry {
         Ok((key_size, value_size, actual_header_size, state))
     }
 
+    /// `decode_header` for the encoded record at the start of `record`,
+    /// which may be shorter than `HEADER_MAX_LEN`.
+    pub fn decode_record_header(record: &[u8]) -> Result<(usize, usize, usize, u8)> {
+        if record.is_empty() {
+            return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+        }
+        Self::decode_header(BytesMut::from(&record[..record.len().min(HEADER_MAX_LEN)]))
+    }
+
     pub fn decode(
         mut body_buf: BytesMut,
         key_size: usize,
@@ -138,9 +213,13 @@ impl DataEntry {
     ) -> Result<Self> {
         let data_entry = DataEntry::new(
             body_buf.get(..key_size).unwrap().to_vec(),
-            body_buf.get(key_size..body_buf.len() - 4).unwrap().to_vec(),
+            body_buf
+                .get(key_size..body_buf.len() - CRC_LEN)
+                .unwrap()
+                .to_vec(),
             state.try_into()?,
-        );
+        )
+        .with_compression(Compression::from_state_byte(state)?);
 
         body_buf.advance(key_size + value_size);
         // Verify CRC
@@ -154,6 +233,29 @@ impl DataEntry {
         matches!(self.state, State::Active)
     }
 }
+thread_local! {
+    // The last header hashed on this thread and the hasher state after it
+    static HEADER_HASHER: RefCell<Option<(Vec<u8>, crc32fast::Hasher)>> =
+        const { RefCell::new(None) };
+}
+
+// A hasher that has consumed `header`. Runs of same-sized records share a
+// header, so its state is cached and cloned instead of recomputed.
+fn header_hasher(header: &[u8]) -> crc32fast::Hasher {
+    HEADER_HASHER.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        match cached.as_ref() {
+            Some((bytes, hasher)) if bytes.as_slice() == header => hasher.clone(),
+            _ => {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(header);
+                *cached = Some((header.to_vec(), hasher.clone()));
+                hasher
+            }
+        }
+    })
+}
+
 // used for merge
 pub fn decode_keydir_entry(keydir_entry: Vec<u8>) -> Result<KeyDirEntry> {
     let mut buf = BytesMut::new();
@@ -237,4 +339,42 @@ mod tests {
         assert_eq!(encoded_entry, entry);
         Ok(())
     }
+
+    #[test]
+    fn test_encoded_len() -> Result<()> {
+        let entries = vec![
+            DataEntry::new("key", "value", State::Active),
+            DataEntry::new("key", Vec::new(), State::Inactive),
+            DataEntry::new(vec![1u8; 200], vec![2u8; 20000], State::Active),
+            DataEntry::new(Vec::new(), vec![3u8; 128], State::Committed),
+        ];
+        for entry in entries {
+            assert_eq!(entry.encoded_len(), entry.encode()?.len());
+        }
+        assert_eq!(
+            HEADER_MAX_LEN,
+            1 + length_delimiter_len(u32::MAX as usize) * 2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc_matches_contiguous_hash() -> Result<()> {
+        // Repeated headers hit the cached hasher, alternating ones miss it
+        let entries = vec![
+            DataEntry::new("key1", "value1", State::Active),
+            DataEntry::new("key2", "value2", State::Active),
+            DataEntry::new("key3", Vec::new(), State::Inactive),
+            DataEntry::new("key4", "value4", State::Active),
+            DataEntry::new(vec![1u8; 200], vec![2u8; 20000], State::Active),
+        ];
+        for entry in entries {
+            let encoded = entry.encode()?;
+            let body = &encoded[..encoded.len() - CRC_LEN];
+            let crc = crc32fast::hash(body);
+            assert_eq!(entry.get_crc()?, crc);
+            assert_eq!(encoded[body.len()..], crc.to_be_bytes());
+        }
+        Ok(())
+    }
 }
@@ -1,11 +1,12 @@
 use bytes::{BufMut, BytesMut};
-use prost::length_delimiter_len;
 
 use crate::{
-    io::{IOHandler, StandardIO, IO},
-    Error, Result,
+    io::{IOHandler, MmapSlice, StandardIO, IO},
+    Compression, Error, Result,
 };
+use parking_lot::Mutex;
 use std::{
+    io::ErrorKind,
     path::Path,
     sync::{
         atomic::{AtomicU32, AtomicU64, Ordering},
@@ -13,18 +14,55 @@ use std::{
     },
 };
 
-use super::DataEntry;
+use super::{DataEntry, FileCrc, State, CRC_LEN, HEADER_MAX_LEN};
 
+/// A data file and its write offset. Clones share the offset, so only the
+/// active file's handle may write; retired handles are frozen.
 #[derive(Debug)]
 pub struct FileHandle {
     data: Arc<DataFile>,
     pub io: IO,
+    frozen: bool,
+}
+
+/// An entry decoded from an mmap-backed file whose value still lives in the
+/// mapping.
+#[derive(Debug)]
+pub struct MappedEntry {
+    key: Vec<u8>,
+    value: MmapSlice,
+    state: State,
+    size: usize,
+}
+
+/// A record as `FileHandle::iter_entries` found it, corrupt or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    pub offset: u64,
+    /// `None` if the state byte isn't one of `State`'s.
+    pub state: Option<State>,
+    /// The key as stored, with its transaction prefix.
+    pub key: Vec<u8>,
+    pub value_size: usize,
+    /// Whether the stored CRC matches the record.
+    pub crc_ok: bool,
+}
+
+/// Iterator returned by `FileHandle::iter_entries`.
+#[derive(Debug)]
+pub struct EntryIter {
+    file: FileHandle,
+    offset: u64,
+    len: u64,
+    done: bool,
 }
 
 #[derive(Debug)]
 struct DataFile {
     file_id: AtomicU32,
     offset: AtomicU64,
+    // CRC32 of the file up to `offset`, under `FileHandle::track_crc`
+    crc: Mutex<Option<crc32fast::Hasher>>,
 }
 
 #[allow(dead_code)]
@@ -33,37 +71,75 @@ impl FileHandle {
         Self {
             data: Arc::new(DataFile::new(file_id)),
             io,
+            frozen: false,
         }
     }
 
+    /// A read-only handle to the same file, for files that are no longer
+    /// appended to. `write` and `sync` on it fail.
+    pub fn freeze(&self) -> Self {
+        Self {
+            frozen: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    fn check_not_frozen(&self) -> Result<()> {
+        if self.frozen {
+            return Err(Error::Unsupported(format!(
+                "Data file {} is frozen",
+                self.get_file_id()
+            )));
+        }
+        Ok(())
+    }
+
     // Delegate IO operations to the internal IO implementation
     pub fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         match &self.io {
             IO::Standard(io) => io.read(buf, offset),
             IO::Mmap(io) => io.read(buf, offset),
+            IO::Memory(io) => io.read(buf, offset),
         }
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let current_offset = self.data.offset.load(Ordering::Relaxed);
-        let written = match &mut self.io {
-            IO::Standard(io) => io.write(buf)?,
-            IO::Mmap(_) => {
-                return Err(Error::Unsupported(
-                    "Mmap does not support write".to_string(),
-                ))
-            }
-        };
-        self.data
-            .offset
-            .store(current_offset + written as u64, Ordering::Release);
-        Ok(written)
+        self.check_not_frozen()?;
+        match &mut self.io {
+            IO::Standard(io) => append(io, &self.data, buf),
+            IO::Memory(io) => append(io, &self.data, buf),
+            IO::Mmap(_) => Err(Error::Unsupported(
+                "Mmap does not support write".to_string(),
+            )),
+        }
+    }
+
+    /// Appends a record encoded elsewhere as it is, CRC included, so it isn't
+    /// encoded again. Only its length is checked against its header; its CRC
+    /// is checked when it's read back like any other record.
+    pub fn write_raw_entry(&mut self, bytes: &[u8]) -> Result<usize> {
+        let (key_size, value_size, header_size, _) = DataEntry::decode_record_header(bytes)?;
+        let len = header_size + key_size + value_size + CRC_LEN;
+        if len != bytes.len() {
+            return Err(Error::Unsupported(format!(
+                "Raw entry of {} bytes holds a record of {}",
+                bytes.len(),
+                len
+            )));
+        }
+        self.write(bytes)
     }
 
     pub fn sync(&self) -> Result<()> {
+        self.check_not_frozen()?;
         match &self.io {
             IO::Standard(io) => io.sync(),
             IO::Mmap(_) => Err(Error::Unsupported("Mmap does not support sync".to_string())),
+            IO::Memory(io) => io.sync(),
         }
     }
 
@@ -83,27 +159,148 @@ impl FileHandle {
         Ok(())
     }
     pub fn extract_data_entry(&self, offset: u64) -> Result<(DataEntry, usize)> {
-        let mut header_buf = BytesMut::zeroed(
-            std::mem::size_of::<u8>() + length_delimiter_len(u32::MAX as usize) * 2,
-        );
+        let (data_entry, size) = self.extract_stored_entry(offset)?;
+        Ok((data_entry.decompress()?, size))
+    }
+
+    /// Same as `extract_data_entry`, but leaves the value as it is stored,
+    /// compressed or not, for rewriting the record elsewhere.
+    pub fn extract_stored_entry(&self, offset: u64) -> Result<(DataEntry, usize)> {
+        let mut header_buf = BytesMut::zeroed(HEADER_MAX_LEN);
         self.read(&mut header_buf, offset)?;
         let (key_size, value_size, actual_header_size, state) =
             DataEntry::decode_header(header_buf)?;
 
         // Read key and value，last 4 bytes crc
-        let mut body_buf = BytesMut::zeroed(key_size + value_size + 4);
+        let mut body_buf = BytesMut::zeroed(key_size + value_size + CRC_LEN);
         self.read(&mut body_buf, offset + actual_header_size as u64)?;
 
         // body_buf.advance(key_size + value_size);
         let data_entry = DataEntry::decode(body_buf, key_size, value_size, state)?;
 
-        Ok((data_entry, actual_header_size + key_size + value_size + 4))
+        Ok((
+            data_entry,
+            actual_header_size + key_size + value_size + CRC_LEN,
+        ))
+    }
+
+    /// Length of the record at `offset` according to its header alone, for
+    /// stepping over a record whose body doesn't check out.
+    pub fn record_len_at(&self, offset: u64) -> Result<usize> {
+        let mut header_buf = BytesMut::zeroed(HEADER_MAX_LEN);
+        self.read(&mut header_buf, offset)?;
+        let (key_size, value_size, actual_header_size, _) = DataEntry::decode_header(header_buf)?;
+        Ok(actual_header_size + key_size + value_size + CRC_LEN)
+    }
+
+    /// Starts reading the whole file into the page cache in the background.
+    pub fn prefetch(&self) -> Result<()> {
+        match &self.io {
+            IO::Standard(io) => io.will_need(),
+            IO::Mmap(io) => io.will_need(),
+            IO::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Current length of the file on disk (or of the mapping).
+    pub fn file_size(&self) -> Result<u64> {
+        match &self.io {
+            IO::Standard(io) => io.size(),
+            IO::Mmap(io) => io.size(),
+            IO::Memory(io) => io.size(),
+        }
+    }
+
+    /// Same as `extract_data_entry`, but borrows the value from the mapping
+    /// instead of copying it. Returns `None` if the file is not mmap-backed.
+    pub fn extract_mapped_entry(&self, offset: u64) -> Result<Option<MappedEntry>> {
+        let io = match &self.io {
+            IO::Mmap(io) => io,
+            IO::Standard(_) | IO::Memory(_) => return Ok(None),
+        };
+        let mut header_buf = BytesMut::zeroed(HEADER_MAX_LEN);
+        io.read(&mut header_buf, offset)?;
+        let (key_size, value_size, actual_header_size, state) =
+            DataEntry::decode_header(header_buf)?;
+        // A compressed value has to be copied out to be decompressed
+        if Compression::from_state_byte(state)? != Compression::None {
+            return Ok(None);
+        }
+        let size = actual_header_size + key_size + value_size + CRC_LEN;
+
+        // Verify CRC over the mapped record
+        let record = io.slice(offset, size)?;
+        let (body, crc) = record.split_at(size - CRC_LEN);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(body);
+        if hasher.finalize() != u32::from_be_bytes(crc.try_into().unwrap()) {
+            return Err(Error::Unsupported("CRC check failed".to_string()));
+        }
+
+        let key_offset = offset + actual_header_size as u64;
+        Ok(Some(MappedEntry {
+            key: body[actual_header_size..actual_header_size + key_size].to_vec(),
+            value: io.slice(key_offset + key_size as u64, value_size)?,
+            state: state.try_into()?,
+            size,
+        }))
+    }
+
+    /// Walks every record in the file, reporting its CRC instead of failing
+    /// on a mismatch, for inspecting a damaged file. Stops at the end of the
+    /// records; a header that doesn't decode, or a record running past the
+    /// end of the file, is returned as an error and ends the walk.
+    pub fn iter_entries(&self) -> Result<EntryIter> {
+        Ok(EntryIter {
+            file: self.clone(),
+            offset: 0,
+            len: self.file_size()?,
+            done: false,
+        })
+    }
+
+    /// CRC32 over the first `len` bytes of the file.
+    pub fn checksum(&self, len: u64) -> Result<u32> {
+        Ok(self.hash_prefix(len)?.finalize())
+    }
+
+    /// Keeps a CRC32 of the whole file from here on, updated as records are
+    /// appended, starting from the bytes up to the offset.
+    pub fn track_crc(&self) -> Result<()> {
+        let hasher = self.hash_prefix(self.get_offset())?;
+        *self.data.crc.lock() = Some(hasher);
+        Ok(())
+    }
+
+    /// The offset and the CRC32 of the file up to it under `track_crc`.
+    /// `None` if it isn't tracked, or an append left bytes it can't account
+    /// for.
+    pub fn running_crc(&self) -> Option<FileCrc> {
+        let crc = self.data.crc.lock();
+        crc.as_ref().map(|hasher| FileCrc {
+            len: self.get_offset(),
+            crc: hasher.clone().finalize(),
+        })
+    }
+
+    fn hash_prefix(&self, len: u64) -> Result<crc32fast::Hasher> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut offset = 0;
+        while offset < len {
+            let n = ((len - offset) as usize).min(buf.len());
+            let read = self.read(&mut buf[..n], offset)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            offset += read as u64;
+        }
+        Ok(hasher)
     }
 
     fn encode_data_entry(&self, data_entry: DataEntry) -> Result<BytesMut> {
-        let mut buf = BytesMut::with_capacity(
-            std::mem::size_of::<u8>() + length_delimiter_len(u32::MAX as usize) * 2,
-        );
+        let mut buf = BytesMut::with_capacity(HEADER_MAX_LEN);
 
         buf.put_u8(data_entry.get_state() as u8);
         buf.put_u32(data_entry.get_key().len() as u32);
@@ -116,19 +313,22 @@ impl FileHandle {
         Ok(buf)
     }
 
-    pub fn set_io(&mut self, dir_path: &Path) -> crate::Result<()> {
+    /// Switches a mmap-backed file to standard IO. A `read_only` file is
+    /// opened without write access.
+    pub fn set_io(&mut self, dir_path: &Path, read_only: bool) -> crate::Result<()> {
         match &self.io {
-            IO::Standard(_) => {
+            IO::Standard(_) | IO::Memory(_) => {
                 return Err(Error::Unsupported(
                     "Only support change mmap to standard io".to_string(),
                 ))
             }
             IO::Mmap(_) => {
-                self.io = StandardIO::new(&Path::new(&dir_path).join(format!(
-                    "{}{}",
-                    self.get_file_id(),
-                    ".db"
-                )))?
+                let path = Path::new(&dir_path).join(format!("{}{}", self.get_file_id(), ".db"));
+                self.io = if read_only {
+                    StandardIO::open_read_only(&path)?
+                } else {
+                    StandardIO::new(&path)?
+                }
                 .into();
             }
         }
@@ -136,13 +336,115 @@ impl FileHandle {
     }
 }
 
+// Appends `buf` at the file's offset. A failed or short write is cut back off
+// so the next append starts where this one did, rather than after a partial
+// record that replay can't step over.
+fn append<H: IOHandler>(io: &mut H, data: &DataFile, buf: &[u8]) -> Result<usize> {
+    let offset = data.get_offset();
+    let error = match io.write(buf) {
+        Ok(written) if written == buf.len() => {
+            if let Some(hasher) = data.crc.lock().as_mut() {
+                hasher.update(buf);
+            }
+            data.set_offset(offset + written as u64);
+            return Ok(written);
+        }
+        Ok(written) => Error::Io(std::io::Error::new(
+            ErrorKind::WriteZero,
+            format!("Short write of {} of {} bytes", written, buf.len()),
+        )),
+        Err(e) => e,
+    };
+    if io.truncate(offset).is_err() {
+        // The partial record stays, so later records have to follow it
+        if let Ok(size) = io.size() {
+            data.set_offset(size);
+        }
+        *data.crc.lock() = None;
+    }
+    match error {
+        Error::Io(e) if e.kind() == ErrorKind::StorageFull => Err(Error::DiskFull),
+        e => Err(e),
+    }
+}
+
 // Manual Clone implementation for FileHandle
 impl Clone for FileHandle {
     fn clone(&self) -> Self {
         Self {
             data: self.data.clone(),
             io: self.io.clone(),
+            frozen: self.frozen,
+        }
+    }
+}
+
+impl EntryIter {
+    fn read_record(&mut self) -> Result<Option<FileRecord>> {
+        let mut header_buf = BytesMut::zeroed(HEADER_MAX_LEN);
+        self.file.read(&mut header_buf, self.offset)?;
+        let (key_size, value_size, header_size, state) = match DataEntry::decode_header(header_buf)
+        {
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        };
+        let size = header_size + key_size + value_size + CRC_LEN;
+        if self.offset + size as u64 > self.len {
+            return Err(Error::Corrupted {
+                file_id: self.file.get_file_id(),
+                offset: self.offset,
+            });
+        }
+
+        let mut record = vec![0u8; size];
+        self.file.read(&mut record, self.offset)?;
+        let (body, crc) = record.split_at(size - CRC_LEN);
+        let record = FileRecord {
+            offset: self.offset,
+            state: state.try_into().ok(),
+            key: body[header_size..header_size + key_size].to_vec(),
+            value_size,
+            crc_ok: crc32fast::hash(body) == u32::from_be_bytes(crc.try_into().unwrap()),
+        };
+        self.offset += size as u64;
+        Ok(Some(record))
+    }
+}
+
+impl Iterator for EntryIter {
+    type Item = Result<FileRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.len {
+            return None;
         }
+        let record = self.read_record().transpose();
+        if !matches!(record, Some(Ok(_))) {
+            self.done = true;
+        }
+        record
+    }
+}
+
+impl MappedEntry {
+    pub fn get_key(&self) -> &Vec<u8> {
+        &self.key
+    }
+
+    pub fn get_value(&self) -> &MmapSlice {
+        &self.value
+    }
+
+    pub fn get_state(&self) -> State {
+        self.state.clone()
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, State::Active)
     }
 }
 
@@ -152,6 +454,7 @@ impl DataFile {
         Self {
             file_id: AtomicU32::new(id),
             offset: AtomicU64::new(0),
+            crc: Mutex::new(None),
         }
     }
 
@@ -172,8 +475,6 @@ impl DataFile {
 mod tests {
     use std::{path::Path, thread};
 
-    use parking_lot::Mutex;
-
     use super::*;
     use crate::*;
     use io::StandardIO;
@@ -212,6 +513,136 @@ mod tests {
         assert_eq!(read_buf, b"helloworld");
         Ok(())
     }
+
+    #[test]
+    fn test_write_through_frozen_handle() -> Result<()> {
+        let path = Path::new("/tmp/test_write_through_frozen_handle");
+        let _ = std::fs::remove_file(path);
+        let mut handle = FileHandle::new(1, StandardIO::new(path)?.into());
+        handle.write(b"hello")?;
+
+        let mut frozen = handle.freeze();
+        assert!(frozen.is_frozen() && !handle.is_frozen());
+        assert!(frozen.write(b"world").is_err());
+        assert!(frozen.sync().is_err());
+        assert_eq!(frozen.get_offset(), 5);
+        // Clones of a frozen handle stay frozen
+        assert!(frozen.clone().write(b"world").is_err());
+
+        let mut read_buf = vec![0; 5];
+        frozen.read(&mut read_buf, 0)?;
+        assert_eq!(read_buf, b"hello");
+        assert_eq!(std::fs::metadata(path)?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_raw_entry() -> Result<()> {
+        let path = Path::new("/tmp/test_write_raw_entry");
+        let _ = std::fs::remove_file(path);
+        let mut handle = FileHandle::new(1, StandardIO::new(path)?.into());
+        let entry = DataEntry::new(b"key".to_vec(), b"value".to_vec(), State::Active);
+        let tombstone = DataEntry::new(b"gone".to_vec(), Vec::new(), State::Inactive);
+        let encoded = entry.encode()?;
+        assert_eq!(handle.write_raw_entry(&encoded)?, encoded.len());
+        handle.write_raw_entry(&tombstone.encode()?)?;
+
+        let (read, size) = handle.extract_data_entry(0)?;
+        assert_eq!(size, encoded.len());
+        assert_eq!(read.get_key(), entry.get_key());
+        assert_eq!(read.get_value(), entry.get_value());
+        assert_eq!(read.get_state(), State::Active);
+        let (read, _) = handle.extract_data_entry(size as u64)?;
+        assert_eq!(read.get_key(), tombstone.get_key());
+        assert_eq!(read.get_state(), State::Inactive);
+
+        // Only whole records go in
+        let offset = handle.get_offset();
+        assert!(handle
+            .write_raw_entry(&encoded[..encoded.len() - 1])
+            .is_err());
+        assert!(handle
+            .write_raw_entry(&[encoded.clone(), encoded].concat())
+            .is_err());
+        assert!(handle.write_raw_entry(&[]).is_err());
+        assert_eq!(handle.get_offset(), offset);
+
+        // A bad CRC is caught on read
+        let mut corrupt = tombstone.encode()?;
+        *corrupt.last_mut().unwrap() ^= 1;
+        handle.write_raw_entry(&corrupt)?;
+        assert!(handle.extract_data_entry(offset).is_err());
+        Ok(())
+    }
+
+    // Writes half of each buffer and then fails as a full disk does, while
+    // `full` is set
+    struct FullDiskIO {
+        inner: StandardIO,
+        full: bool,
+    }
+
+    impl IOHandler for FullDiskIO {
+        fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.inner.read(buf, offset)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if self.full {
+                self.inner.write(&buf[..buf.len() / 2])?;
+                return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn sync(&self) -> Result<()> {
+            self.inner.sync()
+        }
+
+        fn size(&self) -> Result<u64> {
+            self.inner.size()
+        }
+
+        fn truncate(&self, len: u64) -> Result<()> {
+            self.inner.truncate(len)
+        }
+    }
+
+    #[test]
+    fn test_append_rolls_back_on_full_disk() -> Result<()> {
+        let path = Path::new("/tmp/test_append_rolls_back_on_full_disk");
+        let _ = std::fs::remove_file(path);
+        let mut io = FullDiskIO {
+            inner: StandardIO::new(path)?,
+            full: false,
+        };
+        let data = DataFile::new(0);
+        let first = DataEntry::new("key1", "value1", State::Active).encode()?;
+        let second = DataEntry::new("key2", "value2", State::Active).encode()?;
+        append(&mut io, &data, &first)?;
+
+        io.full = true;
+        assert!(matches!(
+            append(&mut io, &data, &second),
+            Err(Error::DiskFull)
+        ));
+        assert_eq!(data.get_offset(), first.len() as u64);
+        assert_eq!(io.size()?, first.len() as u64);
+
+        // Once space is freed the retry lands where the failed write began
+        io.full = false;
+        append(&mut io, &data, &second)?;
+        assert_eq!(data.get_offset(), (first.len() + second.len()) as u64);
+
+        let handle = FileHandle::new(0, StandardIO::new(path)?.into());
+        let (entry, size) = handle.extract_data_entry(0)?;
+        assert_eq!(entry.get_key(), b"key1");
+        let (entry, _) = handle.extract_data_entry(size as u64)?;
+        assert_eq!(entry.get_key(), b"key2");
+        assert!(handle.extract_data_entry(data.get_offset()).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_concurrent_filehandle_updates() -> Result<()> {
         let io: IO = match StandardIO::new(Path::new("/tmp/test_concurrent")) {
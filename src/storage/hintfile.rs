@@ -1,11 +1,24 @@
-use crate::{io::StandardIO, KeyDirEntry, Result};
+use crate::sequencer::IndexUpdate;
+use crate::syncer::Position;
+use crate::{io::StandardIO, Error, KeyDirEntry, Result};
+use parking_lot::Mutex;
 use std::{
+    fs::{self, OpenOptions},
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 
-use super::{DataEntry, FileHandle, State};
+use super::{decode_keydir_entry, DataEntry, FileHandle, State};
 pub const HINT_FILE_NAME: &str = "hint";
+const HINT_COVERAGE_KEY: &str = "__HINT_COVERAGE__";
+/// The hint kept up to date with every write under `Opts::incremental_hint`.
+pub const HINT_LOG_FILE_NAME: &str = "hint-log";
+const HINT_LOG_TMP_FILE_NAME: &str = "hint-log.tmp";
+const HINT_LOG_POSITIONS_KEY: &str = "__HINT_POSITIONS__";
 pub struct HintFile(FileHandle);
 
 impl HintFile {
@@ -18,12 +31,220 @@ impl HintFile {
         ))
     }
 
+    /// Opens an existing hint file for reading only.
+    pub fn open(dir_path: &Path) -> Result<HintFile> {
+        Ok(HintFile(FileHandle::new(
+            0,
+            StandardIO::open_read_only(&dir_path.join(HINT_FILE_NAME))?.into(),
+        )))
+    }
+
     pub fn write_entry(&mut self, key: Vec<u8>, keydir_entry: &KeyDirEntry) -> Result<()> {
         let entry = DataEntry::new(key, keydir_entry.encode(), State::Active);
         let encoded_entry = entry.encode()?;
         self.write(&encoded_entry)?;
         Ok(())
     }
+
+    /// Records the data files the hint was written for as `(file_id, len)`,
+    /// so a hint that no longer matches them can be told apart. Told from
+    /// the index entries by its `Committed` state.
+    pub fn write_coverage(&mut self, files: &[(u32, u64)]) -> Result<()> {
+        let value = files
+            .iter()
+            .map(|(file_id, len)| format!("{}:{}", file_id, len))
+            .collect::<Vec<_>>()
+            .join(",");
+        let entry = DataEntry::new(HINT_COVERAGE_KEY, value, State::Committed);
+        self.write(&entry.encode()?)?;
+        Ok(())
+    }
+}
+
+/// The index updates of every write in the order they were applied, for
+/// `Opts::incremental_hint`.
+///
+/// Each write's updates, a tombstone for a delete, are followed by a marker
+/// holding the sequence number and the end of every record the write
+/// appended, as `(file_id, end)`.
+/// The log isn't synced: a crash can lose its tail, or leave it torn, so it
+/// only counts up to its last intact marker and the data after the positions
+/// recorded there is replayed.
+#[derive(Debug)]
+pub struct HintLog {
+    // `None` once an append failed and the log was given up on
+    file: Mutex<Option<FileHandle>>,
+    dir_path: PathBuf,
+    // The database's next batch sequence number
+    sequence_number: Arc<AtomicU32>,
+}
+
+/// What `HintLog::load` read.
+#[derive(Debug, Default)]
+pub struct LoadedHintLog {
+    pub updates: Vec<IndexUpdate>,
+    /// The positions of every marker, in log order.
+    pub positions: Vec<Position>,
+    /// The highest sequence number a marker recorded.
+    pub sequence_number: u32,
+    /// Bytes up to the last intact marker.
+    pub len: u64,
+}
+
+impl HintLog {
+    /// Reads the log of `dir_path`, `None` if there is none.
+    pub fn load(dir_path: &Path) -> Result<Option<LoadedHintLog>> {
+        let path = dir_path.join(HINT_LOG_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let file = FileHandle::new(0, StandardIO::open_read_only(&path)?.into());
+        let mut loaded = LoadedHintLog::default();
+        let mut pending = Vec::new();
+        let mut offset = 0;
+        while let Ok((entry, size)) = file.extract_data_entry(offset) {
+            offset += size as u64;
+            match entry.get_state() {
+                State::Committed => {
+                    let Some((sequence_number, positions)) = decode_marker(entry.get_value())
+                    else {
+                        break;
+                    };
+                    loaded.sequence_number = loaded.sequence_number.max(sequence_number);
+                    loaded.updates.append(&mut pending);
+                    loaded.positions.extend(positions);
+                    loaded.len = offset;
+                }
+                State::Active => {
+                    let Ok(keydir_entry) = decode_keydir_entry(entry.get_value().clone()) else {
+                        break;
+                    };
+                    pending.push((entry.get_key().clone(), Some(keydir_entry)));
+                }
+                _ => pending.push((entry.get_key().clone(), None)),
+            }
+        }
+        Ok(Some(loaded))
+    }
+
+    /// Opens the log of `dir_path` for appending after its first `len`
+    /// bytes, the ones `load` counted.
+    pub fn open(dir_path: &Path, len: u64, sequence_number: Arc<AtomicU32>) -> Result<HintLog> {
+        let path = dir_path.join(HINT_LOG_FILE_NAME);
+        OpenOptions::new().write(true).open(&path)?.set_len(len)?;
+        Ok(HintLog {
+            file: Mutex::new(Some(FileHandle::new(0, StandardIO::new(&path)?.into()))),
+            dir_path: dir_path.to_path_buf(),
+            sequence_number,
+        })
+    }
+
+    /// Replaces the log of `dir_path` with one holding `entries`, covering
+    /// the data up to `positions`, and opens it for appending.
+    pub fn create(
+        dir_path: &Path,
+        entries: impl IntoIterator<Item = (Vec<u8>, KeyDirEntry)>,
+        positions: &[Position],
+        sequence_number: Arc<AtomicU32>,
+    ) -> Result<HintLog> {
+        let tmp_path = dir_path.join(HINT_LOG_TMP_FILE_NAME);
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let mut file = FileHandle::new(0, StandardIO::new(&tmp_path)?.into());
+        for (key, keydir_entry) in entries {
+            file.write(&DataEntry::new(key, keydir_entry.encode(), State::Active).encode()?)?;
+        }
+        let marker = positions_marker(sequence_number.load(Ordering::SeqCst), positions);
+        file.write(&marker.encode()?)?;
+        file.sync()?;
+        fs::rename(&tmp_path, dir_path.join(HINT_LOG_FILE_NAME))?;
+        let len = fs::metadata(dir_path.join(HINT_LOG_FILE_NAME))?.len();
+        Self::open(dir_path, len, sequence_number)
+    }
+
+    /// Appends the updates of a write that appended up to `positions`. A
+    /// log that fails to append is removed, so the next open replays the
+    /// data files instead of trusting it.
+    pub fn append(&self, updates: &[IndexUpdate], positions: &[Position]) {
+        let mut file = self.file.lock();
+        let Some(handle) = file.as_mut() else {
+            return;
+        };
+        let written = updates
+            .iter()
+            .map(|(key, position)| match position {
+                Some(keydir_entry) => {
+                    DataEntry::new(key.clone(), keydir_entry.encode(), State::Active)
+                }
+                None => DataEntry::new(key.clone(), Vec::new(), State::Inactive),
+            })
+            .chain(std::iter::once(positions_marker(
+                self.sequence_number.load(Ordering::SeqCst),
+                positions,
+            )))
+            .try_for_each(|entry| handle.write(&entry.encode()?).map(|_| ()));
+        if written.is_err() {
+            drop(file);
+            self.abandon();
+        }
+    }
+
+    /// Stops appending and removes the log, for when the index moved in a
+    /// way the log can't record.
+    pub fn abandon(&self) {
+        let mut file = self.file.lock();
+        if file.take().is_some() {
+            let _ = Self::remove(&self.dir_path);
+        }
+    }
+
+    /// Removes the log of `dir_path`, if any.
+    pub fn remove(dir_path: &Path) -> Result<()> {
+        let path = dir_path.join(HINT_LOG_FILE_NAME);
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn positions_marker(sequence_number: u32, positions: &[Position]) -> DataEntry {
+    let positions = positions
+        .iter()
+        .map(|(file_id, end)| format!("{}:{}", file_id, end))
+        .collect::<Vec<_>>()
+        .join(",");
+    DataEntry::new(
+        HINT_LOG_POSITIONS_KEY,
+        format!("{};{}", sequence_number, positions),
+        State::Committed,
+    )
+}
+
+fn decode_marker(value: &[u8]) -> Option<(u32, Vec<Position>)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (sequence_number, positions) = value.split_once(';')?;
+    Some((
+        sequence_number.parse().ok()?,
+        decode_coverage(positions.as_bytes()).ok()?,
+    ))
+}
+
+/// Reverses `HintFile::write_coverage`.
+pub fn decode_coverage(value: &[u8]) -> Result<Vec<(u32, u64)>> {
+    let invalid = || Error::Unsupported("Invalid hint file coverage".to_string());
+    String::from_utf8_lossy(value)
+        .split(',')
+        .filter(|file| !file.is_empty())
+        .map(|file| {
+            let (file_id, len) = file.split_once(':').ok_or_else(invalid)?;
+            Ok((
+                file_id.parse().map_err(|_| invalid())?,
+                len.parse().map_err(|_| invalid())?,
+            ))
+        })
+        .collect()
 }
 
 impl Deref for HintFile {
@@ -0,0 +1,79 @@
+use crate::{io::StandardIO, Error, Result};
+use bytes::Bytes;
+use std::{
+    fs,
+    io::ErrorKind,
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+use super::{DataEntry, FileHandle, State};
+pub const KEY_FILE_NAME: &str = "keys";
+const KEY_FILE_TMP_NAME: &str = "keys.tmp";
+
+/// Sorted, key-only companion of the data files, so key scans don't need the
+/// data files or a snapshot of the index.
+pub struct KeyFile(FileHandle);
+
+impl KeyFile {
+    pub fn open(dir_path: &Path) -> Result<KeyFile> {
+        Ok(KeyFile(FileHandle::new(
+            0,
+            StandardIO::new(&dir_path.join(KEY_FILE_NAME))?.into(),
+        )))
+    }
+
+    /// Replaces the key file in `dir_path` with `keys`, which must be sorted.
+    pub fn write_all(dir_path: &Path, keys: &[Bytes]) -> Result<()> {
+        let tmp_path = dir_path.join(KEY_FILE_TMP_NAME);
+        if tmp_path.is_file() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let mut key_file = KeyFile(FileHandle::new(0, StandardIO::new(&tmp_path)?.into()));
+        for key in keys {
+            let entry = DataEntry::new(key.to_vec(), Vec::new(), State::Active);
+            key_file.write(&entry.encode()?)?;
+        }
+        key_file.sync()?;
+        fs::rename(tmp_path, dir_path.join(KEY_FILE_NAME))?;
+        Ok(())
+    }
+
+    /// Returns the keys starting with `prefix`, in order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Bytes>> {
+        let mut keys = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (entry, size) = match self.extract_data_entry(offset) {
+                Ok((entry, size)) => (entry, size),
+                Err(Error::Io(ref io_error)) if io_error.kind() == ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(e) => return Err(e),
+            };
+            let key = entry.get_key();
+            if key.starts_with(prefix) {
+                keys.push(Bytes::copy_from_slice(key));
+            } else if key.as_slice() > prefix {
+                // Keys are sorted, nothing after this can match
+                break;
+            }
+            offset += size as u64;
+        }
+        Ok(keys)
+    }
+}
+
+impl Deref for KeyFile {
+    type Target = FileHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for KeyFile {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
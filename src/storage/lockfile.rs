@@ -0,0 +1,290 @@
+use crate::{Error, LockPolicy, Result};
+use fs2::FileExt;
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const LOCK_FILE_NAME: &str = "file.lock";
+
+/// Exclusive hold on a database directory.
+///
+/// The file records the holder's pid, host and start time so a lock left by a
+/// dead process can be recognised where flocks outlive their owner (NFS). It
+/// is unlocked and removed exactly once, by `release` or on drop.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// The holder recorded in a lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Holder {
+    pid: u32,
+    host: String,
+    since: u64,
+}
+
+impl LockFile {
+    /// Locks `dir_path`, taking over a lock held by another process if
+    /// `policy` allows, see `LockPolicy`.
+    ///
+    /// Only the OS lock counts: a lock file nobody holds a lock on, as a
+    /// crashed process leaves behind, is taken whatever it records.
+    pub fn acquire(dir_path: &Path, policy: LockPolicy) -> Result<LockFile> {
+        let path = dir_path.join(LOCK_FILE_NAME);
+        let mut broke_lock = false;
+        loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if file.try_lock_exclusive().is_err() {
+                let holder = read_holder(&path);
+                let breakable = match (policy, &holder) {
+                    (LockPolicy::Respect, _) => false,
+                    (LockPolicy::BreakStale, Some(holder)) => holder.is_stale(),
+                    (LockPolicy::BreakStale, None) => false,
+                    (LockPolicy::Force, Some(holder)) => !holder.is_live(),
+                    (LockPolicy::Force, None) => true,
+                };
+                if breakable && !broke_lock {
+                    // A new file gets a new inode, which the stale flock
+                    // doesn't cover
+                    fs::remove_file(&path)?;
+                    broke_lock = true;
+                    continue;
+                }
+                return Err(Error::Unsupported(match holder {
+                    Some(holder) => format!(
+                        "Database is already in use by pid {} on {} since {}",
+                        holder.pid, holder.host, holder.since
+                    ),
+                    None => "Database is already in use".to_string(),
+                }));
+            }
+            // The previous holder may have removed the file between our open
+            // and lock, leaving us locking an unlinked inode
+            if !same_file(&file, &path)? {
+                continue;
+            }
+
+            let holder = Holder::current();
+            file.set_len(0)?;
+            (&file).write_all(holder.encode().as_bytes())?;
+            return Ok(LockFile {
+                path,
+                file: Some(file),
+            });
+        }
+    }
+
+    /// A lock file that holds no lock, for opening without one.
+    pub fn unlocked(dir_path: &Path) -> LockFile {
+        LockFile {
+            path: dir_path.join(LOCK_FILE_NAME),
+            file: None,
+        }
+    }
+
+    /// Removes the lock file and unlocks it. Later calls do nothing.
+    pub fn release(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            // Remove before unlocking so nobody can lock the file on its way out
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            file.unlock()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+impl Holder {
+    fn current() -> Holder {
+        Holder {
+            pid: std::process::id(),
+            host: hostname(),
+            since: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!("{}\n{}\n{}\n", self.pid, self.host, self.since)
+    }
+
+    fn decode(s: &str) -> Option<Holder> {
+        let mut lines = s.lines();
+        Some(Holder {
+            pid: lines.next()?.parse().ok()?,
+            host: lines.next()?.to_string(),
+            since: lines.next()?.parse().ok()?,
+        })
+    }
+
+    // Only a holder on this host can be checked
+    fn is_stale(&self) -> bool {
+        self.host == hostname() && !process_exists(self.pid)
+    }
+
+    fn is_live(&self) -> bool {
+        self.host == hostname() && process_exists(self.pid)
+    }
+}
+
+/// Lock file contents naming a process on this host that doesn't exist.
+#[cfg(test)]
+pub fn stale_lock_contents() -> String {
+    Holder {
+        pid: i32::MAX as u32,
+        ..Holder::current()
+    }
+    .encode()
+}
+
+fn read_holder(path: &Path) -> Option<Holder> {
+    Holder::decode(&fs::read_to_string(path).ok()?)
+}
+
+fn same_file(file: &File, path: &Path) -> Result<bool> {
+    let locked = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(locked.dev() == current.dev() && locked.ino() == current.ino()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer outlives the call and its length is passed along
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn process_exists(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process can be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // The process exists but belongs to someone else
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from(format!("/tmp/{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lock_file_released_once() -> Result<()> {
+        let dir = lock_dir("test_lock_file_released_once");
+        let mut lock = LockFile::acquire(&dir, LockPolicy::Respect)?;
+        assert_eq!(
+            read_holder(&dir.join(LOCK_FILE_NAME)).map(|h| h.pid),
+            Some(std::process::id())
+        );
+        assert!(LockFile::acquire(&dir, LockPolicy::BreakStale).is_err());
+
+        lock.release()?;
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+        // A second release doesn't touch a lock taken since
+        let other = LockFile::acquire(&dir, LockPolicy::Respect)?;
+        lock.release()?;
+        drop(lock);
+        assert!(dir.join(LOCK_FILE_NAME).exists());
+        assert!(LockFile::acquire(&dir, LockPolicy::Respect).is_err());
+        drop(other);
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_break_stale_lock() -> Result<()> {
+        let dir = lock_dir("test_break_stale_lock");
+        // Keep the flock held, as NFS does after its holder died, and record
+        // a holder that no longer exists
+        let file = File::create(dir.join(LOCK_FILE_NAME))?;
+        file.lock_exclusive()?;
+        fs::write(dir.join(LOCK_FILE_NAME), stale_lock_contents())?;
+        let dead = read_holder(&dir.join(LOCK_FILE_NAME)).unwrap();
+
+        let err = LockFile::acquire(&dir, LockPolicy::Respect).unwrap_err();
+        assert!(err.to_string().contains(&format!("pid {}", dead.pid)));
+
+        let lock = LockFile::acquire(&dir, LockPolicy::BreakStale)?;
+        let holder = read_holder(&dir.join(LOCK_FILE_NAME)).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert_eq!(holder.host, hostname());
+        drop(lock);
+
+        // A live holder is never broken
+        let file = File::create(dir.join(LOCK_FILE_NAME))?;
+        file.lock_exclusive()?;
+        fs::write(dir.join(LOCK_FILE_NAME), Holder::current().encode())?;
+        assert!(LockFile::acquire(&dir, LockPolicy::BreakStale).is_err());
+        // Nor one on another host
+        let mut remote = dead;
+        remote.host = format!("{}-elsewhere", hostname());
+        fs::write(dir.join(LOCK_FILE_NAME), remote.encode())?;
+        assert!(LockFile::acquire(&dir, LockPolicy::BreakStale).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_unlock() -> Result<()> {
+        let dir = lock_dir("test_force_unlock");
+        let path = dir.join(LOCK_FILE_NAME);
+        // Stands in for the holders' OS locks
+        let mut held = Vec::new();
+        let file = File::create(&path)?;
+        file.lock_exclusive()?;
+        held.push(file);
+
+        // Holders that can't be checked are only broken by force
+        let mut remote = Holder::current();
+        remote.host = format!("{}-elsewhere", hostname());
+        for contents in [remote.encode(), "garbage".to_string()] {
+            fs::write(&path, contents)?;
+            assert!(LockFile::acquire(&dir, LockPolicy::BreakStale).is_err());
+            let lock = LockFile::acquire(&dir, LockPolicy::Force)?;
+            drop(lock);
+            let file = File::create(&path)?;
+            file.lock_exclusive()?;
+            held.push(file);
+        }
+
+        // A live holder on this host never is
+        fs::write(&path, Holder::current().encode())?;
+        assert!(LockFile::acquire(&dir, LockPolicy::Force).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{io::StandardIO, Error, KeyDirEntry, Result};
+use bytes::BytesMut;
+use prost::encoding::{decode_varint, encode_varint};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use super::{decode_keydir_entry, DataEntry, FileHandle, State};
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_TMP_FILE_NAME: &str = "MANIFEST.tmp";
+pub const SIDECAR_SUFFIX: &str = ".keydir";
+
+/// What replaying an immutable data file produced, recorded so the next open
+/// can skip the replay while the file is unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSummary {
+    pub size: u64,
+    pub entry_count: u64,
+    pub checksum: u32,
+    pub max_seq_no: u32,
+}
+
+/// Summaries of the immutable data files, keyed by file id.
+#[derive(Debug, Default)]
+pub struct Manifest(BTreeMap<u32, FileSummary>);
+
+impl Manifest {
+    /// Loads the manifest of `dir_path`; a missing or unreadable manifest is
+    /// treated as empty, which only costs a replay.
+    pub fn load(dir_path: &Path) -> Manifest {
+        let path = dir_path.join(MANIFEST_FILE_NAME);
+        if !path.is_file() {
+            return Manifest::default();
+        }
+        let file = match StandardIO::new(&path) {
+            Ok(io) => FileHandle::new(0, io.into()),
+            Err(_) => return Manifest::default(),
+        };
+        let mut manifest = Manifest::default();
+        let mut offset = 0;
+        while let Ok((entry, size)) = file.extract_data_entry(offset) {
+            match decode_summary(entry.get_key().as_slice(), entry.get_value().as_slice()) {
+                Ok((file_id, summary)) => manifest.0.insert(file_id, summary),
+                Err(_) => return Manifest::default(),
+            };
+            offset += size as u64;
+        }
+        manifest
+    }
+
+    /// Rewrites the manifest of `dir_path` and drops sidecars of files it no
+    /// longer lists.
+    pub fn save(&self, dir_path: &Path) -> Result<()> {
+        let tmp_path = dir_path.join(MANIFEST_TMP_FILE_NAME);
+        if tmp_path.is_file() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let mut file = FileHandle::new(0, StandardIO::new(&tmp_path)?.into());
+        for (file_id, summary) in self.0.iter() {
+            let (key, value) = encode_summary(*file_id, summary);
+            file.write(&DataEntry::new(key, value, State::Active).encode()?)?;
+        }
+        file.sync()?;
+        fs::rename(tmp_path, dir_path.join(MANIFEST_FILE_NAME))?;
+
+        for dentry in fs::read_dir(dir_path)? {
+            let file_name = dentry?.file_name().to_string_lossy().to_string();
+            if let Some(file_id) = file_name.strip_suffix(SIDECAR_SUFFIX) {
+                match file_id.parse::<u32>() {
+                    Ok(file_id) if self.0.contains_key(&file_id) => {}
+                    _ => fs::remove_file(dir_path.join(&file_name))?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, file_id: u32) -> Option<&FileSummary> {
+        self.0.get(&file_id)
+    }
+
+    pub fn insert(&mut self, file_id: u32, summary: FileSummary) {
+        self.0.insert(file_id, summary);
+    }
+
+    pub fn remove(&mut self, file_id: u32) -> bool {
+        self.0.remove(&file_id).is_some()
+    }
+
+    pub fn retain(&mut self, file_ids: &[u32]) {
+        self.0.retain(|file_id, _| file_ids.contains(file_id));
+    }
+}
+
+/// Removes the manifest and every sidecar of `dir_path`.
+pub fn remove_manifest(dir_path: &Path) -> Result<()> {
+    if dir_path.join(MANIFEST_FILE_NAME).is_file() {
+        Manifest::default().save(dir_path)?;
+        fs::remove_file(dir_path.join(MANIFEST_FILE_NAME))?;
+    }
+    Ok(())
+}
+
+fn sidecar_path(dir_path: &Path, file_id: u32) -> PathBuf {
+    dir_path.join(format!("{}{}", file_id, SIDECAR_SUFFIX))
+}
+
+/// Writes the index contribution of a data file: the final position of every
+/// key it wrote, or `None` for keys it deleted.
+pub fn write_sidecar(
+    dir_path: &Path,
+    file_id: u32,
+    entries: &[(&Vec<u8>, &Option<KeyDirEntry>)],
+) -> Result<()> {
+    let path = sidecar_path(dir_path, file_id);
+    if path.is_file() {
+        fs::remove_file(&path)?;
+    }
+    let mut file = FileHandle::new(0, StandardIO::new(&path)?.into());
+    for (key, position) in entries {
+        let entry = match position {
+            Some(keydir_entry) => {
+                let mut value = keydir_entry.encode();
+                // An empty inline copy leaves nothing after the position to
+                // tell it from none, so it gets its own state
+                let state = match keydir_entry.get_inline_value() {
+                    Some([]) => State::Committed,
+                    Some(inline_value) => {
+                        value.extend_from_slice(inline_value);
+                        State::Active
+                    }
+                    None => State::Active,
+                };
+                DataEntry::new(key.as_slice(), value, state)
+            }
+            None => DataEntry::new(key.as_slice(), Vec::new(), State::Inactive),
+        };
+        file.write(&entry.encode()?)?;
+    }
+    file.sync()
+}
+
+/// Reads back what `write_sidecar` wrote.
+pub fn read_sidecar(dir_path: &Path, file_id: u32) -> Result<Vec<(Vec<u8>, Option<KeyDirEntry>)>> {
+    let path = sidecar_path(dir_path, file_id);
+    if !path.is_file() {
+        return Err(Error::Io(ErrorKind::NotFound.into()));
+    }
+    let file = FileHandle::new(0, StandardIO::new(&path)?.into());
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let (entry, size) = match file.extract_data_entry(offset) {
+            Ok((entry, size)) => (entry, size),
+            Err(Error::Io(ref io_error)) if io_error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        if entry.get_state() == State::Inactive {
+            entries.push((entry.get_key().clone(), None));
+        } else {
+            let keydir_entry = decode_keydir_entry(entry.get_value().clone())?;
+            let inline_value = &entry.get_value()[keydir_entry.encode().len()..];
+            let mut keydir_entry = keydir_entry;
+            if !inline_value.is_empty() || entry.get_state() == State::Committed {
+                keydir_entry.set_inline_value(inline_value);
+            }
+            entries.push((entry.get_key().clone(), Some(keydir_entry)));
+        }
+        offset += size as u64;
+    }
+    Ok(entries)
+}
+
+fn encode_summary(file_id: u32, summary: &FileSummary) -> (Vec<u8>, Vec<u8>) {
+    let mut key = BytesMut::new();
+    encode_varint(file_id as u64, &mut key);
+    let mut value = BytesMut::new();
+    encode_varint(summary.size, &mut value);
+    encode_varint(summary.entry_count, &mut value);
+    encode_varint(summary.checksum as u64, &mut value);
+    encode_varint(summary.max_seq_no as u64, &mut value);
+    (key.to_vec(), value.to_vec())
+}
+
+fn decode_summary(mut key: &[u8], mut value: &[u8]) -> Result<(u32, FileSummary)> {
+    let decode_err = |e| Error::Unsupported(format!("decode manifest err: {}", e));
+    let file_id = decode_varint(&mut key).map_err(decode_err)? as u32;
+    let summary = FileSummary {
+        size: decode_varint(&mut value).map_err(decode_err)?,
+        entry_count: decode_varint(&mut value).map_err(decode_err)?,
+        checksum: decode_varint(&mut value).map_err(decode_err)? as u32,
+        max_seq_no: decode_varint(&mut value).map_err(decode_err)? as u32,
+    };
+    Ok((file_id, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() -> Result<()> {
+        let dir_path = Path::new("/tmp/test_manifest_round_trip");
+        let _ = fs::remove_dir_all(dir_path);
+        fs::create_dir_all(dir_path)?;
+
+        let summary = FileSummary {
+            size: 4096,
+            entry_count: 12,
+            checksum: 0xdead_beef,
+            max_seq_no: 7,
+        };
+        let mut manifest = Manifest::default();
+        manifest.insert(3, summary);
+        manifest.save(dir_path)?;
+        assert_eq!(Manifest::load(dir_path).get(3), Some(&summary));
+
+        let mut position = KeyDirEntry::new(3, 128, 20);
+        position.set_inline_value(b"abc");
+        let mut empty = KeyDirEntry::new(3, 148, 10);
+        empty.set_inline_value(b"");
+        let on_disk = KeyDirEntry::new(3, 158, 30);
+        let key = b"key".to_vec();
+        let empty_key = b"empty".to_vec();
+        let on_disk_key = b"on_disk".to_vec();
+        let deleted = b"deleted".to_vec();
+        write_sidecar(
+            dir_path,
+            3,
+            &[
+                (&key, &Some(position.clone())),
+                (&empty_key, &Some(empty.clone())),
+                (&on_disk_key, &Some(on_disk.clone())),
+                (&deleted, &None),
+            ],
+        )?;
+        let entries = read_sidecar(dir_path, 3)?;
+        assert_eq!(
+            entries,
+            vec![
+                (key, Some(position)),
+                (empty_key, Some(empty)),
+                (on_disk_key, Some(on_disk)),
+                (deleted, None)
+            ]
+        );
+
+        // Sidecars of files the manifest no longer lists are removed
+        manifest.retain(&[]);
+        manifest.save(dir_path)?;
+        assert!(read_sidecar(dir_path, 3).is_err());
+        Ok(())
+    }
+}
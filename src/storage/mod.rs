@@ -1,9 +1,21 @@
+mod crcfile;
 mod entry;
 mod file_handle;
 mod hintfile;
+mod keyfile;
+mod lockfile;
+mod manifest;
+pub use crcfile::FileCrc;
 pub use entry::decode_keydir_entry;
 pub use entry::DataEntry;
 pub use entry::State;
-pub use file_handle::FileHandle;
-pub use hintfile::HintFile;
+pub use entry::{CRC_LEN, HEADER_MAX_LEN};
+pub use file_handle::{EntryIter, FileHandle, FileRecord};
 pub use hintfile::HINT_FILE_NAME;
+pub use hintfile::{decode_coverage, HintFile};
+pub use hintfile::{HintLog, LoadedHintLog};
+pub use keyfile::{KeyFile, KEY_FILE_NAME};
+#[cfg(test)]
+pub use lockfile::stale_lock_contents;
+pub use lockfile::{LockFile, LOCK_FILE_NAME};
+pub use manifest::{read_sidecar, remove_manifest, write_sidecar, FileSummary, Manifest};
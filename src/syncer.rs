@@ -0,0 +1,340 @@
+use crate::runtime::{SharedRuntime, Task};
+use crate::storage::FileHandle;
+use crate::{Error, Result};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A point in the log as `(file_id, offset)`. A file is synced before the next
+/// one is created, so positions order by file first.
+pub(crate) type Position = (u32, u64);
+
+#[derive(Debug, Default)]
+struct SyncState {
+    // Highest position a writer is waiting on
+    requested: Position,
+    // Everything before this position has been fsynced
+    durable: Position,
+    // Sticky: once an fsync fails, nothing after it can be trusted
+    failed: Option<String>,
+    sync_count: u64,
+    shutdown: bool,
+    // On a runtime: a sync pass is queued or running
+    scheduled: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<SyncState>,
+    // Wakes the sync thread
+    work: Condvar,
+    // Wakes writers after an fsync
+    done: Condvar,
+}
+
+/// Funnels every fsync of the active file through one thread.
+///
+/// Writers ask for durability up to a position and block; the thread performs a
+/// single fsync covering everything written so far and releases every writer
+/// at or below it, so concurrent sync writers share one fsync.
+///
+/// Started on a `SharedRuntime`, the fsyncs run as passes on the runtime's
+/// threads instead, one pass at a time.
+#[derive(Debug)]
+pub(crate) struct SyncCoordinator {
+    shared: Arc<Shared>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    runtime: Option<RuntimeSync>,
+}
+
+struct RuntimeSync {
+    runtime: SharedRuntime,
+    active_file: Arc<RwLock<FileHandle>>,
+    // Keeps the interval flush registered with the runtime
+    _interval: Option<Arc<Task>>,
+}
+
+impl std::fmt::Debug for RuntimeSync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RuntimeSync")
+            .field("runtime", &self.runtime)
+            .finish()
+    }
+}
+
+impl SyncCoordinator {
+    /// Starts the sync thread. Data already in the active file counts as
+    /// durable. With an `interval`, the thread also flushes unsynced writes
+    /// that often.
+    pub(crate) fn start(
+        active_file: Arc<RwLock<FileHandle>>,
+        interval: Option<Duration>,
+    ) -> Result<Self> {
+        let shared = Self::shared_state(&active_file);
+        let worker_shared = shared.clone();
+        let worker = thread::Builder::new()
+            .name("zap-sync".to_string())
+            .spawn(move || run(&worker_shared, &active_file, interval))?;
+
+        Ok(Self {
+            shared,
+            worker: Mutex::new(Some(worker)),
+            runtime: None,
+        })
+    }
+
+    /// Like `start`, running the fsyncs and the interval flush on `runtime`
+    /// rather than a thread of their own.
+    pub(crate) fn start_on(
+        active_file: Arc<RwLock<FileHandle>>,
+        interval: Option<Duration>,
+        runtime: &SharedRuntime,
+    ) -> Self {
+        let shared = Self::shared_state(&active_file);
+        let interval = interval.map(|interval| {
+            let shared = shared.clone();
+            let active_file = active_file.clone();
+            let task: Arc<Task> = Arc::new(move || run_pass(&shared, &active_file, true));
+            runtime.every(interval, &task);
+            task
+        });
+
+        Self {
+            shared,
+            worker: Mutex::new(None),
+            runtime: Some(RuntimeSync {
+                runtime: runtime.clone(),
+                active_file,
+                _interval: interval,
+            }),
+        }
+    }
+
+    // Data already in the active file counts as durable
+    fn shared_state(active_file: &RwLock<FileHandle>) -> Arc<Shared> {
+        let shared = Arc::new(Shared::default());
+        {
+            let read_guard = active_file.read();
+            let mut state = shared.state.lock();
+            state.durable = (read_guard.get_file_id(), read_guard.get_offset());
+            state.requested = state.durable;
+        }
+        shared
+    }
+
+    /// Blocks until everything up to `position` has been fsynced.
+    pub(crate) fn wait_durable(&self, position: Position) -> Result<()> {
+        let mut state = self.shared.state.lock();
+        if state.requested < position {
+            state.requested = position;
+            match &self.runtime {
+                Some(sync) if !state.scheduled && !state.shutdown => {
+                    state.scheduled = true;
+                    let shared = self.shared.clone();
+                    let active_file = sync.active_file.clone();
+                    sync.runtime
+                        .spawn(move || run_pass(&shared, &active_file, false));
+                }
+                Some(_) => {}
+                None => {
+                    self.shared.work.notify_one();
+                }
+            }
+        }
+        loop {
+            if state.durable >= position {
+                return Ok(());
+            }
+            if let Some(reason) = &state.failed {
+                return Err(Error::Io(io::Error::other(reason.clone())));
+            }
+            if state.shutdown {
+                return Err(Error::Unsupported("Sync thread has stopped".to_string()));
+            }
+            self.shared.done.wait(&mut state);
+        }
+    }
+
+    /// Stops the sync thread once it's done with the fsync under way.
+    /// Positions already durable can still be waited on. Returns whether the
+    /// thread was running, always false on a runtime.
+    pub(crate) fn stop(&self) -> bool {
+        let mut state = self.shared.state.lock();
+        state.shutdown = true;
+        // A pass on the runtime may still hold the active file
+        while state.scheduled {
+            self.shared.done.wait(&mut state);
+        }
+        drop(state);
+        self.shared.work.notify_one();
+        match self.worker.lock().take() {
+            Some(worker) => {
+                let _ = worker.join();
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn sync_count(&self) -> u64 {
+        self.shared.state.lock().sync_count
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_thread(&self) -> bool {
+        self.worker.lock().is_some()
+    }
+}
+
+impl Drop for SyncCoordinator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(shared: &Shared, active_file: &RwLock<FileHandle>, interval: Option<Duration>) {
+    let mut state = shared.state.lock();
+    while !state.shutdown {
+        if state.requested > state.durable && state.failed.is_none() {
+            sync_active_file(shared, &mut state, active_file);
+            continue;
+        }
+        match interval {
+            Some(interval) => {
+                let timed_out = shared.work.wait_for(&mut state, interval).timed_out();
+                if timed_out && !state.shutdown && state.failed.is_none() {
+                    sync_active_file(shared, &mut state, active_file);
+                }
+            }
+            None => shared.work.wait(&mut state),
+        }
+    }
+    // Release writers still waiting
+    shared.done.notify_all();
+}
+
+// One round of syncing on a runtime: fsyncs until no writer is waiting, and
+// once regardless for the interval flush. Interval passes are skipped while
+// another pass is scheduled, since it covers them.
+fn run_pass(shared: &Shared, active_file: &RwLock<FileHandle>, periodic: bool) {
+    let mut state = shared.state.lock();
+    if periodic {
+        if state.scheduled {
+            return;
+        }
+        state.scheduled = true;
+        if !state.shutdown && state.failed.is_none() {
+            sync_active_file(shared, &mut state, active_file);
+        }
+    }
+    while state.requested > state.durable && state.failed.is_none() && !state.shutdown {
+        sync_active_file(shared, &mut state, active_file);
+    }
+    state.scheduled = false;
+    shared.done.notify_all();
+}
+
+fn sync_active_file(
+    shared: &Shared,
+    state: &mut MutexGuard<'_, SyncState>,
+    active_file: &RwLock<FileHandle>,
+) {
+    let durable = state.durable;
+    let result = MutexGuard::unlocked(state, || {
+        // Clone the handle so writers aren't blocked for the duration of the
+        // fsync; everything written before this point is covered by it
+        let file = active_file.read().clone();
+        let position = (file.get_file_id(), file.get_offset());
+        if position <= durable {
+            return Ok(None);
+        }
+        file.sync().map(|_| Some(position))
+    });
+
+    match result {
+        Ok(Some(position)) => {
+            state.durable = state.durable.max(position);
+            state.sync_count += 1;
+        }
+        Ok(None) => {}
+        Err(e) => state.failed = Some(e.to_string()),
+    }
+    shared.done.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatchOptions;
+    use crate::db::Db;
+    use crate::*;
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_mixed_sync_writers() -> Result<()> {
+        let mut opts = Opts::new(
+            256,
+            1024,
+            false,
+            true,
+            "/tmp/test_mixed_sync_writers".to_string(),
+            64 * 1024,
+        );
+        let _ = std::fs::remove_dir_all(&opts.dir_path);
+        opts.sync_interval = Some(Duration::from_millis(5));
+        let db = Db::open(&opts)?;
+        let sync_writes = AtomicU64::new(0);
+
+        thread::scope(|s| {
+            for t in 0..8 {
+                let db = &db;
+                let sync_writes = &sync_writes;
+                s.spawn(move || {
+                    let sync = t % 2 == 0;
+                    let mut last_durable = (0, 0);
+                    for i in 0..200 {
+                        let batch = db
+                            .new_write_batch(WriteBatchOptions {
+                                max_batch_num: 10,
+                                sync_writes: sync,
+                            })
+                            .unwrap();
+                        let key = Bytes::from(format!("key-{}-{}", t, i));
+                        batch
+                            .put(key.clone(), Bytes::from(format!("value-{}", i)))
+                            .unwrap();
+                        batch.commit().unwrap();
+
+                        let durable = db.shards[0].syncer.shared.state.lock().durable;
+                        assert!(durable >= last_durable);
+                        last_durable = durable;
+                        if sync {
+                            sync_writes.fetch_add(1, Ordering::Relaxed);
+                            // Once acknowledged, a sync write and every write
+                            // before it are durable
+                            let entry = db.locate(&key).unwrap();
+                            let end = entry.get_offset() + entry.get_size() as u64;
+                            assert!(durable > (entry.get_file_id(), end));
+                        }
+                    }
+                });
+            }
+        });
+
+        // Concurrent sync writers shared fsyncs
+        assert!(db.shards[0].syncer.sync_count() < sync_writes.load(Ordering::Relaxed));
+        for t in 0..8 {
+            for i in 0..200 {
+                assert_eq!(
+                    db.get(Bytes::from(format!("key-{}-{}", t, i)))?,
+                    format!("value-{}", i).into_bytes()
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! Serde-encoded keys and values over a shared `Db`.
+//!
+//! A `TypedDb<K, V>` encodes keys and values with a `Codec`, bincode unless
+//! another is picked, and stores the encoded bytes. The key and value size
+//! limits apply to the encoded bytes. A key or value that doesn't decode as
+//! `K` or `V` fails with `Error::Decode`.
+
+use crate::db::Db;
+use crate::{Error, Result};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt, marker::PhantomData, sync::Arc, vec};
+
+/// A way of turning serde types into bytes and back.
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// bincode's default encoding: compact, but neither self-describing nor
+/// ordered like the values it encodes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| Error::Unsupported(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|e| Error::Decode(e.to_string()))
+    }
+}
+
+/// JSON, for data that other tools read too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| Error::Unsupported(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| Error::Decode(e.to_string()))
+    }
+}
+
+// Marks the types without owning values of them, so they needn't be `Send`
+// or `Sync` for the wrappers to be
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// A `Db` whose keys are `K`s and values `V`s, encoded with `C`.
+pub struct TypedDb<K, V, C = Bincode> {
+    db: Arc<Db>,
+    _types: Types<K, V, C>,
+}
+
+impl<K, V, C> TypedDb<K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn new(db: Arc<Db>) -> Self {
+        TypedDb {
+            db,
+            _types: PhantomData,
+        }
+    }
+
+    /// The database underneath, for the untyped operations.
+    pub fn db(&self) -> &Arc<Db> {
+        &self.db
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        let key = C::encode(key)?;
+        let value = C::encode(value)?;
+        self.db.put(Bytes::from(key), Bytes::from(value))
+    }
+
+    /// The value of `key`, `None` if there is none.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = C::encode(key)?;
+        match self.db.get_seq(Bytes::from(key))? {
+            Some((value, _)) => C::decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.db.delete(Bytes::from(C::encode(key)?))
+    }
+
+    /// Iterates over every pair, in the order of the encoded keys. The keys
+    /// are listed when this is called; keys deleted since are skipped and
+    /// values are read as they are when reached.
+    pub fn iter(&self) -> Result<TypedIter<K, V, C>> {
+        let mut keys = self.db.list_keys()?;
+        keys.sort();
+        Ok(TypedIter {
+            db: self.db.clone(),
+            keys: keys.into_iter(),
+            _types: PhantomData,
+        })
+    }
+}
+
+impl<K, V, C> Clone for TypedDb<K, V, C> {
+    fn clone(&self) -> Self {
+        TypedDb {
+            db: self.db.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, C> fmt::Debug for TypedDb<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedDb").field("db", &self.db).finish()
+    }
+}
+
+/// Iterator returned by `TypedDb::iter`, yielding decoded `(K, V)` pairs.
+pub struct TypedIter<K, V, C = Bincode> {
+    db: Arc<Db>,
+    keys: vec::IntoIter<Bytes>,
+    _types: Types<K, V, C>,
+}
+
+impl<K, V, C> Iterator for TypedIter<K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next()?;
+            let value = match self.db.get_seq(key.clone()) {
+                Ok(Some((value, _))) => value,
+                // Deleted since the keys were listed
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            return Some(C::decode(&key).and_then(|key| Ok((key, C::decode(&value)?))));
+        }
+    }
+}
+
+impl<K, V, C> fmt::Debug for TypedIter<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedIter")
+            .field("remaining", &self.keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opts;
+    use serde::Deserialize;
+    use std::fs;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct UserId {
+        tenant: String,
+        id: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Address {
+        city: String,
+        zip: Option<u32>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        addresses: Vec<Address>,
+        tags: std::collections::BTreeMap<String, i32>,
+    }
+
+    // `User` as it was before it grew `addresses` and `tags`
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OldUser {
+        name: String,
+        age: u8,
+    }
+
+    fn open(name: &str) -> Result<Arc<Db>> {
+        let opts = Opts::new(
+            256,
+            128,
+            false,
+            false,
+            format!("/tmp/test_typed_{}", name),
+            1024 * 1024,
+        );
+        let _ = fs::remove_dir_all(&opts.dir_path);
+        Ok(Arc::new(Db::open(&opts)?))
+    }
+
+    fn sample_user(i: u64) -> (UserId, User) {
+        let id = UserId {
+            tenant: "acme".to_string(),
+            id: i,
+        };
+        let user = User {
+            name: format!("user{}", i),
+            addresses: vec![
+                Address {
+                    city: "Oslo".to_string(),
+                    zip: Some(150),
+                },
+                Address {
+                    city: "Bergen".to_string(),
+                    zip: None,
+                },
+            ],
+            tags: [("level".to_string(), i as i32)].into_iter().collect(),
+        };
+        (id, user)
+    }
+
+    fn round_trip<C: Codec>(db: Arc<Db>) -> Result<()> {
+        let users = TypedDb::<UserId, User, C>::new(db);
+        for i in 0..3 {
+            let (id, user) = sample_user(i);
+            users.put(&id, &user)?;
+        }
+        let (id, user) = sample_user(1);
+        assert_eq!(users.get(&id)?, Some(user));
+        users.delete(&id)?;
+        assert_eq!(users.get(&id)?, None);
+
+        let pairs = users.iter()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 2);
+        for (id, user) in pairs {
+            assert_eq!((id.clone(), user), sample_user(id.id));
+        }
+
+        // The size limit applies to the encoded value
+        let (id, mut user) = sample_user(3);
+        user.name = "x".repeat(100);
+        assert!(matches!(users.put(&id, &user), Err(Error::Unsupported(_))));
+        assert_eq!(users.get(&id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        round_trip::<Bincode>(open("bincode")?)?;
+        round_trip::<Json>(open("json")?)?;
+
+        // JSON values are stored as text
+        let db = open("json_text")?;
+        let users = TypedDb::<String, Address, Json>::new(db.clone());
+        let address = Address {
+            city: "Oslo".to_string(),
+            zip: None,
+        };
+        users.put(&"home".to_string(), &address)?;
+        assert_eq!(
+            db.get(Bytes::from("\"home\""))?,
+            r#"{"city":"Oslo","zip":null}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_mismatch() -> Result<()> {
+        let db = open("mismatch")?;
+        let old = TypedDb::<u64, OldUser>::new(db.clone());
+        old.put(
+            &7,
+            &OldUser {
+                name: "old".to_string(),
+                age: 30,
+            },
+        )?;
+
+        let new = TypedDb::<u64, User>::new(db.clone());
+        assert!(matches!(new.get(&7), Err(Error::Decode(_))));
+        assert!(matches!(new.iter()?.next(), Some(Err(Error::Decode(_)))));
+        // Keys of the wrong type fail the same way
+        let by_name = TypedDb::<UserId, OldUser>::new(db);
+        assert!(matches!(
+            by_name.iter()?.next(),
+            Some(Err(Error::Decode(_)))
+        ));
+        // The old type still reads it
+        assert_eq!(old.get(&7)?.unwrap().age, 30);
+        Ok(())
+    }
+}